@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use miltr_client::fuzzing::fuzz_decode_frames;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_decode_frames(data);
+});
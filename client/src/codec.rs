@@ -2,8 +2,9 @@ use asynchronous_codec::{Decoder, Encoder};
 use bytes::{Buf, BufMut, BytesMut};
 
 use miltr_common::decoding::ServerCommand;
-use miltr_common::encoding::{ClientMessage, Writable};
-use miltr_common::ProtocolError;
+use miltr_common::encoding::{frame_len, ClientMessage, Writable};
+use miltr_common::frame::{self, FrameHooks, FrameSizes, FrameTimer};
+use miltr_common::{ProtocolError, TooMuchData};
 use miltr_utils::trace;
 
 // The `MilterCodec` is responsible for decoding from and encoding to bits on
@@ -13,11 +14,36 @@ use miltr_utils::trace;
 #[derive(Debug, Clone)]
 pub(crate) struct MilterCodec {
     max_buffer_size: usize,
+    pub(crate) hooks: FrameHooks,
+    pub(crate) decode_timer: FrameTimer,
+    pub(crate) encode_timer: FrameTimer,
+    pub(crate) frame_sizes: FrameSizes,
+    pub(crate) strict_lengths: bool,
 }
 
 impl MilterCodec {
     pub(crate) fn new(max_buffer_size: usize) -> Self {
-        Self { max_buffer_size }
+        Self {
+            max_buffer_size,
+            hooks: FrameHooks::default(),
+            decode_timer: default_timer(),
+            encode_timer: default_timer(),
+            frame_sizes: FrameSizes::default(),
+            strict_lengths: false,
+        }
+    }
+
+    pub(crate) fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+}
+
+/// Sample codec timings only if they can be traced
+fn default_timer() -> FrameTimer {
+    if cfg!(feature = "tracing") {
+        FrameTimer::default()
+    } else {
+        FrameTimer::new(0)
     }
 }
 
@@ -40,13 +66,15 @@ impl Decoder for MilterCodec {
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
         if length > self.max_buffer_size {
-            return Err(ProtocolError::TooMuchData(length));
+            return Err(
+                TooMuchData::decoding(src.get(4).copied(), length, self.max_buffer_size).into(),
+            );
         }
 
         // If arrived data is smaller than 4 bytes of length marker + the
         // decoded length, we need more data.
         if src.len() < 4 + length {
-            src.reserve(4 + length - src.len());
+            self.frame_sizes.reserve(src, 4 + length);
             return Ok(None);
         }
 
@@ -54,10 +82,29 @@ impl Decoder for MilterCodec {
         // this frame.
         let mut parse_buf = src.split_to(4 + length);
         parse_buf.advance(4);
+        self.frame_sizes.record(length);
 
         trace!(length = parse_buf.len(), "Read bytes from the network");
+        if let Some(&code) = parse_buf.first() {
+            self.hooks.notify_received(code, length);
+        }
+
+        if self.strict_lengths {
+            frame::check_min_len(&parse_buf)?;
+        }
+
+        #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+        let (command, elapsed) = self.decode_timer.time(|| ServerCommand::parse(parse_buf));
+        #[cfg(feature = "tracing")]
+        if let (Ok(command), Some(elapsed)) = (&command, elapsed) {
+            trace!(
+                code = command.code(),
+                decode_ns = elapsed.as_nanos() as u64,
+                "Decoded frame"
+            );
+        }
 
-        Ok(Some(ServerCommand::parse(parse_buf)?))
+        Ok(Some(command?))
     }
 }
 
@@ -68,14 +115,7 @@ impl Encoder for MilterCodec {
     fn encode(&mut self, item: &ClientMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a string if it is longer than the other end will
         // accept or  larger than we will be able to compute.
-        let item_len = item.len();
-        if item_len > self.max_buffer_size || item_len > usize::MAX - 1 {
-            return Err(ProtocolError::TooMuchData(item_len));
-        }
-
-        let packet_len = 1_usize // single character code
-            .checked_add(item_len) // The rest of the stuff
-            .ok_or(ProtocolError::TooMuchData(item_len))?;
+        let packet_len = frame_len(item, self.max_buffer_size)?;
 
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
@@ -87,9 +127,19 @@ impl Encoder for MilterCodec {
         // Write the length, code and string to the buffer.
         dst.extend_from_slice(&packet_len_be);
         dst.put_u8(item.code());
-        item.write(dst);
+        #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+        let ((), elapsed) = self.encode_timer.time(|| item.write(dst));
+        #[cfg(feature = "tracing")]
+        if let Some(elapsed) = elapsed {
+            trace!(
+                code = item.code(),
+                encode_ns = elapsed.as_nanos() as u64,
+                "Encoded frame"
+            );
+        }
 
         trace!(length = dst.len(), "Wrote bytes to the network");
+        self.hooks.notify_sent(item.code(), packet_len);
 
         Ok(())
     }
@@ -98,6 +148,7 @@ impl Encoder for MilterCodec {
 #[cfg(test)]
 mod test {
     use super::*;
+    use miltr_common::test_utils::check_decode_invariants;
 
     #[test]
     fn test_fuzz_1() {
@@ -108,4 +159,80 @@ mod test {
             .decode(&mut input)
             .expect_err("This is not enough data");
     }
+
+    #[test]
+    fn test_decode_invariants_fuzz_corpus() {
+        let corpus: [&[u8]; 5] = [
+            &[
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, b'f', b'f', 0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+            &[0, 0, 0, 5, 67, 58, 255, 1, 0],
+            &[
+                0, 0, 0, 21, 67, 230, 186, 186, 186, 186, 42, 255, 255, 255, 255, 255, 255, 255,
+                255, 255, 255, 186, 0, 52, 72, 255,
+            ],
+            &[0, 0, 0, 4, 109, 255, 255, 7],
+            // Two complete frames followed by an incomplete one
+            &[0, 0, 0, 1, b'c', 0, 0, 0, 1, b'c', 0, 0, 0, 1],
+        ];
+
+        for input in corpus {
+            let mut codec = MilterCodec::new(2_usize.pow(16));
+            check_decode_invariants(input, |buffer| codec.decode(buffer));
+        }
+    }
+
+    #[test]
+    fn test_decode_one_frame_per_call() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 1, b'c', 0, 0, 0, 1, b'c', 0, 0]);
+
+        let command = codec
+            .decode(&mut buffer)
+            .expect("Failed decoding")
+            .expect("Complete frame not decoded");
+
+        assert!(matches!(command, ServerCommand::Continue(_)));
+        assert_eq!(&buffer[..], &[0, 0, 0, 1, b'c', 0, 0]);
+    }
+
+    #[test]
+    fn test_decode_length_zero() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 0, 0, 0, 0, 1, b'c']);
+
+        let err = codec
+            .decode(&mut buffer)
+            .expect_err("Frame without code decoded");
+
+        assert!(matches!(err, ProtocolError::NotEnoughData(_)));
+        assert_eq!(&buffer[..], &[0, 0, 0, 1, b'c']);
+    }
+
+    #[test]
+    fn test_decode_length_one_unknown_code() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 1, 0xff]);
+
+        let err = codec.decode(&mut buffer).expect_err("Unknown code decoded");
+
+        assert!(matches!(err, ProtocolError::InvalidData(_)));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_length_max() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0xff, 0xff, 0xff, 0xff, b'c']);
+
+        let err = codec
+            .decode(&mut buffer)
+            .expect_err("Oversized frame accepted");
+
+        let ProtocolError::TooMuchData(err) = err else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(err.len, u32::MAX as usize);
+        assert_eq!(&buffer[..], &[0xff, 0xff, 0xff, 0xff, b'c']);
+    }
 }
@@ -1,38 +1,74 @@
 #![doc = include_str!("../Readme.md")]
 
 mod codec;
+mod milter_connection;
+mod policy;
+mod quorum;
+mod stats;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 #[cfg(feature = "_fuzzing")]
 pub mod fuzzing;
 
-use std::{ops::Deref, sync::Arc};
+use std::{
+    io,
+    ops::Deref,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
 
 use asynchronous_codec::Framed;
-use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
-use miltr_utils::debug;
+use futures::{
+    future::{self, Either},
+    AsyncRead, AsyncWrite, Future, FutureExt, SinkExt, StreamExt,
+};
+use miltr_utils::{debug, warn};
 use paste::paste;
 use thiserror::Error;
 #[cfg(feature = "tracing")]
-use tracing::{instrument, Level};
+use tracing::{instrument, Instrument, Level, Span};
 
 use miltr_common::{
-    actions::{Abort, Action, Quit},
+    actions::{Abort, Action, Continue, Progress, Quit, QuitNc},
+    clock::{Clock, SystemClock},
+    codes,
     commands::{
-        Body, Command, Connect, Data, EndOfBody, EndOfHeader, Header, Helo, Mail, Recipient,
+        Body, Command, Connect, Data, EndOfBody, EndOfHeader, Header, Helo, Macro, Mail, Recipient,
         Unknown,
     },
     decoding::ServerCommand,
+    encoding::{ClientMessage, Limits, Writable},
+    frame::{FrameInfo, FrameSizes},
+    macros::well_known::MILTR_DEADLINE,
     modifications::{ModificationAction, ModificationResponse},
-    optneg::{CompatibilityError, OptNeg},
+    optneg::{Capability, CompatibilityError, OptNeg, Protocol},
     ProtocolError,
 };
 
 use self::codec::MilterCodec;
+pub use self::milter_connection::MilterConnection;
+pub use self::policy::EarlyModificationPolicy;
+pub use self::quorum::{Aggregation, BackendError, MilterQuorum, Verdict};
+pub use self::stats::ConnectionStats;
 
 /// A milter client using some options and a codec to talk to a milter server
 pub struct Client {
     options: Arc<OptNeg>,
     codec: MilterCodec,
+    pipeline_window: usize,
+    max_messages: Option<u64>,
+    max_modifications: usize,
+    max_modification_bytes: usize,
+    required_capabilities: Capability,
+    required_protocol: Protocol,
+    drift_error: bool,
+    early_modification_policy: EarlyModificationPolicy,
+    clock: Arc<dyn Clock>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    snapshot: Mutex<Option<OptNeg>>,
 }
 
 /// A single milter connection
@@ -76,6 +112,72 @@ pub struct Client {
 pub struct Connection<RW: AsyncRead + AsyncWrite + Unpin> {
     framed: Framed<RW, MilterCodec>,
     options: OptNeg,
+    pipeline_window: usize,
+    pending_responses: usize,
+    /// A mail was started but its end of body not yet answered
+    in_message: bool,
+    max_messages: Option<u64>,
+    /// Quit was sent after the last message allowed
+    limit_reached: bool,
+    max_modifications: usize,
+    max_modification_bytes: usize,
+    early_modification_policy: EarlyModificationPolicy,
+    /// Modifications received before end of body, to attach to its response
+    early_modifications: Vec<ModificationAction>,
+    clock: Arc<dyn Clock>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    /// A read or write timed out, the state of the conversation is unknown
+    timed_out: bool,
+    /// Count and bytes of the modifications that exceeded the limits, the
+    /// rest of that response is unread
+    overrun: Option<(usize, usize)>,
+    /// When the current message must be handled, see
+    /// [`Connection::set_message_deadline`]
+    deadline: Option<Instant>,
+    /// The span to trace commands within, none for the current one
+    #[cfg(feature = "tracing")]
+    parent: Span,
+    stats: ConnectionStats,
+}
+
+/// A connection after [`Connection::quit_nc`], ready for the next session
+pub struct ReusableConnection<RW: AsyncRead + AsyncWrite + Unpin> {
+    connection: Connection<RW>,
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> ReusableConnection<RW> {
+    /// The options negotiated for the previous session
+    #[must_use]
+    pub fn options(&self) -> &OptNeg {
+        &self.connection.options
+    }
+
+    /// Start the next session with the options negotiated before
+    #[must_use]
+    pub fn reuse(self) -> Connection<RW> {
+        self.connection
+    }
+
+    /// Start the next session negotiating the options of `client`
+    ///
+    /// # Errors
+    /// This fails if an io-error is experienced or option negotiation fails
+    pub async fn renegotiate(self, client: &Client) -> Result<Connection<RW>, ResponseError> {
+        let mut connection = self.connection;
+        connection.options = client
+            .recv_option_negotiation(&mut connection.framed)
+            .await?;
+        connection.pipeline_window = client.pipeline_window;
+        connection.max_messages = client.max_messages;
+        connection.max_modifications = client.max_modifications;
+        connection.max_modification_bytes = client.max_modification_bytes;
+        connection.clock = Arc::clone(&client.clock);
+        connection.read_timeout = client.read_timeout;
+        connection.write_timeout = client.write_timeout;
+
+        Ok(connection)
+    }
 }
 
 impl Client {
@@ -88,9 +190,212 @@ impl Client {
         Self {
             options: Arc::new(options),
             codec,
+            pipeline_window: 1,
+            max_messages: None,
+            max_modifications: usize::MAX,
+            max_modification_bytes: usize::MAX,
+            required_capabilities: Capability::empty(),
+            required_protocol: Protocol::empty(),
+            drift_error: false,
+            early_modification_policy: EarlyModificationPolicy::default(),
+            clock: SystemClock::shared(),
+            read_timeout: None,
+            write_timeout: None,
+            snapshot: Mutex::new(None),
         }
     }
 
+    /// Fail option negotiation if the server does not agree to all of
+    /// `capabilities`.
+    ///
+    /// Without this, capabilities the server does not support are silently
+    /// dropped during negotiation and the according modifications never
+    /// arrive.
+    #[must_use]
+    pub fn with_required_capabilities(mut self, capabilities: Capability) -> Self {
+        self.required_capabilities = capabilities;
+        self
+    }
+
+    /// Fail option negotiation if the server does not agree to all of the
+    /// `protocol` flags.
+    #[must_use]
+    pub fn with_required_protocol(mut self, protocol: Protocol) -> Self {
+        self.required_protocol = protocol;
+        self
+    }
+
+    /// Fail option negotiation if the options differ from those negotiated
+    /// on the last connection of this client.
+    ///
+    /// By default, a difference is only logged. Either way, reconnecting
+    /// to a server which changed its configuration in between is noticed,
+    /// instead of silently behaving differently mid-traffic.
+    #[must_use]
+    pub fn with_drift_error(mut self, drift_error: bool) -> Self {
+        self.drift_error = drift_error;
+        self
+    }
+
+    /// Set what to do with modifications a server sends before end of body.
+    ///
+    /// By default, the command they arrived in response to fails with
+    /// [`ResponseError::Unexpected`].
+    #[must_use]
+    pub fn with_early_modification_policy(mut self, policy: EarlyModificationPolicy) -> Self {
+        self.early_modification_policy = policy;
+        self
+    }
+
+    /// Fail with [`ResponseError::Timeout`] if the server does not answer
+    /// within `timeout`.
+    ///
+    /// This covers option negotiation and the answer to every command, each
+    /// awaited on its own. The connection is unusable after a timeout, as
+    /// the answer may still arrive, every later call fails with
+    /// [`ResponseError::Timeout`] as well. By default, answers are awaited
+    /// forever.
+    #[must_use]
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with [`ResponseError::Timeout`] if a command can not be written
+    /// within `timeout`, e.g. as the server stopped reading.
+    ///
+    /// The connection is unusable after a timeout, as the command may have
+    /// been written partially, every later call fails as well. By default,
+    /// writes wait forever.
+    #[must_use]
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Take the time for timeouts from `clock` instead of the system clock.
+    ///
+    /// This covers the read and write timeouts, the grace period of
+    /// [`Connection::finish_and_quit`] and the backend timeouts of a
+    /// [`MilterQuorum`] of its connections. Tests
+    /// pass a [`ManualClock`](miltr_common::clock::ManualClock) to let
+    /// these pass without waiting.
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Reject frames shorter than any valid frame of their code before
+    /// parsing them, see [`check_min_len`](miltr_common::frame::check_min_len).
+    ///
+    /// This also rejects frames of length zero. Off by default, leaving
+    /// short frames to the parser of their type.
+    #[must_use]
+    pub fn with_strict_lengths(mut self, strict: bool) -> Self {
+        self.codec.strict_lengths = strict;
+        self
+    }
+
+    /// Reserve read buffer space for several frames at once, see
+    /// [`FrameSizes`]. Enabled by default.
+    #[must_use]
+    pub fn with_adaptive_reserve(mut self, adaptive: bool) -> Self {
+        self.codec.frame_sizes = FrameSizes::new(adaptive);
+        self
+    }
+
+    /// The options negotiated on the last connection of this client
+    #[must_use]
+    pub fn options_snapshot(&self) -> Option<OptNeg> {
+        self.snapshot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Send up to `window` header and body commands without awaiting their
+    /// responses.
+    ///
+    /// By default (a `window` of 1), every command awaits the server's
+    /// `Continue` before the next command is sent. With a larger window,
+    /// headers and body chunks are sent ahead and their responses are
+    /// collected once the window is full or a command that is not a header
+    /// or body chunk is sent. This is similar to what the `NR_*` protocol
+    /// flags achieve, but without requiring the milter server to support
+    /// them.
+    ///
+    /// As responses are collected later, a non-`Continue` response to a
+    /// header or body chunk is returned as an error by a later call.
+    #[must_use]
+    pub fn with_pipeline_window(mut self, window: usize) -> Self {
+        self.pipeline_window = window.max(1);
+        self
+    }
+
+    /// Quit each connection once `max` messages ended on it, to let the
+    /// milter start over with fresh state.
+    ///
+    /// Quit is sent as soon as the last message ended, by its end of body
+    /// or an abort. Starting another one fails with
+    /// [`ResponseError::MessageLimit`], connect anew then. See
+    /// [`Connection::message_limit_reached`].
+    #[must_use]
+    pub fn with_max_messages_per_connection(mut self, max: u64) -> Self {
+        self.max_messages = Some(max);
+        self
+    }
+
+    /// Fail end of body once the server sent more than `count`
+    /// modifications or more than `bytes` encoded bytes of them, with
+    /// [`ResponseError::TooManyModifications`].
+    ///
+    /// Modifications are collected until the final action arrives. Without
+    /// a limit, a rogue server may send them endlessly. Modifications
+    /// attached by [`EarlyModificationPolicy::Attach`] count as well.
+    #[must_use]
+    pub fn with_max_pending_modifications(mut self, count: usize, bytes: usize) -> Self {
+        self.max_modifications = count;
+        self.max_modification_bytes = bytes;
+        self
+    }
+
+    /// Call `hook` for every frame received from the server.
+    ///
+    /// This is called before the frame is decoded, even if decoding fails.
+    #[must_use]
+    pub fn on_frame_received<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&FrameInfo) + Send + Sync + 'static,
+    {
+        self.codec.hooks.received = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` for every frame sent to the server
+    #[must_use]
+    pub fn on_frame_sent<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&FrameInfo) + Send + Sync + 'static,
+    {
+        self.codec.hooks.sent = Some(Arc::new(hook));
+        self
+    }
+
+    /// Trace the time spent decoding and encoding every `every`-th frame,
+    /// as `decode_ns` and `encode_ns` per frame code.
+    ///
+    /// Defaults to [`FrameTimer::DEFAULT_EVERY`], zero disables it.
+    ///
+    /// [`FrameTimer::DEFAULT_EVERY`]: miltr_common::frame::FrameTimer::DEFAULT_EVERY
+    #[cfg(feature = "tracing")]
+    #[must_use]
+    pub fn with_codec_timing(mut self, every: u32) -> Self {
+        self.codec.decode_timer = miltr_common::frame::FrameTimer::new(every);
+        self.codec.encode_timer = miltr_common::frame::FrameTimer::new(every);
+        self
+    }
+
     /// Option negotiate with the server
     ///
     /// The steps are:
@@ -102,12 +407,22 @@ impl Client {
         framed: &mut Framed<RW, MilterCodec>,
     ) -> Result<OptNeg, ResponseError> {
         let client_options = &self.options;
-        framed.send(&client_options.deref().clone().into()).await?;
+        within(
+            &*self.clock,
+            self.write_timeout,
+            Direction::Write,
+            framed.send(&client_options.deref().clone().into()),
+        )
+        .await??;
 
-        let resp = framed
-            .next()
-            .await
-            .ok_or(ResponseError::MissingServerResponse)??;
+        let resp = within(
+            &*self.clock,
+            self.read_timeout,
+            Direction::Read,
+            framed.next(),
+        )
+        .await?
+        .ok_or(ResponseError::MissingServerResponse)??;
 
         let server_options = match resp {
             ServerCommand::OptNeg(optneg) => Ok(optneg),
@@ -115,10 +430,34 @@ impl Client {
         }?;
 
         let options = server_options.merge_compatible(&self.options)?;
+        options.require(self.required_capabilities, self.required_protocol)?;
+        self.check_drift(&options)?;
 
         Ok(options)
     }
 
+    /// Compare `options` to the snapshot of the last negotiation and
+    /// replace it
+    fn check_drift(&self, options: &OptNeg) -> Result<(), CompatibilityError> {
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(drift) = snapshot
+            .as_ref()
+            .and_then(|previous| options.drift(previous))
+        {
+            if self.drift_error {
+                return Err(CompatibilityError::Drift(drift));
+            }
+            warn!(
+                ?drift,
+                "Negotiated options changed since the last connection"
+            );
+        }
+        *snapshot = Some(options.clone());
+
+        Ok(())
+    }
+
     /// Handle a single milter connection via the provided RW connection
     ///
     /// # Errors
@@ -131,10 +470,62 @@ impl Client {
         let mut framed = Framed::new(connection, codec);
         let options = self.recv_option_negotiation(&mut framed).await?;
 
-        let connection = Connection { framed, options };
+        Ok(self.connection(framed, options))
+    }
 
+    /// Set up a connection like [`Self::connect_via`], tracing it within
+    /// `parent` instead of the current span.
+    ///
+    /// Commands sent later on the connection are traced within `parent`
+    /// as well, wherever they are sent from. An OpenTelemetry context is
+    /// attached by setting it as parent of `parent` first, e.g. with
+    /// `tracing-opentelemetry`.
+    ///
+    /// # Errors
+    /// As [`Self::connect_via`]
+    #[cfg(feature = "tracing")]
+    pub async fn connect_via_in_span<RW: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        connection: RW,
+        parent: &Span,
+    ) -> Result<Connection<RW>, ResponseError> {
+        let mut connection = self
+            .connect_via(connection)
+            .instrument(parent.clone())
+            .await?;
+        connection.parent = parent.clone();
         Ok(connection)
     }
+
+    /// Set up a connection on `framed` with the negotiated `options`
+    pub(crate) fn connection<RW: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        framed: Framed<RW, MilterCodec>,
+        options: OptNeg,
+    ) -> Connection<RW> {
+        Connection {
+            framed,
+            options,
+            pipeline_window: self.pipeline_window,
+            pending_responses: 0,
+            in_message: false,
+            max_messages: self.max_messages,
+            limit_reached: false,
+            max_modifications: self.max_modifications,
+            max_modification_bytes: self.max_modification_bytes,
+            early_modification_policy: self.early_modification_policy,
+            early_modifications: Vec::new(),
+            clock: Arc::clone(&self.clock),
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            timed_out: false,
+            overrun: None,
+            deadline: None,
+            #[cfg(feature = "tracing")]
+            parent: Span::none(),
+            stats: ConnectionStats::default(),
+        }
+    }
 }
 
 macro_rules! command {
@@ -204,6 +595,67 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
         (into) Recipient
     );
 
+    /// Send multiple recipients at once.
+    ///
+    /// Contrary to calling [`Connection::recipient`] for each recipient,
+    /// all recipients are written to the server before any response is
+    /// awaited. This saves a round-trip per recipient. The milter server
+    /// needs to process commands in order (as libmilter and `miltr-server`
+    /// do) for this to work.
+    ///
+    /// The server is allowed to answer each recipient differently, so the
+    /// answer for each recipient is returned in order instead of erroring on
+    /// anything that is not `Continue`.
+    ///
+    /// If no reply is expected for recipients due to
+    /// [`Protocol::NR_RECIPIENT`](miltr_common::optneg::Protocol::NR_RECIPIENT),
+    /// `Continue` is reported for each recipient. If recipients are not to be
+    /// sent at all, an empty `Vec` is returned.
+    ///
+    /// # Errors
+    /// Errors on io or codec errors or if the server responds with something
+    /// other than an [`Action`].
+    pub async fn recipients<I, R>(&mut self, recipients: I) -> Result<Vec<Action>, ResponseError>
+    where
+        I: IntoIterator<Item = R>,
+        R: Into<Recipient>,
+    {
+        if self.limit_reached {
+            return Err(ResponseError::MessageLimit);
+        }
+        self.ensure_usable()?;
+        self.settle_pending().await?;
+
+        let mut sent = 0_usize;
+        for recipient in recipients {
+            let command: Command = recipient.into().into();
+            if self.options.protocol.should_skip_send(&command) {
+                debug!("Skip sending");
+                self.stats.skipped += 1;
+                return Ok(Vec::new());
+            }
+            self.in_message = true;
+            self.feed_deadline(command.code()).await?;
+            self.feed_frame(command.into()).await?;
+            self.stats.sent += 1;
+            sent += 1;
+        }
+        self.flush_frames().await?;
+
+        if self.options.protocol.contains(Protocol::NR_RECIPIENT) {
+            debug!("Skip receiving responses");
+            self.stats.unanswered += sent as u64;
+            return Ok(vec![Continue.into(); sent]);
+        }
+
+        let mut statuses = Vec::with_capacity(sent);
+        for _ in 0..sent {
+            statuses.push(self.receive_action().await?);
+        }
+
+        Ok(statuses)
+    }
+
     command!(
         /// Indicate that data follows
         ///
@@ -231,14 +683,24 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
         (new) EndOfHeader
     );
 
-    command!(
-        /// Send a body part
-        ///
-        ///
-        /// # Errors
-        /// Errors on any response from the milter server that is not Continue
-        (into) Body
-    );
+    /// Send a body part.
+    ///
+    /// Empty parts are not sent, MTAs never send them and milters may not
+    /// expect them. A message without body goes straight to
+    /// [`Connection::end_of_body`].
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    pub async fn body<C: Into<Body>>(&mut self, command: C) -> Result<(), ResponseError> {
+        let body: Body = command.into();
+        if body.as_bytes().is_empty() {
+            debug!("Skip sending empty body part");
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+
+        self.send_command(body.into()).await
+    }
 
     // command!(
     //     /// Indicate all body parts have been sent
@@ -250,14 +712,40 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
 
     /// Indicate all body parts have been sent
     ///
+    /// Progress notifications the server sends while working on the
+    /// message are counted in [`ConnectionStats::progress`] and otherwise
+    /// ignored, the answer is awaited regardless.
+    ///
     /// # Errors
-    /// Errors on any response from the milter server that is not Continue
+    /// Errors on any response from the milter server that is not Continue,
+    /// or if it sent more modifications than allowed by
+    /// [`Client::with_max_pending_modifications`]. The rest of the response
+    /// is not read then, the connection is unusable.
     pub async fn end_of_body(&mut self) -> Result<ModificationResponse, ResponseError> {
+        if self.limit_reached {
+            return Err(ResponseError::MessageLimit);
+        }
+        self.ensure_usable()?;
+        self.settle_pending().await?;
+
         // First, send the eob command
         let command: Command = EndOfBody.into();
-        self.framed.send(&command.into()).await?;
+        self.in_message = true;
+        self.feed_deadline(command.code()).await?;
+        self.send_frame(command.into()).await?;
+        self.stats.sent += 1;
 
         let mut modification_response_builder = ModificationResponse::builder();
+        let mut count = 0;
+        let mut bytes = 0;
+        for action in std::mem::take(&mut self.early_modifications) {
+            count += 1;
+            bytes += action.len();
+            if self.exceeds_modification_limit(count, bytes) {
+                return Err(self.overrun(count, bytes));
+            }
+            modification_response_builder.push(action);
+        }
         loop {
             // Receive a response from the server
             let answer = self.receive_answer().await?;
@@ -267,20 +755,49 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
 
             match command {
                 CommandType::Action(action) => {
+                    self.stats.acknowledged += 1;
+                    self.finish_message().await?;
                     return Ok(modification_response_builder.build(action));
                 }
+                CommandType::Progress(_) => {
+                    debug!("Server is still working on the message");
+                    self.stats.progress += 1;
+                }
                 CommandType::ModificationAction(action) => {
+                    count += 1;
+                    bytes += action.len();
+                    if self.exceeds_modification_limit(count, bytes) {
+                        return Err(self.overrun(count, bytes));
+                    }
                     modification_response_builder.push(action);
                 }
-            };
+            }
         }
     }
 
+    /// Whether `count` modifications of `bytes` exceed the limits of
+    /// [`Client::with_max_pending_modifications`]
+    fn exceeds_modification_limit(&self, count: usize, bytes: usize) -> bool {
+        let exceeded = count > self.max_modifications || bytes > self.max_modification_bytes;
+        if exceeded {
+            warn!(count, bytes, "Server sent too many modifications");
+        }
+        exceeded
+    }
+
+    /// Mark this connection unusable after too many modifications, see
+    /// [`Client::with_max_pending_modifications`]
+    fn overrun(&mut self, count: usize, bytes: usize) -> ResponseError {
+        self.overrun = Some((count, bytes));
+        ResponseError::TooManyModifications { count, bytes }
+    }
+
     /// Receive all modification requests from the server
     ///
     /// # Errors
     /// Errors on error regarding server communication
     pub async fn modification(&mut self) -> Result<CommandType, ResponseError> {
+        self.ensure_usable()?;
         let resp = self.receive_answer().await?;
 
         CommandType::try_from(resp)
@@ -289,31 +806,197 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
     /// Ask for a graceful connection shutdown
     ///
     /// # Errors
-    /// Errors on io or codec Errors
+    /// Errors on io or codec Errors, with an io error of kind
+    /// [`io::ErrorKind::TimedOut`] once a read or write timed out
     pub async fn quit(mut self) -> Result<(), ProtocolError> {
-        self.framed.send(&Action::Quit(Quit).into()).await?;
-
-        Ok(())
+        if self.limit_reached {
+            return Ok(());
+        }
+        self.send_frame(Action::Quit(Quit).into())
+            .await
+            .map_err(into_protocol_error)
     }
 
     /// Ask to re-use this connection for a new mail
     ///
+    /// The server does not answer this. The returned handle starts the next
+    /// session, either with the options negotiated before or negotiating
+    /// them again.
+    ///
     /// # Errors
-    /// Errors on any response from the milter server that is not Continue
-    pub fn quit_nc(self) -> Result<(), ProtocolError> {
-        todo!("Quit_NC Not yet implemented")
+    /// Errors on io or codec Errors or if a pending response was not
+    /// `Continue`
+    pub async fn quit_nc(mut self) -> Result<ReusableConnection<RW>, ResponseError> {
+        if self.limit_reached {
+            return Err(ResponseError::MessageLimit);
+        }
+        self.ensure_usable()?;
+        self.settle_pending().await?;
+        self.send_frame(Action::QuitNc(QuitNc).into()).await?;
+        self.in_message = false;
+        self.early_modifications.clear();
+        self.deadline = None;
+
+        Ok(ReusableConnection { connection: self })
     }
 
-    /// Abort processing for the current mail
+    /// Send `macros` for the command following them, as MTAs do before
+    /// most commands.
+    ///
+    /// Macros are not answered, they are written out along with the next
+    /// command. Set their [`code`](Macro::code) to the one of that command,
+    /// libmilter fails on macros for stages that take none.
     ///
     /// # Errors
     /// Errors on io or codec Errors
-    pub async fn abort(mut self) -> Result<(), ProtocolError> {
-        self.framed.send(&Action::from(Abort).into()).await?;
+    pub async fn macro_(&mut self, macros: Macro) -> Result<(), ResponseError> {
+        if self.limit_reached {
+            return Err(ResponseError::MessageLimit);
+        }
+        self.ensure_usable()?;
+        self.feed_frame(macros.into()).await
+    }
+
+    /// Abort processing for the current mail, keeping the session for the
+    /// next one.
+    ///
+    /// Responses to pipelined commands still in flight are read and
+    /// dropped first, as the mail they belong to is given up. The milter
+    /// does not answer the abort itself. The next mail may start right
+    /// after, with [`Connection::mail`].
+    ///
+    /// Servers run as for postfix close the connection on abort instead,
+    /// see [`Connection::abort_and_close`].
+    ///
+    /// # Errors
+    /// Errors on io or codec Errors
+    pub async fn abort(&mut self) -> Result<(), ResponseError> {
+        if self.limit_reached {
+            return Ok(());
+        }
+        self.ensure_usable()?;
+        while self.pending_responses > 0 {
+            self.pending_responses -= 1;
+            self.receive_action().await?;
+            debug!("Dropped an answer to the aborted mail");
+        }
+
+        self.send_frame(Action::from(Abort).into()).await?;
+        self.early_modifications.clear();
+        self.deadline = None;
+        if self.in_message {
+            self.finish_message().await?;
+        }
 
         Ok(())
     }
 
+    /// Abort processing for the current mail and close the connection
+    ///
+    /// # Errors
+    /// Errors on io or codec Errors, with an io error of kind
+    /// [`io::ErrorKind::TimedOut`] once a read or write timed out
+    pub async fn abort_and_close(mut self) -> Result<(), ProtocolError> {
+        if self.limit_reached {
+            return Ok(());
+        }
+        self.send_frame(Action::from(Abort).into())
+            .await
+            .map_err(into_protocol_error)
+    }
+
+    /// Whether a mail was started but its end of body not yet answered
+    #[must_use]
+    pub fn in_message(&self) -> bool {
+        self.in_message
+    }
+
+    /// Whether this connection was quit after the maximum number of
+    /// messages, see [`Client::with_max_messages_per_connection`]
+    #[must_use]
+    pub fn message_limit_reached(&self) -> bool {
+        self.limit_reached
+    }
+
+    /// How the commands sent so far were answered
+    #[must_use]
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    /// Tell the server the current message has to be handled within
+    /// `remaining`, e.g. what is left of the MTA's own timeout.
+    ///
+    /// Before each following command of the message that may carry macros
+    /// (mail, recipients, data, end of headers and end of body), the time
+    /// then left is sent in the [`MILTR_DEADLINE`] macro. Servers read it
+    /// with `SessionContext::remaining_time`, other milters ignore it. The
+    /// deadline is dropped once the message ends.
+    pub fn set_message_deadline(&mut self, remaining: Duration) {
+        self.deadline = Some(self.clock.now() + remaining);
+    }
+
+    /// The sizes of the frames read on this connection
+    #[must_use]
+    pub fn frame_sizes(&self) -> &FrameSizes {
+        &self.framed.codec().frame_sizes
+    }
+
+    /// The frame limits of this connection, e.g. to split a body into
+    /// chunks of at most [`Limits::max_body_chunk`] bytes
+    #[must_use]
+    pub fn limits(&self) -> Limits {
+        Limits::new(self.framed.codec().max_buffer_size())
+    }
+
+    /// Shut this connection down within `grace`, e.g. when the MTA stops.
+    ///
+    /// Responses to pipelined commands still in flight are awaited first.
+    /// If a mail remains unfinished after that, it is aborted, as the rest
+    /// of it will not arrive. Finally, quit is sent. To complete a mail
+    /// instead of aborting it, send its remaining commands before calling
+    /// this.
+    ///
+    /// If `grace` elapses while awaiting responses, the mail is aborted
+    /// right away.
+    ///
+    /// # Errors
+    /// Errors on io or codec errors, or with [`ResponseError::Timeout`] if
+    /// abort and quit could not be sent within `grace`. The connection is
+    /// closed in any case.
+    pub async fn finish_and_quit(mut self, grace: Duration) -> Result<(), ResponseError> {
+        if self.limit_reached {
+            return Ok(());
+        }
+        self.ensure_usable()?;
+        let mut deadline = self.clock.sleep(grace);
+
+        let settled = {
+            let settle = self.settle_pending();
+            futures::pin_mut!(settle);
+            match future::select(settle, &mut deadline).await {
+                Either::Left((result, _)) => result.is_ok(),
+                Either::Right(_) => {
+                    debug!("Grace period elapsed awaiting pending responses");
+                    false
+                }
+            }
+        };
+
+        let shutdown = async {
+            if !settled || self.in_message {
+                debug!("Aborting unfinished mail");
+                self.framed.feed(&Action::from(Abort).into()).await?;
+            }
+            self.framed.send(&Action::Quit(Quit).into()).await
+        };
+        futures::pin_mut!(shutdown);
+        match future::select(shutdown, deadline).await {
+            Either::Left((result, _)) => Ok(result?),
+            Either::Right(_) => Err(ResponseError::Timeout),
+        }
+    }
+
     command!(
         /// Send an unknown command to the server.
         ///
@@ -324,41 +1007,268 @@ impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
     );
 
     /// Send a command to the server respecting protocol settings
-    #[cfg_attr(feature = "tracing", instrument(level = Level::DEBUG, skip(self), fields(%command), err))]
+    #[cfg_attr(feature = "tracing", instrument(level = Level::DEBUG, parent = self.parent_id(), skip(self), fields(%command), err))]
     async fn send_command(&mut self, command: Command) -> Result<(), ResponseError> {
+        if self.limit_reached {
+            return Err(ResponseError::MessageLimit);
+        }
+        self.ensure_usable()?;
         // Eval skips
         if self.options.protocol.should_skip_send(&command) {
             debug!("Skip sending");
+            self.stats.skipped += 1;
             return Ok(());
         }
         let skip_response = self.options.protocol.should_skip_response(&command);
+        let pipelined =
+            self.pipeline_window > 1 && matches!(command, Command::Header(_) | Command::Body(_));
+        if !pipelined {
+            self.settle_pending().await?;
+        }
 
         // Send it
         debug!("Sending command");
-        self.framed.send(&command.into()).await?;
+        self.in_message |= Self::belongs_to_message(&command);
+        self.feed_deadline(command.code()).await?;
+        self.send_frame(command.into()).await?;
+        self.stats.sent += 1;
 
         // Check response
         if skip_response {
             debug!("Skip receiving response");
+            self.stats.unanswered += 1;
             return Ok(());
         }
+        if pipelined {
+            self.pending_responses += 1;
+            if self.pending_responses < self.pipeline_window {
+                debug!("Defer receiving response");
+                return Ok(());
+            }
+            self.pending_responses -= 1;
+        }
         self.expect_continue().await
     }
 
+    /// Queue the time left until the message deadline as macro for the
+    /// command with `code`, if a deadline is set.
+    ///
+    /// libmilter fails on macros for stages that take none, so they are
+    /// only sent for those that do.
+    async fn feed_deadline(&mut self, code: u8) -> Result<(), ResponseError> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        if !matches!(
+            code,
+            codes::SMFIC_MAIL
+                | codes::SMFIC_RCPT
+                | codes::SMFIC_DATA
+                | codes::SMFIC_EOH
+                | codes::SMFIC_BODYEOB
+        ) {
+            return Ok(());
+        }
+
+        let remaining = deadline.saturating_duration_since(self.clock.now());
+        let mut macros = Macro::new(code);
+        macros.push(
+            MILTR_DEADLINE.as_bytes(),
+            remaining.as_millis().to_string().as_bytes(),
+        );
+        self.feed_frame(macros.into()).await
+    }
+
+    /// The span to trace commands within, the current one unless set by
+    /// [`Client::connect_via_in_span`]
+    #[cfg(feature = "tracing")]
+    fn parent_id(&self) -> Option<tracing::Id> {
+        self.parent.id().or_else(|| Span::current().id())
+    }
+
+    /// Whether `command` is part of a mail, as opposed to the connection
+    fn belongs_to_message(command: &Command) -> bool {
+        !matches!(
+            command,
+            Command::Connect(_) | Command::Helo(_) | Command::Unknown(_)
+        )
+    }
+
+    /// Receive all responses to pipelined commands not yet awaited, failing
+    /// on answers to commands that were not to be answered
+    ///
+    /// Detecting those is best effort: only answers already received are
+    /// found. One still on its way is taken as the answer to a later
+    /// command instead.
+    async fn settle_pending(&mut self) -> Result<(), ResponseError> {
+        while self.pending_responses > 0 {
+            self.pending_responses -= 1;
+            self.expect_continue().await?;
+        }
+
+        // Nothing is to arrive now. Anything that did answers a command
+        // sent with an `NR_*` protocol flag. Detect what already arrived,
+        // without waiting for more.
+        match self.framed.next().now_or_never().flatten() {
+            Some(Ok(command)) => {
+                warn!("Received {} while awaiting no answer", command);
+                Err(ResponseError::Stray(command))
+            }
+            Some(Err(err)) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Account for the current message having ended, quitting if it was
+    /// the last one allowed
+    async fn finish_message(&mut self) -> Result<(), ResponseError> {
+        self.in_message = false;
+        self.deadline = None;
+        self.stats.messages += 1;
+        if self
+            .max_messages
+            .is_some_and(|max| self.stats.messages >= max)
+        {
+            debug!("Message limit reached, quitting");
+            self.send_frame(Action::Quit(Quit).into()).await?;
+            self.limit_reached = true;
+        }
+        Ok(())
+    }
+
     /// Shortcut to fetch an answer from the server
     async fn receive_answer(&mut self) -> Result<ServerCommand, ResponseError> {
-        let resp = self
-            .framed
-            .next()
-            .await
-            .ok_or(ResponseError::MissingServerResponse)??;
+        let resp = within(
+            &*self.clock,
+            self.read_timeout,
+            Direction::Read,
+            self.framed.next(),
+        )
+        .await;
+        self.note_timeout(&resp);
+        let resp = resp?.ok_or(ResponseError::MissingServerResponse)??;
 
         Ok(resp)
     }
+
+    /// Write `message` without flushing it, within the write timeout
+    async fn feed_frame(&mut self, message: ClientMessage) -> Result<(), ResponseError> {
+        self.ensure_usable()?;
+        let fed = within(
+            &*self.clock,
+            self.write_timeout,
+            Direction::Write,
+            self.framed.feed(&message),
+        )
+        .await;
+        self.note_timeout(&fed);
+        Ok(fed??)
+    }
+
+    /// Write `message` and flush, within the write timeout
+    async fn send_frame(&mut self, message: ClientMessage) -> Result<(), ResponseError> {
+        self.ensure_usable()?;
+        let sent = within(
+            &*self.clock,
+            self.write_timeout,
+            Direction::Write,
+            self.framed.send(&message),
+        )
+        .await;
+        self.note_timeout(&sent);
+        Ok(sent??)
+    }
+
+    /// Flush the frames fed so far, within the write timeout
+    async fn flush_frames(&mut self) -> Result<(), ResponseError> {
+        let flushed = within(
+            &*self.clock,
+            self.write_timeout,
+            Direction::Write,
+            self.framed.flush(),
+        )
+        .await;
+        self.note_timeout(&flushed);
+        Ok(flushed??)
+    }
+
+    /// Mark this connection unusable if `result` timed out
+    fn note_timeout<T>(&mut self, result: &Result<T, ResponseError>) {
+        if matches!(result, Err(ResponseError::Timeout)) {
+            self.timed_out = true;
+        }
+    }
+
+    /// Fail once a read or write timed out, see [`Client::with_read_timeout`],
+    /// or the server sent too many modifications
+    #[allow(clippy::result_large_err)] // Same error as the public calls
+    fn ensure_usable(&self) -> Result<(), ResponseError> {
+        if self.timed_out {
+            return Err(ResponseError::Timeout);
+        }
+        if let Some((count, bytes)) = self.overrun {
+            return Err(ResponseError::TooManyModifications { count, bytes });
+        }
+        Ok(())
+    }
+
+    /// Fetch an answer to a command other than end of body, applying the
+    /// [`EarlyModificationPolicy`] to modifications received meanwhile
+    async fn receive_response(&mut self) -> Result<ServerCommand, ResponseError> {
+        loop {
+            let resp = self.receive_answer().await?;
+            let is_modification = matches!(
+                resp,
+                ServerCommand::AddRecipient(_)
+                    | ServerCommand::AddRecipientPar(_)
+                    | ServerCommand::DeleteRecipient(_)
+                    | ServerCommand::ReplaceBody(_)
+                    | ServerCommand::AddHeader(_)
+                    | ServerCommand::InsertHeader(_)
+                    | ServerCommand::ChangeHeader(_)
+                    | ServerCommand::Quarantine(_)
+                    | ServerCommand::ChangeFrom(_)
+            );
+            if !is_modification {
+                return Ok(resp);
+            }
+            match self.early_modification_policy {
+                EarlyModificationPolicy::Error => return Ok(resp),
+                EarlyModificationPolicy::Attach => {
+                    debug!("Keeping modification received before end of body");
+                    if let CommandType::ModificationAction(modification) = resp.try_into()? {
+                        self.early_modifications.push(modification);
+                    }
+                }
+                EarlyModificationPolicy::Drop => {
+                    warn!("Dropping modification received before end of body");
+                }
+            }
+        }
+    }
+
+    /// Shortcut to fetch a control flow action from the server
+    async fn receive_action(&mut self) -> Result<Action, ResponseError> {
+        let resp = self.receive_response().await?;
+        self.stats.acknowledged += 1;
+
+        match resp {
+            ServerCommand::Abort(value) => Ok(value.into()),
+            ServerCommand::Continue(value) => Ok(value.into()),
+            ServerCommand::Discard(value) => Ok(value.into()),
+            ServerCommand::Reject(value) => Ok(value.into()),
+            ServerCommand::Tempfail(value) => Ok(value.into()),
+            ServerCommand::Skip(value) => Ok(value.into()),
+            ServerCommand::Replycode(value) => Ok(value.into()),
+            command => Err(ResponseError::Unexpected(command)),
+        }
+    }
+
     /// Shortcut expect a Continue answer from the server
     async fn expect_continue(&mut self) -> Result<(), ResponseError> {
         // Receive back answer
-        let resp = self.receive_answer().await?;
+        let resp = self.receive_response().await?;
+        self.stats.acknowledged += 1;
 
         // If continue, just continue. Otherwise return an error
         match resp {
@@ -383,6 +1293,74 @@ pub enum ResponseError {
     /// If we have a protocol compatibility issue
     #[error(transparent)]
     CompatibilityError(#[from] CompatibilityError),
+    /// If the server answered while no answer was awaited, e.g. a command
+    /// sent with a negotiated `NR_*` protocol flag
+    #[error("Server sent an answer no command awaited, the protocol is out of sync")]
+    Stray(ServerCommand),
+    /// If the server did not answer or take a command in time, see
+    /// [`Client::with_read_timeout`] and [`Client::with_write_timeout`], or
+    /// did not take the shutdown in time
+    #[error("Server did not respond in time")]
+    Timeout,
+    /// If a command was sent after the connection was quit on reaching
+    /// its message limit, see [`Client::with_max_messages_per_connection`]
+    #[error("Connection was quit after its last message")]
+    MessageLimit,
+    /// If the server sent more modifications with an end of body than
+    /// allowed, see [`Client::with_max_pending_modifications`]
+    #[error("Server sent {count} modifications of {bytes} bytes, more than allowed")]
+    TooManyModifications {
+        /// The number of modifications received so far
+        count: usize,
+        /// Their encoded size in bytes
+        bytes: usize,
+    },
+}
+
+/// The error of quitting or closing, which report protocol errors only. A
+/// timeout is reported as such an io error.
+fn into_protocol_error(err: ResponseError) -> ProtocolError {
+    match err {
+        ResponseError::ProtocolError(err) => err,
+        _ => io::Error::from(io::ErrorKind::TimedOut).into(),
+    }
+}
+
+/// Which way the io awaited [`within`] a timeout goes
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    /// Awaiting an answer of the server
+    Read,
+    /// Writing to the server
+    Write,
+}
+
+/// Await `io`, failing with [`ResponseError::Timeout`] once `timeout`
+/// passed on `clock`
+async fn within<F: Future>(
+    clock: &dyn Clock,
+    timeout: Option<Duration>,
+    direction: Direction,
+    io: F,
+) -> Result<F::Output, ResponseError> {
+    let Some(timeout) = timeout else {
+        return Ok(io.await);
+    };
+    futures::pin_mut!(io);
+    match future::select(io, clock.sleep(timeout)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => {
+            match direction {
+                Direction::Read => {
+                    warn!("Server did not respond within {:?}", timeout);
+                }
+                Direction::Write => {
+                    warn!("Server did not take a write within {:?}", timeout);
+                }
+            }
+            Err(ResponseError::Timeout)
+        }
+    }
 }
 
 /// The types of commands the server may respond with
@@ -391,6 +1369,8 @@ pub enum CommandType {
     Action(Action),
     /// A data modification action
     ModificationAction(ModificationAction),
+    /// A request to keep waiting for the answer to end of body
+    Progress(Progress),
 }
 
 impl TryFrom<ServerCommand> for CommandType {
@@ -406,13 +1386,16 @@ impl TryFrom<ServerCommand> for CommandType {
             ServerCommand::Tempfail(value) => Ok(Self::Action(value.into())),
             ServerCommand::Skip(value) => Ok(Self::Action(value.into())),
             ServerCommand::Replycode(value) => Ok(Self::Action(value.into())),
+            ServerCommand::Progress(value) => Ok(Self::Progress(value)),
             ServerCommand::AddRecipient(value) => Ok(Self::ModificationAction(value.into())),
+            ServerCommand::AddRecipientPar(value) => Ok(Self::ModificationAction(value.into())),
             ServerCommand::DeleteRecipient(value) => Ok(Self::ModificationAction(value.into())),
             ServerCommand::ReplaceBody(value) => Ok(Self::ModificationAction(value.into())),
             ServerCommand::AddHeader(value) => Ok(Self::ModificationAction(value.into())),
             ServerCommand::InsertHeader(value) => Ok(Self::ModificationAction(value.into())),
             ServerCommand::ChangeHeader(value) => Ok(Self::ModificationAction(value.into())),
             ServerCommand::Quarantine(value) => Ok(Self::ModificationAction(value.into())),
+            ServerCommand::ChangeFrom(value) => Ok(Self::ModificationAction(value.into())),
         }
     }
 }
@@ -0,0 +1,159 @@
+//! Abstract over a connection to a milter, e.g. to mock it
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+
+use miltr_common::{
+    commands::{Body, Connect, Header, Helo, Mail, Recipient, Unknown},
+    modifications::ModificationResponse,
+    ProtocolError,
+};
+
+use crate::{Connection, ResponseError};
+
+/// The commands an MTA sends to a milter during a session.
+///
+/// Implemented by [`Connection`]. Code written against this trait can be
+/// tested without a milter server, using a `testing::MockConnection`
+/// (feature `testing`).
+///
+/// All methods behave as their counterparts on [`Connection`].
+#[async_trait]
+pub trait MilterConnection: Send {
+    /// See [`Connection::connect`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn connect(&mut self, connect: Connect) -> Result<(), ResponseError>;
+
+    /// See [`Connection::helo`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn helo(&mut self, helo: Helo) -> Result<(), ResponseError>;
+
+    /// See [`Connection::mail`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn mail(&mut self, mail: Mail) -> Result<(), ResponseError>;
+
+    /// See [`Connection::recipient`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn recipient(&mut self, recipient: Recipient) -> Result<(), ResponseError>;
+
+    /// See [`Connection::data`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn data(&mut self) -> Result<(), ResponseError>;
+
+    /// See [`Connection::header`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn header(&mut self, header: Header) -> Result<(), ResponseError>;
+
+    /// See [`Connection::end_of_header`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn end_of_header(&mut self) -> Result<(), ResponseError>;
+
+    /// See [`Connection::body`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn body(&mut self, body: Body) -> Result<(), ResponseError>;
+
+    /// See [`Connection::end_of_body`]
+    ///
+    /// # Errors
+    /// Errors on any response from the milter server that is not Continue
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, ResponseError>;
+
+    /// See [`Connection::unknown`]
+    ///
+    /// # Errors
+    /// Errors on io or codec Errors
+    async fn unknown(&mut self, unknown: Unknown) -> Result<(), ResponseError>;
+
+    /// See [`Connection::abort`]
+    ///
+    /// # Errors
+    /// Errors on io or codec Errors
+    async fn abort(&mut self) -> Result<(), ResponseError>;
+
+    /// See [`Connection::abort_and_close`]
+    ///
+    /// # Errors
+    /// Errors on io or codec Errors
+    async fn abort_and_close(self) -> Result<(), ProtocolError>
+    where
+        Self: Sized;
+
+    /// See [`Connection::quit`]
+    ///
+    /// # Errors
+    /// Errors on io or codec Errors
+    async fn quit(self) -> Result<(), ProtocolError>
+    where
+        Self: Sized;
+}
+
+#[async_trait]
+impl<RW: AsyncRead + AsyncWrite + Unpin + Send> MilterConnection for Connection<RW> {
+    async fn connect(&mut self, connect: Connect) -> Result<(), ResponseError> {
+        Connection::connect(self, connect).await
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<(), ResponseError> {
+        Connection::helo(self, helo).await
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<(), ResponseError> {
+        Connection::mail(self, mail).await
+    }
+
+    async fn recipient(&mut self, recipient: Recipient) -> Result<(), ResponseError> {
+        Connection::recipient(self, recipient).await
+    }
+
+    async fn data(&mut self) -> Result<(), ResponseError> {
+        Connection::data(self).await
+    }
+
+    async fn header(&mut self, header: Header) -> Result<(), ResponseError> {
+        Connection::header(self, header).await
+    }
+
+    async fn end_of_header(&mut self) -> Result<(), ResponseError> {
+        Connection::end_of_header(self).await
+    }
+
+    async fn body(&mut self, body: Body) -> Result<(), ResponseError> {
+        Connection::body(self, body).await
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, ResponseError> {
+        Connection::end_of_body(self).await
+    }
+
+    async fn unknown(&mut self, unknown: Unknown) -> Result<(), ResponseError> {
+        Connection::unknown(self, unknown).await
+    }
+
+    async fn abort(&mut self) -> Result<(), ResponseError> {
+        Connection::abort(self).await
+    }
+
+    async fn abort_and_close(self) -> Result<(), ProtocolError> {
+        Connection::abort_and_close(self).await
+    }
+
+    async fn quit(self) -> Result<(), ProtocolError> {
+        Connection::quit(self).await
+    }
+}
@@ -0,0 +1,18 @@
+//! Configure how the client copes with non-conformant milter servers
+
+/// What to do with modifications a server sends before end of body.
+///
+/// Modifications are only allowed as answer to end of body. Some broken
+/// milters send them earlier, e.g. right after the header they want to
+/// change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EarlyModificationPolicy {
+    /// Fail the command with [`ResponseError::Unexpected`](crate::ResponseError::Unexpected)
+    #[default]
+    Error,
+    /// Keep them and return them with the response to the next end of
+    /// body, before the modifications sent there
+    Attach,
+    /// Log and discard them
+    Drop,
+}
@@ -0,0 +1,410 @@
+//! Query several milters concurrently and aggregate their verdicts
+
+use std::time::Duration;
+
+use futures::{
+    future::{self, Either},
+    stream::FuturesUnordered,
+    AsyncRead, AsyncWrite, SinkExt, StreamExt,
+};
+use miltr_utils::debug;
+use thiserror::Error;
+
+use miltr_common::{
+    actions::{Abort, Action, Quit},
+    commands::Command,
+    modifications::ModificationResponse,
+    ProtocolError,
+};
+
+use crate::{Connection, ResponseError};
+
+/// How the answers of all milters in a [`MilterQuorum`] are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Aggregation {
+    /// The strictest answer wins.
+    ///
+    /// From lenient to strict: skip, continue, tempfail, discard, reject.
+    /// Skip is the most lenient, as every other milter still wants to see
+    /// the rest of the body. A replycode counts as tempfail or reject,
+    /// depending on its code.
+    #[default]
+    Strictest,
+    /// The answer given by most milters wins, on a tie the stricter one.
+    Majority,
+    /// The answer received first wins.
+    FirstResponse,
+}
+
+/// Why a single milter in a [`MilterQuorum`] did not answer
+#[derive(Debug, Error)]
+pub enum BackendError {
+    /// The milter did not answer within its timeout
+    #[error("Milter did not answer in time")]
+    Timeout,
+    /// Talking to the milter failed
+    #[error(transparent)]
+    Response(Box<ResponseError>),
+    /// The milter failed on an earlier command and is no longer asked
+    #[error("Milter failed before and is disabled")]
+    Disabled,
+}
+
+impl From<ResponseError> for BackendError {
+    fn from(value: ResponseError) -> Self {
+        Self::Response(Box::new(value))
+    }
+}
+
+/// The aggregated answer of a [`MilterQuorum`] to one command
+#[derive(Debug)]
+pub struct Verdict {
+    /// The action the quorum decided on
+    pub action: Action,
+    /// The answer of each milter, in the order they were added.
+    ///
+    /// Modifications are not merged, they are only present in the answer
+    /// of the milter sending them.
+    pub answers: Vec<Result<ModificationResponse, BackendError>>,
+}
+
+struct Backend<RW: AsyncRead + AsyncWrite + Unpin> {
+    /// `None` once this backend failed, as its connection is out of sync
+    connection: Option<Connection<RW>>,
+    timeout: Option<Duration>,
+}
+
+/// Send the same conversation to multiple milters concurrently.
+///
+/// Contrary to asking milters one after the other, all milters receive each
+/// command at once and their answers are combined according to an
+/// [`Aggregation`]. This suits redundant scanners, where the slowest one
+/// should determine the latency instead of the sum of all.
+///
+/// A milter failing or exceeding its timeout is disabled for the rest of
+/// the conversation, as its connection can not be relied on anymore. The
+/// quorum keeps going as long as one milter is left.
+///
+/// All answers are awaited before a command returns, even for
+/// [`Aggregation::FirstResponse`], to keep every connection in sync.
+/// Per-milter timeouts bound this wait.
+pub struct MilterQuorum<RW: AsyncRead + AsyncWrite + Unpin> {
+    backends: Vec<Backend<RW>>,
+    aggregation: Aggregation,
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> MilterQuorum<RW> {
+    /// Create an empty quorum combining answers using `aggregation`
+    #[must_use]
+    pub fn new(aggregation: Aggregation) -> Self {
+        Self {
+            backends: Vec::new(),
+            aggregation,
+        }
+    }
+
+    /// Add a milter without a timeout
+    #[must_use]
+    pub fn with_backend(mut self, connection: Connection<RW>) -> Self {
+        self.backends.push(Backend {
+            connection: Some(connection),
+            timeout: None,
+        });
+        self
+    }
+
+    /// Add a milter, disabling it if it does not answer a command within
+    /// `timeout`
+    #[must_use]
+    pub fn with_backend_timeout(mut self, connection: Connection<RW>, timeout: Duration) -> Self {
+        self.backends.push(Backend {
+            connection: Some(connection),
+            timeout: Some(timeout),
+        });
+        self
+    }
+
+    /// The number of milters still taking part
+    #[must_use]
+    pub fn active(&self) -> usize {
+        self.backends
+            .iter()
+            .filter(|b| b.connection.is_some())
+            .count()
+    }
+
+    /// Send `command` to all milters and aggregate their answers.
+    ///
+    /// For [`Command::EndOfBody`], the answers contain the modifications
+    /// each milter requested.
+    ///
+    /// # Errors
+    /// Errors with [`ResponseError::MissingServerResponse`] if no milter
+    /// answered.
+    pub async fn command<C: Into<Command>>(
+        &mut self,
+        command: C,
+    ) -> Result<Verdict, ResponseError> {
+        let command: Command = command.into();
+        let count = self.backends.len();
+
+        let mut pending: FuturesUnordered<_> = self
+            .backends
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, backend)| {
+                let timeout = backend.timeout;
+                let connection = backend.connection.as_mut()?;
+                let command = command.clone();
+                Some(async move { (index, ask(connection, command, timeout).await) })
+            })
+            .collect();
+
+        let mut arrived = Vec::with_capacity(count);
+        let mut answers: Vec<_> = (0..count).map(|_| Err(BackendError::Disabled)).collect();
+        while let Some((index, answer)) = pending.next().await {
+            if answer.is_ok() {
+                arrived.push(index);
+            }
+            answers[index] = answer;
+        }
+        drop(pending);
+
+        for (backend, answer) in self.backends.iter_mut().zip(&answers) {
+            if let Err(BackendError::Timeout | BackendError::Response(_)) = answer {
+                debug!("Disabling failed milter");
+                backend.connection = None;
+            }
+        }
+
+        let actions = arrived.iter().filter_map(|&index| match &answers[index] {
+            Ok(response) => Some(response.final_action()),
+            Err(_) => None,
+        });
+        let action = aggregate(self.aggregation, actions)
+            .ok_or(ResponseError::MissingServerResponse)?
+            .clone();
+
+        Ok(Verdict { action, answers })
+    }
+
+    /// Abort the current mail on all milters
+    ///
+    /// # Errors
+    /// Returns the first io or codec error, after trying all milters
+    pub async fn abort(self) -> Result<(), ProtocolError> {
+        self.send_all(Abort.into()).await
+    }
+
+    /// Ask all milters for a graceful connection shutdown
+    ///
+    /// # Errors
+    /// Returns the first io or codec error, after trying all milters
+    pub async fn quit(self) -> Result<(), ProtocolError> {
+        self.send_all(Quit.into()).await
+    }
+
+    /// Shut all milters down within `grace`, e.g. when the MTA stops.
+    ///
+    /// See [`Connection::finish_and_quit`] for how each milter is shut
+    /// down. All milters get the same grace period concurrently.
+    ///
+    /// # Errors
+    /// Returns the first error, after trying all milters
+    pub async fn shutdown(self, grace: Duration) -> Result<(), ResponseError> {
+        let results = future::join_all(
+            self.backends
+                .into_iter()
+                .filter_map(|b| Some(b.connection?.finish_and_quit(grace))),
+        )
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    async fn send_all(self, action: Action) -> Result<(), ProtocolError> {
+        let results = future::join_all(self.backends.into_iter().filter_map(|b| {
+            let mut connection = b.connection?;
+            let message = action.clone().into();
+            Some(async move { connection.framed.send(&message).await })
+        }))
+        .await;
+
+        results.into_iter().collect()
+    }
+}
+
+/// Ask a single milter, respecting its negotiated protocol and `timeout`
+async fn ask<RW: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<RW>,
+    command: Command,
+    timeout: Option<Duration>,
+) -> Result<ModificationResponse, BackendError> {
+    let deadline = timeout.map(|timeout| connection.clock.sleep(timeout));
+    let answer = connection.ask(command);
+
+    let Some(deadline) = deadline else {
+        return Ok(answer.await?);
+    };
+
+    futures::pin_mut!(answer);
+    match future::select(answer, deadline).await {
+        Either::Left((answer, _)) => Ok(answer?),
+        Either::Right(_) => Err(BackendError::Timeout),
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite + Unpin> Connection<RW> {
+    /// Send `command` and return the answer instead of erroring on
+    /// anything that is not `Continue`
+    async fn ask(&mut self, command: Command) -> Result<ModificationResponse, ResponseError> {
+        if let Command::EndOfBody(_) = command {
+            return self.end_of_body().await;
+        }
+
+        if self.options.protocol.should_skip_send(&command) {
+            self.stats.skipped += 1;
+            return Ok(ModificationResponse::empty_continue());
+        }
+        self.in_message |= Self::belongs_to_message(&command);
+        let skip_response = self.options.protocol.should_skip_response(&command);
+
+        self.settle_pending().await?;
+        self.framed.send(&command.into()).await?;
+        self.stats.sent += 1;
+
+        if skip_response {
+            self.stats.unanswered += 1;
+            return Ok(ModificationResponse::empty_continue());
+        }
+        let action = self.receive_action().await?;
+
+        Ok(ModificationResponse::builder().build(action))
+    }
+}
+
+/// Rank `action` from lenient to strict
+fn strictness(action: &Action) -> u8 {
+    match action {
+        Action::Skip(_) => 0,
+        Action::Continue(_) => 1,
+        Action::Replycode(reply) if reply.rcode().code()[0] == 5 => 4,
+        Action::Tempfail(_)
+        | Action::Replycode(_)
+        | Action::Abort(_)
+        | Action::Quit(_)
+        | Action::QuitNc(_) => 2,
+        Action::Discard(_) => 3,
+        Action::Reject(_) => 4,
+    }
+}
+
+/// Combine `actions`, given in the order they arrived
+fn aggregate<'a>(
+    aggregation: Aggregation,
+    actions: impl Iterator<Item = &'a Action> + Clone,
+) -> Option<&'a Action> {
+    match aggregation {
+        Aggregation::FirstResponse => actions.clone().next(),
+        // `max_by_key` returns the last maximum, reverse to prefer the earliest
+        Aggregation::Strictest => actions
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .max_by_key(|a| strictness(a)),
+        Aggregation::Majority => {
+            let mut votes = [0_usize; 5];
+            for action in actions.clone() {
+                votes[usize::from(strictness(action))] += 1;
+            }
+            let (winner, _) = votes
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0)
+                .max_by_key(|(rank, &count)| (count, *rank))?;
+            actions
+                .into_iter()
+                .find(|a| usize::from(strictness(a)) == winner)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miltr_common::actions::{Continue, Discard, Reject, Replycode, Skip, Tempfail};
+
+    use super::*;
+
+    fn actions() -> Vec<Action> {
+        vec![
+            Continue.into(),
+            Tempfail.into(),
+            Continue.into(),
+            Discard.into(),
+        ]
+    }
+
+    #[test]
+    fn test_strictest() {
+        let actions = actions();
+
+        let action = aggregate(Aggregation::Strictest, actions.iter());
+
+        assert!(matches!(action, Some(Action::Discard(_))));
+    }
+
+    #[test]
+    fn test_strictest_replycode() {
+        let actions: Vec<Action> = vec![
+            Reject.into(),
+            Replycode::new([5, 5, 0], [5, 7, 1], "Spam").into(),
+        ];
+
+        let action = aggregate(Aggregation::Strictest, actions.iter());
+
+        assert!(matches!(action, Some(Action::Reject(_))));
+    }
+
+    #[test]
+    fn test_continue_beats_skip() {
+        let actions: Vec<Action> = vec![Skip.into(), Continue.into()];
+
+        let action = aggregate(Aggregation::Strictest, actions.iter());
+
+        assert!(matches!(action, Some(Action::Continue(_))));
+    }
+
+    #[test]
+    fn test_majority() {
+        let actions = actions();
+
+        let action = aggregate(Aggregation::Majority, actions.iter());
+
+        assert!(matches!(action, Some(Action::Continue(_))));
+    }
+
+    #[test]
+    fn test_majority_tie_is_strict() {
+        let actions: Vec<Action> = vec![Continue.into(), Reject.into()];
+
+        let action = aggregate(Aggregation::Majority, actions.iter());
+
+        assert!(matches!(action, Some(Action::Reject(_))));
+    }
+
+    #[test]
+    fn test_first_response() {
+        let actions = actions();
+
+        let action = aggregate(Aggregation::FirstResponse, actions.iter());
+
+        assert!(matches!(action, Some(Action::Continue(_))));
+    }
+
+    #[test]
+    fn test_no_answers() {
+        let action = aggregate(Aggregation::Strictest, [].iter());
+
+        assert!(action.is_none());
+    }
+}
@@ -0,0 +1,62 @@
+//! Account for the commands sent on a connection
+
+/// How the commands sent on a [`Connection`](crate::Connection) were
+/// answered, see [`Connection::stats`](crate::Connection::stats).
+///
+/// Only commands of the SMTP stages are counted, not option negotiation,
+/// abort or quit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Commands written to the server
+    pub sent: u64,
+    /// Commands sent without awaiting an answer, due to a negotiated
+    /// `NR_*` protocol flag
+    pub unanswered: u64,
+    /// Commands the server answered
+    pub acknowledged: u64,
+    /// Commands not sent at all, due to a negotiated `NO_*` protocol flag
+    pub skipped: u64,
+    /// Messages ended, by an answered end of body or an abort
+    pub messages: u64,
+    /// Progress notifications received while awaiting the answer to end
+    /// of body
+    pub progress: u64,
+}
+
+impl ConnectionStats {
+    /// Commands sent but not answered yet, e.g. while pipelining
+    #[must_use]
+    pub fn in_flight(&self) -> u64 {
+        let answered = self.unanswered + self.acknowledged;
+        debug_assert!(answered <= self.sent, "More commands answered than sent");
+        self.sent.saturating_sub(answered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight() {
+        let stats = ConnectionStats {
+            sent: 5,
+            unanswered: 1,
+            acknowledged: 2,
+            ..Default::default()
+        };
+        assert_eq!(stats.in_flight(), 2);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "More commands answered than sent")]
+    fn test_in_flight_underflow() {
+        let stats = ConnectionStats {
+            sent: 1,
+            acknowledged: 2,
+            ..Default::default()
+        };
+        let _ = stats.in_flight();
+    }
+}
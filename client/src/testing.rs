@@ -0,0 +1,281 @@
+//! Test code using the client without a milter server, or simulating
+//! legacy MTAs against one
+//!
+//! This module is feature gated behind the `testing` flag.
+
+mod legacy;
+
+pub use legacy::{LegacyMta, LegacyMtaError, POSTFIX_V2_OPTNEG};
+/// Let timeouts pass without waiting, see [`Client::with_clock`](crate::Client::with_clock)
+pub use miltr_common::clock::ManualClock;
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use async_trait::async_trait;
+
+use miltr_common::{
+    actions::Action,
+    commands::{
+        Body, Command, Connect, Data, EndOfBody, EndOfHeader, Header, Helo, Mail, Recipient,
+        Unknown,
+    },
+    decoding::ServerCommand,
+    modifications::ModificationResponse,
+    ProtocolError,
+};
+
+use crate::{MilterConnection, ResponseError};
+
+/// An answer scripted for a [`MockConnection`]
+#[derive(Debug)]
+enum MockAnswer {
+    Action(Action),
+    EndOfBody(ModificationResponse),
+    Error(ResponseError),
+}
+
+/// Something sent to a [`MockConnection`]
+#[derive(Debug, Clone)]
+pub enum Sent {
+    /// A command of the session
+    Command(Command),
+    /// The current mail was aborted
+    Abort,
+    /// The connection was quit
+    Quit,
+}
+
+/// Everything sent to a [`MockConnection`], readable after the connection
+/// was consumed by [`MilterConnection::quit`] or
+/// [`MilterConnection::abort_and_close`]
+#[derive(Debug, Clone, Default)]
+pub struct MockHistory {
+    sent: Arc<Mutex<Vec<Sent>>>,
+}
+
+impl MockHistory {
+    /// Everything sent so far, in order
+    #[must_use]
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn push(&self, sent: Sent) {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sent);
+    }
+}
+
+/// A [`MilterConnection`] answering from a script instead of a server.
+///
+/// Each command takes the next scripted answer. Once the script is
+/// exhausted, every command is answered with continue.
+///
+/// ```
+/// # futures::executor::block_on(async {
+/// use miltr_client::{testing::MockConnection, MilterConnection};
+/// use miltr_common::{actions::{Continue, Reject}, commands::Recipient};
+///
+/// let mut connection = MockConnection::new()
+///     .then(Continue)
+///     .then(Reject);
+///
+/// let rcpt = |r: &[u8]| Recipient::from(r);
+/// assert!(connection.recipient(rcpt(b"<ok@example.com>")).await.is_ok());
+/// assert!(connection.recipient(rcpt(b"<spam@example.com>")).await.is_err());
+/// # });
+/// ```
+#[derive(Debug, Default)]
+pub struct MockConnection {
+    script: VecDeque<MockAnswer>,
+    history: MockHistory,
+}
+
+impl MockConnection {
+    /// A connection answering every command with continue
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the next unanswered command with `action`.
+    ///
+    /// As on a real connection, anything but continue fails with
+    /// [`ResponseError::Unexpected`], except for end of body.
+    #[must_use]
+    pub fn then<A: Into<Action>>(mut self, action: A) -> Self {
+        self.script.push_back(MockAnswer::Action(action.into()));
+        self
+    }
+
+    /// Answer the next unanswered command, usually end of body, with
+    /// `response`
+    #[must_use]
+    pub fn then_respond(mut self, response: ModificationResponse) -> Self {
+        self.script.push_back(MockAnswer::EndOfBody(response));
+        self
+    }
+
+    /// Fail the next unanswered command with `err`
+    #[must_use]
+    pub fn then_fail(mut self, err: ResponseError) -> Self {
+        self.script.push_back(MockAnswer::Error(err));
+        self
+    }
+
+    /// A handle to everything sent to this connection
+    #[must_use]
+    pub fn history(&self) -> MockHistory {
+        self.history.clone()
+    }
+
+    /// Record `command` and expect continue as answer
+    #[allow(clippy::result_large_err)] // Same error as the real connection
+    fn command<C: Into<Command>>(&mut self, command: C) -> Result<(), ResponseError> {
+        self.history.push(Sent::Command(command.into()));
+
+        match self.script.pop_front() {
+            None | Some(MockAnswer::Action(Action::Continue(_))) => Ok(()),
+            Some(MockAnswer::Action(action)) => Err(unexpected(action)),
+            Some(MockAnswer::EndOfBody(response)) => {
+                Err(unexpected(response.final_action().clone()))
+            }
+            Some(MockAnswer::Error(err)) => Err(err),
+        }
+    }
+}
+
+/// The error a real connection returns for `action` instead of continue
+fn unexpected(action: Action) -> ResponseError {
+    let command: ServerCommand = match action {
+        Action::Continue(a) => a.into(),
+        Action::Abort(a) => a.into(),
+        Action::Discard(a) => a.into(),
+        Action::Reject(a) => a.into(),
+        Action::Tempfail(a) => a.into(),
+        Action::Skip(a) => a.into(),
+        Action::Replycode(a) => a.into(),
+        // The server never sends these, answer like a broken server
+        Action::Quit(_) | Action::QuitNc(_) => return ResponseError::MissingServerResponse,
+    };
+    ResponseError::Unexpected(command)
+}
+
+#[async_trait]
+impl MilterConnection for MockConnection {
+    async fn connect(&mut self, connect: Connect) -> Result<(), ResponseError> {
+        self.command(connect)
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<(), ResponseError> {
+        self.command(helo)
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<(), ResponseError> {
+        self.command(mail)
+    }
+
+    async fn recipient(&mut self, recipient: Recipient) -> Result<(), ResponseError> {
+        self.command(recipient)
+    }
+
+    async fn data(&mut self) -> Result<(), ResponseError> {
+        self.command(Data)
+    }
+
+    async fn header(&mut self, header: Header) -> Result<(), ResponseError> {
+        self.command(header)
+    }
+
+    async fn end_of_header(&mut self) -> Result<(), ResponseError> {
+        self.command(EndOfHeader)
+    }
+
+    async fn body(&mut self, body: Body) -> Result<(), ResponseError> {
+        self.command(body)
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, ResponseError> {
+        self.history.push(Sent::Command(EndOfBody.into()));
+
+        match self.script.pop_front() {
+            None => Ok(ModificationResponse::empty_continue()),
+            Some(MockAnswer::Action(action)) => Ok(ModificationResponse::builder().build(action)),
+            Some(MockAnswer::EndOfBody(response)) => Ok(response),
+            Some(MockAnswer::Error(err)) => Err(err),
+        }
+    }
+
+    async fn unknown(&mut self, unknown: Unknown) -> Result<(), ResponseError> {
+        self.command(unknown)
+    }
+
+    async fn abort(&mut self) -> Result<(), ResponseError> {
+        self.history.push(Sent::Abort);
+        Ok(())
+    }
+
+    async fn abort_and_close(self) -> Result<(), ProtocolError> {
+        self.history.push(Sent::Abort);
+        Ok(())
+    }
+
+    async fn quit(self) -> Result<(), ProtocolError> {
+        self.history.push(Sent::Quit);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use miltr_common::{
+        actions::{Continue, Reject},
+        modifications::headers::AddHeader,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        block_on(async {
+            let mut response = ModificationResponse::builder();
+            response.push(AddHeader::new(b"X-Spam", b"yes"));
+            let mut connection = MockConnection::new()
+                .then(Continue)
+                .then(Reject)
+                .then_respond(response.contin());
+            let history = connection.history();
+
+            connection
+                .helo(Helo::from(b"localhost".as_slice()))
+                .await
+                .expect("Continue scripted");
+            let err = connection
+                .mail(Mail::from(b"<spam@test.local>".as_slice()))
+                .await
+                .expect_err("Reject scripted");
+            let response = connection.end_of_body().await.expect("Response scripted");
+            connection.data().await.expect("Script exhausted");
+            connection.quit().await.expect("Quit failed");
+
+            assert!(matches!(
+                err,
+                ResponseError::Unexpected(ServerCommand::Reject(_))
+            ));
+            assert_eq!(response.modifications().len(), 1);
+            let sent = history.sent();
+            assert_eq!(sent.len(), 5);
+            assert!(matches!(sent[1], Sent::Command(Command::Mail(_))));
+            assert!(matches!(sent[4], Sent::Quit));
+        });
+    }
+}
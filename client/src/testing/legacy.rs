@@ -0,0 +1,270 @@
+//! An MTA speaking milter protocol version 2
+
+use asynchronous_codec::Framed;
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
+use thiserror::Error;
+
+use miltr_common::{
+    commands::{Body, Connect, Family, Header, Helo, Mail, Recipient},
+    decoding::ServerCommand,
+    modifications::{ModificationAction, ModificationResponse},
+    optneg::{Capability, OptNeg, Protocol, ProtocolVersion},
+};
+
+use crate::{Client, ResponseError};
+
+/// Option negotiation as sent by postfix 2.3 up to 2.5, or later versions
+/// configured with `milter_protocol = 2`, including the frame header
+pub const POSTFIX_V2_OPTNEG: [u8; 17] = [
+    0, 0, 0, 13, b'O', // Frame header
+    0, 0, 0, 2, // Version
+    0, 0, 0, 0x3f, // Capabilities
+    0, 0, 0, 0x7f, // Protocol flags
+];
+
+/// A milter server answered in a way a version 2 MTA can not handle
+#[derive(Debug, Error)]
+pub enum LegacyMtaError {
+    /// The session failed
+    #[error(transparent)]
+    Response(#[from] ResponseError),
+    /// The server asked for capabilities not offered
+    #[error("Server asked for capabilities {0:?} not offered by a version 2 MTA")]
+    Capabilities(Capability),
+    /// The server asked for protocol flags not offered
+    #[error("Server asked for protocol flags {0:?} not offered by a version 2 MTA")]
+    Protocol(Protocol),
+    /// The server sent a modification without the capability for it
+    #[error("Server sent a modification not negotiated: {0:?}")]
+    Modification(ModificationAction),
+}
+
+/// Simulates an MTA speaking milter protocol version 2, as old postfix
+/// versions do.
+///
+/// Offers what [`POSTFIX_V2_OPTNEG`] does and runs a single mail through
+/// the server. Like such an MTA, it never sends data or unknown commands,
+/// and fails if the server asks for or uses anything not offered.
+///
+/// ```
+/// use miltr_client::testing::LegacyMta;
+/// use miltr_common::commands::Header;
+///
+/// let mta = LegacyMta::new().with_header(Header::new(b"Subject", b"Hello"));
+/// assert_eq!(mta.options().version.number(), 2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LegacyMta {
+    options: OptNeg,
+    connect: Connect,
+    helo: Helo,
+    sender: Mail,
+    recipients: Vec<Recipient>,
+    headers: Vec<Header>,
+    body: Body,
+}
+
+impl Default for LegacyMta {
+    fn default() -> Self {
+        let version = ProtocolVersion::V2;
+        Self {
+            options: OptNeg {
+                version,
+                capabilities: version.supported_capabilities(),
+                protocol: version.supported_protocol_flags(),
+                ..OptNeg::default()
+            },
+            connect: Connect::new(b"localhost", Family::Inet, Some(25), b"127.0.0.1"),
+            helo: Helo::from(b"localhost".as_slice()),
+            sender: Mail::from(b"<sender@test.local>".as_slice()),
+            recipients: vec![Recipient::from(b"<recipient@test.local>".as_slice())],
+            headers: Vec::new(),
+            body: Body::from(b"Hello\r\n".as_slice()),
+        }
+    }
+}
+
+impl LegacyMta {
+    /// An MTA sending a minimal mail
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The options offered in negotiation
+    #[must_use]
+    pub fn options(&self) -> &OptNeg {
+        &self.options
+    }
+
+    /// Add a recipient to the one of the minimal mail
+    #[must_use]
+    pub fn with_recipient(mut self, recipient: Recipient) -> Self {
+        self.recipients.push(recipient);
+        self
+    }
+
+    /// Add a header to the mail
+    #[must_use]
+    pub fn with_header(mut self, header: Header) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    /// Send `body` instead of the default one
+    #[must_use]
+    pub fn with_body(mut self, body: Body) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Negotiate with the server on `io`, send the mail and quit.
+    ///
+    /// Returns how the server answered the end of body.
+    ///
+    /// # Errors
+    /// If the session fails, or the server asks for or uses anything a
+    /// version 2 MTA did not offer
+    pub async fn run<RW: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        io: RW,
+    ) -> Result<ModificationResponse, LegacyMtaError> {
+        let client = Client::new(self.options.clone());
+        let mut framed = Framed::new(io, client.codec.clone());
+
+        framed
+            .send(&self.options.clone().into())
+            .await
+            .map_err(ResponseError::from)?;
+        let reply = match framed
+            .next()
+            .await
+            .ok_or(ResponseError::MissingServerResponse)?
+            .map_err(ResponseError::from)?
+        {
+            ServerCommand::OptNeg(reply) => reply,
+            command => return Err(ResponseError::Unexpected(command).into()),
+        };
+        let capabilities = reply.capabilities.difference(self.options.capabilities);
+        if !capabilities.is_empty() {
+            return Err(LegacyMtaError::Capabilities(capabilities));
+        }
+        let protocol = reply.protocol.difference(self.options.protocol);
+        if !protocol.is_empty() {
+            return Err(LegacyMtaError::Protocol(protocol));
+        }
+        let options = reply
+            .merge_compatible(&self.options)
+            .map_err(ResponseError::from)?;
+        let granted = options.capabilities;
+
+        let mut connection = client.connection(framed, options);
+        connection.connect(self.connect.clone()).await?;
+        connection.helo(self.helo.clone()).await?;
+        connection.mail(self.sender.clone()).await?;
+        for recipient in &self.recipients {
+            connection.recipient(recipient.clone()).await?;
+        }
+        for header in &self.headers {
+            connection.header(header.clone()).await?;
+        }
+        connection.end_of_header().await?;
+        connection.body(self.body.clone()).await?;
+        let response = connection.end_of_body().await?;
+        connection.quit().await.map_err(ResponseError::from)?;
+
+        if let Some(modification) = response
+            .modifications()
+            .iter()
+            .find(|m| !granted.contains(m.required_capability()))
+        {
+            return Err(LegacyMtaError::Modification(modification.clone()));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use bytes::BytesMut;
+    use miltr_common::{
+        actions::{Action, Continue},
+        commands::EsmtpArgs,
+        encoding::Writable,
+        modifications::{headers::AddHeader, recipients::AddRecipientPar},
+        ProtocolError,
+    };
+    use miltr_server::{Error, Milter, Server};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    #[test]
+    fn test_fixture_matches_options() {
+        let options = LegacyMta::new().options().clone();
+        let mut written = BytesMut::new();
+        options.write(&mut written);
+
+        assert_eq!(POSTFIX_V2_OPTNEG[4], options.code());
+        assert_eq!(&written[..], &POSTFIX_V2_OPTNEG[5..]);
+    }
+
+    /// Tries to use everything version 6 offers
+    struct ModernMilter;
+
+    #[async_trait]
+    impl Milter for ModernMilter {
+        type Error = &'static str;
+
+        async fn option_negotiation(
+            &mut self,
+            theirs: OptNeg,
+        ) -> Result<OptNeg, Error<Self::Error>> {
+            let ours = OptNeg {
+                protocol: Protocol::SMFIP_SKIP | Protocol::NO_DATA,
+                ..OptNeg::default()
+            };
+            Ok(ours
+                .merge_compatible(&theirs)
+                .map_err(ProtocolError::from)?)
+        }
+
+        async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+            let mut response = ModificationResponse::builder();
+            response.push(AddHeader::new(b"X-Scanned", b"yes"));
+            response.push(AddRecipientPar::new(
+                b"<bcc@test.local>",
+                &EsmtpArgs::default(),
+            ));
+            Ok(response.contin())
+        }
+
+        async fn abort(&mut self) -> Result<Action, Self::Error> {
+            Ok(Continue.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_downgraded_session() {
+        let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+        let server = tokio::spawn(async move {
+            let mut milter = ModernMilter;
+            Server::default_postfix(&mut milter)
+                .handle_connection(server_side.compat())
+                .await
+        });
+
+        let response = LegacyMta::new()
+            .with_header(Header::new(b"Subject", b"Legacy"))
+            .run(client_side.compat())
+            .await
+            .expect("Version 2 session failed");
+
+        assert_eq!(response.modifications().len(), 1);
+        server
+            .await
+            .expect("Server task failed")
+            .expect("Server failed handling the connection");
+    }
+}
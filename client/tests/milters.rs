@@ -0,0 +1,173 @@
+//! The milters shipped with the server and the modifications they send
+
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue, Reject},
+    assert_modifications,
+    commands::{EsmtpArgs, Notify},
+    modifications::{
+        body::ReplaceBody, headers::AddHeader, recipients::AddRecipientPar, sender::ChangeFrom,
+        ModificationAction, ModificationResponse,
+    },
+    optneg::{Capability, OptNeg},
+};
+use miltr_server::{milter_fn, ScanBackend, ScanMilter, ScanVerdict};
+
+mod utils;
+
+use utils::milter::TestMilter;
+
+/// Finds "virus" in bodies, replaces "secret" with "[redacted]"
+#[derive(Debug, Default)]
+struct SubstringScanner {
+    body: Vec<u8>,
+}
+
+#[async_trait]
+impl ScanBackend for SubstringScanner {
+    type Error = &'static str;
+
+    async fn submit(&mut self, chunk: &[u8]) -> Result<(), Self::Error> {
+        self.body.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    async fn verdict(&mut self) -> Result<ScanVerdict, Self::Error> {
+        let body = String::from_utf8_lossy(&std::mem::take(&mut self.body)).into_owned();
+        if body.contains("virus") {
+            return Ok(ScanVerdict::found("Test-Virus"));
+        }
+        if body.contains("secret") {
+            return Ok(ScanVerdict::clean().with_replacement(b"[redacted]"));
+        }
+        Ok(ScanVerdict::clean())
+    }
+}
+
+#[tokio::test]
+async fn test_scan_milter_found() {
+    let milter = ScanMilter::new(SubstringScanner::default());
+    let (mut connection, _handle) = utils::connect(milter, OptNeg::default()).await;
+
+    connection
+        .body(b"contains a vi".as_slice())
+        .await
+        .expect("Failed sending body");
+    connection
+        .body(b"rus split across chunks".as_slice())
+        .await
+        .expect("Failed sending body");
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    assert!(matches!(response.final_action(), Action::Reject(_)));
+}
+
+#[tokio::test]
+async fn test_scan_milter_replacement() {
+    let milter = ScanMilter::new(SubstringScanner::default());
+    let (mut connection, _handle) = utils::connect(milter, OptNeg::default()).await;
+
+    connection
+        .body(b"a secret body".as_slice())
+        .await
+        .expect("Failed sending body");
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    let mut expected = ModificationResponse::builder();
+    expected.push(ReplaceBody::new(b"[redacted]"));
+    assert_modifications!(expected.contin(), response);
+}
+
+#[tokio::test]
+async fn test_milter_fn() {
+    let milter = milter_fn()
+        .on_rcpt(|rcpt| {
+            if rcpt.recipient().contains("reject") {
+                Reject.into()
+            } else {
+                Continue.into()
+            }
+        })
+        .on_eom(|_ctx| {
+            let mut response = ModificationResponse::builder();
+            response.push(AddHeader::new(b"X-Prototype", b"yes"));
+            response.contin()
+        });
+    let (mut connection, handle) = utils::connect(milter, OptNeg::default()).await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .recipient(b"<reject@test.local>".as_slice())
+        .await
+        .expect_err("Recipient not rejected");
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+
+    let mut expected = ModificationResponse::builder();
+    expected.push(AddHeader::new(b"X-Prototype", b"yes"));
+    assert_modifications!(expected.contin(), response);
+    handle.await.expect("Server task failed");
+}
+
+fn notify_args() -> EsmtpArgs {
+    EsmtpArgs::builder()
+        .notify(Notify::SUCCESS | Notify::FAILURE)
+        .orcpt("rfc822", "bob@test.local")
+        .build()
+        .expect("Valid parameters")
+}
+
+#[tokio::test]
+async fn test_add_recipient_with_parameters() {
+    let milter = TestMilter::new().answering(|| {
+        let mut response = ModificationResponse::builder();
+        response.push(AddRecipientPar::new(b"<bob@test.local>", &notify_args()));
+        response.contin()
+    });
+    let (mut connection, _handle) = utils::connect(milter, OptNeg::default()).await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    let [ModificationAction::AddRecipientPar(add)] = response.modifications() else {
+        panic!("Unexpected modifications {:?}", response.modifications());
+    };
+    assert_eq!(add.recipient(), "<bob@test.local>");
+    assert_eq!(add.esmtp_args(), Ok(notify_args()));
+}
+
+/// A test milter changing the envelope sender of every mail
+fn bouncing_milter() -> TestMilter {
+    TestMilter::new().answering(|| {
+        let mut response = ModificationResponse::builder();
+        response.change_from(ChangeFrom::new(b"<bounces@test.local>").with_args(&notify_args()));
+        response.contin()
+    })
+}
+
+#[tokio::test]
+async fn test_change_from() {
+    let (mut connection, _handle) = utils::connect(bouncing_milter(), OptNeg::default()).await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    let [ModificationAction::ChangeFrom(change)] = response.modifications() else {
+        panic!("Unexpected modifications {:?}", response.modifications());
+    };
+    assert_eq!(change.sender(), "<bounces@test.local>");
+    assert_eq!(change.esmtp_args(), Ok(notify_args()));
+
+    let options = OptNeg {
+        capabilities: Capability::all() - Capability::SMFIF_CHGFROM,
+        ..OptNeg::default()
+    };
+    let (mut connection, _handle) = utils::connect(bouncing_milter(), options).await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+    assert!(response.modifications().is_empty());
+}
@@ -0,0 +1,171 @@
+//! Option negotiation between client and server
+
+use miltr_client::{Client, ResponseError};
+use miltr_common::{
+    actions::Tempfail,
+    commands::{Connect, Family},
+    decoding::ServerCommand,
+    optneg::{Capability, CompatibilityError, OptNeg, Protocol},
+};
+use miltr_server::{MissingCapabilityPolicy, NegotiationPolicy, Server};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::milter::TestMilter;
+
+/// A test milter offering `capabilities`
+fn offering(capabilities: Capability) -> TestMilter {
+    TestMilter::new().offering(OptNeg {
+        capabilities,
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn test_required_capabilities_missing() {
+    let client = Client::new(OptNeg::default())
+        .with_required_capabilities(Capability::SMFIF_ADDHDRS | Capability::SMFIF_CHGBODY);
+
+    let Err(err) = utils::negotiate(offering(Capability::SMFIF_ADDHDRS), &client).await else {
+        panic!("Negotiation did not fail");
+    };
+
+    let ResponseError::CompatibilityError(CompatibilityError::MissingCapability {
+        capabilities,
+        protocol,
+    }) = err
+    else {
+        panic!("Wrong error received: {err:?}");
+    };
+    assert_eq!(capabilities, Capability::SMFIF_CHGBODY);
+    assert_eq!(protocol, Protocol::empty());
+}
+
+#[tokio::test]
+async fn test_required_capabilities_present() {
+    let options = OptNeg {
+        protocol: Protocol::NR_RECIPIENT,
+        ..Default::default()
+    };
+    let milter = TestMilter::new().offering(options.clone());
+    let client = Client::new(options)
+        .with_required_capabilities(Capability::SMFIF_CHGBODY)
+        .with_required_protocol(Protocol::NR_RECIPIENT);
+
+    let connection = utils::negotiate(milter, &client)
+        .await
+        .expect("Negotiation failed");
+    connection.quit().await.expect("Failed to quit");
+}
+
+fn without_quarantine() -> Client {
+    Client::new(OptNeg {
+        capabilities: Capability::all().difference(Capability::SMFIF_QUARANTINE),
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn test_server_missing_capability_errors() {
+    let milter = TestMilter::new().requiring(Capability::SMFIF_QUARANTINE);
+    let result = utils::negotiate(milter, &without_quarantine()).await;
+
+    assert!(result.is_err(), "Server did not refuse negotiation");
+}
+
+#[tokio::test]
+async fn test_server_missing_capability_responds() {
+    let milter = TestMilter::new().requiring(Capability::SMFIF_QUARANTINE);
+    let (mut connection, handle) =
+        utils::connect_configured(milter, without_quarantine(), |server| {
+            server.with_missing_capability_policy(MissingCapabilityPolicy::Respond(Tempfail.into()))
+        })
+        .await;
+
+    let err = connection
+        .connect(Connect::new(b"localhost", Family::Unknown, None, b""))
+        .await
+        .expect_err("Connect was not refused");
+    assert!(matches!(
+        err,
+        ResponseError::Unexpected(ServerCommand::Tempfail(_))
+    ));
+
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+}
+
+#[tokio::test]
+async fn test_negotiation_policy_answers() {
+    let ours = OptNeg {
+        capabilities: Capability::SMFIF_ADDHDRS,
+        protocol: Protocol::NO_HELO,
+        ..Default::default()
+    };
+    let client = Client::new(OptNeg {
+        protocol: Protocol::NO_CONNECT | Protocol::NO_HELO,
+        ..Default::default()
+    });
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let handle = tokio::spawn(async move {
+        // Would answer without any capabilities if asked
+        let mut milter = offering(Capability::empty());
+        Server::default_postfix(&mut milter)
+            .with_negotiation_policy(NegotiationPolicy::ClampTo(ours))
+            .handle_connection(server_side.compat())
+            .await
+    });
+
+    let connection = client
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+    let negotiated = client.options_snapshot().expect("Nothing negotiated");
+    assert_eq!(negotiated.capabilities, Capability::SMFIF_ADDHDRS);
+    assert_eq!(negotiated.protocol, Protocol::NO_HELO);
+
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+}
+
+#[tokio::test]
+async fn test_options_drift() {
+    let client = Client::new(OptNeg::default()).with_drift_error(true);
+
+    utils::negotiate(offering(Capability::SMFIF_ADDHDRS), &client)
+        .await
+        .expect("First negotiation failed");
+    utils::negotiate(offering(Capability::SMFIF_ADDHDRS), &client)
+        .await
+        .expect("Negotiation without drift failed");
+    let Err(err) = utils::negotiate(offering(Capability::SMFIF_CHGBODY), &client).await else {
+        panic!("Drift not detected");
+    };
+
+    let ResponseError::CompatibilityError(CompatibilityError::Drift(drift)) = err else {
+        panic!("Wrong error received: {err:?}");
+    };
+    assert_eq!(drift.capabilities_added, Capability::SMFIF_CHGBODY);
+    assert_eq!(drift.capabilities_removed, Capability::SMFIF_ADDHDRS);
+    let snapshot = client.options_snapshot().expect("No options snapshot");
+    assert_eq!(snapshot.capabilities, Capability::SMFIF_ADDHDRS);
+}
+
+#[tokio::test]
+async fn test_limits() {
+    let (connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    assert_eq!(connection.limits().max_body_chunk(), 2_usize.pow(16) - 1);
+    connection.quit().await.expect("Failed to quit");
+
+    let milter = handle.await.expect("Server task failed");
+    let limits = milter.context().limits().expect("Limits not set");
+    assert_eq!(limits.max_frame_len(), 2_usize.pow(16));
+}
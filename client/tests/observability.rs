@@ -0,0 +1,342 @@
+//! What client and server report about the connections they handle
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use miltr_client::Client;
+use miltr_common::{
+    actions::{Action, ActionKind, Continue},
+    clock::ManualClock,
+    codes,
+    commands::{Connect, Family, Macro},
+    frame::FrameInfo,
+    optneg::OptNeg,
+};
+use miltr_server::{milter_fn, EndedBy, Heartbeat, Heartbeats, ServerStats, SlowCallback};
+
+mod utils;
+
+use utils::milter::TestMilter;
+
+#[tokio::test]
+async fn test_frame_hooks() {
+    let client_received = Arc::new(Mutex::new(Vec::new()));
+    let server_received = Arc::new(Mutex::new(Vec::new()));
+    let server_sent = Arc::new(Mutex::new(Vec::new()));
+
+    let client = Client::new(OptNeg::default()).on_frame_received({
+        let frames = Arc::clone(&client_received);
+        move |frame: &FrameInfo| frames.lock().expect("Poisoned").push(frame.code)
+    });
+    let (received, sent) = (Arc::clone(&server_received), Arc::clone(&server_sent));
+    let (mut connection, handle) =
+        utils::connect_configured(TestMilter::new(), client, move |server| {
+            server
+                .on_frame_received(move |frame| received.lock().expect("Poisoned").push(frame.code))
+                .on_frame_sent(move |frame| sent.lock().expect("Poisoned").push(frame.code))
+        })
+        .await;
+
+    connection
+        .connect(Connect::new(b"localhost", Family::Unknown, None, b""))
+        .await
+        .expect("Failed to connect");
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    assert_eq!(*server_received.lock().expect("Poisoned"), b"OCQ");
+    assert_eq!(*server_sent.lock().expect("Poisoned"), b"Oc");
+    assert_eq!(*client_received.lock().expect("Poisoned"), b"Oc");
+}
+
+#[tokio::test]
+async fn test_server_stats() {
+    let stats = ServerStats::new();
+    let server_stats = stats.clone();
+    let client = Client::new(OptNeg::default());
+    let (mut connection, handle) =
+        utils::connect_configured(TestMilter::new(), client, move |server| {
+            server.with_stats(server_stats)
+        })
+        .await;
+
+    connection
+        .recipient("<first@test.local>".as_bytes())
+        .await
+        .expect("Failed sending recipient");
+    assert_eq!(stats.active_connections(), 1);
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    assert_eq!(stats.connections(), 1);
+    assert_eq!(stats.active_connections(), 0);
+    assert_eq!(stats.failed_connections(), 0);
+    // Option negotiation, recipient and quit
+    assert_eq!(stats.frames_received(), 3);
+    assert_eq!(stats.frames_sent(), 2);
+}
+
+#[tokio::test]
+async fn test_connection_summary() {
+    let (mut connection, handle) = utils::connect_configured(
+        TestMilter::new(),
+        Client::new(OptNeg::default()),
+        |server| server,
+    )
+    .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .recipient(b"<reject@test.local>".as_slice())
+        .await
+        .expect_err("Recipient not rejected");
+    connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+
+    let summary = handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    assert_eq!(summary.ended_by, EndedBy::Quit);
+    assert_eq!(summary.messages, 1);
+    assert_eq!(summary.action_count(ActionKind::Continue), 2);
+    assert_eq!(summary.action_count(ActionKind::Reject), 1);
+    // Option negotiation and three continue or reject frames
+    assert_eq!(summary.bytes_out, 17 + 3 * 5);
+    assert!(summary.bytes_in > summary.bytes_out);
+}
+
+#[tokio::test]
+async fn test_slow_callback() {
+    let stats = ServerStats::new();
+    let slow = Arc::new(Mutex::new(Vec::<SlowCallback>::new()));
+
+    let server_stats = stats.clone();
+    let seen = slow.clone();
+    let milter = TestMilter::new().delaying("mail", Duration::from_millis(50));
+    let (mut connection, handle) =
+        utils::connect_configured(milter, Client::new(OptNeg::default()), move |server| {
+            server
+                .with_stats(server_stats)
+                .with_slow_callback_threshold(Duration::from_millis(20))
+                .on_slow_callback(move |slow| seen.lock().expect("Poisoned").push(slow.clone()))
+        })
+        .await;
+
+    let mut macros = Macro::new(codes::SMFIC_MAIL);
+    macros.push(b"i", b"ABC123");
+    connection
+        .macro_(macros)
+        .await
+        .expect("Failed sending macros");
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    let slow = slow.lock().expect("Poisoned");
+    let mail = slow
+        .iter()
+        .find(|slow| slow.callback == "mail")
+        .expect("Slow mail callback not reported");
+    assert!(mail.duration >= Duration::from_millis(50));
+    assert_eq!(mail.queue_id.as_deref(), Some("ABC123"));
+    assert_eq!(stats.slow_callbacks(), u64::try_from(slow.len()).unwrap());
+}
+
+#[tokio::test]
+async fn test_server_manual_clock() {
+    let clock = ManualClock::new();
+    let slow = Arc::new(Mutex::new(Vec::<SlowCallback>::new()));
+    let seen = slow.clone();
+    let server_clock = clock.clone();
+    let mail_clock = clock.clone();
+    let milter = milter_fn().on_mail(move |_mail| {
+        mail_clock.advance(Duration::from_secs(2));
+        Continue.into()
+    });
+    let (mut connection, handle) =
+        utils::connect_configured(milter, Client::new(OptNeg::default()), move |server| {
+            server
+                .with_clock(server_clock)
+                .with_slow_callback_threshold(Duration::from_secs(1))
+                .on_slow_callback(move |slow| seen.lock().expect("Poisoned").push(slow.clone()))
+        })
+        .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.quit().await.expect("Failed to quit");
+
+    let summary = handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    assert_eq!(summary.duration, Duration::from_secs(2));
+    let slow = slow.lock().expect("Poisoned");
+    assert_eq!(slow.len(), 1);
+    assert_eq!(slow[0].callback, "mail");
+    assert_eq!(slow[0].duration, Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_end_of_body_progress() {
+    let (mut connection, handle) = utils::connect_configured(
+        TestMilter::new().with_progress(2),
+        Client::new(OptNeg::default()),
+        |server| server,
+    )
+    .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    let response = connection.end_of_body().await.expect("Failed end of body");
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+    assert_eq!(connection.stats().progress, 2);
+
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+}
+
+#[tokio::test]
+async fn test_heartbeats() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let heartbeats: Arc<Mutex<Option<Heartbeats>>> = Arc::default();
+    let listener = Arc::clone(&heartbeats);
+    let mail_clock = clock.clone();
+    let milter = milter_fn().on_mail(move |_mail| {
+        mail_clock.advance(Duration::from_millis(5));
+        Continue.into()
+    });
+    let (mut connection, handle) =
+        utils::connect_configured(milter, Client::new(OptNeg::default()), move |mut server| {
+            *listener.lock().expect("Poisoned") = Some(server.heartbeats());
+            server.with_clock(server_clock)
+        })
+        .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.end_of_body().await.expect("Failed end of body");
+    connection.quit().await.expect("Failed to quit");
+    let summary = handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    let heartbeats = heartbeats
+        .lock()
+        .expect("Poisoned")
+        .take()
+        .expect("No heartbeats requested");
+    let heartbeats: Vec<Heartbeat> = heartbeats.collect().await;
+    let commands: Vec<_> = heartbeats.iter().map(|beat| beat.command).collect();
+    assert_eq!(commands, ["OptNeg", "Mail", "EndOfBody", "Quit"]);
+    assert!(heartbeats.iter().all(|beat| beat.connection == 1));
+    assert!(heartbeats
+        .windows(2)
+        .all(|pair| pair[0].bytes_in < pair[1].bytes_in && pair[0].bytes_out < pair[1].bytes_out));
+
+    let quit = heartbeats.last().expect("No heartbeat");
+    assert_eq!(quit.elapsed, Duration::from_millis(5));
+    assert_eq!(quit.messages, 1);
+    assert_eq!(quit.bytes_in, summary.bytes_in);
+    assert_eq!(quit.bytes_out, summary.bytes_out);
+}
+
+/// The name of a span with the name of its parent
+#[cfg(feature = "tracing")]
+type Named = (&'static str, Option<&'static str>);
+
+/// Records the name of each new span with the name of its parent
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct Parents(Arc<Mutex<Vec<Named>>>);
+
+#[cfg(feature = "tracing")]
+impl<S> tracing_subscriber::Layer<S> for Parents
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let span = ctx.span(id).expect("Unknown span");
+        let parent = span.parent().map(|parent| parent.name());
+        self.0.lock().expect("Poisoned").push((span.name(), parent));
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_connect_via_in_span() {
+    use miltr_server::Server;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+    use tracing_subscriber::prelude::*;
+
+    let parents = Parents::default();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(parents.clone()));
+    let session = tracing::info_span!("smtp_session");
+
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let handle = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        Server::default_postfix(&mut milter)
+            .handle_connection(server_side.compat())
+            .await
+    });
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via_in_span(client_side.compat(), &session)
+        .await
+        .expect("Failed to setup connection");
+    // Sent outside of the session span
+    connection
+        .connect(Connect::new(b"localhost", Family::Unknown, None, b""))
+        .await
+        .expect("Failed to connect");
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    let parents = parents.0.lock().expect("Poisoned");
+    assert!(
+        parents.contains(&("send_command", Some("smtp_session"))),
+        "{parents:?}"
+    );
+}
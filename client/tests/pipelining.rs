@@ -0,0 +1,386 @@
+//! Pipelined commands and the answers the client expects to them
+
+use std::time::Duration;
+
+use miltr_client::{Client, EarlyModificationPolicy, ResponseError};
+use miltr_common::{
+    actions::{Action, Continue, Reject},
+    assert_modifications,
+    commands::{Connect, Family, Header, Helo, Mail},
+    decoding::ServerCommand,
+    encoding::Writable,
+    modifications::{headers::AddHeader, ModificationAction, ModificationResponse},
+    optneg::{OptNeg, Protocol},
+};
+use miltr_server::milter_fn;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::{milter::TestMilter, read_frame, write_frame};
+
+#[tokio::test]
+async fn test_recipients_batch() {
+    let (mut connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    let statuses = connection
+        .recipients([
+            "<first@test.local>".as_bytes(),
+            "<reject@test.local>".as_bytes(),
+            "<third@test.local>".as_bytes(),
+        ])
+        .await
+        .expect("Failed sending recipients");
+
+    assert_eq!(statuses.len(), 3);
+    assert!(matches!(statuses[0], Action::Continue(_)));
+    assert!(matches!(statuses[1], Action::Reject(_)));
+    assert!(matches!(statuses[2], Action::Continue(_)));
+
+    connection.quit().await.expect("Failed to quit");
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(
+        milter.seen().recipients,
+        vec![
+            "<first@test.local>",
+            "<reject@test.local>",
+            "<third@test.local>"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_recipients_batch_no_reply() {
+    let options = OptNeg {
+        protocol: Protocol::NR_RECIPIENT,
+        ..Default::default()
+    };
+    let milter = TestMilter::new().offering(options.clone());
+    let (mut connection, handle) = utils::connect(milter, options).await;
+
+    let statuses = connection
+        .recipients([
+            "<first@test.local>".as_bytes(),
+            "<second@test.local>".as_bytes(),
+        ])
+        .await
+        .expect("Failed sending recipients");
+
+    assert_eq!(statuses.len(), 2);
+    assert!(statuses.iter().all(|s| matches!(s, Action::Continue(_))));
+
+    connection.quit().await.expect("Failed to quit");
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().recipients.len(), 2);
+}
+
+#[tokio::test]
+async fn test_no_reply_drops_reject() {
+    let options = OptNeg {
+        protocol: Protocol::NR_RECIPIENT | Protocol::NR_HEADER,
+        ..Default::default()
+    };
+    let milter = TestMilter::new().offering(options.clone());
+    let (mut connection, handle) = utils::connect(milter, options).await;
+
+    connection
+        .recipient(b"<reject@test.local>".as_slice())
+        .await
+        .expect("Failed sending recipient");
+    connection
+        .header(Header::new(b"X-Reject", b"value"))
+        .await
+        .expect("Failed sending header");
+    // Had the rejects been sent, they would answer these
+    connection
+        .end_of_header()
+        .await
+        .expect("Reject answered despite no reply requested");
+    let response = connection.end_of_body().await.expect("Failed end of body");
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+
+    connection.quit().await.expect("Failed to quit");
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().recipients, vec!["<reject@test.local>"]);
+    assert_eq!(milter.seen().calls("header"), 1);
+}
+
+#[tokio::test]
+async fn test_pipelined_headers_and_body() {
+    let client = Client::new(OptNeg::default()).with_pipeline_window(4);
+    let (mut connection, handle) = utils::connect_with(TestMilter::new(), client).await;
+
+    for i in 0..10 {
+        connection
+            .header(Header::new(format!("X-Header-{i}").as_bytes(), b"value"))
+            .await
+            .expect("Failed sending header");
+    }
+    connection
+        .end_of_header()
+        .await
+        .expect("Failed sending end of header");
+    for _ in 0..5 {
+        connection
+            .body(b"body chunk".as_slice())
+            .await
+            .expect("Failed sending body");
+    }
+    let response = connection.end_of_body().await.expect("Failed end of body");
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+
+    connection.quit().await.expect("Failed to quit");
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("header"), 10);
+    assert_eq!(milter.seen().calls("body"), 5);
+}
+
+#[tokio::test]
+async fn test_pipelined_reject_surfaces_later() {
+    let client = Client::new(OptNeg::default()).with_pipeline_window(8);
+    let (mut connection, handle) = utils::connect_with(TestMilter::new(), client).await;
+
+    connection
+        .header(Header::new(b"X-Reject", b"value"))
+        .await
+        .expect("Pipelined header should not await its response");
+
+    let err = connection
+        .end_of_header()
+        .await
+        .expect_err("Reject was not reported");
+    assert!(matches!(
+        err,
+        ResponseError::Unexpected(ServerCommand::Reject(_))
+    ));
+
+    connection.quit().await.expect("Failed to quit");
+    handle.await.expect("Server task failed");
+}
+
+#[tokio::test]
+async fn test_abort_keeps_session() {
+    let (client_side, mut server_side) = tokio::io::duplex(2_usize.pow(16));
+    let server = tokio::spawn(async move {
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, OptNeg::default()).await;
+        // A first mail, its pipelined header rejected
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, Action::from(Continue)).await;
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, Action::from(Reject)).await;
+        // Aborting it is not answered
+        let abort = read_frame(&mut server_side).await;
+        // The next mail
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, Action::from(Continue)).await;
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, Action::from(Continue)).await;
+        abort
+    });
+
+    let mut connection = Client::new(OptNeg::default())
+        .with_pipeline_window(4)
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .header(Header::new(b"Subject", b"First"))
+        .await
+        .expect("Failed sending header");
+    connection.abort().await.expect("Failed to abort");
+    assert!(!connection.in_message());
+
+    connection
+        .mail(b"<b@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail after abort");
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+    assert_eq!(server.await.expect("Server task failed"), b"A");
+}
+
+/// Run a mail against a server adding a header in response to the header
+/// command, long before end of body
+async fn early_modification_session(
+    policy: EarlyModificationPolicy,
+) -> Result<ModificationResponse, ResponseError> {
+    let (client_side, mut server_side) = tokio::io::duplex(2_usize.pow(16));
+    tokio::spawn(async move {
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, OptNeg::default()).await;
+        read_frame(&mut server_side).await;
+        write_frame(
+            &mut server_side,
+            ModificationAction::from(AddHeader::new(b"X-Early", b"yes")),
+        )
+        .await;
+        write_frame(&mut server_side, Action::from(Continue)).await;
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, Action::from(Continue)).await;
+    });
+
+    let mut connection = Client::new(OptNeg::default())
+        .with_early_modification_policy(policy)
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+    connection.header(Header::new(b"Subject", b"Test")).await?;
+    connection.end_of_body().await
+}
+
+#[tokio::test]
+async fn test_early_modification_policy() {
+    let err = early_modification_session(EarlyModificationPolicy::Error)
+        .await
+        .expect_err("Early modification accepted");
+    assert!(matches!(
+        err,
+        ResponseError::Unexpected(ServerCommand::AddHeader(_))
+    ));
+
+    let attached = early_modification_session(EarlyModificationPolicy::Attach)
+        .await
+        .expect("Failed with attached modification");
+    let mut expected = ModificationResponse::builder();
+    expected.push(AddHeader::new(b"X-Early", b"yes"));
+    assert_modifications!(expected.contin(), attached);
+
+    let dropped = early_modification_session(EarlyModificationPolicy::Drop)
+        .await
+        .expect("Failed with dropped modification");
+    assert_modifications!(ModificationResponse::empty_continue(), dropped);
+}
+
+#[tokio::test]
+async fn test_max_pending_modifications() {
+    let headers = || {
+        milter_fn().on_eom(|_ctx| {
+            let mut response = ModificationResponse::builder();
+            for _ in 0..5 {
+                response.push(AddHeader::new(b"X-Flood", b"yes"));
+            }
+            response.contin()
+        })
+    };
+
+    let client = Client::new(OptNeg::default()).with_max_pending_modifications(3, usize::MAX);
+    let (mut connection, _handle) =
+        utils::connect_configured(headers(), client, |server| server).await;
+    let err = connection
+        .end_of_body()
+        .await
+        .expect_err("Too many modifications accepted");
+    assert!(matches!(
+        err,
+        ResponseError::TooManyModifications { count: 4, .. }
+    ));
+    // The rest of the response is unread, nothing else may be sent
+    let err = connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect_err("Overrun connection still used");
+    assert!(matches!(
+        err,
+        ResponseError::TooManyModifications { count: 4, .. }
+    ));
+
+    let size = ModificationAction::from(AddHeader::new(b"X-Flood", b"yes")).len();
+    let client = Client::new(OptNeg::default()).with_max_pending_modifications(10, 2 * size);
+    let (mut connection, _handle) =
+        utils::connect_configured(headers(), client, |server| server).await;
+    let err = connection
+        .end_of_body()
+        .await
+        .expect_err("Too many modification bytes accepted");
+    assert!(matches!(
+        err,
+        ResponseError::TooManyModifications { count: 3, bytes } if bytes == 3 * size
+    ));
+
+    let client = Client::new(OptNeg::default()).with_max_pending_modifications(5, 5 * size);
+    let (mut connection, handle) = utils::connect_with(headers(), client).await;
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Modifications within the limit refused");
+    assert_eq!(response.modifications().len(), 5);
+    connection.quit().await.expect("Failed to quit");
+    handle.await.expect("Server task failed");
+}
+
+/// No reply to connect and helo, no unknown commands
+fn no_reply_options() -> OptNeg {
+    OptNeg {
+        protocol: Protocol::NR_CONNECT | Protocol::NR_HELO | Protocol::NO_UNKNOWN,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_connection_stats() {
+    let milter = TestMilter::new().offering(no_reply_options());
+    let (mut connection, _handle) = utils::connect(milter, no_reply_options()).await;
+
+    connection
+        .connect(Connect::new(b"localhost", Family::Unknown, None, b""))
+        .await
+        .expect("Failed to connect");
+    connection
+        .unknown(b"NOOP".as_slice())
+        .await
+        .expect("Failed unknown");
+    connection
+        .mail(Mail::from(b"<a@test.local>".as_slice()))
+        .await
+        .expect("Failed mail");
+    connection.end_of_body().await.expect("Failed end of body");
+
+    let stats = connection.stats();
+    assert_eq!(stats.sent, 3);
+    assert_eq!(stats.unanswered, 1);
+    assert_eq!(stats.acknowledged, 2);
+    assert_eq!(stats.skipped, 1);
+    assert_eq!(stats.in_flight(), 0);
+}
+
+#[tokio::test]
+async fn test_stray_answer() {
+    let (client_side, mut server_side) = tokio::io::duplex(2_usize.pow(16));
+    tokio::spawn(async move {
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, no_reply_options()).await;
+        // Answer helo although no reply was requested
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, Action::from(Reject)).await;
+        read_frame(&mut server_side).await;
+    });
+    let mut connection = Client::new(no_reply_options())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    connection
+        .helo(Helo::from(b"reject.test".as_slice()))
+        .await
+        .expect("Failed helo");
+    // Let the stray answer arrive
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let err = connection
+        .mail(Mail::from(b"<a@test.local>".as_slice()))
+        .await
+        .expect_err("Stray reject not detected");
+    assert!(matches!(
+        err,
+        ResponseError::Stray(ServerCommand::Reject(_))
+    ));
+}
@@ -0,0 +1,547 @@
+//! How the server handles what the milter or the client got wrong
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use miltr_client::{Client, ResponseError};
+use miltr_common::{
+    actions::{Action, ActionKind, Reject, Tempfail},
+    assert_modifications, codes,
+    commands::{Connect, Family, Macro},
+    decoding::ServerCommand,
+    modifications::{
+        body::ReplaceBody,
+        headers::AddHeader,
+        quarantine::Quarantine,
+        recipients::{AddRecipient, DeleteRecipient},
+        ModificationAction, ModificationResponse,
+    },
+    optneg::{Capability, OptNeg},
+    ProtocolError,
+};
+use miltr_server::{
+    milter_fn, AccessList, ConnectionSummary, DroppedModsPolicy, Error, ImplErrorAction,
+    ImplErrorPolicy, LoadShedMilter, OversizePolicy, QuarantineFallback, RecipientPolicy,
+    ResponseTranslation, Server, ServerStats, UnknownFamilyPolicy, Utf8Action, Utf8Fields,
+    Utf8Policy,
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::{
+    milter::{SeenFamily, TestMilter},
+    write_command,
+};
+
+#[tokio::test]
+async fn test_impl_error_tempfail() {
+    let policy = ImplErrorPolicy::respond(ImplErrorAction::Tempfail);
+    let client = Client::new(OptNeg::default());
+    let (mut connection, handle) =
+        utils::connect_configured(TestMilter::new(), client, move |server| {
+            server.with_impl_error_policy(policy)
+        })
+        .await;
+
+    let statuses = connection
+        .recipients([
+            "<first@test.local>".as_bytes(),
+            "<error@test.local>".as_bytes(),
+            "<third@test.local>".as_bytes(),
+        ])
+        .await
+        .expect("Failed sending recipients");
+
+    assert!(matches!(statuses[0], Action::Continue(_)));
+    assert!(matches!(statuses[1], Action::Tempfail(_)));
+    assert!(matches!(statuses[2], Action::Continue(_)));
+
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Error was not tolerated");
+}
+
+#[tokio::test]
+async fn test_impl_error_reject_and_close() {
+    let policy = ImplErrorPolicy::respond(ImplErrorAction::Reject).and_close();
+    let client = Client::new(OptNeg::default());
+    let (mut connection, handle) =
+        utils::connect_configured(TestMilter::new(), client, move |server| {
+            server.with_impl_error_policy(policy)
+        })
+        .await;
+
+    let statuses = connection
+        .recipients(["<error@test.local>".as_bytes()])
+        .await
+        .expect("Failed sending recipients");
+    assert!(matches!(statuses[0], Action::Reject(_)));
+
+    let result = handle.await.expect("Server task failed");
+    assert!(matches!(result, Err(Error::Impl { .. })));
+}
+
+#[tokio::test]
+async fn test_impl_error_propagates_by_default() {
+    let client = Client::new(OptNeg::default());
+    let (mut connection, handle) =
+        utils::connect_configured(TestMilter::new(), client, |server| server).await;
+
+    connection
+        .recipients(["<error@test.local>".as_bytes()])
+        .await
+        .expect_err("Server answered despite the error");
+
+    let result = handle.await.expect("Server task failed");
+    assert!(matches!(result, Err(Error::Impl { .. })));
+}
+
+/// Larger than the default buffer size of client and server
+const LARGE_BODY_LEN: usize = 2_usize.pow(16) + 10;
+
+/// A test milter replacing every body with one too large for a frame
+fn large_body_milter() -> TestMilter {
+    TestMilter::new().answering(|| {
+        let mut response = ModificationResponse::builder();
+        response.push(ReplaceBody::new(&vec![b'a'; LARGE_BODY_LEN]));
+        response.contin()
+    })
+}
+
+#[tokio::test]
+async fn test_oversize_error() {
+    let (mut connection, handle) = utils::connect_configured(
+        large_body_milter(),
+        Client::new(OptNeg::default()),
+        |server| server,
+    )
+    .await;
+
+    connection
+        .end_of_body()
+        .await
+        .expect_err("Oversized response was sent");
+
+    let result = handle.await.expect("Server task failed");
+    assert!(matches!(
+        result,
+        Err(Error::Codec(ProtocolError::TooMuchData(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_oversize_split() {
+    let (mut connection, _handle) = utils::connect_configured(
+        large_body_milter(),
+        Client::new(OptNeg::default()),
+        |server| server.with_oversize_policy(OversizePolicy::Split),
+    )
+    .await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    let body_len: usize = response
+        .modifications()
+        .iter()
+        .map(|m| match m {
+            ModificationAction::ReplaceBody(body) => body.body().len(),
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(response.modifications().len(), 2);
+    assert_eq!(body_len, LARGE_BODY_LEN);
+}
+
+#[tokio::test]
+async fn test_oversize_drop_body_replacement() {
+    let (mut connection, _handle) = utils::connect_configured(
+        large_body_milter(),
+        Client::new(OptNeg::default()),
+        |server| server.with_oversize_policy(OversizePolicy::DropBodyReplacement),
+    )
+    .await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    assert!(response.modifications().is_empty());
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+}
+
+#[tokio::test]
+async fn test_utf8_validation_tempfail() {
+    let (mut connection, _handle) = utils::connect_configured(
+        TestMilter::new(),
+        Client::new(OptNeg::default()),
+        |server| {
+            server.with_utf8_validation(Utf8Policy::new(Utf8Fields::all(), Utf8Action::Tempfail))
+        },
+    )
+    .await;
+
+    let statuses = connection
+        .recipients(["<first@test.local>".as_bytes(), b"<gr\xfc\xdf@test.local>"])
+        .await
+        .expect("Failed sending recipients");
+
+    assert!(matches!(statuses[0], Action::Continue(_)));
+    assert!(matches!(statuses[1], Action::Tempfail(_)));
+}
+
+#[tokio::test]
+async fn test_utf8_validation_replaces() {
+    let (mut connection, _handle) = utils::connect_configured(
+        TestMilter::new(),
+        Client::new(OptNeg::default()),
+        |server| {
+            server.with_utf8_validation(Utf8Policy::new(Utf8Fields::all(), Utf8Action::Replace))
+        },
+    )
+    .await;
+
+    let statuses = connection
+        .recipients([
+            &b"<gr\xfc\xdf@test.local>"[..],
+            &b"<reject\xff@test.local>"[..],
+        ])
+        .await
+        .expect("Failed sending recipients");
+
+    // The milter is still called, rejecting the second recipient
+    assert!(matches!(statuses[0], Action::Continue(_)));
+    assert!(matches!(statuses[1], Action::Reject(_)));
+}
+
+/// Connect with an unknown family to a server applying `policy`
+async fn unknown_family_session(
+    policy: UnknownFamilyPolicy,
+) -> (
+    Vec<SeenFamily>,
+    Result<ConnectionSummary, Error<&'static str>>,
+) {
+    let milter = TestMilter::new();
+    let seen = milter.record();
+    let client = Client::new(OptNeg::default());
+    let (mut connection, handle) = utils::connect_configured(milter, client, move |server| {
+        server.with_unknown_family_policy(policy)
+    })
+    .await;
+
+    let connect = Connect::new(b"localhost", Family::Other(b'X'), Some(25), b"somewhere");
+    if connection.connect(connect).await.is_ok() {
+        connection.quit().await.expect("Failed to quit");
+    }
+    let result = handle.await.expect("Server task failed");
+    let connects = seen.lock().expect("Poisoned").connects.clone();
+    (connects, result)
+}
+
+#[tokio::test]
+async fn test_unknown_family() {
+    let (seen, result) = unknown_family_session(UnknownFamilyPolicy::Preserve).await;
+    result.expect("Server failed handling the connection");
+    assert_eq!(seen, [(Family::Other(b'X'), Some(25))]);
+
+    let (seen, result) = unknown_family_session(UnknownFamilyPolicy::AsUnknown).await;
+    result.expect("Server failed handling the connection");
+    assert_eq!(seen, [(Family::Unknown, None)]);
+
+    let (seen, result) = unknown_family_session(UnknownFamilyPolicy::Error).await;
+    assert!(result.is_err());
+    assert!(seen.is_empty());
+}
+
+#[tokio::test]
+async fn test_recipient_policy() {
+    let milter = milter_fn().on_eom(|_ctx| {
+        let mut response = ModificationResponse::builder();
+        response.push(DeleteRecipient::new(b"<Bob@test.local>"));
+        response.push(DeleteRecipient::new(b"<eve@test.local>"));
+        response.push(AddRecipient::new(b"<carol@test.local"));
+        response.push(AddRecipient::new(b"<dave@test.local>"));
+        response.contin()
+    });
+    let (mut connection, handle) =
+        utils::connect_configured(milter, Client::new(OptNeg::default()), |server| {
+            server.with_recipient_policy(RecipientPolicy::Trim)
+        })
+        .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .recipient(b"<bob@test.local>".as_slice())
+        .await
+        .expect("Failed sending recipient");
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    let mut expected = ModificationResponse::builder();
+    expected.push(DeleteRecipient::new(b"<Bob@test.local>"));
+    expected.push(AddRecipient::new(b"<dave@test.local>"));
+    assert_modifications!(expected.contin(), response);
+}
+
+#[tokio::test]
+async fn test_dropped_modifications() {
+    let stats = ServerStats::new();
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let milter = milter_fn().on_eom(|_ctx| {
+        let mut response = ModificationResponse::builder();
+        response.push(ReplaceBody::new(b"replaced"));
+        response.contin()
+    });
+    let client = Client::new(OptNeg {
+        capabilities: Capability::SMFIF_ADDHDRS,
+        ..Default::default()
+    });
+
+    let server_stats = stats.clone();
+    let seen = dropped.clone();
+    let (mut connection, handle) = utils::connect_configured(milter, client, move |server| {
+        server
+            .with_stats(server_stats)
+            .with_dropped_mods_policy(DroppedModsPolicy::Respond(Tempfail.into()))
+            .on_dropped_modifications(move |dropped| {
+                seen.lock().expect("Poisoned").extend_from_slice(dropped);
+            })
+    })
+    .await;
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    assert!(response.modifications().is_empty());
+    assert!(matches!(response.final_action(), Action::Tempfail(_)));
+    let dropped = dropped.lock().expect("Poisoned");
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].missing, Capability::SMFIF_CHGBODY);
+    assert_eq!(stats.dropped_modifications(), 1);
+}
+
+#[tokio::test]
+async fn test_access_list() {
+    let access = AccessList::new()
+        .allow("198.51.100.1".parse().expect("Valid network"))
+        .deny("192.0.2.0/24".parse().expect("Valid network"));
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    for (address, allowed) in [("198.51.100.1", true), ("192.0.2.77", false)] {
+        let counted = calls.clone();
+        let milter = milter_fn().on_mail(move |_mail| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            Reject.into()
+        });
+        let list = access.clone();
+        let (mut connection, handle) =
+            utils::connect_configured(milter, Client::new(OptNeg::default()), move |server| {
+                server.with_access_list(list)
+            })
+            .await;
+
+        let connect = connection
+            .connect(Connect::new(
+                b"client",
+                Family::Inet,
+                Some(25),
+                address.as_bytes(),
+            ))
+            .await;
+        if allowed {
+            connect.expect("Allowed connect not accepted");
+            connection
+                .mail(b"<a@test.local>".as_slice())
+                .await
+                .expect("Accepted session not passed by");
+            let response = connection
+                .end_of_body()
+                .await
+                .expect("Failed sending end of body");
+            assert!(response.modifications().is_empty());
+        } else {
+            connect.expect_err("Denied connect not rejected");
+        }
+        connection.quit().await.expect("Failed to quit");
+
+        let summary = handle
+            .await
+            .expect("Server task failed")
+            .expect("Server failed handling the connection");
+        assert_eq!(summary.messages, u64::from(allowed));
+    }
+
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_access_list_bypasses_milter() {
+    let access = AccessList::new().allow("198.51.100.1".parse().expect("Valid network"));
+    let milter = TestMilter::new();
+    let seen = milter.record();
+    let (mut connection, handle) =
+        utils::connect_configured(milter, Client::new(OptNeg::default()), move |server| {
+            server.with_access_list(access)
+        })
+        .await;
+
+    connection
+        .connect(Connect::new(
+            b"client",
+            Family::Inet,
+            Some(25),
+            b"198.51.100.1",
+        ))
+        .await
+        .expect("Allowed connect not accepted");
+    for _ in 0..2 {
+        connection
+            .macro_(Macro::new(codes::SMFIC_MAIL))
+            .await
+            .expect("Failed feeding macros");
+        connection
+            .mail(b"<a@test.local>".as_slice())
+            .await
+            .expect("Accepted session not passed by");
+        connection
+            .end_of_body()
+            .await
+            .expect("Failed sending end of body");
+    }
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Accepted session not passed by");
+    connection.abort_and_close().await.expect("Failed to abort");
+
+    let summary = handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    assert_eq!(summary.messages, 3);
+    // Negotiated before the connect is checked
+    let seen = seen.lock().expect("Poisoned");
+    assert_eq!(seen.total_calls(), seen.calls("option_negotiation"));
+}
+
+#[tokio::test]
+async fn test_load_shed_milter() {
+    let (mut connection, handle) =
+        utils::connect_configured(LoadShedMilter, Client::new(OptNeg::default()), |server| {
+            server
+        })
+        .await;
+
+    let err = connection
+        .connect(Connect::new(
+            b"client",
+            Family::Inet,
+            Some(25),
+            b"192.0.2.1",
+        ))
+        .await
+        .expect_err("Shed connect not failed");
+    assert!(matches!(
+        err,
+        ResponseError::Unexpected(ServerCommand::Tempfail(_))
+    ));
+    connection.quit().await.expect("Failed to quit");
+
+    let summary = handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    assert_eq!(summary.action_count(ActionKind::Tempfail), 1);
+}
+
+#[tokio::test]
+async fn test_strict_lengths() {
+    let (mut client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let server = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        Server::default_postfix(&mut milter)
+            .with_strict_lengths(true)
+            .handle_connection(server_side.compat())
+            .await
+    });
+
+    // A header without even the null byte terminating its name
+    write_command(&mut client_side, b'L', b"").await;
+
+    let err = server
+        .await
+        .expect("Server task failed")
+        .expect_err("Short header accepted");
+    let Error::Codec(ProtocolError::NotEnoughData(err)) = err else {
+        panic!("Unexpected error: {err}");
+    };
+    assert_eq!(err.item, "SMFIC_HEADER");
+    assert_eq!(err.expected, 2);
+}
+
+/// A test milter quarantining every mail, regardless of the negotiated
+/// capabilities
+fn quarantining_milter() -> TestMilter {
+    TestMilter::new().answering(|| {
+        let mut response = ModificationResponse::builder();
+        response.push(Quarantine::new(b"suspicious"));
+        response.contin()
+    })
+}
+
+fn without_quarantine() -> Client {
+    Client::new(OptNeg {
+        capabilities: Capability::all().difference(Capability::SMFIF_QUARANTINE),
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn test_translate_quarantine_to_header() {
+    let (mut connection, _handle) =
+        utils::connect_with(quarantining_milter(), without_quarantine()).await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    let mut expected = ModificationResponse::builder();
+    expected.push(AddHeader::new(b"X-Quarantine", b"suspicious"));
+    assert_modifications!(expected.contin(), response);
+}
+
+#[tokio::test]
+async fn test_translate_quarantine_to_tempfail() {
+    let (mut connection, _handle) =
+        utils::connect_configured(quarantining_milter(), without_quarantine(), |server| {
+            server.with_response_translation(
+                ResponseTranslation::new().with_quarantine_fallback(QuarantineFallback::Tempfail),
+            )
+        })
+        .await;
+
+    let response = connection.end_of_body().await.expect("Failed end of body");
+
+    assert!(response.modifications().is_empty());
+    assert!(matches!(response.final_action(), Action::Tempfail(_)));
+}
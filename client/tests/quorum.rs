@@ -0,0 +1,131 @@
+//! Asking several milters at once
+
+use std::time::Duration;
+
+use miltr_client::{Aggregation, BackendError, Client, MilterQuorum};
+use miltr_common::{
+    actions::Action, clock::ManualClock, commands::Recipient, modifications::ModificationResponse,
+    optneg::OptNeg,
+};
+use miltr_server::milter_fn;
+
+mod utils;
+
+use utils::milter::TestMilter;
+
+#[tokio::test]
+async fn test_quorum_strictest() {
+    let (rejecting, _) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+    let (accepting, _) = utils::connect(milter_fn(), OptNeg::default()).await;
+    let mut quorum = MilterQuorum::new(Aggregation::Strictest)
+        .with_backend(rejecting)
+        .with_backend(accepting);
+
+    let verdict = quorum
+        .command(Recipient::from(b"<reject@test.local>".as_slice()))
+        .await
+        .expect("Quorum did not answer");
+
+    assert!(matches!(verdict.action, Action::Reject(_)));
+    assert!(matches!(
+        verdict.answers[1]
+            .as_ref()
+            .map(ModificationResponse::final_action),
+        Ok(Action::Continue(_))
+    ));
+    quorum.quit().await.expect("Failed to quit");
+}
+
+#[tokio::test]
+async fn test_quorum_disables_failed_backend() {
+    let (failing, _) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+    let (accepting, _) = utils::connect(milter_fn(), OptNeg::default()).await;
+    let mut quorum = MilterQuorum::new(Aggregation::Strictest)
+        .with_backend(failing)
+        .with_backend(accepting);
+
+    let verdict = quorum
+        .command(Recipient::from(b"<error@test.local>".as_slice()))
+        .await
+        .expect("Quorum did not answer");
+
+    assert!(matches!(verdict.action, Action::Continue(_)));
+    assert!(matches!(verdict.answers[0], Err(BackendError::Response(_))));
+    assert_eq!(quorum.active(), 1);
+
+    let verdict = quorum
+        .command(Recipient::from(b"<second@test.local>".as_slice()))
+        .await
+        .expect("Quorum did not answer");
+    assert!(matches!(verdict.answers[0], Err(BackendError::Disabled)));
+    quorum.quit().await.expect("Failed to quit");
+}
+
+#[tokio::test]
+async fn test_quorum_timeout() {
+    let slow = TestMilter::new().delaying("rcpt", Duration::from_secs(1));
+    let (slow, _) = utils::connect(slow, OptNeg::default()).await;
+    let (fast, _) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+    let mut quorum = MilterQuorum::new(Aggregation::FirstResponse)
+        .with_backend_timeout(slow, Duration::from_millis(50))
+        .with_backend(fast);
+
+    let verdict = quorum
+        .command(Recipient::from(b"<first@test.local>".as_slice()))
+        .await
+        .expect("Quorum did not answer");
+
+    assert!(matches!(verdict.action, Action::Continue(_)));
+    assert!(matches!(verdict.answers[0], Err(BackendError::Timeout)));
+    assert_eq!(quorum.active(), 1);
+}
+
+#[tokio::test]
+async fn test_quorum_shutdown() {
+    let (mut first, first_handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+    let (second, second_handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+    first
+        .mail(b"<sender@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    let quorum = MilterQuorum::new(Aggregation::Strictest)
+        .with_backend(first)
+        .with_backend(second);
+
+    quorum
+        .shutdown(Duration::from_secs(1))
+        .await
+        .expect("Failed shutting down");
+
+    let first = first_handle.await.expect("Server task failed");
+    let second = second_handle.await.expect("Server task failed");
+    assert_eq!(first.seen().calls("abort"), 1);
+    assert_eq!(second.seen().calls("abort"), 0);
+}
+
+#[tokio::test]
+async fn test_quorum_manual_clock() {
+    let clock = ManualClock::new();
+    let client = Client::new(OptNeg::default()).with_clock(clock.clone());
+    let slow = TestMilter::new().delaying("rcpt", Duration::from_secs(1));
+    let (slow, _) = utils::connect_with(slow, client).await;
+    let (fast, _) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+    let mut quorum = MilterQuorum::new(Aggregation::FirstResponse)
+        .with_backend_timeout(slow, Duration::from_secs(30))
+        .with_backend(fast);
+
+    let advancing = clock.clone();
+    tokio::spawn(async move {
+        while advancing.sleeping() == 0 {
+            tokio::task::yield_now().await;
+        }
+        advancing.advance(Duration::from_secs(30));
+    });
+    let verdict = quorum
+        .command(Recipient::from(b"<first@test.local>".as_slice()))
+        .await
+        .expect("Quorum did not answer");
+
+    assert!(matches!(verdict.answers[0], Err(BackendError::Timeout)));
+    assert_eq!(clock.elapsed(), Duration::from_secs(30));
+}
@@ -0,0 +1,426 @@
+//! Property test: random conversations between the client and a
+//! `miltr-server` arrive unchanged on both sides.
+//!
+//! Each case generates a session from a seed: connect information, one or
+//! more messages with recipients, headers, body chunks and unknown
+//! commands, macros for the commands that take them, messages aborted
+//! midway, the modifications and final action the milter answers each
+//! end of body with, and possibly an action refusing a command early. The
+//! milter records every command it receives, the client collects every
+//! answer, both are compared with what the other side sent.
+//!
+//! Set `MILTR_ROUND_TRIP_CASES` to run more cases than the default.
+
+mod utils;
+
+use std::{
+    env,
+    sync::{Arc, Mutex},
+};
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use async_trait::async_trait;
+use miltr_client::{Client, ResponseError};
+use miltr_common::{
+    actions::{Action, Continue, Discard, Reject, Replycode, Tempfail},
+    codes,
+    commands::{Body, Connect, Family, Header, Helo, Macro, Mail, Recipient, Unknown},
+    decoding::ServerCommand,
+    encoding::{ServerMessage, Writable},
+    modifications::{ModificationAction, ModificationResponse},
+    optneg::OptNeg,
+};
+use miltr_server::{Milter, Server};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+const DEFAULT_CASES: u64 = 256;
+
+/// A command as the client sent it, or the milter received it
+#[derive(Debug, Clone, PartialEq)]
+enum Sent {
+    Connect(Connect),
+    Helo(Helo),
+    Mail(Mail),
+    Recipient(Recipient),
+    Data,
+    Header(Header),
+    EndOfHeader,
+    Body(Body),
+    Unknown(Unknown),
+    EndOfBody,
+    Macro(Macro),
+    Abort,
+}
+
+/// The answer to a message, as the milter sent it
+#[derive(Debug, Clone)]
+struct Answer {
+    modifications: Vec<ModificationAction>,
+    action: Action,
+}
+
+/// A generated conversation
+#[derive(Debug, Clone)]
+struct Conversation {
+    commands: Vec<Sent>,
+    answers: Vec<Answer>,
+    /// The index of a command the milter refuses, with the action to
+    refusal: Option<(usize, Action)>,
+}
+
+impl<'a> Arbitrary<'a> for Conversation {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut connect: Connect = u.arbitrary()?;
+        // `Other` may carry the byte of a named family
+        connect.family = Family::from(u8::from(connect.family));
+        let mut commands = Vec::new();
+        with_macros(
+            u,
+            &mut commands,
+            codes::SMFIC_CONNECT,
+            Sent::Connect(connect),
+        )?;
+        let helo = Sent::Helo(u.arbitrary()?);
+        with_macros(u, &mut commands, codes::SMFIC_HELO, helo)?;
+        let mut answers = Vec::new();
+        for _ in 0..u.int_in_range(1..=3)? {
+            let mut message = Vec::new();
+            let mail = Sent::Mail(u.arbitrary()?);
+            with_macros(u, &mut message, codes::SMFIC_MAIL, mail)?;
+            for _ in 0..u.int_in_range(1..=3)? {
+                let recipient = Sent::Recipient(u.arbitrary()?);
+                with_macros(u, &mut message, codes::SMFIC_RCPT, recipient)?;
+            }
+            if u.ratio(1, 4)? {
+                message.push(Sent::Unknown(u.arbitrary()?));
+            }
+            with_macros(u, &mut message, codes::SMFIC_DATA, Sent::Data)?;
+            for _ in 0..u.int_in_range(0..=4)? {
+                message.push(Sent::Header(u.arbitrary()?));
+            }
+            with_macros(u, &mut message, codes::SMFIC_EOH, Sent::EndOfHeader)?;
+            for _ in 0..u.int_in_range(0..=3)? {
+                let body: Body = u.arbitrary()?;
+                // Empty chunks are not sent by the client
+                if !body.as_bytes().is_empty() {
+                    message.push(Sent::Body(body));
+                }
+            }
+
+            // Abort some messages midway, the session goes on with the next
+            if u.ratio(1, 4)? {
+                let cut = *u.choose(&commands_only(&message))?;
+                message.truncate(cut + 1);
+                message.push(Sent::Abort);
+            } else {
+                with_macros(u, &mut message, codes::SMFIC_BODYEOB, Sent::EndOfBody)?;
+                answers.push(Answer {
+                    modifications: u.arbitrary()?,
+                    action: final_action(u)?,
+                });
+            }
+            commands.append(&mut message);
+        }
+
+        let refusal = if u.ratio(1, 4)? {
+            let refusable: Vec<usize> = commands_only(&commands)
+                .into_iter()
+                .filter(|index| !matches!(commands[*index], Sent::EndOfBody | Sent::Abort))
+                .collect();
+            Some((*u.choose(&refusable)?, final_action(u)?))
+        } else {
+            None
+        };
+        if let Some((index, action)) = &refusal {
+            if !matches!(action, Action::Continue(_)) {
+                commands.truncate(index + 1);
+                commands.push(Sent::Abort);
+                let messages = commands
+                    .iter()
+                    .filter(|command| matches!(command, Sent::EndOfBody))
+                    .count();
+                answers.truncate(messages);
+            }
+        }
+
+        Ok(Self {
+            commands,
+            answers,
+            refusal,
+        })
+    }
+}
+
+/// Push `command`, possibly after macros for the stage with `code`
+fn with_macros(
+    u: &mut Unstructured<'_>,
+    commands: &mut Vec<Sent>,
+    code: u8,
+    command: Sent,
+) -> Result<()> {
+    if u.ratio(1, 2)? {
+        let mut macros: Macro = u.arbitrary()?;
+        macros.code = code;
+        commands.push(Sent::Macro(macros));
+    }
+    commands.push(command);
+    Ok(())
+}
+
+/// The indices of `commands` that are not macros
+fn commands_only(commands: &[Sent]) -> Vec<usize> {
+    commands
+        .iter()
+        .enumerate()
+        .filter(|(_, command)| !matches!(command, Sent::Macro(_)))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// An action ending a stage
+fn final_action(u: &mut Unstructured<'_>) -> Result<Action> {
+    Ok(match u.int_in_range(0..=4)? {
+        0 => Continue.into(),
+        1 => Reject.into(),
+        2 => Tempfail.into(),
+        3 => Discard.into(),
+        _ => Replycode::new(
+            [
+                u.int_in_range(4..=5)?,
+                u.int_in_range(0..=9)?,
+                u.int_in_range(0..=9)?,
+            ],
+            [u.int_in_range(4..=5)?, u.arbitrary()?, u.arbitrary()?],
+            &String::from_utf8_lossy(&u.arbitrary::<Vec<u8>>()?).replace('\0', ""),
+        )
+        .into(),
+    })
+}
+
+/// Records what it receives, answering as the conversation says
+struct Recorder {
+    conversation: Conversation,
+    received: Arc<Mutex<Vec<Sent>>>,
+    messages: usize,
+}
+
+impl Recorder {
+    fn record(&mut self, command: Sent) -> Action {
+        let mut received = self.received.lock().expect("Poisoned");
+        received.push(command);
+        match &self.conversation.refusal {
+            Some((index, action)) if *index + 1 == received.len() => action.clone(),
+            _ => Continue.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Milter for Recorder {
+    type Error = &'static str;
+
+    async fn connect(&mut self, connect: Connect) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Connect(connect)))
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Helo(helo)))
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Mail(mail)))
+    }
+
+    async fn rcpt(&mut self, recipient: Recipient) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Recipient(recipient)))
+    }
+
+    async fn data(&mut self) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Data))
+    }
+
+    async fn header(&mut self, header: Header) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Header(header)))
+    }
+
+    async fn end_of_header(&mut self) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::EndOfHeader))
+    }
+
+    async fn body(&mut self, body: Body) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Body(body)))
+    }
+
+    async fn unknown(&mut self, unknown: Unknown) -> Result<Action, Self::Error> {
+        Ok(self.record(Sent::Unknown(unknown)))
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        self.record(Sent::EndOfBody);
+        let answer = &self.conversation.answers[self.messages];
+        self.messages += 1;
+
+        let mut builder = ModificationResponse::builder();
+        for modification in &answer.modifications {
+            builder.push(modification.clone());
+        }
+        Ok(builder.build(answer.action.clone()))
+    }
+
+    async fn macro_(&mut self, macros: Macro) -> Result<(), Self::Error> {
+        self.received
+            .lock()
+            .expect("Poisoned")
+            .push(Sent::Macro(macros));
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.received.lock().expect("Poisoned").push(Sent::Abort);
+        Ok(Continue.into())
+    }
+}
+
+/// The wire bytes of `item`, to compare actions
+fn encoded<W: Writable>(item: &W) -> Vec<u8> {
+    let mut buffer = bytes::BytesMut::new();
+    buffer.extend_from_slice(&[item.code()]);
+    item.write(&mut buffer);
+    buffer.to_vec()
+}
+
+/// Whether the milter received what the client sent
+fn same(received: &Sent, sent: &Sent) -> bool {
+    match (received, sent) {
+        // Nothing follows an unknown family on the wire and a missing port
+        // is written as 0, compare what was on the wire
+        (Sent::Connect(received), Sent::Connect(sent)) => encoded(received) == encoded(sent),
+        (received, sent) => received == sent,
+    }
+}
+
+/// Run `conversation`, panicking on any difference
+async fn run(seed: u64, conversation: Conversation) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut milter = Recorder {
+        conversation: conversation.clone(),
+        received: Arc::clone(&received),
+        messages: 0,
+    };
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let handle = tokio::spawn(async move {
+        // Keep the session on abort, unlike for postfix
+        Server::new(&mut milter, false, 2_usize.pow(16))
+            .handle_connection(server_side.compat())
+            .await
+            .unwrap_or_else(|e| panic!("Seed {seed}: server failed: {e:?}"));
+    });
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    let mut answers = conversation.answers.iter();
+    for (index, command) in conversation.commands.iter().enumerate() {
+        let result = match command.clone() {
+            Sent::Connect(connect) => connection.connect(connect).await,
+            Sent::Helo(helo) => connection.helo(helo).await,
+            Sent::Mail(mail) => connection.mail(mail).await,
+            Sent::Recipient(recipient) => connection.recipient(recipient).await,
+            Sent::Data => connection.data().await,
+            Sent::Header(header) => connection.header(header).await,
+            Sent::EndOfHeader => connection.end_of_header().await,
+            Sent::Body(body) => connection.body(body).await,
+            Sent::Unknown(unknown) => connection.unknown(unknown).await,
+            Sent::Macro(macros) => connection.macro_(macros).await,
+            Sent::Abort => connection.abort().await,
+            Sent::EndOfBody => {
+                let response = connection
+                    .end_of_body()
+                    .await
+                    .unwrap_or_else(|e| panic!("Seed {seed}: end of body failed: {e:?}"));
+                let answer = answers.next().expect("An answer per message");
+                assert_eq!(
+                    response.modifications(),
+                    answer.modifications,
+                    "Seed {seed}: modifications differ"
+                );
+                assert_eq!(
+                    encoded(response.final_action()),
+                    encoded(&answer.action),
+                    "Seed {seed}: final action differs"
+                );
+                continue;
+            }
+        };
+
+        match (&conversation.refusal, result) {
+            (Some((refused, action)), Err(ResponseError::Unexpected(answer)))
+                if *refused == index =>
+            {
+                assert_eq!(
+                    encoded(&ServerMessage::from(answer_action(answer))),
+                    encoded(&ServerMessage::from(action.clone())),
+                    "Seed {seed}: refusal differs"
+                );
+            }
+            (_, Ok(())) => {}
+            (_, Err(e)) => panic!("Seed {seed}: command {index} failed: {e:?}"),
+        }
+    }
+    connection.quit().await.expect("Failed to quit");
+
+    handle.await.expect("Server task failed");
+    let received = received.lock().expect("Poisoned").clone();
+    assert_eq!(
+        received.len(),
+        conversation.commands.len(),
+        "Seed {seed}: received {received:?}"
+    );
+    for (index, (received, sent)) in received.iter().zip(&conversation.commands).enumerate() {
+        assert!(
+            same(received, sent),
+            "Seed {seed}: command {index} differs, sent {sent:?}, received {received:?}"
+        );
+    }
+}
+
+/// The action of an unexpected answer
+fn answer_action(answer: ServerCommand) -> Action {
+    match answer {
+        ServerCommand::Reject(action) => action.into(),
+        ServerCommand::Tempfail(action) => action.into(),
+        ServerCommand::Discard(action) => action.into(),
+        ServerCommand::Replycode(action) => action.into(),
+        answer => panic!("Refused with {answer:?}"),
+    }
+}
+
+/// A deterministic byte stream to generate cases from
+fn seeded_bytes(seed: u64, len: usize) -> Vec<u8> {
+    // xorshift64*, never zero
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_round_trip_conversations() {
+    let cases = env::var("MILTR_ROUND_TRIP_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(DEFAULT_CASES);
+
+    for seed in 0..cases {
+        let bytes = seeded_bytes(seed, 4096);
+        let conversation = Conversation::arbitrary(&mut Unstructured::new(&bytes))
+            .unwrap_or_else(|e| panic!("Seed {seed}: failed generating: {e}"));
+        run(seed, conversation).await;
+    }
+}
@@ -0,0 +1,253 @@
+//! Serving many connections with one server
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use miltr_client::Client;
+use miltr_common::{clock::ManualClock, optneg::OptNeg};
+use miltr_server::{Server, ServerStats};
+use tokio::io::AsyncWriteExt;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::milter::{Seen, TestMilter};
+
+#[tokio::test]
+async fn test_serve_incoming() {
+    let (acceptor, incoming) = futures::channel::mpsc::unbounded();
+    let stats = ServerStats::new();
+    let server_stats = stats.clone();
+    let server = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        let mut server = Server::default_postfix(&mut milter).with_stats(server_stats);
+        server
+            .serve_incoming(incoming.map(TokioAsyncReadCompatExt::compat))
+            .await
+    });
+
+    // Neither does the milter failing a connection
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    acceptor
+        .unbounded_send(server_side)
+        .expect("Server stopped accepting");
+    let mut failing = Client::new(OptNeg::default())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+    failing
+        .recipient(b"<error@test.local>".as_slice())
+        .await
+        .expect_err("Milter error not reported");
+
+    // A broken connection does not stop the server
+    let (mut broken, server_side) = tokio::io::duplex(2_usize.pow(16));
+    acceptor
+        .unbounded_send(server_side)
+        .expect("Server stopped accepting");
+    broken
+        .write_all(b"\xff\xff\xff\xffO")
+        .await
+        .expect("Failed writing bogus frame");
+
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    acceptor
+        .unbounded_send(server_side)
+        .expect("Server stopped accepting");
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+
+    drop(acceptor);
+    let handled = server.await.expect("Server task failed");
+    assert_eq!(handled, 3);
+    assert_eq!(stats.connections(), 3);
+    assert_eq!(stats.failed_connections(), 2);
+    assert_eq!(stats.messages(), 1);
+}
+
+#[tokio::test]
+async fn test_serve_concurrently() {
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let shared = Arc::clone(&seen);
+    let (sockets, incoming) = futures::channel::mpsc::unbounded();
+    let server = tokio::spawn(async move {
+        Server::default_postfix_factory(move || {
+            TestMilter::new().recording_into(Arc::clone(&shared))
+        })
+        .serve_concurrently(incoming, 4)
+        .await
+    });
+
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+        sockets
+            .unbounded_send(server_side.compat())
+            .expect("Server gone");
+        let connection = Client::new(OptNeg::default())
+            .connect_via(client_side.compat())
+            .await
+            .expect("Failed to setup connection");
+        connections.push(connection);
+    }
+    // Both connections are open at the same time
+    for connection in &mut connections {
+        connection
+            .mail(b"<a@test.local>".as_slice())
+            .await
+            .expect("Failed sending mail");
+    }
+    for connection in connections {
+        connection.quit().await.expect("Failed to quit");
+    }
+    drop(sockets);
+
+    let handled = server.await.expect("Server task failed");
+    assert_eq!(handled, 2);
+    assert_eq!(seen.lock().expect("Poisoned").calls("mail"), 2);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_serve_unix() {
+    let path = std::env::temp_dir().join(format!("miltr-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let shared = Arc::clone(&seen);
+    let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+    let server_path = path.clone();
+    let server = tokio::spawn(async move {
+        Server::default_postfix_factory(move || {
+            TestMilter::new().recording_into(Arc::clone(&shared))
+        })
+        .serve_unix(server_path, 4, async {
+            let _ = stopped.await;
+        })
+        .await
+    });
+
+    let stream = loop {
+        match tokio::net::UnixStream::connect(&path).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(stream.compat())
+        .await
+        .expect("Failed to setup connection");
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+
+    // The running connection is finished after shutdown
+    stop.send(()).expect("Server gone");
+    connection.end_of_body().await.expect("Failed end of body");
+    connection.quit().await.expect("Failed to quit");
+
+    let handled = server
+        .await
+        .expect("Server task failed")
+        .expect("Server failed");
+    assert_eq!(handled, 1);
+    assert_eq!(seen.lock().expect("Poisoned").calls("mail"), 1);
+    std::fs::remove_file(&path).expect("Failed removing the socket");
+}
+
+#[tokio::test]
+async fn test_shutdown_deadline() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let (sockets, incoming) = futures::channel::mpsc::unbounded();
+    let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        Server::default_postfix_factory(TestMilter::new)
+            .with_clock(server_clock)
+            .with_shutdown_deadline(Duration::from_secs(30))
+            .serve_with_shutdown(incoming, 4, async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    sockets
+        .unbounded_send(server_side.compat())
+        .expect("Server gone");
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+
+    stop.send(()).expect("Server gone");
+    while clock.sleeping() == 0 {
+        tokio::task::yield_now().await;
+    }
+    // The running connection may still finish
+    assert!(!server.is_finished());
+    clock.advance(Duration::from_secs(30));
+
+    let handled = server.await.expect("Server task failed");
+    assert_eq!(handled, 1);
+    connection
+        .end_of_body()
+        .await
+        .expect_err("Connection not closed after the deadline");
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_idle_connections() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let (sockets, incoming) = futures::channel::mpsc::unbounded();
+    let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        Server::default_postfix_factory(TestMilter::new)
+            .with_clock(server_clock)
+            .serve_with_shutdown(incoming, 4, async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    // Idle between messages, like postfix keeps its connections
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    sockets
+        .unbounded_send(server_side.compat())
+        .expect("Server gone");
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    stop.send(()).expect("Server gone");
+    while clock.sleeping() == 0 {
+        tokio::task::yield_now().await;
+    }
+    clock.advance(Duration::from_secs(30));
+
+    assert_eq!(server.await.expect("Server task failed"), 1);
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect_err("Idle connection not closed by default");
+}
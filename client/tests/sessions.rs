@@ -0,0 +1,581 @@
+//! Sessions and messages on a connection and what the milter sees of them
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use miltr_client::{Client, ResponseError};
+use miltr_common::{
+    actions::{Action, Continue, SmtpStage},
+    clock::ManualClock,
+    commands::{Connect, Family, Header, Helo, Mail},
+    modifications::{body::ReplaceBody, ModificationAction, ModificationResponse},
+    optneg::OptNeg,
+    ProtocolError,
+};
+use miltr_server::{Error, Milter, Server, ServerStats, SessionContext};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::{milter::TestMilter, read_frame, write_command, write_optneg};
+
+fn connect_info() -> Connect {
+    Connect::new(b"localhost", Family::Unknown, None, b"")
+}
+
+#[tokio::test]
+async fn test_quit_nc_reuse() {
+    let (mut connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    connection
+        .connect(connect_info())
+        .await
+        .expect("Failed to connect");
+    let mut connection = connection
+        .quit_nc()
+        .await
+        .expect("Failed to quit_nc")
+        .reuse();
+    connection
+        .connect(connect_info())
+        .await
+        .expect("Failed to connect again");
+    connection.quit().await.expect("Failed to quit");
+
+    let milter = handle.await.expect("Server task failed");
+    let seen = milter.seen();
+    assert_eq!(seen.calls("option_negotiation"), 1);
+    assert_eq!(seen.calls("connect"), 2);
+    assert_eq!(seen.calls("quit_nc"), 1);
+}
+
+#[tokio::test]
+async fn test_quit_nc_renegotiate() {
+    let client = Client::new(OptNeg::default());
+    let (connection, handle) =
+        utils::connect_with(TestMilter::new(), Client::new(OptNeg::default())).await;
+
+    let mut connection = connection
+        .quit_nc()
+        .await
+        .expect("Failed to quit_nc")
+        .renegotiate(&client)
+        .await
+        .expect("Failed to renegotiate");
+    connection
+        .connect(connect_info())
+        .await
+        .expect("Failed to connect");
+    connection.quit().await.expect("Failed to quit");
+
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("option_negotiation"), 2);
+    assert_eq!(milter.seen().calls("connect"), 1);
+}
+
+#[tokio::test]
+async fn test_quit_nc_requires_new_session() {
+    let (connection, handle) = utils::connect_configured(
+        TestMilter::new(),
+        Client::new(OptNeg::default()),
+        |server| server,
+    )
+    .await;
+
+    let mut connection = connection
+        .quit_nc()
+        .await
+        .expect("Failed to quit_nc")
+        .reuse();
+    connection
+        .helo(b"localhost".as_slice())
+        .await
+        .expect_err("Server accepted helo without connect");
+
+    let result = handle.await.expect("Server task failed");
+    assert!(matches!(
+        result,
+        Err(Error::Codec(ProtocolError::InvalidData(_)))
+    ));
+}
+
+/// Send the commands of a message and its SMTP connection, as postfix
+/// does through libmilter, with no reply requested to none
+async fn libmilter_session(client_side: &mut tokio::io::DuplexStream, sender: &[u8]) {
+    write_command(client_side, b'D', b"C{client_addr}\x00192.0.2.1\0").await;
+    write_command(client_side, b'C', b"localhost\0U").await;
+    assert_eq!(read_frame(client_side).await, b"c");
+    write_command(client_side, b'H', b"localhost\0").await;
+    assert_eq!(read_frame(client_side).await, b"c");
+    write_command(client_side, b'D', b"Mi\0ABC123\0").await;
+    write_command(client_side, b'M', sender).await;
+    assert_eq!(read_frame(client_side).await, b"c");
+    write_command(client_side, b'E', b"").await;
+    assert_eq!(read_frame(client_side).await, b"c");
+}
+
+#[tokio::test]
+async fn test_quit_nc_libmilter_sequence() {
+    let (mut client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let server = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        Server::default_postfix(&mut milter)
+            .handle_connection(server_side.compat())
+            .await
+            .expect("Server failed handling the connection");
+        milter
+    });
+
+    write_optneg(&mut client_side).await;
+    libmilter_session(&mut client_side, b"<a@test.local>\0").await;
+    // The next session re-uses the options, starting right with connect
+    write_command(&mut client_side, b'K', b"").await;
+    libmilter_session(&mut client_side, b"<b@test.local>\0").await;
+    write_command(&mut client_side, b'Q', b"").await;
+
+    let milter = server.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("option_negotiation"), 1);
+    assert_eq!(milter.seen().calls("connect"), 2);
+    assert_eq!(milter.seen().calls("end_of_body"), 2);
+    assert!(milter.context().options().is_some(), "Options not kept");
+}
+
+#[tokio::test]
+async fn test_message_reset() {
+    let (mut connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    connection
+        .mail(Mail::from(b"<a@test.local>".as_slice()))
+        .await
+        .expect("Failed mail");
+    connection.end_of_body().await.expect("Failed end of body");
+    connection
+        .mail(Mail::from(b"<a@test.local>".as_slice()))
+        .await
+        .expect("Failed mail");
+    connection.abort_and_close().await.expect("Failed to abort");
+
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("message_reset"), 2);
+    assert_eq!(milter.seen().calls("abort"), 1);
+}
+
+#[tokio::test]
+async fn test_abort_not_answered() {
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let handle = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        Server::new(&mut milter, false, 2_usize.pow(16))
+            .handle_connection(server_side.compat())
+            .await
+            .expect("Server failed handling the connection");
+        milter
+    });
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    connection
+        .mail(Mail::from(b"<a@test.local>".as_slice()))
+        .await
+        .expect("Failed mail");
+    connection.abort().await.expect("Failed to abort");
+    connection
+        .mail(Mail::from(b"<b@test.local>".as_slice()))
+        .await
+        .expect("Abort answered, taken as the answer to mail");
+    let response = connection.end_of_body().await.expect("Failed end of body");
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+    connection.quit().await.expect("Failed to quit");
+
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("abort"), 1);
+    assert_eq!(milter.seen().calls("message_reset"), 2);
+}
+
+#[tokio::test]
+async fn test_message_reset_not_between_messages() {
+    let (connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    connection.abort_and_close().await.expect("Failed to abort");
+
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("message_reset"), 0);
+    assert_eq!(milter.seen().calls("abort"), 1);
+}
+
+#[tokio::test]
+async fn test_finish_and_quit_finished_mail() {
+    let (mut connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    connection
+        .mail(b"<sender@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.end_of_body().await.expect("Failed end of body");
+    assert!(!connection.in_message());
+    connection
+        .finish_and_quit(Duration::from_secs(1))
+        .await
+        .expect("Failed shutting down");
+
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("abort"), 0);
+}
+
+#[tokio::test]
+async fn test_finish_and_quit_aborts_unfinished_mail() {
+    let (mut connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    connection
+        .mail(b"<sender@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    assert!(connection.in_message());
+    connection
+        .finish_and_quit(Duration::from_secs(1))
+        .await
+        .expect("Failed shutting down");
+
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("abort"), 1);
+}
+
+#[tokio::test]
+async fn test_client_message_limit() {
+    let (mut connection, handle) = utils::connect_configured(
+        TestMilter::new(),
+        Client::new(OptNeg::default()).with_max_messages_per_connection(2),
+        |server| server,
+    )
+    .await;
+
+    for sender in [b"<a@test.local>".as_slice(), b"<b@test.local>".as_slice()] {
+        connection.mail(sender).await.expect("Failed sending mail");
+        connection
+            .end_of_body()
+            .await
+            .expect("Failed sending end of body");
+    }
+    assert!(connection.message_limit_reached());
+    assert_eq!(connection.stats().messages, 2);
+
+    let err = connection
+        .mail(b"<c@test.local>".as_slice())
+        .await
+        .expect_err("Mail after the limit was sent");
+    assert!(matches!(err, ResponseError::MessageLimit));
+    connection.quit().await.expect("Failed to quit");
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server did not see the quit");
+}
+
+#[tokio::test]
+async fn test_server_message_limit() {
+    let stats = ServerStats::new();
+    let server_stats = stats.clone();
+    let (mut connection, handle) = utils::connect_configured(
+        TestMilter::new(),
+        Client::new(OptNeg::default()),
+        move |server| {
+            server
+                .with_stats(server_stats)
+                .with_max_messages_per_connection(1)
+        },
+    )
+    .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit_nc().await.expect("Failed to quit_nc");
+
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    assert_eq!(stats.messages(), 1);
+    assert_eq!(stats.recycled_connections(), 1);
+}
+
+#[tokio::test]
+async fn test_session_context_timing() {
+    let (mut connection, handle) = utils::connect(TestMilter::new(), OptNeg::default()).await;
+
+    connection
+        .connect(connect_info())
+        .await
+        .expect("Failed sending connect");
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    connection
+        .mail(b"<sender@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.quit().await.expect("Failed to quit");
+
+    let milter = handle.await.expect("Server task failed");
+    let mail = milter.seen().timings[0].clone();
+    assert!(mail.since_previous_command.expect("No previous command") >= Duration::from_millis(20));
+    assert!(mail.elapsed_in_message.is_some());
+    let ctx = milter.context();
+    assert!(ctx.elapsed_since_connect() >= Duration::from_millis(20));
+    assert!(ctx.elapsed_in_stage(SmtpStage::Connect) >= Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn test_message_deadline() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let milter = TestMilter::new();
+    let seen = milter.record();
+    let client = Client::new(OptNeg::default()).with_clock(clock.clone());
+    let (mut connection, handle) = utils::connect_configured(milter, client, move |server| {
+        server.with_clock(server_clock)
+    })
+    .await;
+
+    connection.set_message_deadline(Duration::from_secs(10));
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    clock.advance(Duration::from_secs(4));
+    connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+
+    // The deadline ended with its message
+    connection
+        .mail(b"<b@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.quit().await.expect("Failed to quit");
+
+    handle
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    let remaining: Vec<_> = seen
+        .lock()
+        .expect("Poisoned")
+        .timings
+        .iter()
+        .map(|timing| timing.remaining_time)
+        .collect();
+    assert_eq!(
+        remaining,
+        vec![
+            Some(Duration::from_secs(10)),
+            Some(Duration::from_secs(6)),
+            None
+        ]
+    );
+}
+
+/// The body replacements of `response`, concatenated
+fn replaced_body(response: &ModificationResponse) -> Option<Vec<u8>> {
+    let mut parts = response.modifications().iter().filter_map(|m| match m {
+        ModificationAction::ReplaceBody(body) => Some(body.body().as_bytes().to_vec()),
+        _ => None,
+    });
+    let first = parts.next()?;
+    Some(parts.fold(first, |body, part| [body, part].concat()))
+}
+
+#[tokio::test]
+async fn test_headers_only_message() {
+    let milter = TestMilter::new().answering(|| {
+        let mut builder = ModificationResponse::builder();
+        builder.push(ReplaceBody::new(b"Added body\r\n"));
+        builder.contin()
+    });
+    let (mut connection, handle) = utils::connect(milter, OptNeg::default()).await;
+
+    connection
+        .mail(b"<sender@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection.data().await.expect("Failed sending data");
+    connection
+        .header(Header::new(b"Subject", b"Headers only"))
+        .await
+        .expect("Failed sending header");
+    connection
+        .end_of_header()
+        .await
+        .expect("Failed sending end of header");
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+
+    assert_eq!(replaced_body(&response), Some(b"Added body\r\n".to_vec()));
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("body"), 0);
+    assert_eq!(milter.seen().calls("end_of_body"), 1);
+}
+
+#[tokio::test]
+async fn test_empty_body() {
+    let milter = TestMilter::new().answering(|| {
+        let mut builder = ModificationResponse::builder();
+        builder.push(ReplaceBody::new(b""));
+        builder.contin()
+    });
+    let (mut connection, handle) = utils::connect(milter, OptNeg::default()).await;
+
+    connection
+        .mail(b"<sender@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    connection
+        .body(b"".as_slice())
+        .await
+        .expect("Failed skipping empty body");
+    assert_eq!(connection.stats().skipped, 1);
+    let response = connection
+        .end_of_body()
+        .await
+        .expect("Failed sending end of body");
+    connection.quit().await.expect("Failed to quit");
+
+    // Replacing with an empty body survives the round trip
+    assert_eq!(replaced_body(&response), Some(Vec::new()));
+    let milter = handle.await.expect("Server task failed");
+    assert_eq!(milter.seen().calls("body"), 0);
+    assert_eq!(milter.seen().calls("end_of_body"), 1);
+}
+
+#[tokio::test]
+async fn test_empty_body_part_spared() {
+    let (mut client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let server = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        Server::default_postfix(&mut milter)
+            .handle_connection(server_side.compat())
+            .await
+            .map(|_summary| milter)
+    });
+
+    // The client never sends empty parts, talk to the server directly
+    write_optneg(&mut client_side).await;
+    write_command(&mut client_side, b'B', b"").await;
+    assert_eq!(read_frame(&mut client_side).await, b"c");
+    write_command(&mut client_side, b'Q', b"").await;
+
+    let milter = server
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+    assert_eq!(milter.seen().calls("body"), 0);
+}
+
+/// What a wrapping milter found out about the sender
+#[derive(Debug, Clone, PartialEq)]
+struct SpfResult(&'static str);
+
+/// Records the facts stored by [`SpfLayer`] at end of body
+#[derive(Debug, Default)]
+struct FactMilter {
+    ctx: SessionContext,
+    seen: Vec<(Option<String>, Option<SpfResult>)>,
+}
+
+#[async_trait]
+impl Milter for FactMilter {
+    type Error = &'static str;
+
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        Some(&mut self.ctx)
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        self.seen.push((
+            self.ctx.extensions().get::<String>().cloned(),
+            self.ctx.message_extensions().get::<SpfResult>().cloned(),
+        ));
+        Ok(ModificationResponse::empty_continue())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        Ok(Continue.into())
+    }
+}
+
+/// Wraps [`FactMilter`], storing the helo name and an spf result for
+/// senders at `pass.test`
+#[derive(Debug, Default)]
+struct SpfLayer {
+    inner: FactMilter,
+}
+
+#[async_trait]
+impl Milter for SpfLayer {
+    type Error = &'static str;
+
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        self.inner.session_context()
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<Action, Self::Error> {
+        let name = helo.helo().into_owned();
+        self.inner.ctx.extensions_mut().insert(name);
+        self.inner.helo(helo).await
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<Action, Self::Error> {
+        if mail.sender().ends_with("@pass.test>") {
+            self.inner
+                .ctx
+                .message_extensions_mut()
+                .insert(SpfResult("pass"));
+        }
+        self.inner.mail(mail).await
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        self.inner.end_of_body().await
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.inner.abort().await
+    }
+}
+
+#[tokio::test]
+async fn test_extensions() {
+    let (mut connection, handle) = utils::connect(SpfLayer::default(), OptNeg::default()).await;
+
+    connection
+        .helo(b"mx.test".as_slice())
+        .await
+        .expect("Failed sending helo");
+    for sender in ["<a@pass.test>", "<b@fail.test>"] {
+        connection
+            .mail(sender.as_bytes())
+            .await
+            .expect("Failed sending mail");
+        connection
+            .end_of_body()
+            .await
+            .expect("Failed sending end of body");
+    }
+    connection.quit().await.expect("Failed to quit");
+
+    let milter = handle.await.expect("Server task failed");
+    let helo = Some("mx.test".to_string());
+    assert_eq!(
+        milter.inner.seen,
+        [(helo.clone(), Some(SpfResult("pass"))), (helo, None)]
+    );
+}
@@ -0,0 +1,187 @@
+//! Milters taking their time and both sides giving up on them
+
+use std::{io, time::Duration};
+
+use miltr_client::{Client, ResponseError};
+use miltr_common::{
+    actions::{SmtpStage, Tempfail},
+    clock::ManualClock,
+    codes,
+    commands::{Header, Macro},
+    decoding::ServerCommand,
+    optneg::OptNeg,
+    ProtocolError,
+};
+use miltr_server::{CallbackTimeouts, Error};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::{
+    milter::TestMilter,
+    read_frame,
+    sim::{SimOutcome, Simulation, STAGES},
+    write_frame,
+};
+
+#[tokio::test]
+async fn test_callback_timeout() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let (mut connection, handle) = utils::connect_configured(
+        TestMilter::new().stuck_in("header"),
+        Client::new(OptNeg::default()),
+        move |server| {
+            server.with_clock(server_clock).with_callback_timeouts(
+                CallbackTimeouts::new()
+                    .with_default(Duration::from_secs(1))
+                    .with_stage(SmtpStage::Header, Duration::from_secs(10))
+                    .with_tempfail(true),
+            )
+        },
+    )
+    .await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    let advance = async {
+        while clock.sleeping() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(Duration::from_secs(9));
+        tokio::task::yield_now().await;
+        assert_eq!(clock.sleeping(), 1);
+        clock.advance(Duration::from_secs(1));
+    };
+    let (answer, ()) = tokio::join!(
+        connection.header(Header::new(b"Subject", b"stuck")),
+        advance
+    );
+
+    assert!(matches!(
+        answer,
+        Err(ResponseError::Unexpected(ServerCommand::Tempfail(_)))
+    ));
+    let result = handle.await.expect("Server task failed");
+    assert!(matches!(
+        result,
+        Err(Error::Timeout {
+            callback: "header",
+            timeout,
+        }) if timeout == Duration::from_secs(10)
+    ));
+}
+
+#[tokio::test]
+async fn test_read_timeout() {
+    let clock = ManualClock::new();
+    let client = Client::new(OptNeg::default())
+        .with_clock(clock.clone())
+        .with_read_timeout(Duration::from_secs(5));
+    let (mut connection, _handle) =
+        utils::connect_with(TestMilter::new().stuck_in("header"), client).await;
+
+    connection
+        .mail(b"<a@test.local>".as_slice())
+        .await
+        .expect("Failed sending mail");
+    let advance = async {
+        while clock.sleeping() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(Duration::from_secs(5));
+    };
+    let (answer, ()) = tokio::join!(
+        connection.header(Header::new(b"Subject", b"stuck")),
+        advance
+    );
+
+    assert!(matches!(answer, Err(ResponseError::Timeout)));
+
+    // The late answer to the header must not pass for the next one
+    let answer = connection.header(Header::new(b"Subject", b"next")).await;
+    assert!(matches!(answer, Err(ResponseError::Timeout)));
+    let answer = connection.end_of_body().await;
+    assert!(matches!(answer, Err(ResponseError::Timeout)));
+    let answer = connection.abort().await;
+    assert!(matches!(answer, Err(ResponseError::Timeout)));
+    connection.quit().await.expect_err("Quit after a timeout");
+}
+
+#[tokio::test]
+async fn test_write_timeout_on_quit() {
+    let (client_side, mut server_side) = tokio::io::duplex(64);
+    let server = tokio::spawn(async move {
+        read_frame(&mut server_side).await;
+        write_frame(&mut server_side, OptNeg::default()).await;
+        // Never read again, keeping the connection open
+        std::future::pending::<()>().await;
+        drop(server_side);
+    });
+    let mut connection = Client::new(OptNeg::default())
+        .with_write_timeout(Duration::from_millis(50))
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    // More than the peer buffers, written out along with quit
+    let mut macros = Macro::new(codes::SMFIC_MAIL);
+    macros.push(b"{x}", &[b'x'; 128]);
+    connection
+        .macro_(macros)
+        .await
+        .expect("Failed feeding macros");
+    let err = connection.quit().await.expect_err("Quit did not time out");
+
+    assert!(matches!(
+        err,
+        ProtocolError::CodecError(err) if err.kind() == io::ErrorKind::TimedOut
+    ));
+    server.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulated_delays() {
+    let events = Simulation::new(Duration::from_secs(30))
+        .delay(SmtpStage::Helo, Duration::from_secs(2))
+        .delay(SmtpStage::Body, Duration::from_secs(5))
+        .run()
+        .await;
+
+    assert_eq!(events.len(), STAGES.len());
+    assert!(events.iter().all(|e| e.outcome == SimOutcome::Continue));
+    let helo = &events[1];
+    assert_eq!(helo.stage, SmtpStage::Helo);
+    assert_eq!(helo.at, Duration::from_secs(2));
+    assert_eq!(events.last().map(|e| e.at), Some(Duration::from_secs(7)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulated_timeout() {
+    let events = Simulation::new(Duration::from_secs(10))
+        .delay(SmtpStage::Mail, Duration::from_secs(4))
+        .delay(SmtpStage::Rcpt, Duration::from_secs(11))
+        .run()
+        .await;
+
+    let last = events.last().expect("No stage ran");
+    assert_eq!(last.stage, SmtpStage::Rcpt);
+    assert_eq!(last.outcome, SimOutcome::TimedOut);
+    assert_eq!(last.at, Duration::from_secs(14));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulated_refusal() {
+    let events = Simulation::new(Duration::from_secs(10))
+        .delay(SmtpStage::EndOfMessage, Duration::from_secs(3))
+        .answer(SmtpStage::EndOfMessage, Tempfail)
+        .run()
+        .await;
+
+    let last = events.last().expect("No stage ran");
+    assert_eq!(last.stage, SmtpStage::EndOfMessage);
+    assert_eq!(last.outcome, SimOutcome::Refused("Tempfail"));
+    assert_eq!(last.at, Duration::from_secs(3));
+}
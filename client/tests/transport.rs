@@ -0,0 +1,152 @@
+//! The bytes between client and server and what carries them
+
+use futures::StreamExt;
+use miltr_client::Client;
+use miltr_common::{
+    actions::{Action, Continue},
+    commands::{Connect, Family},
+    compression::Compressed,
+    mux::{Acceptor, Connector},
+    optneg::OptNeg,
+};
+use miltr_server::Server;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+mod utils;
+
+use utils::{milter::TestMilter, read_frame, write_frame};
+
+#[tokio::test]
+async fn test_compressed_transport() {
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let client_side = Compressed::new(client_side.compat());
+    let server_side = Compressed::new(server_side.compat());
+    let client_stats = client_side.stats();
+    let server_stats = server_side.stats();
+
+    let server = tokio::spawn(async move {
+        let mut milter = TestMilter::new();
+        Server::default_postfix(&mut milter)
+            .handle_connection(server_side)
+            .await
+    });
+    let mut connection = Client::new(OptNeg::default())
+        .connect_via(client_side)
+        .await
+        .expect("Failed to setup connection");
+
+    let body = b"compressible ".repeat(1000);
+    connection
+        .body(body.as_slice())
+        .await
+        .expect("Failed sending body");
+    let response = connection.end_of_body().await.expect("Failed end of body");
+    connection.quit().await.expect("Failed to quit");
+    server
+        .await
+        .expect("Server task failed")
+        .expect("Server failed handling the connection");
+
+    assert!(matches!(response.final_action(), Action::Continue(_)));
+    assert_eq!(client_stats.bytes_sent(), server_stats.bytes_received());
+    assert!(client_stats.bytes_sent() > body.len() as u64);
+    assert!(client_stats.compressed_bytes_sent() < client_stats.bytes_sent() / 10);
+}
+
+#[tokio::test]
+async fn test_multiplexed_sessions() {
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+    let (connector, connector_driver) = Connector::new(client_side.compat());
+    let (mut acceptor, acceptor_driver) = Acceptor::new(server_side.compat());
+    tokio::spawn(connector_driver);
+    tokio::spawn(acceptor_driver);
+
+    let server = tokio::spawn(async move {
+        let mut sessions = Vec::new();
+        while let Some(channel) = acceptor.next().await {
+            sessions.push(tokio::spawn(async move {
+                let mut milter = TestMilter::new();
+                Server::default_postfix(&mut milter)
+                    .handle_connection(channel)
+                    .await
+                    .expect("Server failed handling the channel");
+                let recipients = milter.seen().recipients.clone();
+                recipients
+            }));
+        }
+        let mut recipients = Vec::new();
+        for session in sessions {
+            recipients.push(session.await.expect("Session task failed"));
+        }
+        recipients
+    });
+
+    let sessions = [
+        "<first@test.local>",
+        "<reject@test.local>",
+        "<third@test.local>",
+    ]
+    .map(|recipient| {
+        let channel = connector.open().expect("Failed opening channel");
+        async move {
+            let mut connection = Client::new(OptNeg::default())
+                .connect_via(channel)
+                .await
+                .expect("Failed to setup connection");
+            let result = connection.recipient(recipient.as_bytes()).await;
+            connection.quit().await.expect("Failed to quit");
+            result.is_ok()
+        }
+    });
+    let accepted = futures::future::join_all(sessions).await;
+    drop(connector);
+
+    let recipients = server.await.expect("Server task failed");
+    assert_eq!(accepted, [true, false, true]);
+    assert_eq!(
+        recipients,
+        [
+            ["<first@test.local>"],
+            ["<reject@test.local>"],
+            ["<third@test.local>"]
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_connect_wire_format() {
+    // As sendmail writes them: a port is sent for unix sockets, nothing
+    // follows an unknown family
+    let cases: [(Connect, &[u8]); 2] = [
+        (
+            Connect::new(b"localhost", Family::Unix, None, b"/run/smtp.sock"),
+            b"Clocalhost\0L\0\0/run/smtp.sock\0",
+        ),
+        (
+            Connect::new(b"localhost", Family::Unknown, None, b""),
+            b"Clocalhost\0U",
+        ),
+    ];
+
+    for (connect, expected) in cases {
+        let (client_side, mut server_side) = tokio::io::duplex(2_usize.pow(16));
+        let server = tokio::spawn(async move {
+            read_frame(&mut server_side).await;
+            write_frame(&mut server_side, OptNeg::default()).await;
+            let connect = read_frame(&mut server_side).await;
+            write_frame(&mut server_side, Action::from(Continue)).await;
+            connect
+        });
+
+        let mut connection = Client::new(OptNeg::default())
+            .connect_via(client_side.compat())
+            .await
+            .expect("Failed to setup connection");
+        connection
+            .connect(connect)
+            .await
+            .expect("Failed sending connect");
+
+        assert_eq!(server.await.expect("Server task failed"), expected);
+    }
+}
@@ -0,0 +1,290 @@
+//! One milter for all client server tests, configured per test
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue, Reject},
+    commands::{Body, Connect, Family, Header, Helo, Macro, Mail, Recipient, Unknown},
+    modifications::ModificationResponse,
+    optneg::{Capability, OptNeg},
+    ProtocolError,
+};
+use miltr_server::{Error, Milter, ProgressHandle, SessionContext};
+
+/// The family and port of a connect
+pub type SeenFamily = (Family, Option<u16>);
+
+/// What the session context reported to a callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timing {
+    pub callback: &'static str,
+    pub since_previous_command: Option<Duration>,
+    pub elapsed_in_message: Option<Duration>,
+    pub remaining_time: Option<Duration>,
+}
+
+/// Everything a [`TestMilter`] was called with
+#[derive(Debug, Default)]
+pub struct Seen {
+    calls: HashMap<&'static str, usize>,
+    /// Every recipient, in order
+    pub recipients: Vec<String>,
+    /// Family and port of every connect
+    pub connects: Vec<SeenFamily>,
+    /// The session context at every mail and end of body
+    pub timings: Vec<Timing>,
+}
+
+impl Seen {
+    /// How often `callback` was called
+    pub fn calls(&self, callback: &str) -> usize {
+        self.calls.get(callback).copied().unwrap_or_default()
+    }
+
+    /// How often any callback was called
+    pub fn total_calls(&self) -> usize {
+        self.calls.values().sum()
+    }
+}
+
+/// A milter rejecting helo names, senders, recipients and headers
+/// containing "reject" and failing on those containing "error".
+///
+/// Everything else is continued. What it was called with is recorded in
+/// [`Seen`], which may be shared by the milters of several connections.
+#[derive(Debug)]
+pub struct TestMilter {
+    options: OptNeg,
+    required: Capability,
+    end_of_body: Option<fn() -> ModificationResponse>,
+    progress: usize,
+    delays: Vec<(&'static str, Option<Duration>)>,
+    ctx: SessionContext,
+    seen: Arc<Mutex<Seen>>,
+}
+
+impl Default for TestMilter {
+    fn default() -> Self {
+        Self {
+            options: OptNeg::default(),
+            required: Capability::empty(),
+            end_of_body: None,
+            progress: 0,
+            delays: Vec::new(),
+            ctx: SessionContext::default(),
+            seen: Arc::default(),
+        }
+    }
+}
+
+impl TestMilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offer `options` during option negotiation
+    pub fn offering(mut self, options: OptNeg) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Refuse to work without `capabilities`
+    pub fn requiring(mut self, capabilities: Capability) -> Self {
+        self.required = capabilities;
+        self
+    }
+
+    /// Answer end of body with what `response` builds instead of continuing
+    pub fn answering(mut self, response: fn() -> ModificationResponse) -> Self {
+        self.end_of_body = Some(response);
+        self
+    }
+
+    /// Notify the client `count` times of progress during end of body
+    pub fn with_progress(mut self, count: usize) -> Self {
+        self.progress = count;
+        self
+    }
+
+    /// Take `delay` before answering `callback`
+    pub fn delaying(mut self, callback: &'static str, delay: Duration) -> Self {
+        self.delays.push((callback, Some(delay)));
+        self
+    }
+
+    /// Never answer `callback`
+    pub fn stuck_in(mut self, callback: &'static str) -> Self {
+        self.delays.push((callback, None));
+        self
+    }
+
+    /// Record into `seen`, e.g. shared with other connections
+    pub fn recording_into(mut self, seen: Arc<Mutex<Seen>>) -> Self {
+        self.seen = seen;
+        self
+    }
+
+    /// A handle on what this milter records
+    pub fn record(&self) -> Arc<Mutex<Seen>> {
+        Arc::clone(&self.seen)
+    }
+
+    /// What this milter was called with so far
+    pub fn seen(&self) -> MutexGuard<'_, Seen> {
+        self.seen.lock().expect("Poisoned")
+    }
+
+    /// The session context of the last connection
+    pub fn context(&self) -> &SessionContext {
+        &self.ctx
+    }
+
+    async fn called(&mut self, callback: &'static str) {
+        *self.seen().calls.entry(callback).or_default() += 1;
+        match self.delays.iter().find(|(name, _)| *name == callback) {
+            Some((_, Some(delay))) => tokio::time::sleep(*delay).await,
+            Some((_, None)) => futures::future::pending().await,
+            None => {}
+        }
+    }
+
+    fn time(&self, callback: &'static str) {
+        let timing = Timing {
+            callback,
+            since_previous_command: self.ctx.since_previous_command(),
+            elapsed_in_message: self.ctx.elapsed_in_message(),
+            remaining_time: self.ctx.remaining_time(),
+        };
+        self.seen().timings.push(timing);
+    }
+}
+
+/// Reject or fail on `value` by its content
+fn judge(value: &str) -> Result<Action, &'static str> {
+    let value = value.to_ascii_lowercase();
+    if value.contains("error") {
+        return Err("Failed handling the command");
+    }
+    if value.contains("reject") {
+        return Ok(Reject.into());
+    }
+    Ok(Continue.into())
+}
+
+#[async_trait]
+impl Milter for TestMilter {
+    type Error = &'static str;
+
+    fn required_capabilities(&self) -> Capability {
+        self.required
+    }
+
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        Some(&mut self.ctx)
+    }
+
+    async fn option_negotiation(&mut self, theirs: OptNeg) -> Result<OptNeg, Error<Self::Error>> {
+        self.called("option_negotiation").await;
+        Ok(self
+            .options
+            .clone()
+            .merge_compatible(&theirs)
+            .map_err(ProtocolError::from)?)
+    }
+
+    async fn connect(&mut self, connect: Connect) -> Result<Action, Self::Error> {
+        self.called("connect").await;
+        self.seen().connects.push((connect.family, connect.port));
+        Ok(Continue.into())
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<Action, Self::Error> {
+        self.called("helo").await;
+        judge(&helo.helo())
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<Action, Self::Error> {
+        self.called("mail").await;
+        self.time("mail");
+        judge(&mail.sender())
+    }
+
+    async fn rcpt(&mut self, recipient: Recipient) -> Result<Action, Self::Error> {
+        self.called("rcpt").await;
+        let recipient = recipient.recipient().to_string();
+        let action = judge(&recipient);
+        self.seen().recipients.push(recipient);
+        action
+    }
+
+    async fn data(&mut self) -> Result<Action, Self::Error> {
+        self.called("data").await;
+        Ok(Continue.into())
+    }
+
+    async fn header(&mut self, header: Header) -> Result<Action, Self::Error> {
+        self.called("header").await;
+        judge(&format!("{}: {}", header.name(), header.value()))
+    }
+
+    async fn end_of_header(&mut self) -> Result<Action, Self::Error> {
+        self.called("end_of_header").await;
+        Ok(Continue.into())
+    }
+
+    async fn body(&mut self, _body: Body) -> Result<Action, Self::Error> {
+        self.called("body").await;
+        Ok(Continue.into())
+    }
+
+    async fn end_of_body_with_progress(
+        &mut self,
+        progress: ProgressHandle,
+    ) -> Result<ModificationResponse, Self::Error> {
+        self.called("end_of_body").await;
+        self.time("end_of_body");
+        for _ in 0..self.progress {
+            progress.notify();
+            tokio::task::yield_now().await;
+        }
+        Ok(self
+            .end_of_body
+            .map_or_else(ModificationResponse::empty_continue, |response| response()))
+    }
+
+    async fn macro_(&mut self, _macro: Macro) -> Result<(), Self::Error> {
+        self.called("macro_").await;
+        Ok(())
+    }
+
+    async fn unknown(&mut self, _cmd: Unknown) -> Result<Action, Self::Error> {
+        self.called("unknown").await;
+        Ok(Continue.into())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.called("abort").await;
+        // Never sent, would be taken as the answer to the next command
+        Ok(Reject.into())
+    }
+
+    async fn message_reset(&mut self) -> Result<(), Self::Error> {
+        self.called("message_reset").await;
+        Ok(())
+    }
+
+    async fn quit(&mut self) -> Result<(), Self::Error> {
+        self.called("quit").await;
+        Ok(())
+    }
+
+    async fn quit_nc(&mut self) -> Result<(), Self::Error> {
+        self.called("quit_nc").await;
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! Helpers shared by the client server tests, not every test uses all
+
+#![allow(dead_code)]
+
+pub mod milter;
+pub mod sim;
+
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use miltr_client::{Client, Connection, ResponseError};
+use miltr_common::{
+    encoding::{ServerMessage, Writable},
+    optneg::{Capability, OptNeg},
+};
+use miltr_server::{ConnectionSummary, Error, Milter, Server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    task::JoinHandle,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// Run `milter` in a `miltr-server` connected via an in-memory duplex.
+///
+/// Returns a connected client connection and a handle resolving to the
+/// milter once the server finished the conversation.
+pub async fn connect<M>(
+    milter: M,
+    options: OptNeg,
+) -> (Connection<Compat<DuplexStream>>, JoinHandle<M>)
+where
+    M: Milter + 'static,
+    M::Error: Debug,
+{
+    connect_with(milter, Client::new(options)).await
+}
+
+/// Like [`connect`], but using a pre-configured `client`
+pub async fn connect_with<M>(
+    mut milter: M,
+    client: Client,
+) -> (Connection<Compat<DuplexStream>>, JoinHandle<M>)
+where
+    M: Milter + 'static,
+    M::Error: Debug,
+{
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+
+    let join_handle = tokio::spawn(async move {
+        let mut server = Server::default_postfix(&mut milter);
+        server
+            .handle_connection(server_side.compat())
+            .await
+            .expect("Server failed handling the connection");
+        milter
+    });
+
+    let connection = client
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    (connection, join_handle)
+}
+
+/// Like [`connect_with`], but letting `configure` set up the server.
+///
+/// The handle resolves to the result of handling the connection.
+pub async fn connect_configured<M, F>(
+    mut milter: M,
+    client: Client,
+    configure: F,
+) -> (
+    Connection<Compat<DuplexStream>>,
+    JoinHandle<Result<ConnectionSummary, Error<M::Error>>>,
+)
+where
+    M: Milter + 'static,
+    M::Error: 'static,
+    F: for<'m> FnOnce(Server<'m, M>) -> Server<'m, M> + Send + 'static,
+{
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+
+    let join_handle = tokio::spawn(async move {
+        let mut server = configure(Server::default_postfix(&mut milter));
+        server.handle_connection(server_side.compat()).await
+    });
+
+    let connection = client
+        .connect_via(client_side.compat())
+        .await
+        .expect("Failed to setup connection");
+
+    (connection, join_handle)
+}
+
+/// Only negotiate options between `client` and a server running `milter`.
+///
+/// The outcome of the server side is ignored.
+pub async fn negotiate<M>(
+    mut milter: M,
+    client: &Client,
+) -> Result<Connection<Compat<DuplexStream>>, ResponseError>
+where
+    M: Milter + 'static,
+{
+    let (client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+
+    tokio::spawn(async move {
+        let mut server = Server::default_postfix(&mut milter);
+        let _ = server.handle_connection(server_side.compat()).await;
+    });
+
+    client.connect_via(client_side.compat()).await
+}
+
+/// Read the next frame sent to `stream`, without length prefix
+pub async fn read_frame(stream: &mut DuplexStream) -> Vec<u8> {
+    let len = stream.read_u32().await.expect("Failed reading length");
+    let mut frame = vec![0; len as usize];
+    stream
+        .read_exact(&mut frame)
+        .await
+        .expect("Failed reading frame");
+    frame
+}
+
+/// Write `message` as a frame
+pub async fn write_frame<M: Into<ServerMessage>>(stream: &mut DuplexStream, message: M) {
+    let message: ServerMessage = message.into();
+    let mut data = BytesMut::new();
+    message.write(&mut data);
+    let len = u32::try_from(data.len() + 1).expect("Frame too large");
+    stream.write_u32(len).await.expect("Failed writing length");
+    stream
+        .write_u8(message.code())
+        .await
+        .expect("Failed writing code");
+    stream.write_all(&data).await.expect("Failed writing data");
+}
+
+/// Write a client command frame by hand
+pub async fn write_command(stream: &mut DuplexStream, code: u8, data: &[u8]) {
+    let len = u32::try_from(data.len() + 1).expect("Frame too large");
+    stream.write_u32(len).await.expect("Failed writing length");
+    stream.write_u8(code).await.expect("Failed writing code");
+    stream.write_all(data).await.expect("Failed writing data");
+}
+
+/// Negotiate options with a server by hand, offering everything
+pub async fn write_optneg(stream: &mut DuplexStream) {
+    let mut optneg = Vec::new();
+    for field in [6_u32, Capability::all().bits(), 0] {
+        optneg.extend_from_slice(&field.to_be_bytes());
+    }
+    write_command(stream, b'O', &optneg).await;
+    read_frame(stream).await;
+}
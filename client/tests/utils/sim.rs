@@ -0,0 +1,251 @@
+//! Run a client/server session deterministically on tokio's paused clock.
+//!
+//! The milter sleeps a scripted delay per stage before answering, the
+//! client gives up on a command after a timeout. With the clock paused,
+//! tokio advances it whenever all tasks wait for a timer, so runs take no
+//! real time and every event happens at an exact virtual time.
+
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use miltr_client::{Client, Connection, ResponseError};
+use miltr_common::{
+    actions::{Action, Continue, SmtpStage},
+    commands::{Body, Connect, Family, Header, Helo, Mail, Recipient},
+    modifications::ModificationResponse,
+    optneg::OptNeg,
+};
+use miltr_server::Milter;
+use tokio::{
+    io::DuplexStream,
+    time::{sleep, timeout, Instant},
+};
+use tokio_util::compat::Compat;
+
+/// The stages of a session, in the order they are run
+pub const STAGES: [SmtpStage; 9] = [
+    SmtpStage::Connect,
+    SmtpStage::Helo,
+    SmtpStage::Mail,
+    SmtpStage::Rcpt,
+    SmtpStage::Data,
+    SmtpStage::Header,
+    SmtpStage::EndOfHeader,
+    SmtpStage::Body,
+    SmtpStage::EndOfMessage,
+];
+
+/// How a stage ended for the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimOutcome {
+    /// The milter answered with continue
+    Continue,
+    /// The milter answered with anything else, named like the action
+    Refused(&'static str),
+    /// No answer within the timeout
+    TimedOut,
+    /// The connection failed
+    Failed(String),
+}
+
+/// A stage the client ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimEvent {
+    /// The stage
+    pub stage: SmtpStage,
+    /// Virtual time since the session started, when the stage ended
+    pub at: Duration,
+    /// How it ended
+    pub outcome: SimOutcome,
+}
+
+/// A scripted session, see the module docs
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    timeout: Duration,
+    delays: HashMap<SmtpStage, Duration>,
+    answers: HashMap<SmtpStage, Action>,
+}
+
+impl Simulation {
+    /// A session where the client waits `timeout` for every answer
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            delays: HashMap::new(),
+            answers: HashMap::new(),
+        }
+    }
+
+    /// Let the milter take `delay` to answer at `stage`
+    pub fn delay(mut self, stage: SmtpStage, delay: Duration) -> Self {
+        self.delays.insert(stage, delay);
+        self
+    }
+
+    /// Let the milter answer `action` at `stage` instead of continue
+    pub fn answer<A: Into<Action>>(mut self, stage: SmtpStage, action: A) -> Self {
+        self.answers.insert(stage, action.into());
+        self
+    }
+
+    /// Run the session until all stages passed or one did not continue.
+    ///
+    /// Must run on a runtime with paused time, e.g.
+    /// `#[tokio::test(start_paused = true)]`.
+    pub async fn run(self) -> Vec<SimEvent> {
+        let milter = ScriptedMilter {
+            delays: self.delays,
+            answers: self.answers,
+        };
+        let client = Client::new(OptNeg::default());
+        let (mut connection, handle) = super::connect_configured(milter, client, |s| s).await;
+
+        let start = Instant::now();
+        let mut events = Vec::new();
+        for stage in STAGES {
+            let outcome = match timeout(self.timeout, send(&mut connection, stage)).await {
+                Ok(outcome) => outcome,
+                Err(_) => SimOutcome::TimedOut,
+            };
+            let done = outcome != SimOutcome::Continue;
+            events.push(SimEvent {
+                stage,
+                at: start.elapsed(),
+                outcome,
+            });
+            if done {
+                // Like an MTA, give up on the connection
+                handle.abort();
+                return events;
+            }
+        }
+
+        connection.quit().await.expect("Failed to quit");
+        handle
+            .await
+            .expect("Server task failed")
+            .expect("Server failed handling the connection");
+        events
+    }
+}
+
+async fn send(connection: &mut Connection<Compat<DuplexStream>>, stage: SmtpStage) -> SimOutcome {
+    let result = match stage {
+        SmtpStage::Connect => {
+            connection
+                .connect(Connect::new(b"localhost", Family::Unknown, None, b""))
+                .await
+        }
+        SmtpStage::Helo => connection.helo(Helo::from(b"localhost".as_slice())).await,
+        SmtpStage::Mail => {
+            connection
+                .mail(Mail::from(b"<a@test.local>".as_slice()))
+                .await
+        }
+        SmtpStage::Rcpt => {
+            connection
+                .recipient(Recipient::from(b"<b@test.local>".as_slice()))
+                .await
+        }
+        SmtpStage::Data => connection.data().await,
+        SmtpStage::Header => connection.header(Header::new(b"Subject", b"Test")).await,
+        SmtpStage::EndOfHeader => connection.end_of_header().await,
+        SmtpStage::Body => connection.body(Body::from(b"body".as_slice())).await,
+        SmtpStage::EndOfMessage => {
+            return match connection.end_of_body().await {
+                Ok(response) => match response.final_action() {
+                    Action::Continue(_) => SimOutcome::Continue,
+                    action => SimOutcome::Refused(action_name(action)),
+                },
+                Err(err) => SimOutcome::Failed(err.to_string()),
+            };
+        }
+        SmtpStage::Unknown => connection.unknown(b"NOOP".as_slice()).await,
+    };
+
+    match result {
+        Ok(()) => SimOutcome::Continue,
+        Err(ResponseError::Unexpected(command)) => SimOutcome::Refused(command.name()),
+        Err(err) => SimOutcome::Failed(err.to_string()),
+    }
+}
+
+/// Named like the server command it is sent as
+fn action_name(action: &Action) -> &'static str {
+    match action {
+        Action::Continue(_) => "Continue",
+        Action::Abort(_) => "Abort",
+        Action::Discard(_) => "Discard",
+        Action::Reject(_) => "Reject",
+        Action::Tempfail(_) => "Tempfail",
+        Action::Skip(_) => "Skip",
+        Action::Replycode(_) => "Replycode",
+        Action::Quit(_) => "Quit",
+        Action::QuitNc(_) => "QuitNc",
+    }
+}
+
+/// Sleeps and answers as scripted
+#[derive(Debug)]
+struct ScriptedMilter {
+    delays: HashMap<SmtpStage, Duration>,
+    answers: HashMap<SmtpStage, Action>,
+}
+
+impl ScriptedMilter {
+    async fn stage(&mut self, stage: SmtpStage) -> Action {
+        if let Some(delay) = self.delays.get(&stage) {
+            sleep(*delay).await;
+        }
+        self.answers
+            .remove(&stage)
+            .unwrap_or_else(|| Continue.into())
+    }
+}
+
+#[async_trait]
+impl Milter for ScriptedMilter {
+    type Error = &'static str;
+
+    async fn connect(&mut self, _: Connect) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Connect).await)
+    }
+
+    async fn helo(&mut self, _: Helo) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Helo).await)
+    }
+
+    async fn mail(&mut self, _: Mail) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Mail).await)
+    }
+
+    async fn rcpt(&mut self, _: Recipient) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Rcpt).await)
+    }
+
+    async fn data(&mut self) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Data).await)
+    }
+
+    async fn header(&mut self, _: Header) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Header).await)
+    }
+
+    async fn end_of_header(&mut self) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::EndOfHeader).await)
+    }
+
+    async fn body(&mut self, _: Body) -> Result<Action, Self::Error> {
+        Ok(self.stage(SmtpStage::Body).await)
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        let action = self.stage(SmtpStage::EndOfMessage).await;
+        Ok(ModificationResponse::builder().build(action))
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        Ok(Continue.into())
+    }
+}
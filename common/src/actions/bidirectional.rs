@@ -1,7 +1,10 @@
 use bytes::BytesMut;
 
+use crate::codes;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::ProtocolError;
 
 /// Abort / finish processing a mail.
@@ -10,10 +13,16 @@ use crate::ProtocolError;
 /// - abort processing of the current mail
 /// - finish up processing if at the end of a mail processing flow
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Abort;
 
+impl Abort {
+    const CODE: u8 = codes::SMFIC_ABORT;
+}
+
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 impl Parsable for Abort {
-    const CODE: u8 = b'A';
+    const CODE: u8 = Self::CODE;
 
     fn parse(_buffer: BytesMut) -> Result<Self, ProtocolError> {
         Ok(Self)
@@ -37,12 +46,14 @@ impl Writable for Abort {
 
 /// Continue with the next step in the milter protocol
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Continue;
 
 impl Continue {
-    const CODE: u8 = b'c';
+    const CODE: u8 = codes::SMFIR_CONTINUE;
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Continue {
     const CODE: u8 = Self::CODE;
 
@@ -4,12 +4,18 @@
 //! the last command.
 
 mod bidirectional;
+mod outcome;
+mod progress;
 mod quit;
 mod to_mta_only;
 
 use enum_dispatch::enum_dispatch;
 
+use crate::encoding::Writable;
+
 pub use self::bidirectional::{Abort, Continue};
+pub use self::outcome::{Scope, SmtpOutcome, SmtpStage};
+pub use self::progress::Progress;
 pub use self::quit::{Quit, QuitNc};
 pub use self::to_mta_only::{Discard, Reject, Replycode, Skip, Tempfail};
 
@@ -20,6 +26,7 @@ pub use self::to_mta_only::{Discard, Reject, Replycode, Skip, Tempfail};
 #[enum_dispatch]
 #[cfg_attr(feature = "tracing", derive(strum::Display))]
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum Action {
     Continue,
     Abort,
@@ -33,3 +40,44 @@ pub enum Action {
     Quit,
     QuitNc,
 }
+
+impl Action {
+    /// The code byte identifying this action on the wire.
+    ///
+    /// See [`codes`](crate::codes) for all codes.
+    #[must_use]
+    pub fn code(&self) -> u8 {
+        Writable::code(self)
+    }
+
+    /// The kind of this action, without its data
+    #[must_use]
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            Self::Continue(_) => ActionKind::Continue,
+            Self::Abort(_) => ActionKind::Abort,
+            Self::Discard(_) => ActionKind::Discard,
+            Self::Reject(_) => ActionKind::Reject,
+            Self::Tempfail(_) => ActionKind::Tempfail,
+            Self::Skip(_) => ActionKind::Skip,
+            Self::Replycode(_) => ActionKind::Replycode,
+            Self::Quit(_) => ActionKind::Quit,
+            Self::QuitNc(_) => ActionKind::QuitNc,
+        }
+    }
+}
+
+/// The kind of an [`Action`], e.g. to count them
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ActionKind {
+    Continue,
+    Abort,
+    Discard,
+    Reject,
+    Tempfail,
+    Skip,
+    Replycode,
+    Quit,
+    QuitNc,
+}
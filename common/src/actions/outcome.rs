@@ -0,0 +1,197 @@
+//! What an MTA answers its SMTP peer after a milter action
+
+use super::{Action, Replycode};
+
+/// The SMTP stage a milter action was given for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SmtpStage {
+    /// The SMTP client connected
+    Connect,
+    /// `HELO`/`EHLO`
+    Helo,
+    /// `MAIL FROM`
+    Mail,
+    /// `RCPT TO`
+    Rcpt,
+    /// `DATA`
+    Data,
+    /// A single header
+    Header,
+    /// End of headers
+    EndOfHeader,
+    /// A body chunk
+    Body,
+    /// End of message, after the complete body was sent
+    EndOfMessage,
+    /// An unknown SMTP command
+    Unknown,
+}
+
+/// What a refusal applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The whole SMTP connection
+    Connection,
+    /// The current message
+    Message,
+    /// Only the current recipient, the message goes on
+    Recipient,
+}
+
+impl SmtpStage {
+    /// What a refusal at this stage applies to
+    #[must_use]
+    pub fn scope(self) -> Scope {
+        match self {
+            Self::Connect | Self::Helo => Scope::Connection,
+            Self::Rcpt => Scope::Recipient,
+            Self::Mail
+            | Self::Data
+            | Self::Header
+            | Self::EndOfHeader
+            | Self::Body
+            | Self::EndOfMessage
+            | Self::Unknown => Scope::Message,
+        }
+    }
+}
+
+/// What an MTA should do after receiving a milter action
+#[derive(Debug, Clone)]
+pub enum SmtpOutcome {
+    /// Go on with the SMTP conversation and the milter
+    Continue,
+    /// Send no further body chunks, go on with end of message
+    SkipBody,
+    /// Accept the message for delivery
+    Accept,
+    /// Answer as if accepted, but silently drop the message
+    Discard,
+    /// Refuse with the given reply
+    Refuse {
+        /// What is refused
+        scope: Scope,
+        /// The SMTP reply to answer with
+        reply: Replycode,
+    },
+}
+
+impl SmtpOutcome {
+    fn reject(scope: Scope) -> Self {
+        let reply = match scope {
+            Scope::Connection => Replycode::new([5, 5, 4], [5, 7, 1], "Access denied"),
+            Scope::Message | Scope::Recipient => {
+                Replycode::new([5, 5, 0], [5, 7, 1], "Command rejected")
+            }
+        };
+        Self::Refuse { scope, reply }
+    }
+
+    fn tempfail(scope: Scope) -> Self {
+        Self::Refuse {
+            scope,
+            reply: Replycode::new(
+                [4, 5, 1],
+                [4, 7, 1],
+                "Service unavailable - try again later",
+            ),
+        }
+    }
+}
+
+impl Action {
+    /// Translate this action into what an MTA should answer its SMTP peer.
+    ///
+    /// This follows the libmilter documentation:
+    /// - Reject and tempfail refuse the connection at connect and helo, only
+    ///   the current recipient at `RCPT` and the message otherwise.
+    /// - Discard accepts and drops the message. At connect or helo, where it
+    ///   is not allowed, it is treated as tempfail.
+    /// - Continue at end of message accepts it.
+    /// - Skip is only meaningful for body chunks, otherwise it continues.
+    ///
+    /// Abort and quit are no valid answers to a command. As MTAs commonly
+    /// do for protocol errors, they are treated as tempfail.
+    #[must_use]
+    pub fn smtp_outcome(&self, stage: SmtpStage) -> SmtpOutcome {
+        let scope = stage.scope();
+
+        match self {
+            Action::Continue(_) if stage == SmtpStage::EndOfMessage => SmtpOutcome::Accept,
+            Action::Skip(_) if stage == SmtpStage::Body => SmtpOutcome::SkipBody,
+            Action::Continue(_) | Action::Skip(_) => SmtpOutcome::Continue,
+            Action::Discard(_) if scope == Scope::Connection => SmtpOutcome::tempfail(scope),
+            Action::Discard(_) => SmtpOutcome::Discard,
+            Action::Reject(_) => SmtpOutcome::reject(scope),
+            Action::Replycode(reply) => SmtpOutcome::Refuse {
+                scope,
+                reply: reply.clone(),
+            },
+            Action::Tempfail(_) | Action::Abort(_) | Action::Quit(_) | Action::QuitNc(_) => {
+                SmtpOutcome::tempfail(scope)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::actions::{Continue, Discard, Reject, Skip, Tempfail};
+
+    #[rstest]
+    #[case(Continue.into(), SmtpStage::Rcpt, SmtpOutcome::Continue)]
+    #[case(Continue.into(), SmtpStage::EndOfMessage, SmtpOutcome::Accept)]
+    #[case(Skip.into(), SmtpStage::Body, SmtpOutcome::SkipBody)]
+    #[case(Skip.into(), SmtpStage::Header, SmtpOutcome::Continue)]
+    #[case(Discard.into(), SmtpStage::Rcpt, SmtpOutcome::Discard)]
+    fn test_passing_outcomes(
+        #[case] action: Action,
+        #[case] stage: SmtpStage,
+        #[case] expected: SmtpOutcome,
+    ) {
+        let outcome = action.smtp_outcome(stage);
+
+        assert_eq!(
+            core::mem::discriminant(&outcome),
+            core::mem::discriminant(&expected)
+        );
+    }
+
+    #[rstest]
+    #[case(Reject.into(), SmtpStage::Connect, Scope::Connection, [5, 5, 4])]
+    #[case(Reject.into(), SmtpStage::Rcpt, Scope::Recipient, [5, 5, 0])]
+    #[case(Reject.into(), SmtpStage::EndOfMessage, Scope::Message, [5, 5, 0])]
+    #[case(Tempfail.into(), SmtpStage::Mail, Scope::Message, [4, 5, 1])]
+    #[case(Discard.into(), SmtpStage::Helo, Scope::Connection, [4, 5, 1])]
+    fn test_refusals(
+        #[case] action: Action,
+        #[case] stage: SmtpStage,
+        #[case] expected_scope: Scope,
+        #[case] expected_code: [u16; 3],
+    ) {
+        let outcome = action.smtp_outcome(stage);
+
+        assert_matches!(
+            outcome,
+            SmtpOutcome::Refuse { scope, reply }
+                if scope == expected_scope && reply.rcode().code() == expected_code
+        );
+    }
+
+    #[test]
+    fn test_replycode_is_kept() {
+        let action: Action = Replycode::new([5, 5, 3], [5, 1, 3], "No such user").into();
+
+        let outcome = action.smtp_outcome(SmtpStage::Rcpt);
+
+        assert_matches!(
+            outcome,
+            SmtpOutcome::Refuse { scope: Scope::Recipient, reply }
+                if reply.message() == "No such user"
+        );
+    }
+}
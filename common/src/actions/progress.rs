@@ -0,0 +1,46 @@
+use bytes::BytesMut;
+
+use crate::codes;
+#[cfg(feature = "decode-server")]
+use crate::decoding::Parsable;
+use crate::encoding::Writable;
+#[cfg(feature = "decode-server")]
+use crate::ProtocolError;
+
+/// Ask the MTA to keep waiting for the answer to end of body.
+///
+/// Sent by a milter still working on a message, e.g. scanning it, to reset
+/// the timeout of the MTA. Contrary to an [`Action`](super::Action), it
+/// does not answer the end of body, the final action still follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
+pub struct Progress;
+
+impl Progress {
+    const CODE: u8 = codes::SMFIR_PROGRESS;
+}
+
+#[cfg(feature = "decode-server")]
+impl Parsable for Progress {
+    const CODE: u8 = Self::CODE;
+
+    fn parse(_buffer: BytesMut) -> Result<Self, ProtocolError> {
+        Ok(Self)
+    }
+}
+
+impl Writable for Progress {
+    fn write(&self, _buffer: &mut BytesMut) {}
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn code(&self) -> u8 {
+        Self::CODE
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -1,17 +1,22 @@
 use bytes::BytesMut;
 
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-client")]
 use crate::ProtocolError;
 
 /// Quit this connection gracefully
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Quit;
 
 impl Quit {
-    const CODE: u8 = b'Q';
+    const CODE: u8 = codes::SMFIC_QUIT;
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Quit {
     const CODE: u8 = Self::CODE;
 
@@ -38,12 +43,14 @@ impl Writable for Quit {
 
 /// This one mail processing is finished, but re-use this connection for the next one.i
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct QuitNc;
 
 impl QuitNc {
-    const CODE: u8 = b'K';
+    const CODE: u8 = codes::SMFIC_QUIT_NC;
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for QuitNc {
     const CODE: u8 = Self::CODE;
 
@@ -1,22 +1,30 @@
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, string::ToString, vec::Vec};
 
 use bytes::{BufMut, BytesMut};
 use itertools::Itertools;
 
+use crate::codes;
+#[cfg(feature = "decode-server")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+use crate::InvalidData;
+#[cfg(feature = "decode-server")]
+use crate::ProtocolError;
+#[cfg(feature = "decode-server")]
 use crate::{error::STAGE_DECODING, NotEnoughData};
-use crate::{InvalidData, ProtocolError};
+#[cfg(feature = "decode-server")]
 use miltr_utils::ByteParsing;
 
 /// (Silently) discard this mail without forwarding it
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Discard;
 
 impl Discard {
-    const CODE: u8 = b'd';
+    const CODE: u8 = codes::SMFIR_DISCARD;
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Discard {
     const CODE: u8 = Self::CODE;
 
@@ -43,12 +51,14 @@ impl Writable for Discard {
 
 /// Reject this mail, informing the smtp client about it
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Reject;
 
 impl Reject {
-    const CODE: u8 = b'r';
+    const CODE: u8 = codes::SMFIR_REJECT;
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Reject {
     const CODE: u8 = Self::CODE;
 
@@ -75,12 +85,14 @@ impl Writable for Reject {
 
 /// Return a tempfail code to the smtp client
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Tempfail;
 
 impl Tempfail {
-    const CODE: u8 = b't';
+    const CODE: u8 = codes::SMFIR_TEMPFAIL;
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Tempfail {
     const CODE: u8 = Self::CODE;
 
@@ -106,12 +118,14 @@ impl Writable for Tempfail {
 
 /// Skip this mail processing
 #[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Skip;
 
 impl Skip {
-    const CODE: u8 = b's';
+    const CODE: u8 = codes::SMFIR_SKIP;
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Skip {
     const CODE: u8 = Self::CODE;
 
@@ -145,10 +159,28 @@ pub struct Replycode {
     message: BytesMut,
 }
 
+#[cfg(any(test, feature = "arbitrary"))]
+impl<'a> arbitrary::Arbitrary<'a> for Replycode {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // The reply code goes on the wire as three digits
+        let mut rcode = [0; REPLY_CODE_LENGTH];
+        for digit in &mut rcode {
+            *digit = u.int_in_range(0..=9)?;
+        }
+        Ok(Self {
+            rcode: Code::new(rcode),
+            xcode: u.arbitrary()?,
+            message: crate::arbitrary::bytes(u)?,
+        })
+    }
+}
+
 impl Replycode {
-    const CODE: u8 = b'y';
+    const CODE: u8 = codes::SMFIR_REPLYCODE;
 
-    /// Create a Replycode
+    /// Create a Replycode.
+    ///
+    /// Each part of `rcode` is a single digit of the SMTP reply code.
     #[must_use]
     #[allow(clippy::similar_names)]
     pub fn new<R: Into<Code>, X: Into<Code>>(rcode: R, xcode: X, message: &str) -> Self {
@@ -162,9 +194,85 @@ impl Replycode {
         }
     }
 
+    /// Create a Replycode from a formatted SMTP reply line, like
+    /// `554 5.7.1 Blocked by policy`.
+    ///
+    /// ```
+    /// use miltr_common::actions::Replycode;
+    ///
+    /// let reply = Replycode::from_formatted("554 5.7.1 Blocked by policy")
+    ///     .expect("Valid reply");
+    /// assert_eq!(reply.rcode().code(), [5, 5, 4]);
+    /// assert_eq!(reply.xcode().code(), [5, 7, 1]);
+    /// assert_eq!(reply.message(), "Blocked by policy");
+    /// assert_eq!(reply.format(), "554 5.7.1 Blocked by policy");
+    /// ```
+    ///
+    /// # Errors
+    /// If the reply code is not three digits of a failure (4xx or 5xx), the
+    /// enhanced status code is missing or of another class, or the line
+    /// contains line breaks or null bytes.
+    #[allow(clippy::similar_names)]
+    pub fn from_formatted(reply: &str) -> Result<Self, InvalidData> {
+        let invalid = |msg| InvalidData {
+            msg,
+            offending_bytes: BytesMut::from(reply.as_bytes()),
+        };
+        if reply.contains(['\r', '\n', '\0']) {
+            return Err(invalid("line break or null byte in reply"));
+        }
+
+        let mut parts = reply.splitn(3, ' ');
+        let rcode = parts.next().unwrap_or_default();
+        let digits: Option<Vec<u16>> = rcode
+            .chars()
+            .map(|c| c.to_digit(10).and_then(|d| u16::try_from(d).ok()))
+            .collect();
+        let rcode = match digits.as_deref() {
+            Some(&[class @ (4 | 5), subject, detail]) => Code::new([class, subject, detail]),
+            _ => return Err(invalid("reply code is no 4xx or 5xx code")),
+        };
+
+        let Some(xcode) = parts.next().and_then(|xcode| {
+            let numbers: Option<Vec<u16>> = xcode.split('.').map(|n| n.parse().ok()).collect();
+            match numbers.as_deref() {
+                Some(&[class, subject, detail]) => Some([class, subject, detail]),
+                _ => None,
+            }
+        }) else {
+            return Err(invalid("missing enhanced status code in reply"));
+        };
+        if xcode[0] != rcode.code()[0] {
+            return Err(invalid(
+                "enhanced status code of another class than reply code",
+            ));
+        }
+
+        Ok(Self::new(rcode, xcode, parts.next().unwrap_or_default()))
+    }
+
+    /// Format as SMTP reply line, the inverse of [`Self::from_formatted`]
+    #[must_use]
+    pub fn format(&self) -> String {
+        let [class, subject, detail] = self.xcode.code();
+        let mut line = self.rcode.code().iter().map(ToString::to_string).join("");
+        line.push(' ');
+        line.push_str(
+            &[class, subject, detail]
+                .iter()
+                .map(ToString::to_string)
+                .join("."),
+        );
+        if !self.message.is_empty() {
+            line.push(' ');
+            line.push_str(&self.message());
+        }
+        line
+    }
+
     /// The message associated with this reply code
     #[must_use]
-    pub fn message(&self) -> Cow<str> {
+    pub fn message(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.message)
     }
 
@@ -181,71 +289,73 @@ impl Replycode {
     }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Replycode {
     const CODE: u8 = Self::CODE;
 
+    /// Parse the reply as `smfi_setreply` sends it, a single line like
+    /// `550 5.7.1 Blocked by policy` terminated by a null byte
     // rcode and xcode are just named that in the docs. Keeping it consistent.
     #[allow(clippy::similar_names)]
     fn parse(mut buffer: BytesMut) -> Result<Self, ProtocolError> {
-        #[allow(clippy::similar_names)]
-        let Some(rcode) = buffer.delimited(0) else {
+        let Some(mut reply) = buffer.take_null_terminated() else {
             return Err(NotEnoughData::new(
                 STAGE_DECODING,
                 "Replycode",
-                "Missing nullbyte delimiter after rcode",
+                "Missing nullbyte delimiter after reply",
                 1,
                 0,
                 buffer,
             )
             .into());
         };
-        let rcode = Code::parse(rcode)?;
-
-        let Some(xcode) = buffer.delimited(0) else {
-            return Err(NotEnoughData::new(
-                STAGE_DECODING,
-                "Replycode",
-                "Missing nullbyte delimiter after xcode",
-                1,
-                0,
-                buffer,
-            )
+        let Some(rcode) = reply.delimited(b' ') else {
+            return Err(InvalidData {
+                msg: "missing enhanced status code in reply",
+                offending_bytes: reply,
+            }
             .into());
         };
+        let rcode = Code::parse_digits(rcode)?;
+        // The message is optional, so is the space in front of it
+        let xcode = reply.delimited(b' ').unwrap_or_else(|| reply.split());
         let xcode = Code::parse(xcode)?;
 
-        let Some(message) = buffer.delimited(0) else {
-            return Err(NotEnoughData::new(
-                STAGE_DECODING,
-                "Replycode",
-                "Missing nullbyte delimiter after message",
-                1,
-                0,
-                buffer,
-            )
-            .into());
-        };
-
         Ok(Self {
             rcode,
             xcode,
-            message,
+            message: reply,
         })
     }
 }
 
 impl Writable for Replycode {
     fn write(&self, buffer: &mut BytesMut) {
-        buffer.put_slice(self.rcode.as_bytes());
-        buffer.put_u8(0);
+        for digit in self.rcode.code() {
+            buffer.put_slice(digit.to_string().as_bytes());
+        }
+        buffer.put_u8(b' ');
         buffer.put_slice(self.xcode.as_bytes());
-        buffer.put_u8(0);
-        buffer.put_slice(&self.message);
+        if !self.message.is_empty() {
+            buffer.put_u8(b' ');
+            buffer.put_slice(&self.message);
+        }
         buffer.put_u8(0);
     }
 
     fn len(&self) -> usize {
-        self.rcode.len() + 1 + self.xcode.len() + 1 + self.message.len() + 1
+        let rcode: usize = self
+            .rcode
+            .code()
+            .iter()
+            .map(|digit| digit.to_string().len())
+            .sum();
+        let message = if self.message.is_empty() {
+            0
+        } else {
+            1 + self.message.len()
+        };
+        rcode + 1 + self.xcode.len() + message + 1
     }
 
     fn code(&self) -> u8 {
@@ -262,6 +372,13 @@ pub struct Code {
     bytes: BytesMut,
 }
 
+#[cfg(any(test, feature = "arbitrary"))]
+impl<'a> arbitrary::Arbitrary<'a> for Code {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
 impl From<[u16; REPLY_CODE_LENGTH]> for Code {
     fn from(code: [u16; REPLY_CODE_LENGTH]) -> Self {
         Self::new(code)
@@ -276,6 +393,7 @@ impl Code {
         }
     }
 
+    #[cfg(feature = "decode-server")]
     fn parse(buffer: BytesMut) -> Result<Self, InvalidData> {
         let mut positions = buffer.iter().positions(|&c| c == b'.');
         let mut code: [u16; 3] = [0_u16; REPLY_CODE_LENGTH];
@@ -315,6 +433,23 @@ impl Code {
         })
     }
 
+    /// Parse a reply code of three digits, like `550`
+    #[cfg(feature = "decode-server")]
+    fn parse_digits(buffer: BytesMut) -> Result<Self, InvalidData> {
+        let digit = |b: u8| b.is_ascii_digit().then(|| u16::from(b - b'0'));
+        let code = match buffer[..] {
+            [a, b, c] => digit(a).zip(digit(b)).zip(digit(c)),
+            _ => None,
+        };
+        let Some(((a, b), c)) = code else {
+            return Err(InvalidData {
+                msg: "reply code is no three digits",
+                offending_bytes: buffer,
+            });
+        };
+        Ok(Self::new([a, b, c]))
+    }
+
     /// The status code
     #[must_use]
     pub fn code(&self) -> [u16; REPLY_CODE_LENGTH] {
@@ -334,6 +469,7 @@ impl Code {
 mod test {
     use super::*;
 
+    #[cfg(feature = "decode-server")]
     #[test]
     fn test_rcode_valid() {
         let input = BytesMut::from_iter(b"1.20.3");
@@ -345,6 +481,74 @@ mod test {
         assert_eq!(6, code.bytes.len());
     }
 
+    #[cfg(feature = "decode-server")]
+    #[test]
+    fn test_replycode_parse() {
+        let input = BytesMut::from("550 5.7.1 Blocked by policy\0");
+        let reply = Replycode::parse(input).expect("Failed parsing replycode");
+
+        assert_eq!(reply.rcode().code(), [5, 5, 0]);
+        assert_eq!(reply.xcode().code(), [5, 7, 1]);
+        assert_eq!(reply.message(), "Blocked by policy");
+
+        let reply = Replycode::parse(BytesMut::from("451 4.3.0\0"))
+            .expect("Failed parsing replycode without message");
+        assert_eq!(reply.message(), "");
+        assert_eq!(reply.len(), 10);
+    }
+
+    #[cfg(feature = "decode-server")]
+    #[test]
+    fn test_replycode_parse_invalid() {
+        let err = Replycode::parse(BytesMut::from("550 5.7.1 message"))
+            .expect_err("Parsing did not error");
+        let ProtocolError::NotEnoughData(err) = err else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(err.msg, "Missing nullbyte delimiter after reply");
+
+        for (input, msg) in [
+            ("550\0", "missing enhanced status code in reply"),
+            ("5.5.0 5.7.1 message\0", "reply code is no three digits"),
+            ("55x 5.7.1 message\0", "reply code is no three digits"),
+            ("550 5.7 message\0", "missing '.' delimiter in code"),
+        ] {
+            let err = Replycode::parse(BytesMut::from(input)).expect_err("Parsing did not error");
+            let ProtocolError::InvalidData(err) = err else {
+                panic!("Wrong error received: {err:?}");
+            };
+            assert_eq!(err.msg, msg, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_replycode_from_formatted() {
+        let reply = Replycode::from_formatted("451 4.7.1 Try again later")
+            .expect("Failed parsing formatted reply");
+        assert_eq!(reply.rcode().code(), [4, 5, 1]);
+        assert_eq!(reply.xcode().code(), [4, 7, 1]);
+        assert_eq!(reply.format(), "451 4.7.1 Try again later");
+
+        let reply = Replycode::from_formatted("550 5.1.1").expect("Failed parsing bare reply");
+        assert_eq!(reply.message(), "");
+        assert_eq!(reply.format(), "550 5.1.1");
+
+        for (input, msg) in [
+            ("250 2.0.0 Ok", "reply code is no 4xx or 5xx code"),
+            ("55 5.0.0 Short", "reply code is no 4xx or 5xx code"),
+            ("554 Blocked", "missing enhanced status code in reply"),
+            (
+                "554 4.7.1 Blocked",
+                "enhanced status code of another class than reply code",
+            ),
+            ("554 5.7.1 Two\r\nlines", "line break or null byte in reply"),
+        ] {
+            let err = Replycode::from_formatted(input).expect_err("Parsing did not error");
+            assert_eq!(err.msg, msg, "{input}");
+        }
+    }
+
+    #[cfg(feature = "decode-server")]
     #[test]
     fn test_rcode_invalid() {
         let input = BytesMut::from_iter(b"1.23");
@@ -0,0 +1,48 @@
+//! Helpers to derive [`arbitrary::Arbitrary`] for the wire types.
+//!
+//! Fields holding raw bytes get null-free contents, as that is the only
+//! kind of data the protocol is able to transport in them.
+
+use arbitrary::{Result, Unstructured};
+use bytes::BytesMut;
+
+use crate::macros::MacroName;
+use crate::optneg::{Capability, Protocol};
+
+pub(crate) fn bytes(u: &mut Unstructured<'_>) -> Result<BytesMut> {
+    let raw: &[u8] = u.arbitrary()?;
+    Ok(raw.iter().copied().filter(|&b| b != 0).collect())
+}
+
+pub(crate) fn optional_bytes(u: &mut Unstructured<'_>) -> Result<Option<BytesMut>> {
+    if u.arbitrary()? {
+        let mut value = bytes(u)?;
+        if value.is_empty() {
+            value.extend_from_slice(b"x");
+        }
+        Ok(Some(value))
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn byte_pairs(u: &mut Unstructured<'_>) -> Result<Vec<(BytesMut, BytesMut)>> {
+    let count = u.arbitrary_len::<(u8, u8)>()?;
+    (0..count).map(|_| Ok((bytes(u)?, bytes(u)?))).collect()
+}
+
+pub(crate) fn macro_pairs(u: &mut Unstructured<'_>) -> Result<Vec<(MacroName, BytesMut)>> {
+    let pairs = byte_pairs(u)?;
+    Ok(pairs
+        .into_iter()
+        .map(|(name, value)| (MacroName::from_raw(name.freeze()), value))
+        .collect())
+}
+
+pub(crate) fn capability(u: &mut Unstructured<'_>) -> Result<Capability> {
+    Ok(Capability::from_bits_retain(u.arbitrary()?))
+}
+
+pub(crate) fn protocol(u: &mut Unstructured<'_>) -> Result<Protocol> {
+    Ok(Protocol::from_bits_retain(u.arbitrary()?))
+}
@@ -0,0 +1,690 @@
+//! Captured milter conversations, to analyze them offline
+//!
+//! A capture is a sequence of records, each made of
+//! - the direction, `>` for frames sent by the client, `<` for frames sent
+//!   by the server
+//! - the time since the conversation started, in microseconds as 8 byte
+//!   big endian
+//! - the frame as on the wire, a 4 byte big endian length followed by the
+//!   code and the payload
+//!
+//! A [`Tap`] around the transport of a client or server records such a
+//! capture. [`analyze`] sums it up in a [`Report`], e.g. to diagnose
+//! traffic a customer recorded.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{AsyncRead, AsyncWrite};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::Command,
+    decoding::{ClientCommand, ServerCommand},
+    encoding::Writable,
+    error::STAGE_DECODING,
+    optneg::Protocol,
+    InvalidData, NotEnoughData, ProtocolError,
+};
+
+/// Who sent a captured frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The client (MTA) sent it to the server (milter)
+    ToServer,
+    /// The server (milter) sent it to the client (MTA)
+    ToClient,
+}
+
+impl Direction {
+    const TO_SERVER: u8 = b'>';
+    const TO_CLIENT: u8 = b'<';
+}
+
+/// A single frame of a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Who sent the frame
+    pub direction: Direction,
+    /// When it was sent, since the conversation started
+    pub at: Duration,
+    /// The code followed by the payload, without the length prefix
+    pub frame: BytesMut,
+}
+
+impl Record {
+    /// The length of the header preceding each frame
+    const HEADER_LEN: usize = 1 + 8 + 4;
+
+    /// Record `item` sent at `at`
+    #[must_use]
+    pub fn new<W: Writable>(direction: Direction, at: Duration, item: &W) -> Self {
+        let mut frame = BytesMut::with_capacity(item.len() + 1);
+        frame.put_u8(item.code());
+        item.write(&mut frame);
+        Self {
+            direction,
+            at,
+            frame,
+        }
+    }
+
+    /// Append this record to a capture in `buffer`
+    pub fn write(&self, buffer: &mut BytesMut) {
+        buffer.reserve(Self::HEADER_LEN + self.frame.len());
+        buffer.put_u8(match self.direction {
+            Direction::ToServer => Direction::TO_SERVER,
+            Direction::ToClient => Direction::TO_CLIENT,
+        });
+        buffer.put_u64(self.at.as_micros() as u64);
+        buffer.put_u32(self.frame.len() as u32);
+        buffer.extend_from_slice(&self.frame);
+    }
+}
+
+/// A capture being recorded by a [`Tap`]
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    buffer: Arc<Mutex<BytesMut>>,
+}
+
+impl Capture {
+    /// The records of all complete frames so far, in the format described
+    /// in the [module docs](self)
+    #[must_use]
+    pub fn to_bytes(&self) -> BytesMut {
+        self.lock().clone()
+    }
+
+    fn push(&self, record: &Record) {
+        record.write(&mut self.lock());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BytesMut> {
+        self.buffer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A transport recording every frame read from and written to `RW` into
+/// a [`Capture`].
+///
+/// Wrap the transport before handing it to the client or server. Frames
+/// are recorded once they passed completely, with the time since the tap
+/// was created. Data is passed on unchanged, the tap does not decode it.
+#[derive(Debug)]
+pub struct Tap<RW> {
+    inner: RW,
+    capture: Capture,
+    started: Instant,
+    /// Who sent the frames read, the frames written go the other way
+    reading: Direction,
+    /// A frame read partially
+    read: BytesMut,
+    /// A frame written partially
+    written: BytesMut,
+}
+
+impl<RW> Tap<RW> {
+    /// Record the transport of a client, writing to the server
+    pub fn client(inner: RW) -> Self {
+        Self::new(inner, Direction::ToClient)
+    }
+
+    /// Record the transport of a server, reading from the client
+    pub fn server(inner: RW) -> Self {
+        Self::new(inner, Direction::ToServer)
+    }
+
+    fn new(inner: RW, reading: Direction) -> Self {
+        Self {
+            inner,
+            capture: Capture::default(),
+            started: Instant::now(),
+            reading,
+            read: BytesMut::new(),
+            written: BytesMut::new(),
+        }
+    }
+
+    /// A handle to the capture recorded
+    pub fn capture(&self) -> Capture {
+        self.capture.clone()
+    }
+
+    /// The underlying transport
+    pub fn into_inner(self) -> RW {
+        self.inner
+    }
+
+    fn writing(&self) -> Direction {
+        match self.reading {
+            Direction::ToServer => Direction::ToClient,
+            Direction::ToClient => Direction::ToServer,
+        }
+    }
+}
+
+/// Record each complete frame of `pending` into `capture`
+fn record_frames(capture: &Capture, direction: Direction, at: Duration, pending: &mut BytesMut) {
+    while pending.len() >= 4 {
+        let len = u32::from_be_bytes([pending[0], pending[1], pending[2], pending[3]]) as usize;
+        if pending.len() - 4 < len {
+            return;
+        }
+        pending.advance(4);
+        capture.push(&Record {
+            direction,
+            at,
+            frame: pending.split_to(len),
+        });
+    }
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Tap<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read.extend_from_slice(&buf[..read]);
+        let at = this.started.elapsed();
+        record_frames(&this.capture, this.reading, at, &mut this.read);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for Tap<RW> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.written.extend_from_slice(&buf[..written]);
+        let at = this.started.elapsed();
+        let direction = this.writing();
+        record_frames(&this.capture, direction, at, &mut this.written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Split `capture` into its records.
+///
+/// # Errors
+/// If a record has an unknown direction or is cut off. The frames
+/// themselves are not decoded.
+pub fn records(capture: &[u8]) -> Result<Vec<Record>, ProtocolError> {
+    let mut buffer = BytesMut::from(capture);
+    let mut records = Vec::new();
+    while !buffer.is_empty() {
+        if buffer.len() < Record::HEADER_LEN {
+            return Err(NotEnoughData::new(
+                STAGE_DECODING,
+                "Record",
+                "header cut off",
+                Record::HEADER_LEN,
+                buffer.len(),
+                buffer,
+            )
+            .into());
+        }
+        let direction = match buffer[0] {
+            Direction::TO_SERVER => Direction::ToServer,
+            Direction::TO_CLIENT => Direction::ToClient,
+            _ => {
+                return Err(InvalidData::new(
+                    "Unknown direction in capture record",
+                    buffer.split_to(1),
+                )
+                .into())
+            }
+        };
+        buffer.advance(1);
+        let at = Duration::from_micros(buffer.get_u64());
+        let len = buffer.get_u32() as usize;
+        if buffer.len() < len {
+            return Err(NotEnoughData::new(
+                STAGE_DECODING,
+                "Record",
+                "frame cut off",
+                len,
+                buffer.len(),
+                buffer,
+            )
+            .into());
+        }
+        records.push(Record {
+            direction,
+            at,
+            frame: buffer.split_to(len),
+        });
+    }
+    Ok(records)
+}
+
+/// How long the server took to answer a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Latency {
+    /// Answers seen
+    pub count: u64,
+    /// The time of all answers together
+    pub total: Duration,
+    /// The slowest answer
+    pub max: Duration,
+}
+
+impl Latency {
+    /// The average time to answer, zero without answers
+    #[must_use]
+    pub fn mean(&self) -> Duration {
+        let count = u32::try_from(self.count).unwrap_or(u32::MAX);
+        self.total.checked_div(count).unwrap_or_default()
+    }
+
+    fn add(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+/// A frame not following the protocol
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    /// The index of the record, counting from 0. Commands never answered
+    /// are reported past the last record.
+    pub record: usize,
+    /// What is wrong with it
+    pub reason: String,
+}
+
+/// What happened in a captured conversation, see [`analyze`]
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// Frames sent by the client
+    pub frames_to_server: u64,
+    /// Frames sent by the server
+    pub frames_to_client: u64,
+    /// How long the server took to answer, by command name, e.g. `Mail`
+    pub latencies: BTreeMap<String, Latency>,
+    /// Actions sent by the server, by name, e.g. `Reject`
+    pub actions: BTreeMap<String, u64>,
+    /// Modifications sent by the server, by name, e.g. `AddHeader`
+    pub modifications: BTreeMap<String, u64>,
+    /// Frames not following the protocol, in order
+    pub violations: Vec<Violation>,
+}
+
+/// Analyze a capture in the format described in the [module docs](self).
+///
+/// Latencies are measured from a command to its answer, for commands the
+/// client expects one for according to the negotiated protocol. Frames
+/// failing to decode or not fitting the conversation so far are reported
+/// as [`Violation`]s, the analysis goes on after them.
+///
+/// # Errors
+/// If the capture itself is broken, see [`records`]
+pub fn analyze(capture: &[u8]) -> Result<Report, ProtocolError> {
+    let mut analyzer = Analyzer::default();
+    for (index, record) in records(capture)?.into_iter().enumerate() {
+        analyzer.index = index;
+        match record.direction {
+            Direction::ToServer => {
+                analyzer.report.frames_to_server += 1;
+                match ClientCommand::parse(record.frame) {
+                    Ok(command) => analyzer.command(command, record.at),
+                    Err(err) => analyzer.violation(format!("Undecodable command: {err}")),
+                }
+            }
+            Direction::ToClient => {
+                analyzer.report.frames_to_client += 1;
+                match ServerCommand::parse(record.frame) {
+                    Ok(answer) => analyzer.answer(&answer, record.at),
+                    Err(err) => analyzer.violation(format!("Undecodable answer: {err}")),
+                }
+            }
+        }
+    }
+    analyzer.index += 1;
+    for (name, _) in std::mem::take(&mut analyzer.pending) {
+        analyzer.violation(format!("No answer to {name}"));
+    }
+    Ok(analyzer.report)
+}
+
+/// Follows a conversation while building its report
+#[derive(Debug, Default)]
+struct Analyzer {
+    report: Report,
+    /// The record analyzed
+    index: usize,
+    /// Commands awaiting an answer, by name with the time they were sent
+    pending: VecDeque<(&'static str, Duration)>,
+    /// The flags negotiated, none before negotiation
+    protocol: Protocol,
+}
+
+impl Analyzer {
+    fn command(&mut self, command: ClientCommand, at: Duration) {
+        let name = command.name();
+        let expects_answer = match command {
+            ClientCommand::OptNeg(_) => {
+                self.protocol = Protocol::empty();
+                true
+            }
+            ClientCommand::Abort(_)
+            | ClientCommand::Quit(_)
+            | ClientCommand::QuitNc(_)
+            | ClientCommand::Macro(_) => false,
+            command => Command::try_from(command)
+                .is_ok_and(|command| !self.protocol.should_skip_response(&command)),
+        };
+        if expects_answer {
+            self.pending.push_back((name, at));
+        }
+    }
+
+    fn answer(&mut self, answer: &ServerCommand, at: Duration) {
+        let name = answer.name();
+        match answer {
+            ServerCommand::OptNeg(options) => {
+                if self.take_pending("OptNeg", at).is_some() {
+                    self.protocol = options.protocol;
+                } else {
+                    self.violation("Option negotiation without a request".to_string());
+                }
+            }
+            ServerCommand::Progress(_) => {
+                if !self.awaits_end_of_body() {
+                    self.violation("Progress outside of end of body".to_string());
+                }
+            }
+            ServerCommand::AddRecipient(_)
+            | ServerCommand::DeleteRecipient(_)
+            | ServerCommand::AddRecipientPar(_)
+            | ServerCommand::ReplaceBody(_)
+            | ServerCommand::AddHeader(_)
+            | ServerCommand::InsertHeader(_)
+            | ServerCommand::ChangeHeader(_)
+            | ServerCommand::Quarantine(_)
+            | ServerCommand::ChangeFrom(_) => {
+                *self
+                    .report
+                    .modifications
+                    .entry(name.to_string())
+                    .or_default() += 1;
+                if !self.awaits_end_of_body() {
+                    self.violation(format!("{name} outside of end of body"));
+                }
+            }
+            ServerCommand::Abort(_)
+            | ServerCommand::Continue(_)
+            | ServerCommand::Discard(_)
+            | ServerCommand::Reject(_)
+            | ServerCommand::Tempfail(_)
+            | ServerCommand::Skip(_)
+            | ServerCommand::Replycode(_) => {
+                *self.report.actions.entry(name.to_string()).or_default() += 1;
+                match self.pending.front() {
+                    Some(("OptNeg", _)) | None => {
+                        self.violation(format!("{name} without a command awaiting it"));
+                    }
+                    Some(&(command, _)) => {
+                        if matches!(answer, ServerCommand::Skip(_)) && command != "Body" {
+                            self.violation(format!("Skip answering {command}"));
+                        }
+                        self.take_pending(command, at);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Account for the answer to the oldest pending command, if it is
+    /// `command`
+    fn take_pending(&mut self, command: &str, at: Duration) -> Option<()> {
+        let (name, sent) = *self.pending.front().filter(|(name, _)| *name == command)?;
+        self.pending.pop_front();
+        self.report
+            .latencies
+            .entry(name.to_string())
+            .or_default()
+            .add(at.saturating_sub(sent));
+        Some(())
+    }
+
+    fn awaits_end_of_body(&self) -> bool {
+        matches!(self.pending.front(), Some(("EndOfBody", _)))
+    }
+
+    fn violation(&mut self, reason: String) {
+        self.report.violations.push(Violation {
+            record: self.index,
+            reason,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor, AsyncReadExt, AsyncWriteExt};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        actions::{Continue, Progress, Reject},
+        commands::{Body, EndOfBody, Mail},
+        modifications::headers::AddHeader,
+        optneg::OptNeg,
+    };
+
+    fn capture(records: &[Record]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        for record in records {
+            record.write(&mut buffer);
+        }
+        buffer
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let written = vec![
+            Record::new(
+                Direction::ToServer,
+                ms(1),
+                &Mail::from(b"<a@test.local>".as_slice()),
+            ),
+            Record::new(Direction::ToClient, ms(3), &Continue),
+        ];
+
+        let read = records(&capture(&written)).expect("Failed reading records");
+
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn test_records_cut_off() {
+        let mut buffer = capture(&[Record::new(Direction::ToClient, ms(1), &Continue)]);
+        buffer.truncate(buffer.len() - 1);
+
+        assert!(matches!(
+            records(&buffer),
+            Err(ProtocolError::NotEnoughData(_))
+        ));
+    }
+
+    /// `item` as on the wire, with its length prefix
+    fn frame<W: Writable>(item: &W) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(item.len() as u32 + 1);
+        buffer.put_u8(item.code());
+        item.write(&mut buffer);
+        buffer.to_vec()
+    }
+
+    /// A transport reading prepared answers, keeping what is written
+    struct Wire {
+        answers: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl AsyncRead for Wire {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.answers).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Wire {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.sent).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_tap() {
+        let mail = frame(&Mail::from(b"<a@test.local>".as_slice()));
+        let mut tap = Tap::client(Wire {
+            answers: Cursor::new(frame(&Continue)),
+            sent: Vec::new(),
+        });
+
+        block_on(async {
+            // A frame written in pieces is recorded once complete
+            tap.write_all(&mail[..6]).await.expect("Failed writing");
+            assert!(tap.capture().to_bytes().is_empty());
+            tap.write_all(&mail[6..]).await.expect("Failed writing");
+            let mut answer = [0; 5];
+            tap.read_exact(&mut answer).await.expect("Failed reading");
+        });
+
+        let capture = tap.capture();
+        assert_eq!(tap.into_inner().sent, mail);
+        let read = records(&capture.to_bytes()).expect("Failed reading records");
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].direction, Direction::ToServer);
+        assert_eq!(read[0].frame, mail[4..]);
+        assert_eq!(read[1].direction, Direction::ToClient);
+        assert_eq!(read[1].frame, b"c"[..]);
+        let report = analyze(&capture.to_bytes()).expect("Failed analyzing");
+        assert_eq!(report.latencies["Mail"].count, 1);
+        assert!(report.violations.is_empty());
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mail = Mail::from(b"<a@test.local>".as_slice());
+        let buffer = capture(&[
+            Record::new(Direction::ToServer, ms(0), &OptNeg::default()),
+            Record::new(Direction::ToClient, ms(1), &OptNeg::default()),
+            Record::new(Direction::ToServer, ms(2), &mail),
+            Record::new(Direction::ToClient, ms(4), &Continue),
+            Record::new(Direction::ToServer, ms(5), &Body::from(b"Hi".as_slice())),
+            Record::new(Direction::ToClient, ms(6), &Continue),
+            Record::new(Direction::ToServer, ms(7), &EndOfBody),
+            Record::new(Direction::ToClient, ms(8), &Progress),
+            Record::new(Direction::ToClient, ms(9), &AddHeader::new(b"X-Test", b"1")),
+            Record::new(Direction::ToClient, ms(17), &Continue),
+            // Nothing awaits this
+            Record::new(Direction::ToClient, ms(18), &Reject),
+            Record::new(Direction::ToServer, ms(19), &mail),
+        ]);
+
+        let report = analyze(&buffer).expect("Failed analyzing");
+
+        assert_eq!(report.frames_to_server, 5);
+        assert_eq!(report.frames_to_client, 7);
+        assert_eq!(
+            report.latencies["Mail"],
+            Latency {
+                count: 1,
+                total: ms(2),
+                max: ms(2)
+            }
+        );
+        assert_eq!(report.latencies["EndOfBody"].max, ms(10));
+        assert_eq!(report.latencies["OptNeg"].count, 1);
+        assert_eq!(report.actions["Continue"], 3);
+        assert_eq!(report.actions["Reject"], 1);
+        assert_eq!(report.modifications["AddHeader"], 1);
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation {
+                    record: 10,
+                    reason: "Reject without a command awaiting it".to_string()
+                },
+                Violation {
+                    record: 12,
+                    reason: "No answer to Mail".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_analyze_no_reply() {
+        let options = OptNeg {
+            protocol: Protocol::NR_MAIL,
+            ..OptNeg::default()
+        };
+        let buffer = capture(&[
+            Record::new(Direction::ToServer, ms(0), &OptNeg::default()),
+            Record::new(Direction::ToClient, ms(1), &options),
+            Record::new(
+                Direction::ToServer,
+                ms(2),
+                &Mail::from(b"<a@test.local>".as_slice()),
+            ),
+            Record::new(Direction::ToClient, ms(3), &AddHeader::new(b"X-Test", b"1")),
+        ]);
+
+        let report = analyze(&buffer).expect("Failed analyzing");
+
+        assert!(!report.latencies.contains_key("Mail"));
+        assert_eq!(
+            report.violations,
+            vec![Violation {
+                record: 3,
+                reason: "AddHeader outside of end of body".to_string()
+            }]
+        );
+    }
+}
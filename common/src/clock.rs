@@ -0,0 +1,219 @@
+//! Time sources for timeouts, replaceable to test them without waiting
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures_timer::Delay;
+
+/// A future completing once a [`Clock`] passed a duration
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The source of time for timeouts and durations measured by clients and
+/// servers.
+///
+/// [`SystemClock`] is used unless another one is configured. Tests use a
+/// [`ManualClock`] to let time pass at will.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// The current point in time
+    fn now(&self) -> Instant;
+
+    /// Complete once `duration` passed from now
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    /// The system clock, shared as configured
+    #[must_use]
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(Self)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(Delay::new(duration))
+    }
+}
+
+/// A clock only moving on [`ManualClock::advance`].
+///
+/// Clones share the same time, keep one to advance it after handing
+/// another to a client or server.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use miltr_common::clock::{Clock, ManualClock};
+///
+/// let clock = ManualClock::new();
+/// let start = clock.now();
+///
+/// clock.advance(Duration::from_secs(30));
+/// assert_eq!(clock.now() - start, Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+#[derive(Debug)]
+struct ManualState {
+    start: Instant,
+    elapsed: Duration,
+    next_id: u64,
+    /// Sleeps not yet completed, by id, with their deadline
+    sleeping: Vec<(u64, Duration, Waker)>,
+}
+
+impl ManualClock {
+    /// A clock standing at the current time
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ManualState {
+                start: Instant::now(),
+                elapsed: Duration::ZERO,
+                next_id: 0,
+                sleeping: Vec::new(),
+            })),
+        }
+    }
+
+    /// Let `duration` pass, waking every sleep ending until then
+    pub fn advance(&self, duration: Duration) {
+        let woken: Vec<Waker> = {
+            let mut state = self.lock();
+            state.elapsed += duration;
+            let elapsed = state.elapsed;
+            let (woken, sleeping) = state
+                .sleeping
+                .drain(..)
+                .partition(|(_, deadline, _)| *deadline <= elapsed);
+            state.sleeping = sleeping;
+            woken.into_iter().map(|(_, _, waker)| waker).collect()
+        };
+        woken.into_iter().for_each(Waker::wake);
+    }
+
+    /// Time advanced since this clock was created
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    /// The number of sleeps awaited but not yet completed.
+    ///
+    /// Useful to advance only after the code under test started waiting.
+    #[must_use]
+    pub fn sleeping(&self) -> usize {
+        self.lock().sleeping.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ManualState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        let state = self.lock();
+        state.start + state.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        Box::pin(ManualSleep {
+            clock: self.clone(),
+            id,
+            deadline: state.elapsed + duration,
+        })
+    }
+}
+
+/// A sleep on a [`ManualClock`]
+struct ManualSleep {
+    clock: ManualClock,
+    id: u64,
+    deadline: Duration,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.lock();
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+        let waker = cx.waker().clone();
+        match state.sleeping.iter_mut().find(|(id, _, _)| *id == self.id) {
+            Some(entry) => entry.2 = waker,
+            None => state.sleeping.push((self.id, self.deadline, waker)),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for ManualSleep {
+    fn drop(&mut self) {
+        self.clock
+            .lock()
+            .sleeping
+            .retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::FutureExt;
+
+    #[test]
+    fn test_manual_sleep() {
+        let clock = ManualClock::new();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!((&mut sleep).now_or_never().is_none());
+        assert_eq!(clock.sleeping(), 1);
+
+        clock.advance(Duration::from_secs(9));
+        assert!((&mut sleep).now_or_never().is_none());
+        assert_eq!(clock.sleeping(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(sleep.now_or_never().is_some());
+        assert_eq!(clock.sleeping(), 0);
+    }
+
+    #[test]
+    fn test_dropped_sleep_forgotten() {
+        let clock = ManualClock::new();
+        let mut sleep = clock.sleep(Duration::from_secs(1));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        drop(sleep);
+        assert_eq!(clock.sleeping(), 0);
+    }
+}
@@ -0,0 +1,220 @@
+//! The code bytes identifying every packet of the milter protocol
+//!
+//! `SMFIC_*` codes are sent by the MTA (commands), `SMFIR_*` codes by the
+//! milter (responses). Names follow libmilter's `mfdef.h`.
+
+/// Abort the current message
+pub const SMFIC_ABORT: u8 = b'A';
+/// A body chunk
+pub const SMFIC_BODY: u8 = b'B';
+/// Connection information
+pub const SMFIC_CONNECT: u8 = b'C';
+/// Macros for the following command
+pub const SMFIC_MACRO: u8 = b'D';
+/// End of body
+pub const SMFIC_BODYEOB: u8 = b'E';
+/// HELO/EHLO name
+pub const SMFIC_HELO: u8 = b'H';
+/// Quit, but keep the connection for a new session
+pub const SMFIC_QUIT_NC: u8 = b'K';
+/// A header
+pub const SMFIC_HEADER: u8 = b'L';
+/// MAIL FROM
+pub const SMFIC_MAIL: u8 = b'M';
+/// End of headers
+pub const SMFIC_EOH: u8 = b'N';
+/// Option negotiation, also used for the milter's answer
+pub const SMFIC_OPTNEG: u8 = b'O';
+/// Quit the connection
+pub const SMFIC_QUIT: u8 = b'Q';
+/// RCPT TO
+pub const SMFIC_RCPT: u8 = b'R';
+/// DATA
+pub const SMFIC_DATA: u8 = b'T';
+/// An unknown SMTP command
+pub const SMFIC_UNKNOWN: u8 = b'U';
+
+/// Add a recipient
+pub const SMFIR_ADDRCPT: u8 = b'+';
+/// Remove a recipient
+pub const SMFIR_DELRCPT: u8 = b'-';
+/// Add a recipient with ESMTP arguments
+pub const SMFIR_ADDRCPT_PAR: u8 = b'2';
+/// Shut down (internal to libmilter)
+pub const SMFIR_SHUTDOWN: u8 = b'4';
+/// Accept the message
+pub const SMFIR_ACCEPT: u8 = b'a';
+/// Replace the body
+pub const SMFIR_REPLBODY: u8 = b'b';
+/// Continue
+pub const SMFIR_CONTINUE: u8 = b'c';
+/// Discard the message
+pub const SMFIR_DISCARD: u8 = b'd';
+/// Change the envelope sender
+pub const SMFIR_CHGFROM: u8 = b'e';
+/// Cause a connection failure
+pub const SMFIR_CONN_FAIL: u8 = b'f';
+/// Add a header
+pub const SMFIR_ADDHEADER: u8 = b'h';
+/// Insert a header
+pub const SMFIR_INSHEADER: u8 = b'i';
+/// Set the list of macros requested
+pub const SMFIR_SETSYMLIST: u8 = b'l';
+/// Change a header
+pub const SMFIR_CHGHEADER: u8 = b'm';
+/// Progress, reset timeouts
+pub const SMFIR_PROGRESS: u8 = b'p';
+/// Quarantine the message
+pub const SMFIR_QUARANTINE: u8 = b'q';
+/// Reject the command or message
+pub const SMFIR_REJECT: u8 = b'r';
+/// Skip further calls of the same kind
+pub const SMFIR_SKIP: u8 = b's';
+/// Fail temporarily
+pub const SMFIR_TEMPFAIL: u8 = b't';
+/// Answer with an SMTP reply code
+pub const SMFIR_REPLYCODE: u8 = b'y';
+
+/// All codes with their libmilter name and the name of the command this
+/// crate parses them into, if any
+const NAMES: &[(u8, &str, Option<&str>)] = &[
+    (SMFIC_ABORT, "SMFIC_ABORT", Some("Abort")),
+    (SMFIC_BODY, "SMFIC_BODY", Some("Body")),
+    (SMFIC_CONNECT, "SMFIC_CONNECT", Some("Connect")),
+    (SMFIC_MACRO, "SMFIC_MACRO", Some("Macro")),
+    (SMFIC_BODYEOB, "SMFIC_BODYEOB", Some("EndOfBody")),
+    (SMFIC_HELO, "SMFIC_HELO", Some("Helo")),
+    (SMFIC_QUIT_NC, "SMFIC_QUIT_NC", Some("QuitNc")),
+    (SMFIC_HEADER, "SMFIC_HEADER", Some("Header")),
+    (SMFIC_MAIL, "SMFIC_MAIL", Some("Mail")),
+    (SMFIC_EOH, "SMFIC_EOH", Some("EndOfHeader")),
+    (SMFIC_OPTNEG, "SMFIC_OPTNEG", Some("OptNeg")),
+    (SMFIC_QUIT, "SMFIC_QUIT", Some("Quit")),
+    (SMFIC_RCPT, "SMFIC_RCPT", Some("Recipient")),
+    (SMFIC_DATA, "SMFIC_DATA", Some("Data")),
+    (SMFIC_UNKNOWN, "SMFIC_UNKNOWN", Some("Unknown")),
+    (SMFIR_ADDRCPT, "SMFIR_ADDRCPT", Some("AddRecipient")),
+    (SMFIR_DELRCPT, "SMFIR_DELRCPT", Some("DeleteRecipient")),
+    (
+        SMFIR_ADDRCPT_PAR,
+        "SMFIR_ADDRCPT_PAR",
+        Some("AddRecipientPar"),
+    ),
+    (SMFIR_SHUTDOWN, "SMFIR_SHUTDOWN", None),
+    (SMFIR_ACCEPT, "SMFIR_ACCEPT", None),
+    (SMFIR_REPLBODY, "SMFIR_REPLBODY", Some("ReplaceBody")),
+    (SMFIR_CONTINUE, "SMFIR_CONTINUE", Some("Continue")),
+    (SMFIR_DISCARD, "SMFIR_DISCARD", Some("Discard")),
+    (SMFIR_CHGFROM, "SMFIR_CHGFROM", Some("ChangeFrom")),
+    (SMFIR_CONN_FAIL, "SMFIR_CONN_FAIL", None),
+    (SMFIR_ADDHEADER, "SMFIR_ADDHEADER", Some("AddHeader")),
+    (SMFIR_INSHEADER, "SMFIR_INSHEADER", Some("InsertHeader")),
+    (SMFIR_SETSYMLIST, "SMFIR_SETSYMLIST", None),
+    (SMFIR_CHGHEADER, "SMFIR_CHGHEADER", Some("ChangeHeader")),
+    (SMFIR_PROGRESS, "SMFIR_PROGRESS", Some("Progress")),
+    (SMFIR_QUARANTINE, "SMFIR_QUARANTINE", Some("Quarantine")),
+    (SMFIR_REJECT, "SMFIR_REJECT", Some("Reject")),
+    (SMFIR_SKIP, "SMFIR_SKIP", Some("Skip")),
+    (SMFIR_TEMPFAIL, "SMFIR_TEMPFAIL", Some("Tempfail")),
+    (SMFIR_REPLYCODE, "SMFIR_REPLYCODE", Some("Replycode")),
+];
+
+/// The libmilter name of `code`, e.g. `SMFIC_RCPT` for `b'R'`
+#[must_use]
+pub fn name(code: u8) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .map(|(_, name, _)| *name)
+}
+
+/// The name of the command identified by `code`, e.g. `Recipient` for
+/// `b'R'`.
+///
+/// Codes shared by client and server (abort, option negotiation) have the
+/// same name on both sides. `None` for codes no command is parsed from.
+#[must_use]
+pub fn command_name(code: u8) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .and_then(|(_, _, command)| *command)
+}
+
+/// The least payload any valid frame with `code` carries after the code
+/// byte, e.g. the null bytes terminating name and value of a header.
+///
+/// Zero for codes without payload, codes with optional payload and unknown
+/// codes.
+#[must_use]
+pub fn min_payload_len(code: u8) -> usize {
+    match code {
+        // Version, capabilities and protocol flags
+        SMFIC_OPTNEG => 12,
+        // Index and header
+        SMFIR_INSHEADER | SMFIR_CHGHEADER => 4 + 2,
+        // Response code, extended code and message
+        SMFIR_REPLYCODE => 3,
+        // Hostname and family, header name and value, recipient and
+        // arguments
+        SMFIC_CONNECT | SMFIC_HEADER | SMFIR_ADDHEADER | SMFIR_ADDRCPT_PAR => 2,
+        // A single null terminated string, the stage of macros
+        SMFIC_HELO | SMFIC_MAIL | SMFIC_RCPT | SMFIC_UNKNOWN | SMFIC_MACRO | SMFIR_ADDRCPT
+        | SMFIR_DELRCPT | SMFIR_CHGFROM => 1,
+        _ => 0,
+    }
+}
+
+/// The code of a libmilter `name`, e.g. `b'R'` for `SMFIC_RCPT`
+#[must_use]
+pub fn from_name(name: &str) -> Option<u8> {
+    NAMES
+        .iter()
+        .find(|(_, n, _)| *n == name)
+        .map(|(code, _, _)| *code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_unique() {
+        for (i, (code, name, _)) in NAMES.iter().enumerate() {
+            assert!(
+                NAMES[i + 1..].iter().all(|(c, _, _)| c != code),
+                "{name} is not unique"
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (code, name, command) in NAMES {
+            assert_eq!(self::name(*code), Some(*name));
+            assert_eq!(from_name(name), Some(*code));
+            assert_eq!(command_name(*code), *command);
+        }
+        assert_eq!(self::name(0xff), None);
+        assert_eq!(command_name(SMFIR_ACCEPT), None);
+        assert_eq!(from_name("SMFIC_NONE"), None);
+    }
+
+    #[test]
+    fn test_min_payload_len() {
+        assert_eq!(min_payload_len(SMFIC_HEADER), 2);
+        assert_eq!(min_payload_len(SMFIC_BODYEOB), 0);
+        // Quarantine reasons may be empty
+        assert_eq!(min_payload_len(SMFIR_QUARANTINE), 0);
+        assert_eq!(min_payload_len(0xff), 0);
+    }
+
+    #[test]
+    fn test_types_use_codes() {
+        use crate::actions::{Action, Continue};
+        use crate::commands::{Command, EndOfBody};
+
+        assert_eq!(Action::from(Continue).code(), SMFIR_CONTINUE);
+        assert_eq!(Command::from(EndOfBody).code(), SMFIC_BODYEOB);
+    }
+}
@@ -1,12 +1,21 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Debug};
+
 use bytes::BytesMut;
 
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+use crate::redact::{self, DebugWith};
+#[cfg(feature = "decode-client")]
 use crate::ProtocolError;
 
 /// An email body part received by the milter client
-#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Clone, PartialEq, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Body {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     body: BytesMut,
 }
 
@@ -25,7 +34,7 @@ impl From<&[u8]> for Body {
 }
 
 impl Body {
-    const CODE: u8 = b'B';
+    const CODE: u8 = codes::SMFIC_BODY;
 
     /// Access the contained body bytes.
     #[must_use]
@@ -44,8 +53,29 @@ impl Body {
     pub fn to_vec(self) -> Vec<u8> {
         self.into()
     }
+
+    /// Debug this body part in full, even with the `redact-debug` feature.
+    ///
+    /// Only for troubleshooting, this prints mail content.
+    #[must_use]
+    pub fn dangerous_full_debug(&self) -> impl Debug + '_ {
+        DebugWith(|f: &mut fmt::Formatter<'_>| self.fmt_debug(f, false))
+    }
+
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, redact: bool) -> fmt::Result {
+        f.debug_struct("Body")
+            .field("body", &redact::bytes(&self.body, redact))
+            .finish()
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, redact::REDACT)
+    }
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Body {
     const CODE: u8 = Self::CODE;
 
@@ -74,12 +104,14 @@ impl Writable for Body {
 
 /// No more body parts will be received after this
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct EndOfBody;
 
 impl EndOfBody {
-    const CODE: u8 = b'E';
+    const CODE: u8 = codes::SMFIC_BODYEOB;
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for EndOfBody {
     const CODE: u8 = Self::CODE;
 
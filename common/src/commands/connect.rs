@@ -1,51 +1,67 @@
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String};
+
+#[cfg(feature = "std")]
+use std::net::IpAddr;
 
 use bytes::{BufMut, BytesMut};
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use num_enum::{FromPrimitive, IntoPrimitive};
 
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-client")]
+use crate::InvalidData;
+#[cfg(feature = "decode-client")]
 use crate::ProtocolError;
-use crate::{error::STAGE_DECODING, InvalidData, NotEnoughData};
+#[cfg(feature = "decode-client")]
+use crate::{error::STAGE_DECODING, NotEnoughData};
+#[cfg(feature = "decode-client")]
 use miltr_utils::ByteParsing;
 
 /// A marker for the connection family
 #[allow(missing_docs)]
-#[derive(Copy, Clone, PartialEq, Debug, IntoPrimitive, TryFromPrimitive)]
+#[derive(Copy, Clone, PartialEq, Debug, IntoPrimitive, FromPrimitive)]
 #[repr(u8)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum Family {
     Unknown = b'U',
     Unix = b'L',
     Inet = b'4',
     Inet6 = b'6',
+    /// A family byte this implementation does not know, kept as received.
+    ///
+    /// Use [`Family::from`] to get the named variant of a known byte.
+    #[num_enum(catch_all)]
+    Other(u8),
 }
 
 impl Family {
-    fn parse(buffer: &[u8]) -> Result<Self, ProtocolError> {
-        match Family::try_from(buffer[0]) {
-            Ok(f) => Ok(f),
-            Err(_) => Err(InvalidData {
-                msg: "Received unknown protocol family for connection info",
-                offending_bytes: BytesMut::from_iter(&[buffer[0]]),
-            }
-            .into()),
-        }
+    /// Whether port and address follow this family on the wire, as for
+    /// every family but [`Family::Unknown`]
+    fn has_details(self) -> bool {
+        self != Self::Unknown
     }
 }
 
 /// Connect information about the smtp client
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Connect {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     hostname: BytesMut,
     /// The connection type connected to the milter client
     pub family: Family,
-    /// On an IP connection, the port of the connection
+    /// The port of the connection, sent for every family but
+    /// [`Family::Unknown`]. MTAs send 0 for unix sockets, as is written
+    /// for `None`.
     pub port: Option<u16>,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     address: BytesMut,
 }
 
 impl Connect {
-    const CODE: u8 = b'C';
+    const CODE: u8 = codes::SMFIC_CONNECT;
     /// Create a new connect package
     #[must_use]
     pub fn new(hostname: &[u8], family: Family, port: Option<u16>, address: &[u8]) -> Self {
@@ -58,7 +74,7 @@ impl Connect {
     }
     /// Get the received hostname as as string-like type.
     #[must_use]
-    pub fn hostname(&self) -> Cow<str> {
+    pub fn hostname(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.hostname)
     }
 
@@ -66,11 +82,36 @@ impl Connect {
     ///
     /// Remember, this can contain an IP-Address or a unix socket.
     #[must_use]
-    pub fn address(&self) -> Cow<str> {
+    pub fn address(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.address)
     }
+
+    /// The address as IP, `None` for other families or if it does not
+    /// parse.
+    ///
+    /// ```
+    /// use miltr_common::commands::{Connect, Family};
+    ///
+    /// let connect = Connect::new(b"mx", Family::Inet6, Some(25), b"IPv6:2001:db8::1");
+    /// assert_eq!(connect.ip(), "2001:db8::1".parse().ok());
+    /// ```
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub fn ip(&self) -> Option<IpAddr> {
+        if !matches!(self.family, Family::Inet | Family::Inet6) {
+            return None;
+        }
+        let address = self.address();
+        // Sendmail prefixes v6 addresses
+        let address = address
+            .strip_prefix("IPv6:")
+            .or_else(|| address.strip_prefix("ipv6:"))
+            .unwrap_or(&address);
+        address.parse().ok()
+    }
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Connect {
     const CODE: u8 = Self::CODE;
 
@@ -94,31 +135,29 @@ impl Parsable for Connect {
             )
             .into());
         };
-        let family = Family::parse(&family)?;
-
-        let port = {
-            match family {
-                Family::Inet | Family::Inet6 => {
-                    let Some(buf) = buffer.safe_split_to(2) else {
-                        return Err(NotEnoughData::new(
-                            STAGE_DECODING,
-                            "Connect",
-                            "Port missing",
-                            2,
-                            buffer.len(),
-                            buffer,
-                        )
-                        .into());
-                    };
-                    let mut raw: [u8; 2] = [0; 2];
-                    raw.copy_from_slice(&buf);
-
-                    Some(u16::from_be_bytes(raw))
-                }
-                _ => None,
-            }
+        let family = Family::from(family[0]);
+
+        let port = if family.has_details() {
+            let Some(buf) = buffer.safe_split_to(2) else {
+                return Err(NotEnoughData::new(
+                    STAGE_DECODING,
+                    "Connect",
+                    "Port missing",
+                    2,
+                    buffer.len(),
+                    buffer,
+                )
+                .into());
+            };
+            let mut raw: [u8; 2] = [0; 2];
+            raw.copy_from_slice(&buf);
+
+            Some(u16::from_be_bytes(raw))
+        } else {
+            None
         };
 
+        // Unknown families carry no address, but tolerate a null byte
         let address;
         if let Some(b'\0') = buffer.last() {
             address = buffer.split_to(buffer.len() - 1);
@@ -144,14 +183,21 @@ impl Writable for Connect {
 
         buffer.put_u8(self.family.into());
 
-        buffer.put_u16(self.port.unwrap_or_default());
-
-        buffer.extend_from_slice(&self.address);
-        buffer.put_u8(0);
+        // Like sendmail, send nothing after an unknown family
+        if self.family.has_details() {
+            buffer.put_u16(self.port.unwrap_or_default());
+            buffer.extend_from_slice(&self.address);
+            buffer.put_u8(0);
+        }
     }
 
     fn len(&self) -> usize {
-        self.hostname.len() + 1 + 1 + 2 + self.address.len() + 1
+        let details = if self.family.has_details() {
+            2 + self.address.len() + 1
+        } else {
+            0
+        };
+        self.hostname.len() + 1 + 1 + details
     }
 
     fn code(&self) -> u8 {
@@ -163,10 +209,10 @@ impl Writable for Connect {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "decode-client"))]
 mod tests {
     use super::Family;
-    use crate::{commands::Connect, decoding::Parsable};
+    use crate::{commands::Connect, decoding::Parsable, encoding::Writable};
     use bytes::BytesMut;
     use pretty_assertions::assert_eq;
 
@@ -197,6 +243,51 @@ mod tests {
         assert_eq!(b"127.0.0.1", connect.address.to_vec().as_slice());
     }
 
+    #[test]
+    fn test_round_trip() {
+        let cases: [&[u8]; 4] = [
+            b"localhost\x004\x12\x34192.0.2.1\0",
+            b"localhost\0L\0\0/run/smtp.sock\0",
+            b"localhost\0U",
+            b"localhost\0X\x00\x19somewhere\0",
+        ];
+
+        for wire in cases {
+            let connect = Connect::parse(BytesMut::from(wire)).expect("Failed parsing connect");
+            let mut written = BytesMut::new();
+            connect.write(&mut written);
+
+            assert_eq!(wire, written.as_ref());
+            assert_eq!(connect.len(), written.len());
+        }
+    }
+
+    #[test]
+    fn test_other_family() {
+        let connect = Connect::parse(BytesMut::from(&b"localhost\0X\x00\x19somewhere\0"[..]))
+            .expect("Failed parsing connect");
+
+        assert_eq!(connect.family, Family::Other(b'X'));
+        assert_eq!(connect.port, Some(25));
+        assert_eq!(connect.address(), "somewhere");
+        assert_eq!(Family::from(b'6'), Family::Inet6);
+    }
+
+    #[test]
+    fn test_unknown_family() {
+        // Written by older versions with a null byte after the family
+        let connect =
+            Connect::parse(BytesMut::from(&b"localhost\0U\0"[..])).expect("Failed parsing connect");
+        assert_eq!(connect.family, Family::Unknown);
+        assert_eq!(connect.address(), "");
+
+        let connect = Connect::new(b"localhost", Family::Unknown, Some(25), b"ignored");
+        let mut written = BytesMut::new();
+        connect.write(&mut written);
+        assert_eq!(written.as_ref(), b"localhost\0U");
+        assert_eq!(connect.len(), written.len());
+    }
+
     #[cfg(feature = "count-allocations")]
     #[test]
     fn test_parse_connect() {
@@ -0,0 +1,543 @@
+//! Build and validate ESMTP parameters, as sent along `MAIL FROM` or
+//! `RCPT TO`
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::{self, Display, Write};
+
+use bitflags::bitflags;
+use thiserror::Error;
+
+bitflags! {
+    /// The conditions of the `NOTIFY` parameter of RFC 3461.
+    ///
+    /// Empty means `NEVER`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Notify: u8 {
+        /// Notify on successful delivery
+        const SUCCESS = 0b001;
+        /// Notify on failed delivery
+        const FAILURE = 0b010;
+        /// Notify on delayed delivery
+        const DELAY = 0b100;
+    }
+}
+
+impl Display for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("NEVER");
+        }
+        let names = [
+            (Self::SUCCESS, "SUCCESS"),
+            (Self::FAILURE, "FAILURE"),
+            (Self::DELAY, "DELAY"),
+        ];
+        let mut first = true;
+        for (flag, name) in names {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(",")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Notify {
+    /// Parse `NEVER` or a comma separated list of conditions
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Some(Self::empty());
+        }
+        value
+            .split(',')
+            .try_fold(Self::empty(), |notify, condition| {
+                let flag = match condition.to_ascii_uppercase().as_str() {
+                    "SUCCESS" => Self::SUCCESS,
+                    "FAILURE" => Self::FAILURE,
+                    "DELAY" => Self::DELAY,
+                    _ => return None,
+                };
+                Some(notify | flag)
+            })
+    }
+}
+
+/// The `RET` parameter of RFC 3461, what a failure notification returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ret {
+    /// The full message
+    Full,
+    /// Only the headers
+    Hdrs,
+}
+
+impl Ret {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("FULL") {
+            Some(Self::Full)
+        } else if value.eq_ignore_ascii_case("HDRS") {
+            Some(Self::Hdrs)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Ret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => f.write_str("FULL"),
+            Self::Hdrs => f.write_str("HDRS"),
+        }
+    }
+}
+
+/// The `ORCPT` parameter of RFC 3461, the original recipient.
+///
+/// Displayed as sent on the wire, with the address xtext encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orcpt {
+    addr_type: String,
+    address: String,
+}
+
+impl Orcpt {
+    /// An original recipient of `addr_type`, usually `rfc822`
+    #[must_use]
+    pub fn new(addr_type: &str, address: &str) -> Self {
+        Self {
+            addr_type: addr_type.to_string(),
+            address: address.to_string(),
+        }
+    }
+
+    /// The address type, e.g. `rfc822`
+    #[must_use]
+    pub fn addr_type(&self) -> &str {
+        &self.addr_type
+    }
+
+    /// The address, xtext decoded
+    #[must_use]
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Display for Orcpt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{};{}", self.addr_type, encode_xtext(&self.address))
+    }
+}
+
+/// An invalid ESMTP parameter
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum EsmtpArgsError {
+    /// The keyword is empty or contains characters not allowed in it
+    #[error("Invalid esmtp keyword '{0}'")]
+    InvalidKeyword(String),
+    /// The value contains spaces, `=` or non-printable characters
+    #[error("Invalid value '{value}' of esmtp parameter {keyword}")]
+    InvalidValue {
+        /// The keyword of the parameter
+        keyword: String,
+        /// The invalid value
+        value: String,
+    },
+    /// The value of `NOTIFY` is not `NEVER` or a list of conditions
+    #[error("Invalid NOTIFY value '{0}'")]
+    InvalidNotify(String),
+    /// The value of `ORCPT` is not an address type and an xtext address
+    #[error("Invalid ORCPT value '{0}'")]
+    InvalidOrcpt(String),
+    /// The value of `RET` is not `FULL` or `HDRS`
+    #[error("Invalid RET value '{0}'")]
+    InvalidRet(String),
+    /// The value of `ENVID` is not xtext
+    #[error("Invalid ENVID value '{0}'")]
+    InvalidEnvid(String),
+    /// A keyword is given more than once
+    #[error("Duplicate esmtp parameter {0}")]
+    Duplicate(String),
+}
+
+/// Validated ESMTP parameters.
+///
+/// Displayed as sent on the wire, space separated `KEYWORD=value` pairs.
+///
+/// ```
+/// use miltr_common::commands::{EsmtpArgs, Notify};
+///
+/// let args = EsmtpArgs::builder()
+///     .notify(Notify::FAILURE | Notify::DELAY)
+///     .orcpt("rfc822", "bob@example.com")
+///     .build()
+///     .expect("Valid parameters");
+/// assert_eq!(
+///     args.to_string(),
+///     "NOTIFY=FAILURE,DELAY ORCPT=rfc822;bob@example.com"
+/// );
+/// assert_eq!(EsmtpArgs::parse(&args.to_string()), Ok(args));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsmtpArgs {
+    params: Vec<(String, Option<String>)>,
+}
+
+impl EsmtpArgs {
+    /// Create a builder to assemble parameters
+    #[must_use]
+    pub fn builder() -> EsmtpArgsBuilder {
+        EsmtpArgsBuilder::default()
+    }
+
+    /// Parse and validate space separated parameters as sent on the wire
+    ///
+    /// # Errors
+    /// If any parameter is invalid, see [`EsmtpArgsBuilder::build`]
+    pub fn parse(args: &str) -> Result<Self, EsmtpArgsError> {
+        let mut builder = Self::builder();
+        for param in args.split(' ').filter(|p| !p.is_empty()) {
+            builder = match param.split_once('=') {
+                Some((keyword, value)) => builder.param(keyword, Some(value)),
+                None => builder.param(param, None),
+            };
+        }
+        builder.build()
+    }
+
+    /// The value of the parameter `keyword`, compared case-insensitively.
+    ///
+    /// Returns `Some(None)` for a parameter without value.
+    #[must_use]
+    pub fn get(&self, keyword: &str) -> Option<Option<&str>> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(keyword))
+            .map(|(_, value)| value.as_deref())
+    }
+
+    /// All parameters in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.params
+            .iter()
+            .map(|(keyword, value)| (keyword.as_str(), value.as_deref()))
+    }
+
+    /// Whether there are no parameters
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    /// When to send delivery status notifications, from `NOTIFY`
+    #[must_use]
+    pub fn notify(&self) -> Option<Notify> {
+        self.get("NOTIFY").flatten().and_then(Notify::parse)
+    }
+
+    /// What a failure notification returns, from `RET`
+    #[must_use]
+    pub fn ret(&self) -> Option<Ret> {
+        self.get("RET").flatten().and_then(Ret::parse)
+    }
+
+    /// The envelope id for notifications, xtext decoded, from `ENVID`
+    #[must_use]
+    pub fn envid(&self) -> Option<String> {
+        self.get("ENVID").flatten().map(decode_xtext)
+    }
+
+    /// The original recipient, from `ORCPT`
+    #[must_use]
+    pub fn orcpt(&self) -> Option<Orcpt> {
+        let (addr_type, address) = self.get("ORCPT").flatten()?.split_once(';')?;
+        Some(Orcpt::new(addr_type, &decode_xtext(address)))
+    }
+}
+
+impl Display for EsmtpArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (keyword, value)) in self.params.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(keyword)?;
+            if let Some(value) = value {
+                write!(f, "={value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Assemble [`EsmtpArgs`], validating them on [`Self::build`]
+#[derive(Debug, Clone, Default)]
+pub struct EsmtpArgsBuilder {
+    params: Vec<(String, Option<String>)>,
+}
+
+impl EsmtpArgsBuilder {
+    /// Request delivery status notifications, RFC 3461
+    #[must_use]
+    pub fn notify(self, notify: Notify) -> Self {
+        let value = notify.to_string();
+        self.param("NOTIFY", Some(&value))
+    }
+
+    /// Set the original recipient, RFC 3461. The address is xtext encoded.
+    #[must_use]
+    pub fn orcpt(self, addr_type: &str, address: &str) -> Self {
+        let value = format!("{addr_type};{}", encode_xtext(address));
+        self.param("ORCPT", Some(&value))
+    }
+
+    /// Set what a failure notification returns, RFC 3461
+    #[must_use]
+    pub fn ret(self, ret: Ret) -> Self {
+        let value = ret.to_string();
+        self.param("RET", Some(&value))
+    }
+
+    /// Set the envelope id for notifications, RFC 3461. The id is xtext
+    /// encoded.
+    #[must_use]
+    pub fn envid(self, envid: &str) -> Self {
+        let value = encode_xtext(envid);
+        self.param("ENVID", Some(&value))
+    }
+
+    /// Add any parameter, with or without a value
+    #[must_use]
+    pub fn param(mut self, keyword: &str, value: Option<&str>) -> Self {
+        self.params
+            .push((keyword.to_string(), value.map(ToString::to_string)));
+        self
+    }
+
+    /// Validate all parameters.
+    ///
+    /// Keywords and values have to follow RFC 5321, values of `NOTIFY`,
+    /// `RET`, `ENVID` and `ORCPT` RFC 3461. Keywords are case-insensitive and may be given
+    /// once only.
+    ///
+    /// # Errors
+    /// On the first invalid parameter
+    pub fn build(self) -> Result<EsmtpArgs, EsmtpArgsError> {
+        for (i, (keyword, value)) in self.params.iter().enumerate() {
+            validate(keyword, value.as_deref())?;
+            if self.params[..i]
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case(keyword))
+            {
+                return Err(EsmtpArgsError::Duplicate(keyword.clone()));
+            }
+        }
+        Ok(EsmtpArgs {
+            params: self.params,
+        })
+    }
+}
+
+fn validate(keyword: &str, value: Option<&str>) -> Result<(), EsmtpArgsError> {
+    let valid_keyword = keyword
+        .bytes()
+        .next()
+        .is_some_and(|b| b.is_ascii_alphanumeric())
+        && keyword
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    if !valid_keyword {
+        return Err(EsmtpArgsError::InvalidKeyword(keyword.to_string()));
+    }
+
+    if let Some(value) = value {
+        let valid_value =
+            !value.is_empty() && value.bytes().all(|b| matches!(b, 33..=60 | 62..=126));
+        if !valid_value {
+            return Err(EsmtpArgsError::InvalidValue {
+                keyword: keyword.to_string(),
+                value: value.to_string(),
+            });
+        }
+    }
+
+    if keyword.eq_ignore_ascii_case("NOTIFY") && !value.is_some_and(|v| Notify::parse(v).is_some())
+    {
+        return Err(EsmtpArgsError::InvalidNotify(
+            value.unwrap_or_default().to_string(),
+        ));
+    }
+    if keyword.eq_ignore_ascii_case("ORCPT") && !value.is_some_and(valid_orcpt) {
+        return Err(EsmtpArgsError::InvalidOrcpt(
+            value.unwrap_or_default().to_string(),
+        ));
+    }
+    if keyword.eq_ignore_ascii_case("RET") && !value.is_some_and(|v| Ret::parse(v).is_some()) {
+        return Err(EsmtpArgsError::InvalidRet(
+            value.unwrap_or_default().to_string(),
+        ));
+    }
+    if keyword.eq_ignore_ascii_case("ENVID") && !value.is_some_and(|v| valid_xtext(v.as_bytes())) {
+        return Err(EsmtpArgsError::InvalidEnvid(
+            value.unwrap_or_default().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// An address type atom, `;` and an xtext encoded address
+fn valid_orcpt(value: &str) -> bool {
+    let Some((addr_type, address)) = value.split_once(';') else {
+        return false;
+    };
+    let valid_type = !addr_type.is_empty()
+        && addr_type
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    valid_type && !address.is_empty() && valid_xtext(address.as_bytes())
+}
+
+fn valid_xtext(mut xtext: &[u8]) -> bool {
+    while let Some((&first, rest)) = xtext.split_first() {
+        xtext = match first {
+            b'+' => match rest {
+                [a, b, rest @ ..] if is_upper_hex(*a) && is_upper_hex(*b) => rest,
+                _ => return false,
+            },
+            33..=126 if first != b'=' => rest,
+            _ => return false,
+        };
+    }
+    true
+}
+
+fn is_upper_hex(b: u8) -> bool {
+    b.is_ascii_digit() || (b'A'..=b'F').contains(&b)
+}
+
+/// Decode validated xtext, replacing invalid utf-8
+fn decode_xtext(xtext: &str) -> String {
+    let mut decoded = Vec::with_capacity(xtext.len());
+    let mut bytes = xtext.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'+' {
+            let hex = [bytes.next(), bytes.next()];
+            let value = hex
+                .into_iter()
+                .flatten()
+                .filter_map(|h| char::from(h).to_digit(16))
+                .fold(0, |acc, digit| acc * 16 + digit);
+            // Two hex digits do not exceed a byte
+            decoded.push(value as u8);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encode `value` as xtext, RFC 3461
+fn encode_xtext(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if matches!(b, 33..=126) && b != b'+' && b != b'=' {
+            encoded.push(char::from(b));
+        } else {
+            // Writing to a string does not fail
+            let _ = write!(encoded, "+{b:02X}");
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let args = EsmtpArgs::builder()
+            .notify(Notify::empty())
+            .orcpt("rfc822", "a+b=c@test.local")
+            .param("X-FLAG", None)
+            .build()
+            .expect("Valid parameters");
+
+        assert_eq!(
+            args.to_string(),
+            "NOTIFY=NEVER ORCPT=rfc822;a+2Bb+3Dc@test.local X-FLAG"
+        );
+        assert_eq!(args.get("orcpt"), Some(Some("rfc822;a+2Bb+3Dc@test.local")));
+        assert_eq!(args.get("X-FLAG"), Some(None));
+        assert_eq!(args.get("RET"), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let cases = [
+            (
+                "NOTIFY=SUCCESS,SOMETIMES",
+                "Invalid NOTIFY value 'SUCCESS,SOMETIMES'",
+            ),
+            ("NOTIFY", "Invalid NOTIFY value ''"),
+            (
+                "ORCPT=bob@test.local",
+                "Invalid ORCPT value 'bob@test.local'",
+            ),
+            ("ORCPT=rfc822;a+2b", "Invalid ORCPT value 'rfc822;a+2b'"),
+            ("-X=1", "Invalid esmtp keyword '-X'"),
+            ("X=a=b", "Invalid value 'a=b' of esmtp parameter X"),
+            ("RET=HDRS ret=FULL", "Duplicate esmtp parameter ret"),
+            ("RET=BODY", "Invalid RET value 'BODY'"),
+            ("ENVID=a+zz", "Invalid ENVID value 'a+zz'"),
+        ];
+
+        for (args, message) in cases {
+            let err = EsmtpArgs::parse(args).expect_err(args);
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = EsmtpArgs::parse(" NOTIFY=success,Delay  RET=HDRS ").expect("Valid parameters");
+
+        let params: Vec<_> = args.iter().collect();
+        assert_eq!(
+            params,
+            vec![("NOTIFY", Some("success,Delay")), ("RET", Some("HDRS"))]
+        );
+    }
+
+    #[test]
+    fn test_dsn_parameters() {
+        let args = EsmtpArgs::parse(
+            "RET=hdrs ENVID=id+2B1 NOTIFY=Failure,DELAY ORCPT=rfc822;a+2Bb@test.local",
+        )
+        .expect("Valid parameters");
+
+        assert_eq!(args.ret(), Some(Ret::Hdrs));
+        assert_eq!(args.envid().as_deref(), Some("id+1"));
+        assert_eq!(args.notify(), Some(Notify::FAILURE | Notify::DELAY));
+        let orcpt = args.orcpt().expect("ORCPT missing");
+        assert_eq!(orcpt.address(), "a+b@test.local");
+        assert_eq!(orcpt.to_string(), "rfc822;a+2Bb@test.local");
+
+        let rebuilt = EsmtpArgs::builder()
+            .ret(Ret::Hdrs)
+            .envid("id+1")
+            .build()
+            .expect("Valid parameters");
+        assert_eq!(rebuilt.to_string(), "RET=HDRS ENVID=id+2B1");
+        assert_eq!(EsmtpArgs::default().notify(), None);
+    }
+}
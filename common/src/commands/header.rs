@@ -1,22 +1,33 @@
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String};
+use core::fmt::{self, Debug};
 
 use bytes::{BufMut, BytesMut};
 
+use super::utf8::{self, InvalidUtf8, TextFields};
+use crate::codes;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+use crate::redact::{self, DebugWith};
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::InvalidData;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::ProtocolError;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use miltr_utils::ByteParsing;
 
 /// An smtp header received
-#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Header {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     name: BytesMut,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     value: BytesMut,
 }
 
 impl Header {
-    const CODE: u8 = b'L';
+    const CODE: u8 = codes::SMFIC_HEADER;
 
     /// Create a Header from some bytes
     #[must_use]
@@ -28,35 +39,122 @@ impl Header {
     }
     /// The name of the received header
     #[must_use]
-    pub fn name(&self) -> Cow<str> {
+    pub fn name(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.name)
     }
 
     /// The value of the received header
     #[must_use]
-    pub fn value(&self) -> Cow<str> {
+    pub fn value(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.value)
     }
+
+    /// The raw bytes of the header name
+    #[must_use]
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// The raw bytes of the header value
+    #[must_use]
+    pub fn value_bytes(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Whether this header is named `name`, ignoring case as header names
+    /// are case-insensitive (RFC 5322)
+    #[must_use]
+    pub fn name_eq_ignore_case(&self, name: &str) -> bool {
+        name_eq_ignore_case(&self.name, name)
+    }
+
+    /// The name of this header in its canonical casing, see
+    /// [`canonical_header_name`]
+    #[must_use]
+    pub fn canonical_name(&self) -> String {
+        canonical_header_name(&self.name())
+    }
+
+    /// Debug this header in full, even with the `redact-debug` feature.
+    ///
+    /// Only for troubleshooting, this prints mail content.
+    #[must_use]
+    pub fn dangerous_full_debug(&self) -> impl Debug + '_ {
+        DebugWith(|f: &mut fmt::Formatter<'_>| self.fmt_debug(f, false))
+    }
+
+    /// The name is kept, as it carries no content
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, redact: bool) -> fmt::Result {
+        f.debug_struct("Header")
+            .field("name", &redact::bytes(&self.name, false))
+            .field("value", &redact::bytes(&self.value, redact))
+            .finish()
+    }
 }
 
+impl Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, redact::REDACT)
+    }
+}
+
+/// Compare raw header name bytes to `name`, ignoring case
+pub(crate) fn name_eq_ignore_case(raw: &[u8], name: &str) -> bool {
+    raw.eq_ignore_ascii_case(name.as_bytes())
+}
+
+/// Parts of header names spelled in upper case, like `DKIM-Signature` or
+/// `Message-ID`
+const UPPERCASE_PARTS: &[&str] = &["ARC", "DKIM", "DMARC", "ID", "MIME", "MS", "SPF"];
+
+/// Bring a header name into its usual casing, e.g. `subject` into `Subject`
+/// or `dkim-signature` into `DKIM-Signature`.
+///
+/// Every part separated by `-` is capitalized, well known abbreviations are
+/// spelled in upper case.
+///
+/// ```
+/// use miltr_common::commands::canonical_header_name;
+///
+/// assert_eq!(canonical_header_name("content-TYPE"), "Content-Type");
+/// assert_eq!(canonical_header_name("message-id"), "Message-ID");
+/// ```
+#[must_use]
+pub fn canonical_header_name(name: &str) -> String {
+    let mut canonical = String::with_capacity(name.len());
+    for (i, part) in name.split('-').enumerate() {
+        if i > 0 {
+            canonical.push('-');
+        }
+        if let Some(upper) = UPPERCASE_PARTS
+            .iter()
+            .find(|upper| upper.eq_ignore_ascii_case(part))
+        {
+            canonical.push_str(upper);
+            continue;
+        }
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            canonical.push(first.to_ascii_uppercase());
+            canonical.extend(chars.map(|c| c.to_ascii_lowercase()));
+        }
+    }
+    canonical
+}
+
+// Modifications carry headers too
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 impl Parsable for Header {
     const CODE: u8 = Self::CODE;
 
     fn parse(mut buffer: BytesMut) -> Result<Self, ProtocolError> {
-        let Some(name) = buffer.delimited(0) else {
-            return Err(InvalidData::new(
-                "Received header package without name terminated by null byte in it",
-                buffer,
-            )
-            .into());
-        };
-
-        let Some(value) = buffer.delimited(0) else {
-            return Err(InvalidData::new(
-                "Received header package without value terminated by null byte in it",
-                buffer,
-            )
-            .into());
+        let Some((name, value)) = buffer.delimited_pair(0) else {
+            let msg = if buffer.contains(&0) {
+                "Received header package without value terminated by null byte in it"
+            } else {
+                "Received header package without name terminated by null byte in it"
+            };
+            return Err(InvalidData::new(msg, buffer).into());
         };
 
         Ok(Self { name, value })
@@ -84,14 +182,28 @@ impl Writable for Header {
     }
 }
 
+impl TextFields for Header {
+    fn validate_utf8(&self) -> Result<(), InvalidUtf8> {
+        utf8::validate("header name", &self.name)?;
+        utf8::validate("header value", &self.value)
+    }
+
+    fn replace_invalid_utf8(&mut self) {
+        utf8::replace(&mut self.name);
+        utf8::replace(&mut self.value);
+    }
+}
+
 /// After all headers have been sent, end of header is sent
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct EndOfHeader;
 
 impl EndOfHeader {
-    const CODE: u8 = b'N';
+    const CODE: u8 = codes::SMFIC_EOH;
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for EndOfHeader {
     const CODE: u8 = Self::CODE;
 
@@ -119,10 +231,27 @@ impl Writable for EndOfHeader {
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(feature = "decode-client")]
     use crate::decoding::Parsable;
     use pretty_assertions::assert_eq;
     use rstest::rstest;
 
+    #[test]
+    fn test_debug() {
+        let header = Header::new(b"Subject", b"Salary for Alice");
+        let redacted = DebugWith(|f: &mut fmt::Formatter<'_>| header.fmt_debug(f, true));
+
+        assert_eq!(
+            alloc::format!("{redacted:?}"),
+            r#"Header { name: b"Subject", value: <16 bytes, fnv1a de2896531adc8a04> }"#
+        );
+        assert_eq!(
+            alloc::format!("{:?}", header.dangerous_full_debug()),
+            r#"Header { name: b"Subject", value: b"Salary for Alice" }"#
+        );
+    }
+
+    #[cfg(feature = "decode-client")]
     #[rstest]
     #[case(BytesMut::from("name\0value\0"), Ok(Header {name: BytesMut::from("name"), value: BytesMut::from("value")} ))]
     #[case(
@@ -148,8 +277,27 @@ mod test {
             }
             (Ok(expected), Ok(parsed)) => assert_eq!(expected, parsed),
             (expected, parsed) => panic!("Did not get expected:\n{expected:?}\n vs \n{parsed:?}"),
-        };
+        }
+    }
+
+    #[rstest]
+    #[case("subject", "Subject")]
+    #[case("X-SPAM-status", "X-Spam-Status")]
+    #[case("dkim-signature", "DKIM-Signature")]
+    #[case("Mime-Version", "MIME-Version")]
+    #[case("x--odd-", "X--Odd-")]
+    fn test_canonical_header_name(#[case] name: &str, #[case] expected: &str) {
+        assert_eq!(canonical_header_name(name), expected);
+    }
+
+    #[test]
+    fn test_name_eq_ignore_case() {
+        let header = Header::new(b"Content-Type", b"text/plain");
+
+        assert!(header.name_eq_ignore_case("content-type"));
+        assert!(!header.name_eq_ignore_case("content"));
     }
+
     #[cfg(feature = "count-allocations")]
     #[test]
     fn test_parse_header() {
@@ -1,14 +1,20 @@
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String};
 
 use bytes::{BufMut, BytesMut};
 
+use super::utf8::{self, InvalidUtf8, TextFields};
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-client")]
 use crate::{InvalidData, ProtocolError};
 
 /// Helo information sent by the smtp client
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Helo {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     buffer: BytesMut,
 }
 
@@ -21,14 +27,15 @@ impl From<&[u8]> for Helo {
 }
 
 impl Helo {
-    const CODE: u8 = b'H';
+    const CODE: u8 = codes::SMFIC_HELO;
     /// The helo greeting sent by the client
     #[must_use]
-    pub fn helo(&self) -> Cow<str> {
+    pub fn helo(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.buffer[..])
     }
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Helo {
     const CODE: u8 = Self::CODE;
 
@@ -74,7 +81,17 @@ impl Writable for Helo {
     }
 }
 
-#[cfg(test)]
+impl TextFields for Helo {
+    fn validate_utf8(&self) -> Result<(), InvalidUtf8> {
+        utf8::validate("helo", &self.buffer)
+    }
+
+    fn replace_invalid_utf8(&mut self) {
+        utf8::replace(&mut self.buffer);
+    }
+}
+
+#[cfg(all(test, feature = "decode-client"))]
 mod test {
     use super::*;
     use crate::decoding::Parsable;
@@ -1,16 +1,27 @@
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec::Vec};
+use core::fmt::{self, Debug};
 
 use bytes::{BufMut, BytesMut};
 
+use super::esmtp::{EsmtpArgs, EsmtpArgsError};
+use super::utf8::{self, InvalidUtf8, TextFields};
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+use crate::redact::{self, DebugWith};
+#[cfg(feature = "decode-client")]
 use crate::{InvalidData, ProtocolError};
+#[cfg(feature = "decode-client")]
 use miltr_utils::ByteParsing;
 
 /// Information about a mail to be processed
-#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Clone, PartialEq, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Mail {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     sender: BytesMut,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::optional_bytes))]
     esmtp_args: Option<BytesMut>,
 }
 
@@ -24,10 +35,10 @@ impl From<&[u8]> for Mail {
 }
 
 impl Mail {
-    const CODE: u8 = b'M';
+    const CODE: u8 = codes::SMFIC_MAIL;
     /// The sender of this email
     #[must_use]
-    pub fn sender(&self) -> Cow<str> {
+    pub fn sender(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.sender)
     }
 
@@ -35,7 +46,7 @@ impl Mail {
     ///
     /// If those are empty, an empty vector is returned.
     #[must_use]
-    pub fn esmtp_args(&self) -> Vec<Cow<str>> {
+    pub fn esmtp_args(&self) -> Vec<Cow<'_, str>> {
         let Some(args) = &self.esmtp_args else {
             return Vec::new();
         };
@@ -45,8 +56,73 @@ impl Mail {
             .map(String::from_utf8_lossy)
             .collect()
     }
+
+    /// Parse and validate the esmtp args, e.g. to inspect DSN parameters.
+    ///
+    /// ```
+    /// use miltr_common::commands::{EsmtpArgs, Mail, Ret};
+    ///
+    /// let args = EsmtpArgs::builder()
+    ///     .ret(Ret::Hdrs).envid("QQ314159")
+    ///     .build()
+    ///     .expect("Valid parameters");
+    /// let mail = Mail::from(b"<alice@example.com>".as_slice()).with_esmtp_args(&args);
+    ///
+    /// let args = mail.esmtp().expect("Valid parameters");
+    /// assert_eq!(args.ret(), Some(Ret::Hdrs));
+    /// assert_eq!(args.envid().as_deref(), Some("QQ314159"));
+    /// ```
+    ///
+    /// # Errors
+    /// If any parameter is invalid, see [`EsmtpArgs::parse`]
+    pub fn esmtp(&self) -> Result<EsmtpArgs, EsmtpArgsError> {
+        EsmtpArgs::parse(&self.esmtp_args().join(" "))
+    }
+
+    /// Replace the esmtp args with `args`
+    #[must_use]
+    pub fn with_esmtp_args(mut self, args: &EsmtpArgs) -> Self {
+        let mut raw = BytesMut::new();
+        for (keyword, value) in args.iter() {
+            raw.extend_from_slice(keyword.as_bytes());
+            if let Some(value) = value {
+                raw.put_u8(b'=');
+                raw.extend_from_slice(value.as_bytes());
+            }
+            raw.put_u8(0);
+        }
+        self.esmtp_args = (!raw.is_empty()).then_some(raw);
+        self
+    }
+
+    /// Debug this sender in full, even with the `redact-debug` feature.
+    ///
+    /// Only for troubleshooting, this prints addresses.
+    #[must_use]
+    pub fn dangerous_full_debug(&self) -> impl Debug + '_ {
+        DebugWith(|f: &mut fmt::Formatter<'_>| self.fmt_debug(f, false))
+    }
+
+    /// The esmtp args are redacted as well, they may hold addresses
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, redact: bool) -> fmt::Result {
+        let esmtp_args = self
+            .esmtp_args
+            .as_deref()
+            .map(|args| redact::bytes(args, redact));
+        f.debug_struct("Mail")
+            .field("sender", &redact::bytes(&self.sender, redact))
+            .field("esmtp_args", &esmtp_args)
+            .finish()
+    }
+}
+
+impl Debug for Mail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, redact::REDACT)
+    }
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Mail {
     const CODE: u8 = Self::CODE;
 
@@ -99,14 +175,33 @@ impl Writable for Mail {
     }
 }
 
+impl TextFields for Mail {
+    fn validate_utf8(&self) -> Result<(), InvalidUtf8> {
+        utf8::validate("sender", &self.sender)?;
+        match &self.esmtp_args {
+            Some(args) => utf8::validate("esmtp args", args),
+            None => Ok(()),
+        }
+    }
+
+    fn replace_invalid_utf8(&mut self) {
+        utf8::replace(&mut self.sender);
+        if let Some(args) = &mut self.esmtp_args {
+            utf8::replace(args);
+        }
+    }
+}
+
 /// SMTP Data command has been sent
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Data;
 
 impl Data {
-    const CODE: u8 = b'T';
+    const CODE: u8 = codes::SMFIC_DATA;
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Data {
     const CODE: u8 = Self::CODE;
 
@@ -131,13 +226,29 @@ impl Writable for Data {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "decode-client"))]
 mod test {
     use super::*;
     use crate::decoding::Parsable;
     use pretty_assertions::assert_eq;
     use rstest::rstest;
 
+    #[test]
+    fn test_debug() {
+        let mail = Mail::parse(BytesMut::from("<alice@example.com>\0SIZE=1\0"))
+            .expect("Failed parsing mail");
+        let redacted = DebugWith(|f: &mut fmt::Formatter<'_>| mail.fmt_debug(f, true));
+
+        assert_eq!(
+            alloc::format!("{redacted:?}"),
+            "Mail { sender: <19 bytes, fnv1a b85db9a9367a6a0a>, esmtp_args: Some(<7 bytes, fnv1a 3499cced9ed2bed2>) }"
+        );
+        assert_eq!(
+            alloc::format!("{:?}", mail.dangerous_full_debug()),
+            r#"Mail { sender: b"<alice@example.com>", esmtp_args: Some(b"SIZE=1\0") }"#
+        );
+    }
+
     #[rstest]
     #[case(BytesMut::from("sender\0arg1\0arg2"), Ok( Mail {sender: BytesMut::from("sender"), esmtp_args: Some(BytesMut::from("arg1\0arg2"))}))]
     #[case(
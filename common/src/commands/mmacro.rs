@@ -1,26 +1,118 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Debug};
+
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
+use crate::encoding::Writable;
+#[cfg(feature = "decode-client")]
 use crate::error::STAGE_DECODING;
+use crate::macros::{well_known, MacroInterner, MacroName};
+use crate::redact::{self, DebugWith};
+#[cfg(feature = "decode-client")]
 use crate::{NotEnoughData, ProtocolError};
-use bytes::BytesMut;
+use bytes::{BufMut, Bytes, BytesMut};
+#[cfg(feature = "decode-client")]
 use miltr_utils::ByteParsing;
 
-/// A macro received for the command identified by `Macro.code`.
-#[derive(Clone, PartialEq, Debug, Default)]
+/// Macros sent for the command identified by `Macro.code`.
+///
+/// Well known names are kept as static strings, see [`MacroName`].
+///
+/// `Debug` never shows the values of sensitive macros, like the SASL login
+/// name in `{auth_authen}`. With the `redact-debug` feature, it shows none
+/// of the values.
+#[derive(Clone, PartialEq, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Macro {
     /// The code of the stage this macro belongs to.
     pub code: u8,
-    macros: Vec<(BytesMut, BytesMut)>,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::macro_pairs))]
+    macros: Vec<(MacroName, BytesMut)>,
 }
 
 impl Macro {
+    /// Macros for the stage with `code`, e.g. [`codes::SMFIC_MAIL`], to
+    /// be filled with [`Self::push`]
+    #[must_use]
+    pub fn new(code: u8) -> Self {
+        Self {
+            code,
+            macros: Vec::new(),
+        }
+    }
+
+    /// Add the macro `name` with `value`
+    pub fn push(&mut self, name: &[u8], value: &[u8]) {
+        self.macros.push((
+            MacroName::from_raw(Bytes::copy_from_slice(name)),
+            BytesMut::from(value),
+        ));
+    }
+
     /// An iterator over received macros in (key, value) format.
     pub fn macros(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
-        self.macros.iter().map(|(b, c)| (&b[..], &c[..]))
+        self.macros.iter().map(|(b, c)| (b.as_bytes(), &c[..]))
+    }
+
+    /// An iterator over received macros with their names as handles
+    pub fn entries(&self) -> impl Iterator<Item = (&MacroName, &[u8])> {
+        self.macros.iter().map(|(b, c)| (b, &c[..]))
+    }
+
+    /// Share the names not well known through `interner`, see
+    /// [`MacroInterner::share`]
+    pub fn intern(&mut self, interner: &mut MacroInterner) {
+        for (name, _) in &mut self.macros {
+            interner.share(name);
+        }
+    }
+
+    /// Debug these macros in full, even with the `redact-debug` feature.
+    ///
+    /// Only for troubleshooting, this prints e.g. addresses. Sensitive
+    /// values stay hidden.
+    #[must_use]
+    pub fn dangerous_full_debug(&self) -> impl Debug + '_ {
+        DebugWith(|f: &mut fmt::Formatter<'_>| self.fmt_debug(f, false))
+    }
+
+    /// The names are kept, as they carry no content
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, redact: bool) -> fmt::Result {
+        let macros = DebugWith(|f: &mut fmt::Formatter<'_>| {
+            f.debug_list()
+                .entries(self.macros().map(|(name, value)| {
+                    (redact::bytes(name, false), macro_value(name, value, redact))
+                }))
+                .finish()
+        });
+        f.debug_struct("Macro")
+            .field("code", &self.code)
+            .field("macros", &macros)
+            .finish()
+    }
+}
+
+impl Debug for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, redact::REDACT)
     }
 }
 
+/// Debug the `value` of the macro `name`, never if it is sensitive
+fn macro_value<'a>(name: &'a [u8], value: &'a [u8], redact: bool) -> impl Debug + 'a {
+    DebugWith(move |f: &mut fmt::Formatter<'_>| {
+        if well_known::is_secret(name) {
+            f.write_str("<redacted>")
+        } else {
+            redact::bytes(value, redact).fmt(f)
+        }
+    })
+}
+
+#[cfg(feature = "decode-client")]
 impl Parsable for Macro {
-    const CODE: u8 = b'D';
+    const CODE: u8 = codes::SMFIC_MACRO;
 
     fn parse(mut buffer: BytesMut) -> Result<Self, ProtocolError> {
         // Basic length check
@@ -34,44 +126,59 @@ impl Parsable for Macro {
         // Decode macros
         let mut macros = Vec::with_capacity(field_count / 2);
         while !buffer.is_empty() {
-            let Some(name) = buffer.delimited(0) else {
-                return Err(NotEnoughData::new(
-                    STAGE_DECODING,
-                    "Macro",
-                    "missing null byte delimiter after name",
-                    1,
-                    0,
-                    buffer,
-                )
-                .into());
+            let Some((name, value)) = buffer.delimited_pair(0) else {
+                let msg = if buffer.contains(&0) {
+                    "missing null byte delimiter after value"
+                } else {
+                    "missing null byte delimiter after name"
+                };
+                return Err(NotEnoughData::new(STAGE_DECODING, "Macro", msg, 1, 0, buffer).into());
             };
 
-            let Some(value) = buffer.delimited(0) else {
-                return Err(NotEnoughData::new(
-                    STAGE_DECODING,
-                    "Macro",
-                    "missing null byte delimiter after value",
-                    1,
-                    0,
-                    buffer,
-                )
-                .into());
-            };
-
-            macros.push((name, value));
+            macros.push((MacroName::from_raw(name.freeze()), value));
         }
 
         Ok(Self { code, macros })
     }
 }
 
+impl Writable for Macro {
+    fn write(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.code);
+        for (name, value) in &self.macros {
+            buffer.extend_from_slice(name.as_bytes());
+            buffer.put_u8(0);
+            buffer.extend_from_slice(value);
+            buffer.put_u8(0);
+        }
+    }
+
+    fn len(&self) -> usize {
+        1 + self
+            .macros
+            .iter()
+            .map(|(name, value)| name.len() + 1 + value.len() + 1)
+            .sum::<usize>()
+    }
+
+    fn code(&self) -> u8 {
+        codes::SMFIC_MACRO
+    }
+
+    fn is_empty(&self) -> bool {
+        false
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
     use pretty_assertions::assert_eq;
+    #[cfg(feature = "decode-client")]
     use rstest::rstest;
 
+    #[cfg(feature = "decode-client")]
     #[rstest]
     #[case("O\0\0", b'O', "", "")]
     // #[case("", None)]
@@ -88,8 +195,66 @@ mod tests {
 
         assert_eq!(res.code, code);
         assert_eq!(
-            res.macros,
-            vec![(BytesMut::from(key), BytesMut::from(value))]
+            res.macros().collect::<Vec<_>>(),
+            vec![(key.as_bytes(), value.as_bytes())]
+        );
+    }
+
+    #[cfg(feature = "decode-client")]
+    #[test]
+    fn test_write_parse() {
+        let mut macro_ = Macro::new(b'M');
+        macro_.push(b"{mail_addr}", b"a@test.local");
+        macro_.push(b"i", b"ABC123");
+
+        let mut buffer = BytesMut::new();
+        macro_.write(&mut buffer);
+        assert_eq!(buffer.len(), macro_.len());
+
+        assert_eq!(Macro::parse(buffer).expect("Parse unsuccessful"), macro_);
+    }
+
+    #[cfg(feature = "decode-client")]
+    #[rstest]
+    #[case("Ckey", "missing null byte delimiter after name")]
+    #[case("Ckey\0value", "missing null byte delimiter after value")]
+    fn test_parse_err(#[case] input: &str, #[case] msg: &str) {
+        let input = BytesMut::from(input);
+        let err = Macro::parse(input).expect_err("Parse did not error");
+
+        let ProtocolError::NotEnoughData(err) = err else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(err.msg, msg);
+    }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let mut macro_ = Macro::new(b'E');
+        macro_.push(b"{auth_authen}", b"alice");
+        macro_.push(b"auth_author", b"bob");
+        macro_.push(b"{auth_type}", b"PLAIN");
+
+        assert_eq!(
+            alloc::format!("{:?}", macro_.dangerous_full_debug()),
+            r#"Macro { code: 69, macros: [(b"{auth_authen}", <redacted>), (b"auth_author", <redacted>), (b"{auth_type}", b"PLAIN")] }"#
+        );
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let mut macro_ = Macro::new(b'M');
+        macro_.push(b"{mail_addr}", b"alice@example.com");
+        macro_.push(b"{auth_authen}", b"alice");
+        let redacted = DebugWith(|f: &mut fmt::Formatter<'_>| macro_.fmt_debug(f, true));
+
+        assert_eq!(
+            alloc::format!("{redacted:?}"),
+            r#"Macro { code: 77, macros: [(b"{mail_addr}", <17 bytes, fnv1a 67023fc4a7ff2a46>), (b"{auth_authen}", <redacted>)] }"#
+        );
+        assert_eq!(
+            alloc::format!("{:?}", macro_.dangerous_full_debug()),
+            r#"Macro { code: 77, macros: [(b"{mail_addr}", b"alice@example.com"), (b"{auth_authen}", <redacted>)] }"#
         );
     }
 
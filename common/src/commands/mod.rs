@@ -5,29 +5,37 @@
 
 mod body;
 mod connect;
+mod esmtp;
 mod header;
 mod helo;
 mod mail;
 mod mmacro;
 mod recipient;
 mod unknown;
+mod utf8;
 
 use enum_dispatch::enum_dispatch;
 
+use crate::encoding::Writable;
+
 pub use self::body::{Body, EndOfBody};
 pub use self::connect::{Connect, Family};
-pub use self::header::{EndOfHeader, Header};
+pub use self::esmtp::{EsmtpArgs, EsmtpArgsBuilder, EsmtpArgsError, Notify, Orcpt, Ret};
+pub(crate) use self::header::name_eq_ignore_case;
+pub use self::header::{canonical_header_name, EndOfHeader, Header};
 pub use self::helo::Helo;
 pub use self::mail::{Data, Mail};
 pub use self::mmacro::Macro;
 pub use self::recipient::Recipient;
-pub use self::unknown::Unknown;
+pub use self::unknown::{SmtpVerb, Unknown};
+pub use self::utf8::{InvalidUtf8, TextFields};
 
 /// See the respective contents about documentation
 #[allow(missing_docs)]
 #[enum_dispatch]
 #[cfg_attr(feature = "tracing", derive(strum::Display))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum Command {
     // SMTP opening
     Connect,
@@ -44,3 +52,13 @@ pub enum Command {
     // Unknown
     Unknown,
 }
+
+impl Command {
+    /// The code byte identifying this command on the wire.
+    ///
+    /// See [`codes`](crate::codes) for all codes.
+    #[must_use]
+    pub fn code(&self) -> u8 {
+        Writable::code(self)
+    }
+}
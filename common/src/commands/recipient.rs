@@ -1,16 +1,27 @@
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec::Vec};
+use core::fmt::{self, Debug};
 
 use bytes::{BufMut, BytesMut};
 
+use super::esmtp::{EsmtpArgs, EsmtpArgsError};
+use super::utf8::{self, InvalidUtf8, TextFields};
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+use crate::redact::{self, DebugWith};
+#[cfg(feature = "decode-client")]
 use crate::{InvalidData, ProtocolError};
+#[cfg(feature = "decode-client")]
 use miltr_utils::ByteParsing;
 
 /// An smtp recipient
-#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Clone, PartialEq, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Recipient {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     recipient: BytesMut,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::optional_bytes))]
     esmtp_args: Option<BytesMut>,
 }
 
@@ -24,17 +35,17 @@ impl From<&[u8]> for Recipient {
 }
 
 impl Recipient {
-    const CODE: u8 = b'R';
+    const CODE: u8 = codes::SMFIC_RCPT;
     /// The recipient as received by the milter client
     #[must_use]
-    pub fn recipient(&self) -> Cow<str> {
+    pub fn recipient(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.recipient)
     }
 
     /// Optional esmtp arguments regarding the recipients.
     ///
     /// Returns an empty `Vec` if no esmtp args where received
-    pub fn esmtp_args(&self) -> Vec<Cow<str>> {
+    pub fn esmtp_args(&self) -> Vec<Cow<'_, str>> {
         let Some(args) = &self.esmtp_args else {
             return Vec::new();
         };
@@ -44,8 +55,73 @@ impl Recipient {
             .map(String::from_utf8_lossy)
             .collect()
     }
+
+    /// Parse and validate the esmtp args, e.g. to inspect DSN parameters.
+    ///
+    /// ```
+    /// use miltr_common::commands::{EsmtpArgs, Notify, Recipient};
+    ///
+    /// let args = EsmtpArgs::builder()
+    ///     .notify(Notify::FAILURE).orcpt("rfc822", "bob@example.com")
+    ///     .build()
+    ///     .expect("Valid parameters");
+    /// let recipient = Recipient::from(b"<bob@example.com>".as_slice()).with_esmtp_args(&args);
+    ///
+    /// let args = recipient.esmtp().expect("Valid parameters");
+    /// assert_eq!(args.notify(), Some(Notify::FAILURE));
+    /// assert_eq!(args.orcpt().map(|o| o.address().to_string()).as_deref(), Some("bob@example.com"));
+    /// ```
+    ///
+    /// # Errors
+    /// If any parameter is invalid, see [`EsmtpArgs::parse`]
+    pub fn esmtp(&self) -> Result<EsmtpArgs, EsmtpArgsError> {
+        EsmtpArgs::parse(&self.esmtp_args().join(" "))
+    }
+
+    /// Replace the esmtp args with `args`
+    #[must_use]
+    pub fn with_esmtp_args(mut self, args: &EsmtpArgs) -> Self {
+        let mut raw = BytesMut::new();
+        for (keyword, value) in args.iter() {
+            raw.extend_from_slice(keyword.as_bytes());
+            if let Some(value) = value {
+                raw.put_u8(b'=');
+                raw.extend_from_slice(value.as_bytes());
+            }
+            raw.put_u8(0);
+        }
+        self.esmtp_args = (!raw.is_empty()).then_some(raw);
+        self
+    }
+
+    /// Debug this recipient in full, even with the `redact-debug` feature.
+    ///
+    /// Only for troubleshooting, this prints addresses.
+    #[must_use]
+    pub fn dangerous_full_debug(&self) -> impl Debug + '_ {
+        DebugWith(|f: &mut fmt::Formatter<'_>| self.fmt_debug(f, false))
+    }
+
+    /// The esmtp args are redacted as well, they may hold addresses
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, redact: bool) -> fmt::Result {
+        let esmtp_args = self
+            .esmtp_args
+            .as_deref()
+            .map(|args| redact::bytes(args, redact));
+        f.debug_struct("Recipient")
+            .field("recipient", &redact::bytes(&self.recipient, redact))
+            .field("esmtp_args", &esmtp_args)
+            .finish()
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, redact::REDACT)
+    }
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Recipient {
     const CODE: u8 = Self::CODE;
 
@@ -102,12 +178,44 @@ impl Writable for Recipient {
     }
 }
 
-#[cfg(test)]
+impl TextFields for Recipient {
+    fn validate_utf8(&self) -> Result<(), InvalidUtf8> {
+        utf8::validate("recipient", &self.recipient)?;
+        match &self.esmtp_args {
+            Some(args) => utf8::validate("esmtp args", args),
+            None => Ok(()),
+        }
+    }
+
+    fn replace_invalid_utf8(&mut self) {
+        utf8::replace(&mut self.recipient);
+        if let Some(args) = &mut self.esmtp_args {
+            utf8::replace(args);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "decode-client"))]
 mod test {
     use super::*;
     use crate::decoding::Parsable;
     use rstest::rstest;
 
+    #[test]
+    fn test_debug() {
+        let recipient = Recipient::from(b"<bob@example.com>".as_slice());
+        let redacted = DebugWith(|f: &mut fmt::Formatter<'_>| recipient.fmt_debug(f, true));
+
+        assert_eq!(
+            alloc::format!("{redacted:?}"),
+            "Recipient { recipient: <17 bytes, fnv1a d6c33855d7b56dc7>, esmtp_args: None }"
+        );
+        assert_eq!(
+            alloc::format!("{:?}", recipient.dangerous_full_debug()),
+            r#"Recipient { recipient: b"<bob@example.com>", esmtp_args: None }"#
+        );
+    }
+
     #[rstest]
     #[case(BytesMut::from("recipient1 recipient2\0arg1\0arg2"), Ok( Recipient {recipient: BytesMut::from("recipient1 recipient2"), esmtp_args: Some(BytesMut::from("arg1\0arg2"))}))]
     #[case(
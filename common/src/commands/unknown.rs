@@ -1,21 +1,73 @@
+use alloc::vec::Vec;
+
 use bytes::{BufMut, BytesMut};
 
+use crate::codes;
+#[cfg(feature = "decode-client")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-client")]
 use crate::{InvalidData, ProtocolError};
+#[cfg(feature = "decode-client")]
 use miltr_utils::ByteParsing;
 
 /// An unknown SMTP command.
 ///
 ///
-/// This allows extending the SMTP protocol by special commands.
+/// This allows extending the SMTP protocol by special commands. The data is
+/// the raw command line as the MTA received it, e.g.
+/// `XCLIENT ADDR=192.0.2.1 NAME=mail.example.com`, see [`Unknown::verb`] and
+/// [`Unknown::arguments`] to pick it apart.
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Unknown {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     data: BytesMut,
 }
 
 impl Unknown {
-    const CODE: u8 = b'U';
+    const CODE: u8 = codes::SMFIC_UNKNOWN;
+
+    /// The verb of the Postfix `XCLIENT` extension
+    pub const XCLIENT: &'static str = "XCLIENT";
+    /// The verb of the Postfix `XFORWARD` extension
+    pub const XFORWARD: &'static str = "XFORWARD";
+
+    /// Create an unknown command from a verb and its arguments
+    #[must_use]
+    pub fn new(verb: &str, arguments: &[u8]) -> Self {
+        let mut data = BytesMut::with_capacity(verb.len() + 1 + arguments.len());
+        data.extend_from_slice(verb.as_bytes());
+        if !arguments.is_empty() {
+            data.put_u8(b' ');
+            data.extend_from_slice(arguments);
+        }
+        Self { data }
+    }
+}
+
+/// The verb of an [`Unknown`] command, with the extensions a milter usually
+/// cares about spelled out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpVerb<'a> {
+    /// [`Unknown::XCLIENT`]
+    Xclient,
+    /// [`Unknown::XFORWARD`]
+    Xforward,
+    /// Any other verb, as received
+    Other(&'a str),
+}
+
+impl SmtpVerb<'_> {
+    /// The verb as sent over SMTP
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Xclient => Unknown::XCLIENT,
+            Self::Xforward => Unknown::XFORWARD,
+            Self::Other(verb) => verb,
+        }
+    }
 }
 
 impl From<&[u8]> for Unknown {
@@ -44,6 +96,68 @@ impl Unknown {
     pub fn as_mut_bytes(&mut self) -> &mut [u8] {
         &mut self.data
     }
+
+    /// The verb of the command line, matched case-insensitively.
+    ///
+    /// `None` if the line is empty or the verb is not valid utf8.
+    #[must_use]
+    pub fn verb(&self) -> Option<SmtpVerb<'_>> {
+        let (verb, _) = self.split();
+        let verb = core::str::from_utf8(verb).ok()?;
+        if verb.is_empty() {
+            None
+        } else if verb.eq_ignore_ascii_case(Self::XCLIENT) {
+            Some(SmtpVerb::Xclient)
+        } else if verb.eq_ignore_ascii_case(Self::XFORWARD) {
+            Some(SmtpVerb::Xforward)
+        } else {
+            Some(SmtpVerb::Other(verb))
+        }
+    }
+
+    /// Everything after the verb, without leading whitespace
+    #[must_use]
+    pub fn arguments(&self) -> &[u8] {
+        self.split().1
+    }
+
+    /// The `NAME=VALUE` attributes of the arguments, as used by `XCLIENT`
+    /// and `XFORWARD`.
+    ///
+    /// Arguments without `=` are returned with an empty value.
+    pub fn attributes(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.arguments()
+            .split(u8::is_ascii_whitespace)
+            .filter(|argument| !argument.is_empty())
+            .map(|argument| match argument.iter().position(|&b| b == b'=') {
+                Some(i) => (&argument[..i], &argument[i + 1..]),
+                None => (argument, &[][..]),
+            })
+    }
+
+    /// The command line terminated by `CRLF`, to mirror it to the MTA the
+    /// milter proxies to
+    #[must_use]
+    pub fn to_smtp_line(&self) -> Vec<u8> {
+        let mut line = Vec::with_capacity(self.data.len() + 2);
+        line.extend_from_slice(&self.data);
+        line.extend_from_slice(b"\r\n");
+        line
+    }
+
+    /// Split into verb and arguments at the first whitespace
+    fn split(&self) -> (&[u8], &[u8]) {
+        let line = &self.data[..];
+        let Some(end) = line.iter().position(u8::is_ascii_whitespace) else {
+            return (line, &[]);
+        };
+        let rest = &line[end..];
+        let start = rest
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        (&line[..end], &rest[start..])
+    }
 }
 
 impl Writable for Unknown {
@@ -53,7 +167,8 @@ impl Writable for Unknown {
     }
 
     fn len(&self) -> usize {
-        1 + self.data.len()
+        // data plus its null terminator, the code byte is added by the codec
+        self.data.len() + 1
     }
 
     fn code(&self) -> u8 {
@@ -65,6 +180,7 @@ impl Writable for Unknown {
     }
 }
 
+#[cfg(feature = "decode-client")]
 impl Parsable for Unknown {
     const CODE: u8 = Self::CODE;
 
@@ -78,10 +194,44 @@ impl Parsable for Unknown {
     }
 }
 
-#[cfg(all(test, feature = "count-allocations"))]
+#[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_verb_and_attributes() {
+        let unknown =
+            Unknown::from(b"xclient  ADDR=192.0.2.1 NAME=mail.example.com LOGIN".as_slice());
+
+        assert_eq!(unknown.verb(), Some(SmtpVerb::Xclient));
+        assert_eq!(
+            unknown.attributes().collect::<Vec<_>>(),
+            vec![
+                (b"ADDR".as_slice(), b"192.0.2.1".as_slice()),
+                (b"NAME", b"mail.example.com"),
+                (b"LOGIN", b""),
+            ]
+        );
+        assert_eq!(
+            unknown.to_smtp_line(),
+            b"xclient  ADDR=192.0.2.1 NAME=mail.example.com LOGIN\r\n"
+        );
+    }
+
+    #[test]
+    fn test_new() {
+        let unknown = Unknown::new("NOOP", b"");
+
+        assert_eq!(unknown.verb(), Some(SmtpVerb::Other("NOOP")));
+        assert!(unknown.arguments().is_empty());
+        assert_eq!(
+            Unknown::new(Unknown::XFORWARD, b"HELO=a").as_bytes(),
+            b"XFORWARD HELO=a"
+        );
+        assert_eq!(Unknown::from(b"".as_slice()).verb(), None);
+    }
+
+    #[cfg(feature = "count-allocations")]
     #[test]
     fn test_parse_unknown() {
         let buffer = BytesMut::from_iter([255, 0, 0, 0]);
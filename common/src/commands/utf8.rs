@@ -0,0 +1,120 @@
+use alloc::string::String;
+
+use bytes::BytesMut;
+use thiserror::Error;
+
+/// A textual field of a command is not valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("{field} is not valid utf-8 after {valid_up_to} bytes")]
+pub struct InvalidUtf8 {
+    /// The name of the offending field
+    pub field: &'static str,
+    /// The number of valid bytes before the first invalid one
+    pub valid_up_to: usize,
+}
+
+/// Commands carrying text from the smtp session.
+///
+/// The accessors of these commands convert lossily. This allows checking
+/// the raw fields beforehand, or replacing invalid sequences once for all
+/// later consumers.
+pub trait TextFields {
+    /// Check all textual fields for valid UTF-8
+    ///
+    /// # Errors
+    /// Returns the first field that is not valid UTF-8
+    fn validate_utf8(&self) -> Result<(), InvalidUtf8>;
+
+    /// Replace invalid UTF-8 sequences in all textual fields by U+FFFD
+    fn replace_invalid_utf8(&mut self);
+}
+
+/// Check a single `field`
+pub(crate) fn validate(field: &'static str, bytes: &[u8]) -> Result<(), InvalidUtf8> {
+    match core::str::from_utf8(bytes) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(InvalidUtf8 {
+            field,
+            valid_up_to: err.valid_up_to(),
+        }),
+    }
+}
+
+/// Replace invalid sequences in `bytes`, leaving valid ones untouched
+pub(crate) fn replace(bytes: &mut BytesMut) {
+    if core::str::from_utf8(bytes).is_ok() {
+        return;
+    }
+    let replaced = String::from_utf8_lossy(bytes).into_owned();
+    *bytes = BytesMut::from(replaced.as_bytes());
+}
+
+#[cfg(all(test, feature = "decode-client"))]
+mod test {
+    use super::*;
+    use crate::commands::{Header, Helo, Mail, Recipient};
+    use crate::decoding::Parsable;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_valid() {
+        let header = Header::new(b"Subject", "Grüße".as_bytes());
+
+        assert_eq!(header.validate_utf8(), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_header_value() {
+        let mut header = Header::new(b"Subject", b"Gr\xfc\xdfe");
+
+        assert_eq!(
+            header.validate_utf8(),
+            Err(InvalidUtf8 {
+                field: "header value",
+                valid_up_to: 2,
+            })
+        );
+
+        header.replace_invalid_utf8();
+        assert_eq!(header.validate_utf8(), Ok(()));
+        assert_eq!(header.value(), "Gr\u{fffd}\u{fffd}e");
+        assert_eq!(header.name(), "Subject");
+    }
+
+    #[test]
+    fn test_invalid_helo() {
+        let mut helo = Helo::from(&b"mail.\xffexample.com"[..]);
+
+        assert_eq!(helo.validate_utf8().map_err(|e| e.field), Err("helo"));
+
+        helo.replace_invalid_utf8();
+        assert_eq!(helo.helo(), "mail.\u{fffd}example.com");
+    }
+
+    #[test]
+    fn test_invalid_esmtp_args() {
+        let mut mail = Mail::parse(BytesMut::from(&b"<a@example.com>\x00SIZE=1\x00\xffX"[..]))
+            .expect("Failed parsing mail");
+
+        assert_eq!(mail.validate_utf8().map_err(|e| e.field), Err("esmtp args"));
+
+        mail.replace_invalid_utf8();
+        assert_eq!(mail.esmtp_args(), vec!["SIZE=1", "\u{fffd}X"]);
+    }
+
+    #[test]
+    fn test_invalid_recipient() {
+        let mut recipient = Recipient::from(&b"<\xc3@example.com>"[..]);
+
+        assert_eq!(
+            recipient.validate_utf8(),
+            Err(InvalidUtf8 {
+                field: "recipient",
+                valid_up_to: 1,
+            })
+        );
+
+        recipient.replace_invalid_utf8();
+        assert_eq!(recipient.recipient(), "<\u{fffd}@example.com>");
+    }
+}
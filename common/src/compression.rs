@@ -0,0 +1,412 @@
+//! Compress the transport between our own clients and servers
+//!
+//! This is not part of the milter protocol. Both ends have to agree on it
+//! out-of-band, e.g. by a config flag, never enable it towards a foreign
+//! MTA or milter.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use futures::{AsyncRead, AsyncWrite};
+
+/// The zstd level used by [`Compressed::new`]
+pub const DEFAULT_LEVEL: i32 = 3;
+/// Buffered data is compressed once it reaches this size, even without a
+/// flush
+const BLOCK_SIZE: usize = 2_usize.pow(20);
+/// Refuse blocks decompressing to more than this
+const MAX_BLOCK_SIZE: usize = 16 * BLOCK_SIZE;
+/// Compressed and raw length of a block, 4 bytes each
+const HEADER_LEN: usize = 8;
+
+/// Byte counts of a [`Compressed`] transport
+#[derive(Debug, Clone, Default)]
+pub struct CompressionStats {
+    counters: Arc<[AtomicU64; 4]>,
+}
+
+impl CompressionStats {
+    const SENT: usize = 0;
+    const SENT_COMPRESSED: usize = 1;
+    const RECEIVED: usize = 2;
+    const RECEIVED_COMPRESSED: usize = 3;
+
+    /// Bytes written by the milter codec
+    #[must_use]
+    pub fn bytes_sent(&self) -> u64 {
+        self.get(Self::SENT)
+    }
+
+    /// Bytes written to the underlying transport
+    #[must_use]
+    pub fn compressed_bytes_sent(&self) -> u64 {
+        self.get(Self::SENT_COMPRESSED)
+    }
+
+    /// Bytes read by the milter codec
+    #[must_use]
+    pub fn bytes_received(&self) -> u64 {
+        self.get(Self::RECEIVED)
+    }
+
+    /// Bytes read from the underlying transport
+    #[must_use]
+    pub fn compressed_bytes_received(&self) -> u64 {
+        self.get(Self::RECEIVED_COMPRESSED)
+    }
+
+    fn get(&self, counter: usize) -> u64 {
+        self.counters[counter].load(Ordering::Relaxed)
+    }
+
+    fn add(&self, counter: usize, bytes: usize) {
+        self.counters[counter].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A transport compressing everything written to and decompressing
+/// everything read from `RW` using zstd.
+///
+/// Wrap the transport on both ends before handing it to the client or
+/// server. Written data is compressed as one block per flush, so every
+/// milter frame, or batch of frames, is sent right away.
+///
+/// On the wire, each block is prefixed by its compressed and its raw
+/// length, both as big endian `u32`.
+#[derive(Debug)]
+pub struct Compressed<RW> {
+    inner: RW,
+    level: i32,
+    stats: CompressionStats,
+    /// Written, not yet compressed data
+    pending: Vec<u8>,
+    /// A compressed block being written to `inner`
+    out: Vec<u8>,
+    out_pos: usize,
+    /// The header of the block being read
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    /// The compressed block being read
+    block: Vec<u8>,
+    block_read: usize,
+    /// Decompressed data not yet read
+    plain: Vec<u8>,
+    plain_pos: usize,
+}
+
+impl<RW> Compressed<RW> {
+    /// Compress `inner` with the [`DEFAULT_LEVEL`]
+    pub fn new(inner: RW) -> Self {
+        Self::with_level(inner, DEFAULT_LEVEL)
+    }
+
+    /// Compress `inner` with zstd `level`
+    pub fn with_level(inner: RW, level: i32) -> Self {
+        Self {
+            inner,
+            level,
+            stats: CompressionStats::default(),
+            pending: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+            header: [0; HEADER_LEN],
+            header_read: 0,
+            block: Vec::new(),
+            block_read: 0,
+            plain: Vec::new(),
+            plain_pos: 0,
+        }
+    }
+
+    /// A handle to the byte counts of this transport
+    pub fn stats(&self) -> CompressionStats {
+        self.stats.clone()
+    }
+
+    /// The underlying transport
+    pub fn into_inner(self) -> RW {
+        self.inner
+    }
+
+    /// Compress all pending data into `out`
+    fn compress_pending(&mut self) -> io::Result<()> {
+        let compressed = zstd::bulk::compress(&self.pending, self.level)?;
+
+        self.out.clear();
+        self.out.extend_from_slice(&block_len(compressed.len())?);
+        self.out.extend_from_slice(&block_len(self.pending.len())?);
+        self.out.extend_from_slice(&compressed);
+        self.out_pos = 0;
+
+        self.stats.add(CompressionStats::SENT, self.pending.len());
+        self.stats
+            .add(CompressionStats::SENT_COMPRESSED, self.out.len());
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Decompress the block read completely
+    fn decompress_block(&mut self, raw_len: usize) -> io::Result<()> {
+        self.plain = zstd::bulk::decompress(&self.block, raw_len)?;
+        self.plain_pos = 0;
+        if self.plain.len() != raw_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed block shorter than announced",
+            ));
+        }
+
+        self.stats.add(CompressionStats::RECEIVED, raw_len);
+        self.stats.add(
+            CompressionStats::RECEIVED_COMPRESSED,
+            HEADER_LEN + self.block.len(),
+        );
+        self.header_read = 0;
+        self.block_read = 0;
+        Ok(())
+    }
+}
+
+fn block_len(len: usize) -> io::Result<[u8; 4]> {
+    u32::try_from(len)
+        .map(u32::to_be_bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+impl<RW: AsyncWrite + Unpin> Compressed<RW> {
+    /// Write the block written partially before and compress pending data
+    /// to `inner` completely, until nothing is left
+    fn poll_emit(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            while self.out_pos < self.out.len() {
+                let written =
+                    ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.out_pos += written;
+            }
+            if self.pending.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            self.compress_pending()?;
+        }
+    }
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for Compressed<RW> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending.len() >= BLOCK_SIZE {
+            ready!(this.poll_emit(cx))?;
+        }
+        this.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_emit(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_emit(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Compressed<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let available = &this.plain[this.plain_pos..];
+                let len = available.len().min(buf.len());
+                buf[..len].copy_from_slice(&available[..len]);
+                this.plain_pos += len;
+                return Poll::Ready(Ok(len));
+            }
+
+            while this.header_read < HEADER_LEN {
+                let read =
+                    ready!(Pin::new(&mut this.inner)
+                        .poll_read(cx, &mut this.header[this.header_read..]))?;
+                if read == 0 {
+                    if this.header_read == 0 {
+                        return Poll::Ready(Ok(0));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.header_read += read;
+            }
+
+            let [c0, c1, c2, c3, r0, r1, r2, r3] = this.header;
+            let compressed_len = u32::from_be_bytes([c0, c1, c2, c3]) as usize;
+            let raw_len = u32::from_be_bytes([r0, r1, r2, r3]) as usize;
+            if raw_len > MAX_BLOCK_SIZE
+                || compressed_len > zstd::zstd_safe::compress_bound(MAX_BLOCK_SIZE)
+            {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed block exceeds the size limit",
+                )));
+            }
+            this.block.resize(compressed_len, 0);
+
+            while this.block_read < compressed_len {
+                let read = ready!(
+                    Pin::new(&mut this.inner).poll_read(cx, &mut this.block[this.block_read..])
+                )?;
+                if read == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.block_read += read;
+            }
+
+            this.decompress_block(raw_len)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor, poll, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// A sink taking a few bytes per write, every other write pending
+    #[derive(Default)]
+    struct Trickle {
+        written: Vec<u8>,
+        ready: bool,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        block_on(async {
+            let body = b"a highly compressible body ".repeat(1000);
+            let mut writer = Compressed::new(Cursor::new(Vec::new()));
+            let sent = writer.stats();
+
+            writer.write_all(b"first").await.expect("Failed writing");
+            writer.flush().await.expect("Failed flushing");
+            writer.write_all(&body).await.expect("Failed writing");
+            writer.flush().await.expect("Failed flushing");
+
+            let wire = writer.into_inner().into_inner();
+            let mut reader = Compressed::new(Cursor::new(wire.clone()));
+            let received = reader.stats();
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).await.expect("Failed reading");
+
+            assert_eq!(read[..5], *b"first");
+            assert_eq!(read[5..], body);
+            assert_eq!(sent.bytes_sent(), 5 + body.len() as u64);
+            assert_eq!(sent.compressed_bytes_sent(), wire.len() as u64);
+            assert!(wire.len() < body.len() / 10);
+            assert_eq!(received.bytes_received(), sent.bytes_sent());
+            assert_eq!(received.compressed_bytes_received(), wire.len() as u64);
+        });
+    }
+
+    #[test]
+    fn test_flush_after_partial_write() {
+        block_on(async {
+            let mut writer = Compressed::new(Trickle::default());
+            writer.write_all(b"first").await.expect("Failed writing");
+            // Leave the first block written partially
+            assert!(poll!(writer.flush()).is_pending());
+            writer.write_all(b"second").await.expect("Failed writing");
+            writer.flush().await.expect("Failed flushing");
+
+            let wire = writer.into_inner().written;
+            let mut reader = Compressed::new(Cursor::new(wire));
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).await.expect("Failed reading");
+
+            assert_eq!(read, b"firstsecond");
+        });
+    }
+
+    #[test]
+    fn test_truncated() {
+        block_on(async {
+            let mut writer = Compressed::new(Cursor::new(Vec::new()));
+            writer
+                .write_all(b"some data")
+                .await
+                .expect("Failed writing");
+            writer.flush().await.expect("Failed flushing");
+            let mut wire = writer.into_inner().into_inner();
+            wire.truncate(wire.len() - 1);
+
+            let mut reader = Compressed::new(Cursor::new(wire));
+            let err = reader
+                .read_to_end(&mut Vec::new())
+                .await
+                .expect_err("Truncated block not detected");
+
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn test_oversized_block() {
+        block_on(async {
+            let mut wire = Vec::new();
+            wire.extend_from_slice(&1_u32.to_be_bytes());
+            wire.extend_from_slice(&u32::MAX.to_be_bytes());
+            wire.push(0);
+
+            let mut reader = Compressed::new(Cursor::new(wire));
+            let err = reader
+                .read_to_end(&mut Vec::new())
+                .await
+                .expect_err("Oversized block not detected");
+
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
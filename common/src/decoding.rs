@@ -1,25 +1,37 @@
 //! Implement what components may be parsed from the wire
+//!
+//! Parsing what a client sends needs the `decode-client` feature, parsing
+//! what a server sends the `decode-server` feature.
 
-use bytes::{Buf, BytesMut};
-use enum_dispatch::enum_dispatch;
+use crate::codes;
 
-use crate::actions::{Abort, Continue, Discard, Quit, QuitNc, Reject, Replycode, Skip, Tempfail};
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
+use {
+    crate::{
+        actions::Abort, error::STAGE_DECODING, optneg::OptNeg, InvalidData, NotEnoughData,
+        ProtocolError,
+    },
+    bytes::{Buf, BytesMut},
+    enum_dispatch::enum_dispatch,
+};
 
+#[cfg(feature = "decode-client")]
 use crate::{
-    error::STAGE_DECODING, AddHeader, AddRecipient, ChangeHeader, DeleteRecipient, InsertHeader,
-    InvalidData, NotEnoughData, ProtocolError, Quarantine, ReplaceBody,
+    actions::{Quit, QuitNc},
+    commands::{
+        Body, Command, Connect, Data, EndOfBody, EndOfHeader, Header, Helo, Macro, Mail, Recipient,
+        Unknown,
+    },
 };
 
-use super::commands::Connect;
-use super::commands::Helo;
-use super::commands::Macro;
-use super::commands::Recipient;
-use super::commands::Unknown;
-use super::commands::{Body, EndOfBody};
-use super::commands::{Data, Mail};
-use super::commands::{EndOfHeader, Header};
-use super::optneg::OptNeg;
+#[cfg(feature = "decode-server")]
+use crate::{
+    actions::{Continue, Discard, Progress, Reject, Replycode, Skip, Tempfail},
+    AddHeader, AddRecipient, AddRecipientPar, ChangeFrom, ChangeHeader, DeleteRecipient,
+    InsertHeader, Quarantine, ReplaceBody,
+};
 
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 /// Parse something 'from the wire'.
 pub(crate) trait Parsable: Sized {
     /// The unique id code for this item
@@ -32,6 +44,7 @@ pub(crate) trait Parsable: Sized {
     fn parse(buffer: BytesMut) -> Result<Self, ProtocolError>;
 }
 
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 macro_rules! parse_command {
     ($container_name:ident, $($variant:ident),+$(,)?) => {
         /// See the contained variants for more.
@@ -44,6 +57,20 @@ macro_rules! parse_command {
         }
 
         impl $container_name {
+            /// The code byte identifying this command on the wire
+            #[must_use]
+            pub fn code(&self) -> u8 {
+                match self {
+                    $(Self::$variant(_) => $variant::CODE,)+
+                }
+            }
+
+            /// A stable, human readable name of this command
+            #[must_use]
+            pub fn name(&self) -> &'static str {
+                from_code(self.code()).unwrap_or("unknown")
+            }
+
             /// Parse a bytes buffer into this structured data
             ///
             /// # Errors
@@ -80,6 +107,7 @@ macro_rules! parse_command {
 }
 
 // Parse a command sent by the client.
+#[cfg(feature = "decode-client")]
 parse_command!(
     // The name of this enum
     ClientCommand,
@@ -107,6 +135,7 @@ parse_command!(
 );
 
 // Parse a command sent by the server.
+#[cfg(feature = "decode-server")]
 parse_command!(
     // The name of this enum
     ServerCommand,
@@ -120,22 +149,157 @@ parse_command!(
     Tempfail,
     Skip,
     Replycode,
+    // Not an answer, but a request to keep waiting for one
+    Progress,
     // Modifications
     AddRecipient,
     DeleteRecipient,
+    AddRecipientPar,
     ReplaceBody,
     AddHeader,
     InsertHeader,
     ChangeHeader,
     Quarantine,
+    ChangeFrom,
 );
 
-#[cfg(test)]
+#[cfg(feature = "decode-client")]
+/// A [`ClientCommand`] split by what it is used for, see
+/// [`ClientCommand::into_kind`]
+#[derive(Debug, Clone)]
+pub enum ClientFrameKind {
+    /// A command carrying data of the smtp session
+    Command(Command),
+    /// A command controlling the milter session
+    Control(ClientControl),
+    /// Macros for the following command
+    Macro(Macro),
+}
+
+#[cfg(feature = "decode-client")]
+/// The client commands controlling the milter session instead of carrying
+/// smtp data
+#[allow(missing_docs)]
+#[allow(clippy::large_enum_variant)] // Same layout as `ClientCommand`
+#[derive(Debug, Clone)]
+pub enum ClientControl {
+    Abort(Abort),
+    OptNeg(OptNeg),
+    Quit(Quit),
+    QuitNc(QuitNc),
+}
+
+#[cfg(feature = "decode-client")]
+impl ClientCommand {
+    /// Split this into a session command, a control command or macros.
+    ///
+    /// ```
+    /// use miltr_common::{
+    ///     commands::{Command, Helo},
+    ///     decoding::{ClientCommand, ClientFrameKind},
+    /// };
+    ///
+    /// let command = ClientCommand::from(Helo::from(b"localhost".as_slice()));
+    /// assert!(matches!(
+    ///     command.into_kind(),
+    ///     ClientFrameKind::Command(Command::Helo(_))
+    /// ));
+    /// ```
+    #[must_use]
+    pub fn into_kind(self) -> ClientFrameKind {
+        match self {
+            Self::Abort(c) => ClientFrameKind::Control(ClientControl::Abort(c)),
+            Self::OptNeg(c) => ClientFrameKind::Control(ClientControl::OptNeg(c)),
+            Self::Quit(c) => ClientFrameKind::Control(ClientControl::Quit(c)),
+            Self::QuitNc(c) => ClientFrameKind::Control(ClientControl::QuitNc(c)),
+            Self::Macro(c) => ClientFrameKind::Macro(c),
+            Self::Unknown(c) => ClientFrameKind::Command(c.into()),
+            Self::Connect(c) => ClientFrameKind::Command(c.into()),
+            Self::Helo(c) => ClientFrameKind::Command(c.into()),
+            Self::Mail(c) => ClientFrameKind::Command(c.into()),
+            Self::Recipient(c) => ClientFrameKind::Command(c.into()),
+            Self::Header(c) => ClientFrameKind::Command(c.into()),
+            Self::EndOfHeader(c) => ClientFrameKind::Command(c.into()),
+            Self::Data(c) => ClientFrameKind::Command(c.into()),
+            Self::Body(c) => ClientFrameKind::Command(c.into()),
+            Self::EndOfBody(c) => ClientFrameKind::Command(c.into()),
+        }
+    }
+}
+
+#[cfg(feature = "decode-client")]
+impl From<Command> for ClientCommand {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Connect(c) => c.into(),
+            Command::Helo(c) => c.into(),
+            Command::Mail(c) => c.into(),
+            Command::Recipient(c) => c.into(),
+            Command::Header(c) => c.into(),
+            Command::EndOfHeader(c) => c.into(),
+            Command::Data(c) => c.into(),
+            Command::Body(c) => c.into(),
+            Command::EndOfBody(c) => c.into(),
+            Command::Unknown(c) => c.into(),
+        }
+    }
+}
+
+#[cfg(feature = "decode-client")]
+impl From<ClientControl> for ClientCommand {
+    fn from(value: ClientControl) -> Self {
+        match value {
+            ClientControl::Abort(c) => c.into(),
+            ClientControl::OptNeg(c) => c.into(),
+            ClientControl::Quit(c) => c.into(),
+            ClientControl::QuitNc(c) => c.into(),
+        }
+    }
+}
+
+#[cfg(feature = "decode-client")]
+impl From<ClientFrameKind> for ClientCommand {
+    fn from(value: ClientFrameKind) -> Self {
+        match value {
+            ClientFrameKind::Command(c) => c.into(),
+            ClientFrameKind::Control(c) => c.into(),
+            ClientFrameKind::Macro(c) => c.into(),
+        }
+    }
+}
+
+#[cfg(feature = "decode-client")]
+impl TryFrom<ClientCommand> for Command {
+    type Error = ClientCommand;
+
+    /// Only succeeds for smtp session commands, returning any other command
+    /// unchanged
+    fn try_from(value: ClientCommand) -> Result<Self, Self::Error> {
+        match value.into_kind() {
+            ClientFrameKind::Command(c) => Ok(c),
+            other => Err(other.into()),
+        }
+    }
+}
+
+/// Look up the name of the command identified by `code`.
+///
+/// Codes shared by client and server (abort, option negotiation) have the
+/// same name on both sides, so a single lookup covers both directions. See
+/// [`codes::command_name`], which names commands even if the decoding
+/// features leave those out.
+#[must_use]
+pub fn from_code(code: u8) -> Option<&'static str> {
+    codes::command_name(code)
+}
+
+#[cfg(all(test, feature = "decode-client", feature = "decode-server"))]
 mod tests {
     use assert_matches::assert_matches;
     use bytes::BytesMut;
 
     use super::*;
+    use crate::optneg::ProtocolVersion;
 
     #[test]
     fn test_create_abort() {
@@ -154,6 +318,55 @@ mod tests {
         let command =
             ClientCommand::parse(BytesMut::from_iter(data)).expect("Failed parsing optneg data");
 
-        assert_matches!(command, ClientCommand::OptNeg(o) if o.version == 6);
+        assert_matches!(command, ClientCommand::OptNeg(o) if o.version == ProtocolVersion::V6);
+    }
+
+    #[test]
+    fn test_into_kind() {
+        let command = ClientCommand::parse(BytesMut::from_iter([b'A'])).expect("Failed parsing");
+        assert_matches!(
+            command.into_kind(),
+            ClientFrameKind::Control(ClientControl::Abort(_))
+        );
+
+        let command = ClientCommand::from(Command::from(Data));
+        let kind = command.into_kind();
+        assert_matches!(kind, ClientFrameKind::Command(Command::Data(_)));
+        assert_matches!(ClientCommand::from(kind), ClientCommand::Data(_));
+
+        let macros = ClientCommand::from(Macro::default());
+        assert_matches!(Command::try_from(macros), Err(ClientCommand::Macro(_)));
+    }
+
+    #[test]
+    fn test_code_and_name() {
+        let command = ClientCommand::parse(BytesMut::from_iter([b'A'])).expect("Failed parsing");
+
+        assert_eq!(command.code(), b'A');
+        assert_eq!(command.name(), "Abort");
+    }
+
+    #[test]
+    fn test_from_code_matches_accessors() {
+        let command = ClientCommand::from(Command::from(EndOfHeader));
+        assert_eq!(command.name(), "EndOfHeader");
+        assert_eq!(from_code(command.code()), Some(command.name()));
+
+        let command = ServerCommand::from(Continue);
+        assert_eq!(from_code(command.code()), Some(command.name()));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_display_is_name_only() {
+        let command = ClientCommand::parse(BytesMut::from("DE{auth_authen}\0alice\0"))
+            .expect("Failed parsing");
+
+        assert_eq!(alloc::format!("{command}"), "Macro");
+    }
+
+    #[test]
+    fn test_from_code_unknown() {
+        assert_eq!(from_code(b'Z'), None);
     }
 }
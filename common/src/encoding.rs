@@ -1,20 +1,22 @@
 //! Implement what components may write to the wire
 
 #[cfg(feature = "tracing")]
-use std::fmt::{self, Display};
+use core::fmt::{self, Display};
 
 use bytes::BytesMut;
 use enum_dispatch::enum_dispatch;
 
 use super::actions::{
-    Abort, Action, Continue, Discard, Quit, QuitNc, Reject, Replycode, Skip, Tempfail,
+    Abort, Action, Continue, Discard, Progress, Quit, QuitNc, Reject, Replycode, Skip, Tempfail,
 };
 use super::modifications::ModificationAction;
 
 use super::commands::{
-    Body, Command, Connect, Data, EndOfBody, EndOfHeader, Header, Helo, Mail, Recipient, Unknown,
+    Body, Command, Connect, Data, EndOfBody, EndOfHeader, Header, Helo, Macro, Mail, Recipient,
+    Unknown,
 };
 use super::optneg::OptNeg;
+use crate::TooMuchData;
 
 /// Write something 'to the wire'.
 #[enum_dispatch(ServerMessage)]
@@ -37,11 +39,73 @@ pub trait Writable {
     fn is_empty(&self) -> bool;
 }
 
+/// The length of the frame `item` is sent in.
+///
+/// This is the code byte plus the data written by `item`, excluding the 4
+/// byte length prefix. Use this to check whether `item` fits into the limit
+/// of the other end before sending it.
+///
+/// # Errors
+/// Errors if the frame exceeds `limit` or can not be framed at all.
+pub fn frame_len<W: Writable + ?Sized>(item: &W, limit: usize) -> Result<usize, TooMuchData> {
+    // The frame length includes the code byte, as checked when decoding
+    let frame_len = item.len().saturating_add(1);
+    // It must fit the u32 prefix
+    let limit = limit.min(u32::MAX as usize);
+
+    if frame_len > limit {
+        return Err(TooMuchData::encoding(item.code(), frame_len, limit));
+    }
+
+    Ok(frame_len)
+}
+
+/// The size limits frames on a connection have to keep.
+///
+/// Both ends are configured with the maximum frame length they accept, it
+/// is not part of option negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    max_frame_len: usize,
+}
+
+impl Limits {
+    /// Limits for frames of at most `max_frame_len` bytes, code byte
+    /// included
+    #[must_use]
+    pub fn new(max_frame_len: usize) -> Self {
+        Self {
+            max_frame_len: max_frame_len.min(u32::MAX as usize),
+        }
+    }
+
+    /// The maximum length of a frame, code byte included
+    #[must_use]
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    /// The maximum number of body bytes in a single body chunk.
+    ///
+    /// Use this to allocate a buffer for body chunks once per connection.
+    ///
+    /// ```
+    /// use miltr_common::encoding::Limits;
+    ///
+    /// assert_eq!(Limits::new(65536).max_body_chunk(), 65535);
+    /// ```
+    #[must_use]
+    pub fn max_body_chunk(&self) -> usize {
+        self.max_frame_len.saturating_sub(1)
+    }
+}
+
 /// Messages sent by the Server
 ///
 /// This is used to decode things sent by the server and received by the client.
 #[enum_dispatch]
 #[derive(Debug)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum ServerMessage {
     /// Options received from the server
     Optneg(OptNeg),
@@ -49,6 +113,8 @@ pub enum ServerMessage {
     Action,
     /// Modifications requested by the server to be applied to the mail
     ModificationAction,
+    /// A request to keep waiting for the end of body answer
+    Progress,
 }
 
 #[cfg(feature = "tracing")]
@@ -60,6 +126,7 @@ impl Display for ServerMessage {
             ServerMessage::ModificationAction(mod_action) => {
                 write!(f, "ModificationAction/{mod_action}")
             }
+            ServerMessage::Progress(_progress) => write!(f, "Progress"),
         }
     }
 }
@@ -69,6 +136,7 @@ impl Display for ServerMessage {
 /// This is used to decode things sent by the client and received by the server.
 #[enum_dispatch]
 #[derive(Debug)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum ClientMessage {
     /// Options received from the client
     Optneg(OptNeg),
@@ -76,6 +144,8 @@ pub enum ClientMessage {
     Action,
     /// SMTP commands reported by the client
     Command,
+    /// Macros for the following command
+    Macro,
 }
 
 #[cfg(feature = "tracing")]
@@ -85,6 +155,70 @@ impl Display for ClientMessage {
             ClientMessage::Optneg(_optneg) => write!(f, "Optneg"),
             ClientMessage::Action(action) => write!(f, "Action/{action}"),
             ClientMessage::Command(command) => write!(f, "Command/{command}"),
+            ClientMessage::Macro(macro_) => write!(f, "Macro/{}", macro_.code as char),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    use super::*;
+
+    const ITERATIONS: usize = 2_000;
+
+    /// Deterministic pseudo random input to feed [`Unstructured`]
+    fn seeded_input(seed: u64, size: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_be_bytes()[0]
+            })
+            .collect()
+    }
+
+    fn assert_len_matches<'a, W>(input: &'a [u8])
+    where
+        W: Arbitrary<'a> + Writable + std::fmt::Debug,
+    {
+        let mut u = Unstructured::new(input);
+        let Ok(item) = W::arbitrary(&mut u) else {
+            return;
+        };
+
+        let mut buffer = BytesMut::new();
+        item.write(&mut buffer);
+
+        assert_eq!(buffer.len(), item.len(), "len() mismatch for {item:?}");
+    }
+
+    #[test]
+    fn test_written_len_matches_len() {
+        for seed in 0..ITERATIONS as u64 {
+            let input = seeded_input(seed, 512);
+            assert_len_matches::<ClientMessage>(&input);
+            assert_len_matches::<ServerMessage>(&input);
+        }
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let header = Header::new(b"Subject", b"Hello");
+
+        assert_eq!(
+            frame_len(&header, 64).expect("Header fits"),
+            1 + header.len()
+        );
+
+        let err = frame_len(&header, 4).expect_err("Header does not fit");
+        assert_eq!(err.code, Some(b'L'));
+        assert_eq!(err.len, header.len() + 1);
+
+        // The limit applies to the whole frame, as when decoding
+        frame_len(&header, header.len()).expect_err("Code byte not accounted for");
+    }
+}
@@ -1,9 +1,12 @@
+use core::fmt::{self, Debug};
+#[cfg(feature = "std")]
 use std::io;
 
 use bytes::BytesMut;
 use thiserror::Error;
 
 use super::optneg::CompatibilityError;
+use crate::redact;
 
 /// Encapsulating error for the different de-/encoding problems
 #[derive(Debug, Error)]
@@ -17,16 +20,20 @@ pub enum ProtocolError {
     /// If we have a protocol compatibility issue
     #[error(transparent)]
     CompatibilityError(#[from] CompatibilityError),
-    /// To much data was received to make sense
-    #[error("Received a packet too large to decode (len {0})")]
-    TooMuchData(usize),
+    /// A packet exceeded the configured size limit
+    #[error(transparent)]
+    TooMuchData(#[from] TooMuchData),
     /// An io error from the underlying codec implementation
+    #[cfg(feature = "std")]
     #[error(transparent)]
     CodecError(#[from] io::Error),
 }
 
-/// Error when receiving bogus data from the other end
-#[derive(Debug, Error)]
+/// Error when receiving bogus data from the other end.
+///
+/// With the `redact-debug` feature, `Debug` only shows the length and hash
+/// of the offending bytes, as they may be mail content.
+#[derive(Error)]
 #[error("{msg}")]
 pub struct InvalidData {
     /// A human readable message
@@ -46,11 +53,26 @@ impl InvalidData {
     }
 }
 
+impl Debug for InvalidData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InvalidData")
+            .field("msg", &self.msg)
+            .field(
+                "offending_bytes",
+                &redact::bytes(&self.offending_bytes, redact::REDACT),
+            )
+            .finish()
+    }
+}
+
 pub const STAGE_DECODING: &str = "decoding";
-// pub const STAGE_ENCODING: &str = "encoding";
+pub const STAGE_ENCODING: &str = "encoding";
 
-/// Raised when definitely more data is necessary
-#[derive(Debug, Error)]
+/// Raised when definitely more data is necessary.
+///
+/// Like [`InvalidData`], `Debug` redacts the buffer with the
+/// `redact-debug` feature.
+#[derive(Error)]
 #[error("{stage} {item}: expected '{expected}' bytes but got only '{got}': {msg}")]
 pub struct NotEnoughData {
     /// The stage at which we are missing data
@@ -88,3 +110,58 @@ impl NotEnoughData {
         }
     }
 }
+
+impl Debug for NotEnoughData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NotEnoughData")
+            .field("stage", &self.stage)
+            .field("item", &self.item)
+            .field("msg", &self.msg)
+            .field("expected", &self.expected)
+            .field("got", &self.got)
+            .field("buffer", &redact::bytes(&self.buffer, redact::REDACT))
+            .finish()
+    }
+}
+
+/// Raised when a packet is larger than allowed
+#[derive(Debug, Error)]
+#[error("{stage} {item}: {len} bytes exceed the limit of {limit} bytes")]
+pub struct TooMuchData {
+    /// The direction the packet was going, decoding or encoding
+    pub stage: &'static str,
+    /// The kind of packet, `"unknown"` if it could not be determined
+    pub item: &'static str,
+    /// The code of the packet, if known
+    pub code: Option<u8>,
+    /// The length of the packet
+    pub len: usize,
+    /// The limit that was exceeded
+    pub limit: usize,
+}
+
+impl TooMuchData {
+    /// A packet with `code` and `len` bytes received exceeds `limit`
+    #[must_use]
+    pub fn decoding(code: Option<u8>, len: usize, limit: usize) -> Self {
+        Self::new(STAGE_DECODING, code, len, limit)
+    }
+
+    /// A packet with `code` and `len` bytes to be sent exceeds `limit`
+    #[must_use]
+    pub fn encoding(code: u8, len: usize, limit: usize) -> Self {
+        Self::new(STAGE_ENCODING, Some(code), len, limit)
+    }
+
+    fn new(stage: &'static str, code: Option<u8>, len: usize, limit: usize) -> Self {
+        Self {
+            stage,
+            item: code
+                .and_then(crate::decoding::from_code)
+                .unwrap_or("unknown"),
+            code,
+            len,
+            limit,
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Observe frames on the wire without decoding them
+
+use bytes::BytesMut;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{codes, error::STAGE_DECODING, InvalidData, NotEnoughData, ProtocolError};
+
+/// A single frame received or sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// The code of the command, action or modification in this frame
+    pub code: u8,
+    /// The length from the frame's length prefix, covering code and payload
+    pub len: usize,
+    /// When the frame was received or sent
+    pub timestamp: SystemTime,
+}
+
+/// A callback called for every frame
+pub type FrameHook = Arc<dyn Fn(&FrameInfo) + Send + Sync>;
+
+/// Callbacks for frames received and sent on a connection
+#[derive(Clone, Default)]
+pub struct FrameHooks {
+    /// Called for every frame read, before it is decoded
+    pub received: Option<FrameHook>,
+    /// Called for every frame written
+    pub sent: Option<FrameHook>,
+}
+
+impl FrameHooks {
+    /// Call the `received` hook, if any
+    pub fn notify_received(&self, code: u8, len: usize) {
+        Self::notify(self.received.as_ref(), code, len);
+    }
+
+    /// Call the `sent` hook, if any
+    pub fn notify_sent(&self, code: u8, len: usize) {
+        Self::notify(self.sent.as_ref(), code, len);
+    }
+
+    fn notify(hook: Option<&FrameHook>, code: u8, len: usize) {
+        if let Some(hook) = hook {
+            hook(&FrameInfo {
+                code,
+                len,
+                timestamp: SystemTime::now(),
+            });
+        }
+    }
+}
+
+impl fmt::Debug for FrameHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameHooks")
+            .field("received", &self.received.is_some())
+            .field("sent", &self.sent.is_some())
+            .finish()
+    }
+}
+
+/// Check `frame`, the code followed by the payload, is not shorter than any
+/// valid frame of its code, see [`codes::min_payload_len`].
+///
+/// Decoders call this before parsing the frame with strict length
+/// validation, rejecting garbage with a clear error.
+///
+/// # Errors
+/// If the frame is empty, without even a code, or shorter than its code
+/// requires
+pub fn check_min_len(frame: &BytesMut) -> Result<(), ProtocolError> {
+    let Some(&code) = frame.first() else {
+        return Err(InvalidData::new("Received a frame of length zero", frame.clone()).into());
+    };
+    let min = codes::min_payload_len(code);
+    if frame.len() - 1 < min {
+        return Err(NotEnoughData::new(
+            STAGE_DECODING,
+            codes::name(code).unwrap_or("frame"),
+            "shorter than any valid frame of this code",
+            min,
+            frame.len() - 1,
+            frame.clone(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Samples the time spent decoding or encoding frames.
+///
+/// Timing every frame would cost more than decoding most of them, so only
+/// every n-th call is timed.
+#[derive(Debug, Clone)]
+pub struct FrameTimer {
+    every: u32,
+    calls: u32,
+}
+
+impl FrameTimer {
+    /// The sample rate of [`FrameTimer::default`]
+    pub const DEFAULT_EVERY: u32 = 64;
+
+    /// Time every `every`-th call, starting with the first. Zero disables
+    /// timing.
+    #[must_use]
+    pub fn new(every: u32) -> Self {
+        Self { every, calls: 0 }
+    }
+
+    /// Call `f`, returning its duration if this call was sampled
+    pub fn time<T>(&mut self, f: impl FnOnce() -> T) -> (T, Option<Duration>) {
+        if self.every == 0 {
+            return (f(), None);
+        }
+        let sampled = self.calls == 0;
+        self.calls = (self.calls + 1) % self.every;
+        if !sampled {
+            return (f(), None);
+        }
+
+        let started = Instant::now();
+        let result = f();
+        (result, Some(started.elapsed()))
+    }
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_EVERY)
+    }
+}
+
+/// Sizes of the frames read on a connection.
+///
+/// Decoders use them to reserve buffer space. Reserving just the rest of
+/// the frame currently read allocates anew for every frame as long as the
+/// decoded frames are alive, e.g. while a milter collects body chunks.
+/// Adaptively, room for the next [`FrameSizes::FRAMES_AHEAD`] frames of
+/// the largest recent size is reserved at once, capped at
+/// [`FrameSizes::RESERVE_CAP`].
+#[derive(Debug, Clone)]
+pub struct FrameSizes {
+    adaptive: bool,
+    frames: u64,
+    bytes: u64,
+    max: usize,
+    recent_max: usize,
+    buffer_grows: u64,
+}
+
+impl FrameSizes {
+    /// For how many frames to reserve space at once
+    pub const FRAMES_AHEAD: usize = 4;
+    /// The most buffer space reserved beyond the frame currently read
+    pub const RESERVE_CAP: usize = 256 * 1024;
+
+    /// Track frame sizes, reserving adaptively if `adaptive` or exactly
+    /// the missing bytes otherwise
+    #[must_use]
+    pub fn new(adaptive: bool) -> Self {
+        Self {
+            adaptive,
+            frames: 0,
+            bytes: 0,
+            max: 0,
+            recent_max: 0,
+            buffer_grows: 0,
+        }
+    }
+
+    /// Whether buffer space is reserved adaptively
+    #[must_use]
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    /// Frames read
+    #[must_use]
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Bytes read in frames, length prefixes excluded
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The length of the largest frame read
+    #[must_use]
+    pub fn max_frame_len(&self) -> usize {
+        self.max
+    }
+
+    /// The length of the largest recent frame, decaying with every smaller
+    /// frame read after it
+    #[must_use]
+    pub fn recent_max_frame_len(&self) -> usize {
+        self.recent_max
+    }
+
+    /// How often the read buffer lacked the space to be reserved and had
+    /// to grow, usually reallocating
+    #[must_use]
+    pub fn buffer_grows(&self) -> u64 {
+        self.buffer_grows
+    }
+
+    /// Forget all frames read, e.g. for the next connection
+    pub fn reset(&mut self) {
+        *self = Self::new(self.adaptive);
+    }
+
+    /// Record a frame of `len` bytes read
+    pub fn record(&mut self, len: usize) {
+        self.frames += 1;
+        self.bytes += len as u64;
+        self.max = self.max.max(len);
+        self.recent_max = len.max(self.recent_max - self.recent_max / 8);
+    }
+
+    /// Make room in `buffer` for the rest of a frame, which takes
+    /// `frame_len` bytes including its length prefix
+    pub fn reserve(&mut self, buffer: &mut BytesMut, frame_len: usize) {
+        let missing = frame_len.saturating_sub(buffer.len());
+        if buffer.capacity() - buffer.len() >= missing {
+            return;
+        }
+        self.buffer_grows += 1;
+        if self.adaptive {
+            let ahead = (self.recent_max + 4).saturating_mul(Self::FRAMES_AHEAD);
+            buffer.reserve(missing.max(ahead.min(Self::RESERVE_CAP)));
+        } else {
+            buffer.reserve(missing);
+        }
+    }
+}
+
+impl Default for FrameSizes {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_samples() {
+        let mut timer = FrameTimer::new(3);
+
+        let sampled: Vec<bool> = (0..7).map(|_| timer.time(|| ()).1.is_some()).collect();
+
+        assert_eq!(sampled, [true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_check_min_len() {
+        assert!(matches!(
+            check_min_len(&BytesMut::new()),
+            Err(ProtocolError::InvalidData(_))
+        ));
+        assert!(matches!(
+            check_min_len(&BytesMut::from(&b"L\0"[..])),
+            Err(ProtocolError::NotEnoughData(_))
+        ));
+        assert!(check_min_len(&BytesMut::from(&b"L\0\0"[..])).is_ok());
+        assert!(check_min_len(&BytesMut::from(&b"E"[..])).is_ok());
+    }
+
+    /// Read `frames` frames of `len` bytes, arriving in `segment` sized reads
+    fn read_fragmented(sizes: &mut FrameSizes, frames: usize, len: usize, segment: usize) {
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        for _ in 0..frames {
+            let mut read = 0;
+            while read < 4 + len {
+                sizes.reserve(&mut buffer, 4 + len);
+                let n = segment.min(4 + len - read);
+                buffer.extend_from_slice(&vec![0; n]);
+                read += n;
+            }
+            sizes.record(len);
+            // Keep the frames alive, like a milter collecting body chunks
+            decoded.push(buffer.split_to(4 + len));
+        }
+    }
+
+    #[test]
+    fn test_adaptive_reserve_grows_less() {
+        let mut exact = FrameSizes::new(false);
+        let mut adaptive = FrameSizes::new(true);
+
+        read_fragmented(&mut exact, 16, 60_000, 1460);
+        read_fragmented(&mut adaptive, 16, 60_000, 1460);
+
+        assert_eq!(adaptive.frames(), 16);
+        assert_eq!(adaptive.max_frame_len(), 60_000);
+        assert!(
+            adaptive.buffer_grows() < exact.buffer_grows(),
+            "{} vs {}",
+            adaptive.buffer_grows(),
+            exact.buffer_grows()
+        );
+    }
+
+    #[test]
+    fn test_recent_max_decays() {
+        let mut sizes = FrameSizes::default();
+
+        sizes.record(800);
+        for _ in 0..16 {
+            sizes.record(10);
+        }
+
+        assert_eq!(sizes.max_frame_len(), 800);
+        assert!(sizes.recent_max_frame_len() < 100);
+    }
+
+    #[test]
+    fn test_timer_disabled() {
+        let mut timer = FrameTimer::new(0);
+
+        assert_eq!(timer.time(|| 1), (1, None));
+    }
+}
@@ -1,21 +1,45 @@
 #![doc = include_str!("../Readme.md")]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
 
 pub mod actions;
+#[cfg(feature = "capture")]
+pub mod capture;
+#[cfg(feature = "clock")]
+pub mod clock;
+pub mod codes;
 pub mod commands;
+#[cfg(feature = "compression")]
+pub mod compression;
 pub mod decoding;
 pub mod encoding;
+#[cfg(feature = "std")]
+pub mod frame;
+pub mod macros;
 pub mod modifications;
+#[cfg(feature = "mux")]
+pub mod mux;
 pub mod optneg;
+pub mod secret;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 
 mod error;
+mod redact;
+
+#[cfg(any(test, feature = "arbitrary"))]
+mod arbitrary;
 
 use encoding::ServerMessage;
 
-pub use error::{InvalidData, NotEnoughData, ProtocolError};
+pub use error::{InvalidData, NotEnoughData, ProtocolError, TooMuchData};
 
+#[cfg(feature = "decode-server")]
 use modifications::{
     body::ReplaceBody,
     headers::{AddHeader, ChangeHeader, InsertHeader},
     quarantine::Quarantine,
-    recipients::{AddRecipient, DeleteRecipient},
+    recipients::{AddRecipient, AddRecipientPar, DeleteRecipient},
+    sender::ChangeFrom,
 };
@@ -0,0 +1,97 @@
+//! SASL authentication state of the SMTP session as reported by the MTA
+
+use alloc::string::String;
+
+use bytes::BytesMut;
+
+use super::{well_known, MacroContext};
+use crate::secret::Secret;
+
+/// The SASL authentication the remote SMTP client performed.
+///
+/// Built from the `{auth_type}`, `{auth_authen}`, `{auth_ssf}` and
+/// `{auth_author}` macros. The login name and authorized sender are kept
+/// as [`Secret`], to not log them by accident.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct AuthInfo {
+    /// The SASL login method, e.g. `PLAIN`
+    pub auth_type: Option<String>,
+    /// The SASL login name
+    pub authen: Option<Secret<BytesMut>>,
+    /// The SASL security strength factor
+    pub ssf: Option<u32>,
+    /// The authorized sender, as given in the `AUTH=` parameter of `MAIL`
+    pub author: Option<Secret<BytesMut>>,
+}
+
+impl AuthInfo {
+    /// Collect authentication information from `macros`
+    #[must_use]
+    pub fn from_macros(macros: &MacroContext) -> Self {
+        Self {
+            auth_type: non_empty(macros, well_known::AUTH_TYPE)
+                .map(|value| String::from_utf8_lossy(value).into_owned()),
+            authen: non_empty(macros, well_known::AUTH_AUTHEN).map(secret),
+            ssf: macros
+                .get_str(well_known::AUTH_SSF)
+                .and_then(|ssf| ssf.trim().parse().ok()),
+            author: non_empty(macros, well_known::AUTH_AUTHOR).map(secret),
+        }
+    }
+
+    /// Whether the client successfully authenticated
+    #[must_use]
+    pub fn is_authenticated(&self) -> bool {
+        self.authen.is_some()
+    }
+}
+
+/// Postfix sends empty values for unauthenticated sessions
+fn non_empty<'m>(macros: &'m MacroContext, name: &str) -> Option<&'m [u8]> {
+    macros.get(name).filter(|value| !value.is_empty())
+}
+
+fn secret(value: &[u8]) -> Secret<BytesMut> {
+    Secret::new(BytesMut::from(value))
+}
+
+#[cfg(all(test, feature = "decode-client"))]
+mod tests {
+    use bytes::BytesMut;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{commands::Macro, decoding::Parsable};
+
+    fn context(raw: &str) -> MacroContext {
+        let mut context = MacroContext::new();
+        context.insert(Macro::parse(BytesMut::from(raw)).expect("Failed parsing macro"));
+        context
+    }
+
+    #[test]
+    fn test_authenticated() {
+        let context = context("M{auth_type}\0PLAIN\0{auth_authen}\0alice\0{auth_ssf}\x000\0");
+
+        let auth = context.auth();
+
+        assert!(auth.is_authenticated());
+        assert_eq!(auth.auth_type.as_deref(), Some("PLAIN"));
+        assert!(auth
+            .authen
+            .as_ref()
+            .is_some_and(|authen| authen.eq_bytes(b"alice")));
+        assert_eq!(auth.ssf, Some(0));
+        assert_eq!(auth.author, None);
+    }
+
+    #[test]
+    fn test_unauthenticated() {
+        let context = context("M{auth_authen}\0\0{auth_type}\0\0");
+
+        let auth = context.auth();
+
+        assert!(!auth.is_authenticated());
+        assert_eq!(auth, AuthInfo::default());
+    }
+}
@@ -0,0 +1,268 @@
+//! Share macro names instead of copying them for every message
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{
+    borrow::Borrow,
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+};
+
+use bytes::Bytes;
+
+use super::well_known;
+
+/// The name of a macro, including curly braces of long names.
+///
+/// Parsing keeps the [`well_known`] names as static strings and others as
+/// received, until a [`MacroInterner`] shares them within a connection.
+/// Names compare by their bytes, whichever variant holds them.
+#[derive(Clone)]
+pub enum MacroName {
+    /// One of the [`well_known`] names
+    Static(&'static str),
+    /// Any other name, shared within a connection by a [`MacroInterner`]
+    Shared(Arc<[u8]>),
+    /// Any other name as received, not interned yet
+    Raw(Bytes),
+}
+
+impl MacroName {
+    /// The well known name equal to `name`, or `name` as received
+    pub(crate) fn from_raw(name: Bytes) -> Self {
+        match find_well_known(&name) {
+            Some(known) => Self::Static(known),
+            None => Self::Raw(name),
+        }
+    }
+
+    /// The name as sent
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Static(name) => name.as_bytes(),
+            Self::Shared(name) => name,
+            Self::Raw(name) => name,
+        }
+    }
+
+    /// Whether the name is well known or shared by an interner
+    #[must_use]
+    pub fn is_interned(&self) -> bool {
+        !matches!(self, Self::Raw(_))
+    }
+}
+
+impl Deref for MacroName {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Borrow<[u8]> for MacroName {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq for MacroName {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for MacroName {}
+
+impl Hash for MacroName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state);
+    }
+}
+
+impl PartialOrd for MacroName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MacroName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl fmt::Debug for MacroName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&String::from_utf8_lossy(self.as_bytes()), f)
+    }
+}
+
+impl fmt::Display for MacroName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+/// Hands out [`MacroName`]s, allocating each name at most once.
+///
+/// Well known names never allocate. Keep one interner per connection, as
+/// every name seen is kept until the interner is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MacroInterner {
+    names: Vec<Arc<[u8]>>,
+}
+
+impl MacroInterner {
+    /// Create an empty interner
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The handle for the raw macro name `name`
+    pub fn intern(&mut self, name: &[u8]) -> MacroName {
+        if let Some(known) = find_well_known(name) {
+            return MacroName::Static(known);
+        }
+        if let Some(shared) = self.names.iter().find(|n| ***n == *name) {
+            return MacroName::Shared(Arc::clone(shared));
+        }
+
+        let shared: Arc<[u8]> = name.into();
+        self.names.push(Arc::clone(&shared));
+        MacroName::Shared(shared)
+    }
+
+    /// Replace `name` by its handle, if not interned yet
+    pub fn share(&mut self, name: &mut MacroName) {
+        if let MacroName::Raw(raw) = name {
+            *name = self.intern(raw);
+        }
+    }
+
+    /// How many names not well known were interned
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Whether only well known names were interned so far
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// The well known name equal to `name`, looked up without allocating
+fn find_well_known(name: &[u8]) -> Option<&'static str> {
+    well_known::ALL
+        .iter()
+        .copied()
+        .find(|known| known.as_bytes() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "decode-client")]
+    use bytes::BytesMut;
+
+    use super::*;
+    #[cfg(feature = "decode-client")]
+    use crate::{commands::Macro, decoding::Parsable};
+
+    #[test]
+    fn test_intern() {
+        let mut interner = MacroInterner::new();
+
+        let known = interner.intern(b"{client_addr}");
+        let first = interner.intern(b"{hdr_count}");
+        let second = interner.intern(b"{hdr_count}");
+
+        assert!(matches!(known, MacroName::Static(well_known::CLIENT_ADDR)));
+        assert!(
+            matches!((&first, &second), (MacroName::Shared(a), MacroName::Shared(b)) if Arc::ptr_eq(a, b))
+        );
+        assert_eq!(&*first, b"{hdr_count}");
+        assert_eq!(interner.len(), 1);
+    }
+
+    #[test]
+    fn test_compare_by_bytes() {
+        let raw = MacroName::Raw(Bytes::from_static(b"{x}"));
+        let shared = MacroInterner::new().intern(b"{x}");
+
+        assert_eq!(raw, shared);
+        assert_eq!(
+            MacroName::Raw(Bytes::from_static(b"i")),
+            MacroName::Static(well_known::QUEUE_ID)
+        );
+    }
+
+    #[cfg(feature = "decode-client")]
+    #[test]
+    fn test_interned_macros() {
+        let mut macro_ =
+            Macro::parse(BytesMut::from("Ci\x00ABC\0{x}\x001\0")).expect("Failed parsing");
+        let names: Vec<bool> = macro_.entries().map(|(n, _)| n.is_interned()).collect();
+        assert_eq!(names, [true, false]);
+
+        let mut interner = MacroInterner::new();
+        macro_.intern(&mut interner);
+
+        let names: Vec<_> = macro_.entries().collect();
+        assert!(matches!(
+            names[0],
+            (MacroName::Static(well_known::QUEUE_ID), b"ABC")
+        ));
+        assert!(matches!(names[1], (MacroName::Shared(name), b"1") if **name == *b"{x}"));
+        assert_eq!(interner.len(), 1);
+    }
+
+    #[cfg(feature = "count-allocations")]
+    #[test]
+    fn test_intern_allocations() {
+        let mut interner = MacroInterner::new();
+        let names: [&[u8]; 3] = [b"{client_addr}", b"i", b"{hdr_count}"];
+        // Seen once, shared from now on
+        interner.intern(names[2]);
+
+        let info = allocation_counter::measure(|| {
+            for _ in 0..1000 {
+                for name in names {
+                    let _ = interner.intern(name);
+                }
+            }
+        });
+
+        assert_eq!(info.count_total, 0);
+    }
+
+    #[cfg(all(feature = "count-allocations", feature = "decode-client"))]
+    #[test]
+    fn test_context_allocations() {
+        use crate::macros::MacroContext;
+
+        let parse = || {
+            Macro::parse(BytesMut::from(
+                "Mi\0ABC\0{x}\x001\0{mail_addr}\0a@test.local\0",
+            ))
+            .expect("Failed parsing")
+        };
+        let messages: Vec<Macro> = (0..1000).map(|_| parse()).collect();
+        let mut context = MacroContext::new();
+        // The first message interns the names of the connection
+        context.insert(parse());
+
+        let info = allocation_counter::measure(|| {
+            for macro_ in messages {
+                context.insert(macro_);
+                let _ = context.get("x");
+            }
+        });
+
+        assert_eq!(info.count_total, 0);
+    }
+}
@@ -0,0 +1,103 @@
+//! TLS state of the SMTP session as reported by the MTA
+
+use alloc::string::String;
+
+use super::{well_known, MacroContext};
+
+/// The TLS parameters the remote SMTP client connected with.
+///
+/// Built from the `{tls_version}`, `{cipher}`, `{cipher_bits}`,
+/// `{cert_subject}` and `{cert_issuer}` macros.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The TLS protocol version, e.g. `TLSv1.3`
+    pub version: String,
+    /// The negotiated cipher
+    pub cipher: Option<String>,
+    /// The key size of the cipher in bits
+    pub cipher_bits: Option<u32>,
+    /// Subject of the client certificate, if one was presented
+    pub cert_subject: Option<String>,
+    /// Issuer of the client certificate, if one was presented
+    pub cert_issuer: Option<String>,
+}
+
+impl TlsInfo {
+    /// Collect TLS information from `macros`.
+    ///
+    /// Returns `None` if the MTA did not report a TLS version, which is the
+    /// case for plaintext sessions.
+    #[must_use]
+    pub fn from_macros(macros: &MacroContext) -> Option<Self> {
+        let version = macros.tls_version()?.into_owned();
+
+        Some(Self {
+            version,
+            cipher: macros.get_str(well_known::CIPHER).map(Into::into),
+            cipher_bits: macros
+                .get_str(well_known::CIPHER_BITS)
+                .and_then(|bits| bits.trim().parse().ok()),
+            cert_subject: macros.get_str(well_known::CERT_SUBJECT).map(Into::into),
+            cert_issuer: macros.get_str(well_known::CERT_ISSUER).map(Into::into),
+        })
+    }
+
+    /// Whether the cipher is known to use at least `bits` key bits
+    #[must_use]
+    pub fn has_cipher_bits(&self, bits: u32) -> bool {
+        self.cipher_bits.is_some_and(|b| b >= bits)
+    }
+
+    /// Whether the client presented a certificate
+    #[must_use]
+    pub fn has_client_cert(&self) -> bool {
+        self.cert_subject.is_some()
+    }
+}
+
+#[cfg(all(test, feature = "decode-client"))]
+mod tests {
+    use bytes::BytesMut;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{commands::Macro, decoding::Parsable};
+
+    fn context(raw: &str) -> MacroContext {
+        let mut context = MacroContext::new();
+        context.insert(Macro::parse(BytesMut::from(raw)).expect("Failed parsing macro"));
+        context
+    }
+
+    #[test]
+    fn test_tls_info() {
+        let context = context(
+            "H{tls_version}\0TLSv1.3\0{cipher}\0TLS_AES_256_GCM_SHA384\0{cipher_bits}\x00256\0",
+        );
+
+        let tls = context.tls().expect("No tls info");
+
+        assert_eq!(tls.version, "TLSv1.3");
+        assert_eq!(tls.cipher.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+        assert_eq!(tls.cipher_bits, Some(256));
+        assert!(tls.has_cipher_bits(128));
+        assert!(!tls.has_client_cert());
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let context = context("H{cipher_bits}\x000\0");
+
+        assert_eq!(context.tls(), None);
+    }
+
+    #[test]
+    fn test_invalid_bits() {
+        let context = context("H{tls_version}\0TLSv1.2\0{cipher_bits}\0many\0");
+
+        let tls = context.tls().expect("No tls info");
+
+        assert_eq!(tls.cipher_bits, None);
+        assert!(!tls.has_cipher_bits(0));
+    }
+}
@@ -0,0 +1,125 @@
+//! Names of the macros commonly sent by sendmail and postfix
+//!
+//! Macros with a long name are sent in curly braces, exactly as listed
+//! here. Lookups on [`super::MacroContext`] accept names with or without
+//! those braces.
+
+/// The queue id of the current message
+pub const QUEUE_ID: &str = "i";
+/// The hostname of the MTA
+pub const MY_HOSTNAME: &str = "j";
+/// Validated client name and address
+pub const CLIENT_INFO: &str = "_";
+/// The MTA version
+pub const MTA_VERSION: &str = "v";
+
+/// Name of the MTA daemon
+pub const DAEMON_NAME: &str = "{daemon_name}";
+/// Address the MTA daemon accepted the connection on
+pub const DAEMON_ADDR: &str = "{daemon_addr}";
+/// Port the MTA daemon accepted the connection on
+pub const DAEMON_PORT: &str = "{daemon_port}";
+/// Name of the interface the connection came in on
+pub const IF_NAME: &str = "{if_name}";
+/// Address of the interface the connection came in on
+pub const IF_ADDR: &str = "{if_addr}";
+
+/// Remote client IP address
+pub const CLIENT_ADDR: &str = "{client_addr}";
+/// Remote client port
+pub const CLIENT_PORT: &str = "{client_port}";
+/// Remote client hostname
+pub const CLIENT_NAME: &str = "{client_name}";
+/// Remote client name from the reverse lookup
+pub const CLIENT_PTR: &str = "{client_ptr}";
+/// Result of the reverse lookup of the client
+pub const CLIENT_RESOLVE: &str = "{client_resolve}";
+/// Connection count of this client
+pub const CLIENT_CONNECTIONS: &str = "{client_connections}";
+
+/// TLS protocol version
+pub const TLS_VERSION: &str = "{tls_version}";
+/// TLS cipher
+pub const CIPHER: &str = "{cipher}";
+/// TLS cipher key size in bits
+pub const CIPHER_BITS: &str = "{cipher_bits}";
+/// Subject of the client certificate
+pub const CERT_SUBJECT: &str = "{cert_subject}";
+/// Issuer of the client certificate
+pub const CERT_ISSUER: &str = "{cert_issuer}";
+
+/// SASL login method
+pub const AUTH_TYPE: &str = "{auth_type}";
+/// SASL login name
+pub const AUTH_AUTHEN: &str = "{auth_authen}";
+/// SASL security strength factor
+pub const AUTH_SSF: &str = "{auth_ssf}";
+/// SASL authorized sender
+pub const AUTH_AUTHOR: &str = "{auth_author}";
+
+/// Delivery agent of the sender address
+pub const MAIL_MAILER: &str = "{mail_mailer}";
+/// Domain of the sender address
+pub const MAIL_HOST: &str = "{mail_host}";
+/// The sender address
+pub const MAIL_ADDR: &str = "{mail_addr}";
+
+/// Delivery agent of the recipient address
+pub const RCPT_MAILER: &str = "{rcpt_mailer}";
+/// Domain of the recipient address
+pub const RCPT_HOST: &str = "{rcpt_host}";
+/// The recipient address
+pub const RCPT_ADDR: &str = "{rcpt_addr}";
+
+/// The time left to handle the current message, in milliseconds.
+///
+/// Not sent by any MTA, but by the `miltr-client` when given a deadline.
+/// Milters not knowing it ignore it like any other macro.
+pub const MILTR_DEADLINE: &str = "{miltr_deadline}";
+
+/// All names above, to look up received names without allocating
+pub const ALL: &[&str] = &[
+    QUEUE_ID,
+    MY_HOSTNAME,
+    CLIENT_INFO,
+    MTA_VERSION,
+    DAEMON_NAME,
+    DAEMON_ADDR,
+    DAEMON_PORT,
+    IF_NAME,
+    IF_ADDR,
+    CLIENT_ADDR,
+    CLIENT_PORT,
+    CLIENT_NAME,
+    CLIENT_PTR,
+    CLIENT_RESOLVE,
+    CLIENT_CONNECTIONS,
+    TLS_VERSION,
+    CIPHER,
+    CIPHER_BITS,
+    CERT_SUBJECT,
+    CERT_ISSUER,
+    AUTH_TYPE,
+    AUTH_AUTHEN,
+    AUTH_SSF,
+    AUTH_AUTHOR,
+    MAIL_MAILER,
+    MAIL_HOST,
+    MAIL_ADDR,
+    RCPT_MAILER,
+    RCPT_HOST,
+    RCPT_ADDR,
+    MILTR_DEADLINE,
+];
+
+/// Names of the macros with sensitive values, never shown in `Debug` output
+const SECRET: &[&str] = &[AUTH_AUTHEN, AUTH_AUTHOR];
+
+/// Whether the value of the macro `name`, with or without braces, is
+/// sensitive, e.g. a SASL login name
+pub(crate) fn is_secret(name: &[u8]) -> bool {
+    let name = super::strip_braces(name);
+    SECRET
+        .iter()
+        .any(|secret| super::strip_braces(secret.as_bytes()) == name)
+}
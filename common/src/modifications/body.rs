@@ -1,11 +1,16 @@
 //! Replace body parts
 
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec::Vec};
+use core::fmt::{self, Debug};
 
 use bytes::BytesMut;
 
+use crate::codes;
+#[cfg(feature = "decode-server")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+use crate::redact::{self, DebugWith};
+#[cfg(feature = "decode-server")]
 use crate::ProtocolError;
 
 /// Replace the body of the incoming mail.
@@ -13,8 +18,10 @@ use crate::ProtocolError;
 /// If this modification action is used, the **whole** body has to be sent back.
 /// It can be split across multiple `ReplaceBody` actions, but in the end,
 /// the complete intended response has to be sent.
-#[derive(Debug, Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct ReplaceBody {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     body: BytesMut,
 }
 
@@ -27,7 +34,7 @@ impl<'a> FromIterator<&'a u8> for ReplaceBody {
 }
 
 impl ReplaceBody {
-    const CODE: u8 = b'b';
+    const CODE: u8 = codes::SMFIR_REPLBODY;
 
     /// A body part to replace the original
     #[must_use]
@@ -41,11 +48,49 @@ impl ReplaceBody {
     ///
     /// Will be interpreted by the client as a valid mail.
     #[must_use]
-    pub fn body(&self) -> Cow<str> {
+    pub fn body(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.body)
     }
+
+    /// Split into consecutive parts of at most `max_len` bytes.
+    ///
+    /// Sent in order, the parts replace the body just like `self` would.
+    #[must_use]
+    pub fn split(mut self, max_len: usize) -> Vec<Self> {
+        let max_len = max_len.max(1);
+        let mut parts = Vec::with_capacity(self.body.len().div_ceil(max_len));
+        while self.body.len() > max_len {
+            parts.push(Self {
+                body: self.body.split_to(max_len),
+            });
+        }
+        parts.push(self);
+
+        parts
+    }
+
+    /// Debug this body part in full, even with the `redact-debug` feature.
+    ///
+    /// Only for troubleshooting, this prints mail content.
+    #[must_use]
+    pub fn dangerous_full_debug(&self) -> impl Debug + '_ {
+        DebugWith(|f: &mut fmt::Formatter<'_>| self.fmt_debug(f, false))
+    }
+
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, redact: bool) -> fmt::Result {
+        f.debug_struct("ReplaceBody")
+            .field("body", &redact::bytes(&self.body, redact))
+            .finish()
+    }
+}
+
+impl Debug for ReplaceBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, redact::REDACT)
+    }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for ReplaceBody {
     const CODE: u8 = Self::CODE;
 
@@ -86,4 +131,12 @@ mod test {
 
         assert_eq!(buffer, BytesMut::from("bnew body"));
     }
+
+    #[test]
+    fn test_split() {
+        let parts = ReplaceBody::new(b"new body").split(3);
+
+        let parts: Vec<_> = parts.iter().map(ReplaceBody::body).collect();
+        assert_eq!(parts, vec!["new", " bo", "dy"]);
+    }
 }
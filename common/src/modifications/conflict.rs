@@ -0,0 +1,348 @@
+//! Combine the modifications of several layers, resolving their conflicts
+//!
+//! If several layers of a milter each decide on modifications, some of them
+//! can not all be applied: two layers changing the same header, or both
+//! replacing the body.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt::{self, Display};
+
+use thiserror::Error;
+
+use super::{
+    quarantine::Quarantine, ModificationAction, ModificationResponse, ModificationResponseBuilder,
+};
+use crate::actions::Action;
+
+/// How to resolve modifications of two layers that conflict, see
+/// [`LayeredModifications`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the modification of the layer with the higher priority, or of
+    /// the layer pushed later on equal priority
+    #[default]
+    LastWins,
+    /// Fail resolving with a [`ModificationConflict`]
+    Error,
+    /// Combine the modifications where possible, joining the reasons of
+    /// quarantines. Header, sender and body changes can not be
+    /// combined, they are resolved like [`ConflictPolicy::LastWins`].
+    Merge,
+}
+
+/// What two layers modify both
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConflictKind {
+    /// The same occurrence of a header is changed
+    Header {
+        /// The name of the header, lowercase
+        name: String,
+        /// The index of the occurrence
+        index: u32,
+    },
+    /// The envelope sender is changed
+    Sender,
+    /// The body is replaced
+    Body,
+    /// The message is quarantined
+    Quarantine,
+}
+
+impl ConflictKind {
+    /// The conflict `modification` may be part of, if any
+    fn of(modification: &ModificationAction) -> Option<Self> {
+        match modification {
+            ModificationAction::ChangeHeader(change) => Some(Self::Header {
+                name: change.name().to_ascii_lowercase(),
+                index: change.index(),
+            }),
+            ModificationAction::ChangeFrom(_) => Some(Self::Sender),
+            ModificationAction::ReplaceBody(_) => Some(Self::Body),
+            ModificationAction::Quarantine(_) => Some(Self::Quarantine),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ConflictKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header { name, index } => write!(f, "header '{name}' at index {index}"),
+            Self::Sender => write!(f, "sender change"),
+            Self::Body => write!(f, "body replacement"),
+            Self::Quarantine => write!(f, "quarantine"),
+        }
+    }
+}
+
+/// Two layers modify the same thing with [`ConflictPolicy::Error`]
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("Conflicting {kind} of layers with priority {existing} and {conflicting}")]
+pub struct ModificationConflict {
+    /// What both layers modify
+    pub kind: ConflictKind,
+    /// The priority of the layer resolved first
+    pub existing: i32,
+    /// The priority of the layer conflicting with it
+    pub conflicting: i32,
+}
+
+/// The modifications of one layer
+#[derive(Debug, Clone)]
+struct Layer {
+    priority: i32,
+    modifications: Vec<ModificationAction>,
+}
+
+/// Collects the modifications of several layers, e.g. of milters chained
+/// into one, to resolve them into a single response.
+///
+/// Layers are resolved from lowest to highest priority, layers of equal
+/// priority in the order pushed. Additions, insertions and recipient
+/// changes never conflict and are all kept, identical ones once. Changing
+/// the same header or the sender, replacing the body or quarantining in
+/// more than one layer conflicts and is resolved with the [`ConflictPolicy`]. Within a
+/// single layer nothing conflicts, a layer may well replace the body in
+/// several chunks.
+///
+/// ```
+/// use miltr_common::actions::Continue;
+/// use miltr_common::modifications::{
+///     conflict::ConflictPolicy, headers::ChangeHeader, ModificationResponse,
+/// };
+///
+/// let mut spam = ModificationResponse::builder();
+/// spam.push(ChangeHeader::new(1, b"Subject", b"[SPAM] Hello"));
+/// let mut virus = ModificationResponse::builder();
+/// virus.push(ChangeHeader::new(1, b"Subject", b"[VIRUS] Hello"));
+///
+/// let mut layers = ModificationResponse::layered(ConflictPolicy::LastWins);
+/// layers.push_layer(10, virus);
+/// layers.push_layer(0, spam);
+/// let response = layers.build(Continue).expect("Last wins never fails");
+///
+/// assert_eq!(response.modifications().len(), 1);
+/// assert_eq!(
+///     response.modifications()[0],
+///     ChangeHeader::new(1, b"Subject", b"[VIRUS] Hello").into()
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LayeredModifications {
+    policy: ConflictPolicy,
+    layers: Vec<Layer>,
+}
+
+impl LayeredModifications {
+    /// Collect layers, resolving conflicts with `policy`
+    #[must_use]
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            policy,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Add the modifications of a layer with `priority`, higher priorities
+    /// winning conflicts
+    pub fn push_layer(&mut self, priority: i32, layer: ModificationResponseBuilder) {
+        self.layers.push(Layer {
+            priority,
+            modifications: layer.modifications,
+        });
+    }
+
+    /// Whether no layer was pushed
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Resolve all layers into a response with `final_action`, see
+    /// [`Self::resolve`].
+    ///
+    /// # Errors
+    /// With [`ConflictPolicy::Error`], if two layers conflict
+    pub fn build<A: Into<Action>>(
+        self,
+        final_action: A,
+    ) -> Result<ModificationResponse, ModificationConflict> {
+        Ok(self.resolve()?.build(final_action))
+    }
+
+    /// Resolve all layers into one builder to set the final action on.
+    ///
+    /// # Errors
+    /// With [`ConflictPolicy::Error`], if two layers conflict
+    pub fn resolve(mut self) -> Result<ModificationResponseBuilder, ModificationConflict> {
+        // Stable, so equal priorities stay in the order pushed
+        self.layers.sort_by_key(|layer| layer.priority);
+
+        let mut resolved: Vec<Option<ModificationAction>> = Vec::new();
+        // Which layer last claimed a conflict kind, and where
+        let mut claims: Vec<(ConflictKind, usize, i32, Vec<usize>)> = Vec::new();
+
+        for (layer_index, layer) in self.layers.into_iter().enumerate() {
+            for modification in layer.modifications {
+                let Some(kind) = ConflictKind::of(&modification) else {
+                    if !resolved.iter().flatten().any(|m| *m == modification) {
+                        resolved.push(Some(modification));
+                    }
+                    continue;
+                };
+
+                let Some(claim) = claims.iter_mut().find(|claim| claim.0 == kind) else {
+                    claims.push((kind, layer_index, layer.priority, vec![resolved.len()]));
+                    resolved.push(Some(modification));
+                    continue;
+                };
+                if claim.1 == layer_index {
+                    claim.3.push(resolved.len());
+                    resolved.push(Some(modification));
+                    continue;
+                }
+
+                match (self.policy, &modification) {
+                    (ConflictPolicy::Error, _) => {
+                        return Err(ModificationConflict {
+                            kind,
+                            existing: claim.2,
+                            conflicting: layer.priority,
+                        });
+                    }
+                    (ConflictPolicy::Merge, ModificationAction::Quarantine(quarantine)) => {
+                        let position = claim.3[0];
+                        if let Some(ModificationAction::Quarantine(existing)) =
+                            &mut resolved[position]
+                        {
+                            *existing = merge_quarantine(existing, quarantine);
+                        }
+                    }
+                    (ConflictPolicy::LastWins | ConflictPolicy::Merge, _) => {
+                        for &position in &claim.3 {
+                            resolved[position] = None;
+                        }
+                        *claim = (kind, layer_index, layer.priority, vec![resolved.len()]);
+                        resolved.push(Some(modification));
+                    }
+                }
+            }
+        }
+
+        Ok(ModificationResponseBuilder {
+            modifications: resolved.into_iter().flatten().collect(),
+        })
+    }
+}
+
+/// Join the reasons of two quarantines
+fn merge_quarantine(existing: &Quarantine, other: &Quarantine) -> Quarantine {
+    match (existing.has_reason(), other.has_reason()) {
+        (_, false) => existing.clone(),
+        (false, true) => other.clone(),
+        (true, true) => {
+            let mut reason = String::from(existing.reason());
+            reason.push_str("; ");
+            reason.push_str(&other.reason());
+            Quarantine::new(reason.as_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::actions::Reject;
+    use crate::modifications::{
+        body::ReplaceBody,
+        headers::{AddHeader, ChangeHeader},
+    };
+
+    fn layers(policy: ConflictPolicy) -> LayeredModifications {
+        let mut first = ModificationResponse::builder();
+        first.push(AddHeader::new(b"X-Scanned", b"yes"));
+        first.push(ChangeHeader::new(1, b"Subject", b"[SPAM] Hi"));
+        first.push(ReplaceBody::new(b"first "));
+        first.push(ReplaceBody::new(b"body"));
+        first.push(Quarantine::new(b"spam"));
+
+        let mut second = ModificationResponse::builder();
+        second.push(AddHeader::new(b"X-Scanned", b"yes"));
+        second.push(ChangeHeader::new(1, b"subject", b"[VIRUS] Hi"));
+        second.push(ReplaceBody::new(b"second body"));
+        second.push(Quarantine::new(b"virus"));
+
+        let mut layers = ModificationResponse::layered(policy);
+        layers.push_layer(5, second);
+        layers.push_layer(1, first);
+        layers
+    }
+
+    #[test]
+    fn test_last_wins() {
+        let builder = layers(ConflictPolicy::LastWins)
+            .resolve()
+            .expect("Last wins never fails");
+
+        assert_eq!(
+            builder.modifications,
+            vec![
+                AddHeader::new(b"X-Scanned", b"yes").into(),
+                ChangeHeader::new(1, b"subject", b"[VIRUS] Hi").into(),
+                ReplaceBody::new(b"second body").into(),
+                Quarantine::new(b"virus").into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let builder = layers(ConflictPolicy::Merge)
+            .resolve()
+            .expect("Merge never fails");
+
+        assert_eq!(
+            builder.modifications,
+            vec![
+                AddHeader::new(b"X-Scanned", b"yes").into(),
+                Quarantine::new(b"spam; virus").into(),
+                ChangeHeader::new(1, b"subject", b"[VIRUS] Hi").into(),
+                ReplaceBody::new(b"second body").into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_response() {
+        let response = layers(ConflictPolicy::LastWins)
+            .build(Reject)
+            .expect("Last wins never fails");
+
+        assert_eq!(response.modifications().len(), 4);
+        assert!(matches!(response.final_action(), Action::Reject(_)));
+
+        let conflict = layers(ConflictPolicy::Error).build(Reject);
+        assert!(conflict.is_err());
+    }
+
+    #[test]
+    fn test_error() {
+        let conflict = layers(ConflictPolicy::Error)
+            .resolve()
+            .expect_err("Layers conflict");
+
+        assert_eq!(
+            conflict,
+            ModificationConflict {
+                kind: ConflictKind::Header {
+                    name: "subject".into(),
+                    index: 1,
+                },
+                existing: 1,
+                conflicting: 5,
+            }
+        );
+    }
+}
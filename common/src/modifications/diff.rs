@@ -0,0 +1,275 @@
+//! Compare modification responses, e.g. in tests
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Display, Write};
+
+use bytes::BytesMut;
+
+use super::{ModificationAction, ModificationResponse};
+use crate::{actions::Action, decoding::from_code, encoding::Writable};
+
+/// A difference between an expected and an actual [`ModificationResponse`],
+/// see [`ModificationResponse::diff`]
+#[derive(Debug, Clone)]
+pub enum Difference {
+    /// An expected modification is missing
+    Missing(ModificationAction),
+    /// A modification was not expected
+    Unexpected(ModificationAction),
+    /// A header is changed or inserted as expected, but at another index
+    WrongIndex {
+        /// The modification expected
+        expected: ModificationAction,
+        /// The same modification with another index
+        actual: ModificationAction,
+    },
+    /// The final action differs
+    FinalAction {
+        /// The final action expected
+        expected: Action,
+        /// The final action returned
+        actual: Action,
+    },
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(expected) => write!(f, "missing {}", Described(expected)),
+            Self::Unexpected(actual) => write!(f, "unexpected {}", Described(actual)),
+            Self::WrongIndex { expected, actual } => write!(
+                f,
+                "wrong index, expected {} but got {}",
+                Described(expected),
+                Described(actual)
+            ),
+            Self::FinalAction { expected, actual } => write!(
+                f,
+                "final action {} instead of {}",
+                action_name(actual),
+                action_name(expected)
+            ),
+        }
+    }
+}
+
+/// Formats a modification for humans
+struct Described<'a>(&'a ModificationAction);
+
+impl Display for Described<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            ModificationAction::AddRecipient(m) => write!(f, "AddRecipient {}", m.recipient()),
+            ModificationAction::AddRecipientPar(m) => match m.args() {
+                Some(args) => write!(f, "AddRecipientPar {} {}", m.recipient(), args),
+                None => write!(f, "AddRecipientPar {}", m.recipient()),
+            },
+            ModificationAction::ChangeFrom(m) => match m.args() {
+                Some(args) => write!(f, "ChangeFrom {} {}", m.sender(), args),
+                None => write!(f, "ChangeFrom {}", m.sender()),
+            },
+            ModificationAction::DeleteRecipient(m) => {
+                write!(f, "DeleteRecipient {}", m.recipient())
+            }
+            ModificationAction::ReplaceBody(m) => {
+                write!(f, "ReplaceBody of {} bytes", m.body().len())
+            }
+            ModificationAction::AddHeader(m) => {
+                write!(f, "AddHeader {}: {}", m.name(), m.value())
+            }
+            ModificationAction::InsertHeader(m) => {
+                write!(f, "InsertHeader #{} {}: {}", m.index(), m.name(), m.value())
+            }
+            ModificationAction::ChangeHeader(m) => {
+                write!(f, "ChangeHeader #{} {}: {}", m.index(), m.name(), m.value())
+            }
+            ModificationAction::Quarantine(m) => write!(f, "Quarantine {}", m.reason()),
+        }
+    }
+}
+
+fn action_name(action: &Action) -> &'static str {
+    from_code(action.code()).unwrap_or("unknown")
+}
+
+/// Whether both actions would be sent as the same frame
+fn same_action(a: &Action, b: &Action) -> bool {
+    let (mut a_bytes, mut b_bytes) = (BytesMut::new(), BytesMut::new());
+    a.write(&mut a_bytes);
+    b.write(&mut b_bytes);
+    a.code() == b.code() && a_bytes == b_bytes
+}
+
+/// Whether both modify the same header, regardless of the index
+fn same_header(a: &ModificationAction, b: &ModificationAction) -> bool {
+    match (a, b) {
+        (ModificationAction::ChangeHeader(a), ModificationAction::ChangeHeader(b)) => {
+            a.name() == b.name() && a.value() == b.value()
+        }
+        (ModificationAction::InsertHeader(a), ModificationAction::InsertHeader(b)) => {
+            a.name() == b.name() && a.value() == b.value()
+        }
+        _ => false,
+    }
+}
+
+impl ModificationResponse {
+    /// The differences of `actual` to this expected response.
+    ///
+    /// The order of modifications is ignored, [`Self::normalize`] both and
+    /// compare them to check it as well. An empty result means both match.
+    ///
+    /// ```
+    /// use miltr_common::modifications::{
+    ///     diff::Difference,
+    ///     headers::{AddHeader, ChangeHeader},
+    ///     ModificationResponse,
+    /// };
+    ///
+    /// let mut expected = ModificationResponse::builder();
+    /// expected.push(AddHeader::new(b"X-Scanned", b"yes"));
+    /// expected.push(ChangeHeader::new(1, b"Subject", b"[SPAM]"));
+    /// let mut actual = ModificationResponse::builder();
+    /// actual.push(ChangeHeader::new(2, b"Subject", b"[SPAM]"));
+    ///
+    /// let differences = expected.contin().diff(&actual.contin());
+    /// assert!(matches!(differences[0], Difference::Missing(_)));
+    /// assert!(matches!(differences[1], Difference::WrongIndex { .. }));
+    /// ```
+    #[must_use]
+    pub fn diff(&self, actual: &Self) -> Vec<Difference> {
+        let mut unmatched: Vec<Option<&ModificationAction>> =
+            actual.modifications.iter().map(Some).collect();
+        let mut missing = Vec::new();
+        for expected in &self.modifications {
+            match unmatched.iter_mut().find(|m| *m == &Some(expected)) {
+                Some(matched) => *matched = None,
+                None => missing.push(expected),
+            }
+        }
+
+        let mut differences = Vec::new();
+        for expected in missing {
+            let moved = unmatched
+                .iter_mut()
+                .find(|m| m.is_some_and(|actual| same_header(expected, actual)));
+            match moved.and_then(Option::take) {
+                Some(actual) => differences.push(Difference::WrongIndex {
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                }),
+                None => differences.push(Difference::Missing(expected.clone())),
+            }
+        }
+        differences.extend(
+            unmatched
+                .into_iter()
+                .flatten()
+                .map(|actual| Difference::Unexpected(actual.clone())),
+        );
+
+        if !same_action(&self.final_action, &actual.final_action) {
+            differences.push(Difference::FinalAction {
+                expected: self.final_action.clone(),
+                actual: actual.final_action.clone(),
+            });
+        }
+        differences
+    }
+}
+
+/// Panic listing all differences, if `actual` does not match `expected`.
+///
+/// See [`ModificationResponse::diff`], or use
+/// [`assert_modifications!`](crate::assert_modifications) which calls this.
+///
+/// # Panics
+/// If the responses differ
+#[track_caller]
+pub fn assert_matches(expected: &ModificationResponse, actual: &ModificationResponse) {
+    let differences = expected.diff(actual);
+    if differences.is_empty() {
+        return;
+    }
+    let mut message = String::from("Modifications differ:");
+    for difference in &differences {
+        // Writing to a string does not fail
+        let _ = write!(message, "\n  {difference}");
+    }
+    panic!("{message}");
+}
+
+/// Assert that two [`ModificationResponse`]s match, ignoring the order of
+/// modifications.
+///
+/// On failure, every difference is listed on its own line.
+///
+/// ```should_panic
+/// use miltr_common::{
+///     assert_modifications,
+///     modifications::{headers::AddHeader, ModificationResponse},
+/// };
+///
+/// let mut expected = ModificationResponse::builder();
+/// expected.push(AddHeader::new(b"X-Scanned", b"yes"));
+///
+/// // Panics with "missing AddHeader X-Scanned: yes"
+/// assert_modifications!(expected.contin(), ModificationResponse::empty_continue());
+/// ```
+#[macro_export]
+macro_rules! assert_modifications {
+    ($expected:expr, $actual:expr $(,)?) => {
+        $crate::modifications::diff::assert_matches(&$expected, &$actual)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::{
+        actions::Reject,
+        modifications::{
+            headers::{AddHeader, InsertHeader},
+            recipients::AddRecipient,
+        },
+    };
+
+    #[test]
+    fn test_diff() {
+        let mut expected = ModificationResponse::builder();
+        expected.push(AddRecipient::new(b"<a@test.local>"));
+        expected.push(InsertHeader::new(0, b"X-First", b"1"));
+        expected.push(AddHeader::new(b"X-Scanned", b"yes"));
+        let mut actual = ModificationResponse::builder();
+        actual.push(AddHeader::new(b"X-Scanned", b"yes"));
+        actual.push(InsertHeader::new(1, b"X-First", b"1"));
+        actual.push(AddRecipient::new(b"<b@test.local>"));
+
+        let differences = expected.contin().diff(&actual.build(Reject));
+
+        let lines: Vec<_> = differences.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "missing AddRecipient <a@test.local>",
+                "wrong index, expected InsertHeader #0 X-First: 1 but got InsertHeader #1 X-First: 1",
+                "unexpected AddRecipient <b@test.local>",
+                "final action Reject instead of Continue",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_ignores_order() {
+        let mut expected = ModificationResponse::builder();
+        expected.push(AddHeader::new(b"X-A", b"a"));
+        expected.push(AddHeader::new(b"X-B", b"b"));
+        let mut actual = ModificationResponse::builder();
+        actual.push(AddHeader::new(b"X-B", b"b"));
+        actual.push(AddHeader::new(b"X-A", b"a"));
+
+        assert_modifications!(expected.contin(), actual.contin());
+    }
+}
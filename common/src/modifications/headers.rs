@@ -1,24 +1,30 @@
 //! Add, change or insert smtp headers
 
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec::Vec};
 
 use bytes::{BufMut, BytesMut};
 
-use crate::commands::Header;
+use crate::codes;
+use crate::commands::{name_eq_ignore_case, Header};
+#[cfg(feature = "decode-server")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-server")]
 use crate::error::STAGE_DECODING;
+#[cfg(feature = "decode-server")]
 use crate::{NotEnoughData, ProtocolError};
+#[cfg(feature = "decode-server")]
 use miltr_utils::ByteParsing;
 
 /// Add a header
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct AddHeader {
     header: Header,
 }
 
 impl AddHeader {
-    const CODE: u8 = b'h';
+    const CODE: u8 = codes::SMFIR_ADDHEADER;
 
     /// Create a Header from some bytes
     #[must_use]
@@ -30,17 +36,18 @@ impl AddHeader {
 
     /// The name of the header
     #[must_use]
-    pub fn name(&self) -> Cow<str> {
+    pub fn name(&self) -> Cow<'_, str> {
         self.header.name()
     }
 
     /// The value of the header
     #[must_use]
-    pub fn value(&self) -> Cow<str> {
+    pub fn value(&self) -> Cow<'_, str> {
         self.header.value()
     }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for AddHeader {
     const CODE: u8 = Self::CODE;
 
@@ -69,7 +76,8 @@ impl Writable for AddHeader {
 }
 
 /// Change an existing header
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct ChangeHeader {
     /// The index in a list of headers sharing `name` which to change
     ///
@@ -81,7 +89,7 @@ pub struct ChangeHeader {
 }
 
 impl ChangeHeader {
-    const CODE: u8 = b'm';
+    const CODE: u8 = codes::SMFIR_CHGHEADER;
 
     /// Create a Header from some bytes
     #[must_use]
@@ -94,13 +102,13 @@ impl ChangeHeader {
 
     /// The name of the header
     #[must_use]
-    pub fn name(&self) -> Cow<str> {
+    pub fn name(&self) -> Cow<'_, str> {
         self.header.name()
     }
 
     /// The value of the header
     #[must_use]
-    pub fn value(&self) -> Cow<str> {
+    pub fn value(&self) -> Cow<'_, str> {
         self.header.value()
     }
 
@@ -114,6 +122,7 @@ impl ChangeHeader {
     }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for ChangeHeader {
     const CODE: u8 = Self::CODE;
 
@@ -162,14 +171,15 @@ impl Writable for ChangeHeader {
 }
 
 /// Insert header at a specified position (modification action)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct InsertHeader {
     index: u32,
     header: Header,
 }
 
 impl InsertHeader {
-    const CODE: u8 = b'i';
+    const CODE: u8 = codes::SMFIR_INSHEADER;
 
     /// Create a Header from some bytes
     #[must_use]
@@ -182,13 +192,13 @@ impl InsertHeader {
 
     /// The name of the header
     #[must_use]
-    pub fn name(&self) -> Cow<str> {
+    pub fn name(&self) -> Cow<'_, str> {
         self.header.name()
     }
 
     /// The value of the header
     #[must_use]
-    pub fn value(&self) -> Cow<str> {
+    pub fn value(&self) -> Cow<'_, str> {
         self.header.value()
     }
 
@@ -199,6 +209,7 @@ impl InsertHeader {
     }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for InsertHeader {
     const CODE: u8 = Self::CODE;
 
@@ -240,6 +251,117 @@ impl Writable for InsertHeader {
     }
 }
 
+/// Keeps track of the order of headers received for a mail.
+///
+/// Modifications like [`InsertHeader`] and [`ChangeHeader`] address
+/// headers by index. Feed every received [`Header`] into this index to
+/// calculate those indices.
+///
+/// Note that postfix counts it's own `Received` header, which is not sent
+/// to the milter, as the first header. Use [`HeaderIndex::postfix`] to
+/// account for it when resolving an [`InsertPosition`].
+#[derive(Debug, Clone, Default)]
+pub struct HeaderIndex {
+    names: Vec<BytesMut>,
+    prepended: usize,
+}
+
+impl HeaderIndex {
+    /// An index for postfix, which prepends it's own `Received` header
+    /// without sending it to the milter
+    #[must_use]
+    pub fn postfix() -> Self {
+        Self::default().with_prepended(1)
+    }
+
+    /// Account for `count` headers the MTA prepends without sending them
+    /// to the milter, shifting the indices resolved by [`InsertPosition`].
+    ///
+    /// The positions returned by this index stay those of the received
+    /// headers.
+    #[must_use]
+    pub fn with_prepended(mut self, count: usize) -> Self {
+        self.prepended = count;
+        self
+    }
+
+    /// Record a received header
+    pub fn push(&mut self, header: &Header) {
+        self.names.push(BytesMut::from(header.name_bytes()));
+    }
+
+    /// The number of recorded headers
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Whether no headers have been recorded
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// How many headers named `name` have been recorded
+    #[must_use]
+    pub fn count(&self, name: &str) -> usize {
+        self.positions(name).count()
+    }
+
+    /// The position of the first header named `name` in all headers
+    #[must_use]
+    pub fn first_position(&self, name: &str) -> Option<usize> {
+        self.positions(name).next()
+    }
+
+    /// The position of the last header named `name` in all headers
+    #[must_use]
+    pub fn last_position(&self, name: &str) -> Option<usize> {
+        self.positions(name).last()
+    }
+
+    fn positions<'a>(&'a self, name: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.names
+            .iter()
+            .enumerate()
+            .filter(move |(_, n)| name_eq_ignore_case(n, name))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Where to insert a header, relative to the received headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertPosition {
+    /// Before all other headers
+    Top,
+    /// Directly after the last header with this name
+    AfterLast(String),
+    /// Directly before the first header with this name
+    BeforeFirst(String),
+}
+
+impl InsertPosition {
+    /// Resolve this position into an [`InsertHeader`] index.
+    ///
+    /// Headers the MTA prepended, see [`HeaderIndex::with_prepended`], are
+    /// counted in front of the received ones. [`InsertPosition::Top`] is
+    /// before those as well.
+    ///
+    /// If no header with the requested name is present in `headers`, this
+    /// falls back to [`InsertPosition::Top`].
+    #[must_use]
+    pub fn resolve(&self, headers: &HeaderIndex) -> u32 {
+        let position = match self {
+            Self::Top => None,
+            Self::AfterLast(name) => headers.last_position(name).map(|p| p + 1),
+            Self::BeforeFirst(name) => headers.first_position(name),
+        };
+        let position = position.map(|p| p + headers.prepended);
+
+        u32::try_from(position.unwrap_or_default()).unwrap_or(u32::MAX)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -247,6 +369,20 @@ mod test {
     use pretty_assertions::assert_eq;
     use rstest::rstest;
 
+    #[cfg(feature = "redact-debug")]
+    #[test]
+    fn test_debug_redacted() {
+        let debug = alloc::format!(
+            "{:?} {:?} {:?}",
+            AddHeader::new(b"X-Add", b"Salary for Alice"),
+            ChangeHeader::new(1, b"X-Change", b"Salary for Alice"),
+            InsertHeader::new(1, b"X-Insert", b"Salary for Alice"),
+        );
+
+        assert!(!debug.contains("Alice"), "{debug}");
+        assert!(debug.contains("X-Insert"), "{debug}");
+    }
+
     #[test]
     fn test_add_header() {
         let mut buffer = BytesMut::from("h");
@@ -258,6 +394,63 @@ mod test {
         assert_eq!(buffer, BytesMut::from("hname\0value\0"));
     }
 
+    fn received_headers() -> HeaderIndex {
+        let mut headers = HeaderIndex::default();
+        for name in ["Received", "received", "Subject", "From", "Received"] {
+            headers.push(&Header::new(name.as_bytes(), b"value"));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_header_index() {
+        let headers = received_headers();
+
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers.count("RECEIVED"), 3);
+        assert_eq!(headers.first_position("Received"), Some(0));
+        assert_eq!(headers.last_position("Received"), Some(4));
+        assert_eq!(headers.first_position("To"), None);
+    }
+
+    #[rstest]
+    #[case(InsertPosition::Top, 0)]
+    #[case(InsertPosition::AfterLast("Received".to_string()), 5)]
+    #[case(InsertPosition::AfterLast("subject".to_string()), 3)]
+    #[case(InsertPosition::BeforeFirst("From".to_string()), 3)]
+    #[case(InsertPosition::BeforeFirst("To".to_string()), 0)]
+    fn test_insert_position(#[case] position: InsertPosition, #[case] expected: u32) {
+        assert_eq!(position.resolve(&received_headers()), expected);
+    }
+
+    #[rstest]
+    #[case(InsertPosition::AfterLast("X-Missing".to_string()))]
+    #[case(InsertPosition::BeforeFirst("X-Missing".to_string()))]
+    fn test_insert_position_missing(#[case] position: InsertPosition) {
+        let top = InsertPosition::Top;
+
+        assert_eq!(position.resolve(&received_headers()), 0);
+        assert_eq!(
+            position.resolve(&HeaderIndex::postfix()),
+            top.resolve(&HeaderIndex::postfix())
+        );
+    }
+
+    #[rstest]
+    #[case(InsertPosition::Top, 0)]
+    #[case(InsertPosition::AfterLast("Received".to_string()), 2)]
+    #[case(InsertPosition::BeforeFirst("Subject".to_string()), 2)]
+    #[case(InsertPosition::BeforeFirst("To".to_string()), 0)]
+    fn test_insert_position_postfix(#[case] position: InsertPosition, #[case] expected: u32) {
+        // Postfix's own Received header is index 0, the milter never sees it
+        let mut headers = HeaderIndex::postfix();
+        headers.push(&Header::new(b"Received", b"from somewhere"));
+        headers.push(&Header::new(b"Subject", b"Hello"));
+
+        assert_eq!(headers.first_position("Subject"), Some(1));
+        assert_eq!(position.resolve(&headers), expected);
+    }
+
     #[rstest]
     #[case((1, String::from("name"), String::from("value")), BytesMut::from("m\0\0\0\x01name\0value\0"))]
     #[case((0, String::from("name"), String::from("value")), BytesMut::from("m\0\0\0\0name\0value\0"))]
@@ -4,9 +4,15 @@
 //! These are modification actions.
 
 pub mod body;
+pub mod conflict;
+pub mod diff;
 pub mod headers;
 pub mod quarantine;
 pub mod recipients;
+pub mod sender;
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
 
 use enum_dispatch::enum_dispatch;
 
@@ -15,14 +21,48 @@ use super::{
     ServerMessage,
 };
 
-use crate::encoding::Writable;
-use crate::{actions::Abort, optneg::Capability};
+use crate::encoding::{frame_len, Writable};
+use crate::{actions::Abort, optneg::Capability, TooMuchData};
 use bytes::BytesMut;
 
 use body::ReplaceBody;
-use headers::{AddHeader, ChangeHeader, InsertHeader};
+use conflict::{ConflictPolicy, LayeredModifications};
+use headers::{AddHeader, ChangeHeader, HeaderIndex, InsertHeader, InsertPosition};
 use quarantine::Quarantine;
-use recipients::{AddRecipient, DeleteRecipient};
+use recipients::{AddRecipient, AddRecipientPar, DeleteRecipient};
+use sender::ChangeFrom;
+
+/// A modification removed from a [`ModificationResponse`] as the client
+/// did not grant the capability for it, see
+/// [`ModificationResponse::remove_unsupported`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DroppedModification {
+    /// The modification removed
+    pub modification: ModificationAction,
+    /// The capability it requires but was not granted
+    pub missing: Capability,
+}
+
+/// Why a recipient modification was removed from a
+/// [`ModificationResponse`], see
+/// [`ModificationResponse::remove_invalid_recipients`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientIssue {
+    /// A recipient to delete was never given for the message
+    Unknown,
+    /// A recipient to add is not a plausible address
+    Malformed,
+}
+
+/// A recipient modification removed as invalid, see
+/// [`ModificationResponse::remove_invalid_recipients`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRecipientChange {
+    /// The modification removed
+    pub modification: ModificationAction,
+    /// What is wrong with it
+    pub issue: RecipientIssue,
+}
 
 /// A container for multiple modification requests towards the milter client.
 ///
@@ -57,6 +97,16 @@ impl ModificationResponse {
         }
     }
 
+    /// Create a builder combining the modifications of several layers,
+    /// e.g. of milters chained into one, resolving their conflicts with
+    /// `policy`.
+    ///
+    /// See [`LayeredModifications`] for what conflicts.
+    #[must_use]
+    pub fn layered(policy: ConflictPolicy) -> LayeredModifications {
+        LayeredModifications::new(policy)
+    }
+
     /// Create an empty `ModificationResponse` just to continue
     #[must_use]
     pub fn empty_continue() -> Self {
@@ -68,27 +118,81 @@ impl ModificationResponse {
 
     /// Filter modification actions in `self`, keep only those which have been
     /// allowed by the specified `capabilities`.
+    ///
+    /// See [`Self::remove_unsupported`] to learn what was removed.
     pub fn filter_mods_by_caps(&mut self, capabilities: Capability) {
-        self.modifications
-            .retain(|m| Self::mod_matches_caps(m, capabilities));
+        self.remove_unsupported(capabilities);
     }
 
-    /// Returns true, if a single modification action matches the set `capabilities`
-    fn mod_matches_caps(modification: &ModificationAction, capabilities: Capability) -> bool {
-        match modification {
-            ModificationAction::AddHeader(_) => capabilities.contains(Capability::SMFIF_ADDHDRS),
-            ModificationAction::ReplaceBody(_) => capabilities.contains(Capability::SMFIF_CHGBODY),
-            ModificationAction::AddRecipient(_) => capabilities.contains(Capability::SMFIF_ADDRCPT),
-            ModificationAction::DeleteRecipient(_) => {
-                capabilities.contains(Capability::SMFIF_DELRCPT)
-            }
-            ModificationAction::ChangeHeader(_) | ModificationAction::InsertHeader(_) => {
-                capabilities.contains(Capability::SMFIF_CHGHDRS)
-            }
-            ModificationAction::Quarantine(_) => {
-                capabilities.contains(Capability::SMFIF_QUARANTINE)
+    /// Remove modification actions not allowed by `capabilities`, returning
+    /// them in order together with the capability each lacks
+    pub fn remove_unsupported(&mut self, capabilities: Capability) -> Vec<DroppedModification> {
+        let mut dropped = Vec::new();
+        self.modifications.retain(|modification| {
+            let required = modification.required_capability();
+            if capabilities.contains(required) {
+                return true;
             }
-        }
+            dropped.push(DroppedModification {
+                modification: modification.clone(),
+                missing: required,
+            });
+            false
+        });
+        dropped
+    }
+
+    /// Remove recipient modifications MTAs would only warn about, returning
+    /// them in order together with their issue.
+    ///
+    /// Deleted recipients must be one of `recipients`, those given for the
+    /// message, compared as by [`ModificationResponseBuilder::add_recipients`].
+    /// Added recipients must be plausible addresses.
+    ///
+    /// ```
+    /// use miltr_common::modifications::{
+    ///     recipients::{AddRecipient, DeleteRecipient},
+    ///     ModificationResponse, RecipientIssue,
+    /// };
+    ///
+    /// let mut builder = ModificationResponse::builder();
+    /// builder.push(DeleteRecipient::new(b"<Bob@test.local>"));
+    /// builder.push(DeleteRecipient::new(b"<eve@test.local>"));
+    /// builder.push(AddRecipient::new(b"<alice@test.local"));
+    /// let mut response = builder.contin();
+    ///
+    /// let invalid = response.remove_invalid_recipients(&["<bob@test.local>"]);
+    /// assert_eq!(invalid[0].issue, RecipientIssue::Unknown);
+    /// assert_eq!(invalid[1].issue, RecipientIssue::Malformed);
+    /// assert_eq!(response.modifications().len(), 1);
+    /// ```
+    pub fn remove_invalid_recipients<R: AsRef<[u8]>>(
+        &mut self,
+        recipients: &[R],
+    ) -> Vec<InvalidRecipientChange> {
+        let mut invalid = Vec::new();
+        self.modifications.retain(|modification| {
+            let issue = match modification {
+                ModificationAction::DeleteRecipient(delete)
+                    if !recipients.iter().any(|r| delete.is_address(r.as_ref())) =>
+                {
+                    RecipientIssue::Unknown
+                }
+                ModificationAction::AddRecipient(add) if !add.is_well_formed() => {
+                    RecipientIssue::Malformed
+                }
+                ModificationAction::AddRecipientPar(add) if !add.is_well_formed() => {
+                    RecipientIssue::Malformed
+                }
+                _ => return true,
+            };
+            invalid.push(InvalidRecipientChange {
+                modification: modification.clone(),
+                issue,
+            });
+            false
+        });
+        invalid
     }
 
     /// Get the received modification actions
@@ -102,6 +206,94 @@ impl ModificationResponse {
     pub fn final_action(&self) -> &Action {
         &self.final_action
     }
+
+    /// Take the modification actions and the final action out of this
+    /// response, e.g. to re-assemble it with a builder
+    #[must_use]
+    pub fn into_parts(self) -> (Vec<ModificationAction>, Action) {
+        (self.modifications, self.final_action)
+    }
+
+    /// The number of bytes sending this response takes on the wire,
+    /// including the length prefix of every frame.
+    #[must_use]
+    pub fn encoded_len(&self) -> usize {
+        // Length prefix and code byte
+        const FRAME_OVERHEAD: usize = 4 + 1;
+
+        self.modifications
+            .iter()
+            .map(|m| FRAME_OVERHEAD + m.len())
+            .sum::<usize>()
+            + FRAME_OVERHEAD
+            + self.final_action.len()
+    }
+
+    /// Check that every frame of this response fits into `limit`, the
+    /// buffer size of the other end.
+    ///
+    /// # Errors
+    /// Errors with the first frame which is too large
+    pub fn check_frame_len(&self, limit: usize) -> Result<(), TooMuchData> {
+        for modification in &self.modifications {
+            frame_len(modification, limit)?;
+        }
+        frame_len(&self.final_action, limit)?;
+
+        Ok(())
+    }
+
+    /// Sort the modification actions into a canonical order, so two
+    /// responses with the same modifications compare equal.
+    ///
+    /// Header changes come first, ordered by index and name, then header
+    /// insertions and additions, then the sender change, then recipients
+    /// ordered lexicographically, deletions before additions. Quarantines
+    /// follow and body replacements come last.
+    ///
+    /// Quarantines and body replacements compare equal among themselves,
+    /// they keep their order only because the sort is stable. Body
+    /// replacements rely on that, together they form one body.
+    ///
+    /// The MTA applies modifications in order, so normalizing may change
+    /// the outcome for header modifications depending on each other.
+    pub fn normalize(&mut self) {
+        self.modifications
+            .sort_by(ModificationAction::canonical_cmp);
+    }
+
+    /// Remove all body replacements, keeping the original body
+    pub fn drop_body_replacement(&mut self) {
+        self.modifications
+            .retain(|m| !matches!(m, ModificationAction::ReplaceBody(_)));
+    }
+
+    /// Split body replacements into multiple frames fitting into `limit`.
+    ///
+    /// See [`ReplaceBody::split`].
+    pub fn split_body_replacement(&mut self, limit: usize) {
+        // Leave room for the code byte
+        let max_len = limit.min(u32::MAX as usize).saturating_sub(1);
+        if !self
+            .modifications
+            .iter()
+            .any(|m| matches!(m, ModificationAction::ReplaceBody(b) if b.len() > max_len))
+        {
+            return;
+        }
+
+        let modifications = core::mem::take(&mut self.modifications);
+        for modification in modifications {
+            match modification {
+                ModificationAction::ReplaceBody(body) => self.modifications.extend(
+                    body.split(max_len)
+                        .into_iter()
+                        .map(ModificationAction::from),
+                ),
+                modification => self.modifications.push(modification),
+            }
+        }
+    }
 }
 
 impl From<ModificationResponse> for Vec<ServerMessage> {
@@ -130,6 +322,124 @@ impl ModificationResponseBuilder {
         self.modifications.push(mod_action.into());
     }
 
+    /// Insert a header at a `position` relative to the received `headers`.
+    ///
+    /// ```
+    /// use miltr_common::commands::Header;
+    /// use miltr_common::modifications::{
+    ///     headers::{HeaderIndex, InsertPosition},
+    ///     ModificationResponse,
+    /// };
+    ///
+    /// let mut headers = HeaderIndex::postfix();
+    /// headers.push(&Header::new(b"Received", b"from somewhere"));
+    /// headers.push(&Header::new(b"Subject", b"Hello"));
+    ///
+    /// let mut builder = ModificationResponse::builder();
+    /// builder.insert_header_at(
+    ///     &headers,
+    ///     &InsertPosition::AfterLast("Received".to_string()),
+    ///     b"Authentication-Results",
+    ///     b"mx.example.com; spf=pass",
+    /// );
+    /// ```
+    pub fn insert_header_at(
+        &mut self,
+        headers: &HeaderIndex,
+        position: &InsertPosition,
+        name: &[u8],
+        value: &[u8],
+    ) {
+        self.push(InsertHeader::new(position.resolve(headers), name, value));
+    }
+
+    /// Add all `recipients`, skipping those already added by this builder
+    /// or earlier in `recipients`.
+    ///
+    /// Addresses are compared case-insensitively, ignoring angle brackets.
+    /// Returns how many recipients were added.
+    ///
+    /// ```
+    /// use miltr_common::modifications::ModificationResponse;
+    ///
+    /// let mut builder = ModificationResponse::builder();
+    /// let added = builder.add_recipients(["<a@example.com>", "A@example.com", "<b@example.com>"]);
+    /// assert_eq!(added, 2);
+    /// ```
+    pub fn add_recipients<I, R>(&mut self, recipients: I) -> usize
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[u8]>,
+    {
+        let mut added = 0;
+        for recipient in recipients {
+            let recipient = recipient.as_ref();
+            let duplicate = self.modifications.iter().any(|m| match m {
+                ModificationAction::AddRecipient(a) => a.is_address(recipient),
+                ModificationAction::AddRecipientPar(a) => a.is_address(recipient),
+                _ => false,
+            });
+            if !duplicate {
+                self.push(AddRecipient::new(recipient));
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Replace `original` by `recipients` in the same response, e.g. when
+    /// expanding an alias.
+    ///
+    /// `original` is deleted, unless it is part of `recipients`. The others
+    /// are added as by [`Self::add_recipients`], returning how many were
+    /// added.
+    pub fn replace_recipient<I, R>(&mut self, original: &[u8], recipients: I) -> usize
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[u8]>,
+    {
+        let recipients: Vec<R> = recipients.into_iter().collect();
+        let keep_original = recipients
+            .iter()
+            .any(|r| recipients::same_address(r.as_ref(), original));
+
+        if keep_original {
+            // Still a recipient, adding it again would be a duplicate
+            return self.add_recipients(
+                recipients
+                    .iter()
+                    .filter(|r| !recipients::same_address(r.as_ref(), original)),
+            );
+        }
+
+        self.push(DeleteRecipient::new(original));
+        self.add_recipients(recipients)
+    }
+
+    /// Change the envelope sender to `change`.
+    ///
+    /// A message has one sender, so this replaces a sender change pushed
+    /// before.
+    ///
+    /// ```
+    /// use miltr_common::modifications::{sender::ChangeFrom, ModificationResponse};
+    ///
+    /// let mut builder = ModificationResponse::builder();
+    /// builder.change_from(ChangeFrom::new(b"<first@example.com>"));
+    /// builder.change_from(ChangeFrom::new(b"<bounces@example.com>"));
+    ///
+    /// let response = builder.contin();
+    /// assert_eq!(
+    ///     response.modifications(),
+    ///     [ChangeFrom::new(b"<bounces@example.com>").into()]
+    /// );
+    /// ```
+    pub fn change_from(&mut self, change: ChangeFrom) {
+        self.modifications
+            .retain(|m| !matches!(m, ModificationAction::ChangeFrom(_)));
+        self.push(change);
+    }
+
     /// Send the `Abort` command to the milter client
     #[must_use]
     pub fn abort(self) -> ModificationResponse {
@@ -156,23 +466,22 @@ impl ModificationResponseBuilder {
 /// The container of possible milter modification actions
 #[enum_dispatch]
 #[cfg_attr(feature = "tracing", derive(strum::Display))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum ModificationAction {
     /// Add recipient
     AddRecipient,
     /// Delete recipient
     DeleteRecipient,
-    // /* add recipient (incl. ESMTP args) */
-    // currently not supported, feel free to implement
-    // SmfirAddrcptPar,
+    /// Add recipient with ESMTP parameters
+    AddRecipientPar,
     // /* 421: shutdown (internal to MTA) */
     // Not implemented in Milter
     // SmfirShutdown,
     /// Replace mail body
     ReplaceBody,
-    // /* change envelope sender (from) */
-    // currently not supported, feel free to implement
-    // SmfirChgfrom,
+    /// Change the envelope sender
+    ChangeFrom,
     // /* cause a connection failure */
     // currently not supported, feel free to implement. But why would you
     // need the connection to fail? Please, at least try to reason why you
@@ -193,3 +502,191 @@ pub enum ModificationAction {
     /// Quarantine this mail
     Quarantine,
 }
+
+impl ModificationAction {
+    /// The capability a client has to grant for this modification
+    #[must_use]
+    pub fn required_capability(&self) -> Capability {
+        match self {
+            Self::AddHeader(_) => Capability::SMFIF_ADDHDRS,
+            Self::ReplaceBody(_) => Capability::SMFIF_CHGBODY,
+            Self::AddRecipient(_) => Capability::SMFIF_ADDRCPT,
+            Self::AddRecipientPar(_) => Capability::SMFIF_ADDRCPT_PAR,
+            Self::DeleteRecipient(_) => Capability::SMFIF_DELRCPT,
+            Self::ChangeFrom(_) => Capability::SMFIF_CHGFROM,
+            Self::ChangeHeader(_) | Self::InsertHeader(_) => Capability::SMFIF_CHGHDRS,
+            Self::Quarantine(_) => Capability::SMFIF_QUARANTINE,
+        }
+    }
+
+    /// The position of this kind of modification in the canonical order
+    fn canonical_rank(&self) -> u8 {
+        match self {
+            Self::ChangeHeader(_) => 0,
+            Self::InsertHeader(_) => 1,
+            Self::AddHeader(_) => 2,
+            Self::ChangeFrom(_) => 3,
+            Self::DeleteRecipient(_) => 4,
+            Self::AddRecipient(_) => 5,
+            Self::AddRecipientPar(_) => 6,
+            Self::Quarantine(_) => 7,
+            Self::ReplaceBody(_) => 8,
+        }
+    }
+
+    /// Compare in the canonical order of [`ModificationResponse::normalize`].
+    ///
+    /// Body replacements and quarantines compare equal among themselves.
+    #[must_use]
+    pub fn canonical_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::ChangeHeader(a), Self::ChangeHeader(b)) => a.cmp(b),
+            (Self::InsertHeader(a), Self::InsertHeader(b)) => a.cmp(b),
+            (Self::AddHeader(a), Self::AddHeader(b)) => a.cmp(b),
+            (Self::ChangeFrom(a), Self::ChangeFrom(b)) => a.cmp(b),
+            (Self::DeleteRecipient(a), Self::DeleteRecipient(b)) => a.cmp(b),
+            (Self::AddRecipient(a), Self::AddRecipient(b)) => a.cmp(b),
+            (Self::AddRecipientPar(a), Self::AddRecipientPar(b)) => a.cmp(b),
+            _ => self.canonical_rank().cmp(&other.canonical_rank()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn response() -> ModificationResponse {
+        let mut builder = ModificationResponse::builder();
+        builder.push(AddHeader::new(b"X-Scanned", b"yes"));
+        builder.push(ReplaceBody::new(b"a replaced body"));
+        builder.contin()
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let frames: Vec<ServerMessage> = response().into();
+        let mut written = 0;
+        for frame in &frames {
+            let mut buffer = BytesMut::new();
+            frame.write(&mut buffer);
+            written += 4 + 1 + buffer.len();
+        }
+
+        assert_eq!(response().encoded_len(), written);
+    }
+
+    #[test]
+    fn test_check_frame_len() {
+        let response = response();
+
+        response.check_frame_len(16).expect("Response should fit");
+        let err = response
+            .check_frame_len(15)
+            .expect_err("Too large body not detected");
+        assert_eq!(err.code, Some(b'b'));
+    }
+
+    #[test]
+    fn test_split_body_replacement() {
+        let mut response = response();
+
+        response.split_body_replacement(15);
+
+        response
+            .check_frame_len(15)
+            .expect("Split response should fit");
+        let bodies: Vec<_> = response
+            .modifications()
+            .iter()
+            .filter_map(|m| match m {
+                ModificationAction::ReplaceBody(body) => Some(body.body().into_owned()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bodies, vec!["a replaced bod", "y"]);
+        assert!(matches!(
+            response.modifications()[0],
+            ModificationAction::AddHeader(_)
+        ));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut builder = ModificationResponse::builder();
+        builder.push(ReplaceBody::new(b"second"));
+        builder.push(AddRecipient::new(b"<b@test.local>"));
+        builder.push(ChangeHeader::new(2, b"X-A", b"a"));
+        builder.push(ReplaceBody::new(b"first"));
+        builder.push(AddRecipient::new(b"<a@test.local>"));
+        builder.push(ChangeHeader::new(1, b"X-B", b"b"));
+        builder.push(DeleteRecipient::new(b"<c@test.local>"));
+        let mut response = builder.contin();
+
+        response.normalize();
+
+        let mut expected = ModificationResponse::builder();
+        expected.push(ChangeHeader::new(1, b"X-B", b"b"));
+        expected.push(ChangeHeader::new(2, b"X-A", b"a"));
+        expected.push(DeleteRecipient::new(b"<c@test.local>"));
+        expected.push(AddRecipient::new(b"<a@test.local>"));
+        expected.push(AddRecipient::new(b"<b@test.local>"));
+        expected.push(ReplaceBody::new(b"second"));
+        expected.push(ReplaceBody::new(b"first"));
+        assert_eq!(response.modifications(), expected.contin().modifications());
+    }
+
+    #[test]
+    fn test_replace_recipient() {
+        let mut builder = ModificationResponse::builder();
+        builder.push(AddRecipient::new(b"<postmaster@test.local>"));
+
+        let added = builder.replace_recipient(
+            b"<team@test.local>",
+            [
+                "<a@test.local>",
+                "<Postmaster@test.local>",
+                " <A@TEST.local> ",
+            ],
+        );
+        let kept = builder.replace_recipient(b"<b@test.local>", ["b@test.local", "<c@test.local>"]);
+
+        assert_eq!(added, 1);
+        assert_eq!(kept, 1);
+        let mut expected = ModificationResponse::builder();
+        expected.push(AddRecipient::new(b"<postmaster@test.local>"));
+        expected.push(DeleteRecipient::new(b"<team@test.local>"));
+        expected.push(AddRecipient::new(b"<a@test.local>"));
+        expected.push(AddRecipient::new(b"<c@test.local>"));
+        assert_eq!(
+            builder.contin().modifications(),
+            expected.contin().modifications()
+        );
+    }
+
+    #[test]
+    fn test_drop_body_replacement() {
+        let mut response = response();
+
+        response.drop_body_replacement();
+
+        assert_eq!(response.modifications().len(), 1);
+    }
+
+    #[test]
+    fn test_remove_unsupported() {
+        let mut response = response();
+
+        let dropped = response.remove_unsupported(Capability::SMFIF_ADDHDRS);
+
+        assert_eq!(response.modifications().len(), 1);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].missing, Capability::SMFIF_CHGBODY);
+        assert!(matches!(
+            dropped[0].modification,
+            ModificationAction::ReplaceBody(_)
+        ));
+    }
+}
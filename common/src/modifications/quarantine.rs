@@ -1,23 +1,31 @@
 //! Carefully put this mail in a box and leave it
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String};
 
 use bytes::{BufMut, BytesMut};
 
+use crate::codes;
+#[cfg(feature = "decode-server")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-server")]
 use crate::ProtocolError;
 
 /// This quarantines the message into a holding pool defined by the MTA.
 /// (First implemented in Sendmail in version 8.13; offered to the milter by
 /// the `SMFIF_QUARANTINE` flag in "actions" of `SMFIC_OPTNEG`.)
-#[derive(Debug, Clone)]
+///
+/// The reason may be empty. On the wire this is a single null byte, which
+/// the MTA still treats as a quarantine request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Quarantine {
     /// Give a reason to the client why this was quarantined
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     reason: BytesMut,
 }
 
 impl Quarantine {
-    const CODE: u8 = b'q';
+    const CODE: u8 = codes::SMFIR_QUARANTINE;
 
     /// Quarantine with the given message
     #[must_use]
@@ -27,17 +35,37 @@ impl Quarantine {
         }
     }
 
+    /// Quarantine without giving a reason
+    #[must_use]
+    pub fn without_reason() -> Self {
+        Self {
+            reason: BytesMut::new(),
+        }
+    }
+
     /// Give a reason to the client why this was quarantined
+    ///
+    /// This is an empty string if no reason was given.
     #[must_use]
-    pub fn reason(&self) -> Cow<str> {
+    pub fn reason(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.reason)
     }
+
+    /// Whether a non-empty reason was given
+    #[must_use]
+    pub fn has_reason(&self) -> bool {
+        !self.reason.is_empty()
+    }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for Quarantine {
     const CODE: u8 = Self::CODE;
 
-    fn parse(buffer: BytesMut) -> Result<Self, ProtocolError> {
+    fn parse(mut buffer: BytesMut) -> Result<Self, ProtocolError> {
+        if buffer.last() == Some(&0) {
+            buffer.truncate(buffer.len() - 1);
+        }
         Ok(Self { reason: buffer })
     }
 }
@@ -63,6 +91,10 @@ impl Writable for Quarantine {
 #[cfg(test)]
 mod test {
     use super::*;
+    use pretty_assertions::assert_eq;
+    #[cfg(feature = "decode-server")]
+    use rstest::rstest;
+
     #[test]
     fn test_quarantine() {
         let mut buffer = BytesMut::from("");
@@ -73,4 +105,39 @@ mod test {
 
         assert_eq!(buffer, BytesMut::from("Invalid Input\0"));
     }
+
+    #[test]
+    fn test_quarantine_without_reason() {
+        let mut buffer = BytesMut::new();
+        let quarantine = Quarantine::without_reason();
+        quarantine.write(&mut buffer);
+
+        assert_eq!(buffer, BytesMut::from("\0"));
+        assert_eq!(quarantine.len(), buffer.len());
+        assert!(!quarantine.has_reason());
+        assert_eq!(quarantine.reason(), "");
+    }
+
+    #[cfg(feature = "decode-server")]
+    #[rstest]
+    #[case(b"Invalid Input\0", "Invalid Input")]
+    #[case(b"Invalid Input", "Invalid Input")]
+    #[case(b"\0", "")]
+    #[case(b"", "")]
+    fn test_parse(#[case] input: &[u8], #[case] expected: &str) {
+        let quarantine = Quarantine::parse(BytesMut::from(input)).expect("Failed parsing");
+
+        assert_eq!(quarantine.reason(), expected);
+        assert_eq!(quarantine.has_reason(), !expected.is_empty());
+    }
+
+    #[cfg(feature = "decode-server")]
+    #[test]
+    fn test_roundtrip_without_reason() {
+        let mut buffer = BytesMut::new();
+        Quarantine::without_reason().write(&mut buffer);
+
+        let parsed = Quarantine::parse(buffer).expect("Failed parsing");
+        assert!(!parsed.has_reason());
+    }
 }
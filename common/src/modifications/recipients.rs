@@ -1,23 +1,33 @@
 //! Add or delete recipients
 
-use std::borrow::Cow;
+use alloc::{
+    borrow::Cow,
+    string::{String, ToString},
+};
 
 use bytes::{BufMut, BytesMut};
 
+use crate::codes;
+use crate::commands::{EsmtpArgs, EsmtpArgsError};
+#[cfg(feature = "decode-server")]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(feature = "decode-server")]
 use crate::{InvalidData, ProtocolError};
+#[cfg(feature = "decode-server")]
 use miltr_utils::ByteParsing;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 
 ///Does not change To in Header
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct AddRecipient {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     recipient: BytesMut,
 }
 
 impl AddRecipient {
-    const CODE: u8 = b'+';
+    const CODE: u8 = codes::SMFIR_ADDRCPT;
 
     /// Add the specified recipient
     #[must_use]
@@ -29,11 +39,69 @@ impl AddRecipient {
 
     /// The recipient to add
     #[must_use]
-    pub fn recipient(&self) -> Cow<str> {
+    pub fn recipient(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.recipient)
     }
+
+    /// Whether this adds the same address as `recipient`, see
+    /// [`same_address`]
+    pub(crate) fn is_address(&self, recipient: &[u8]) -> bool {
+        same_address(&self.recipient, recipient)
+    }
+
+    /// Whether the recipient is a plausible address, see [`well_formed`]
+    pub(crate) fn is_well_formed(&self) -> bool {
+        well_formed(&self.recipient)
+    }
+}
+
+/// Whether `recipient` is plausible as address to add.
+///
+/// It must not be empty, contain control characters, unquoted spaces or
+/// angle brackets other than a pair surrounding it. This catches paths
+/// mangled by a bug, not addresses the MTA will not deliver to.
+pub(crate) fn well_formed(recipient: &[u8]) -> bool {
+    let address = address(recipient);
+    let mut quoted = false;
+    !address.is_empty()
+        && address.iter().all(|&b| match b {
+            b'"' => {
+                quoted = !quoted;
+                true
+            }
+            b'<' | b'>' => false,
+            b' ' => quoted,
+            b => !b.is_ascii_control(),
+        })
+        && !quoted
 }
 
+/// Whether two recipients name the same address.
+///
+/// Surrounding whitespace and angle brackets are ignored and addresses are
+/// compared case-insensitively, as MTAs warn about such duplicates.
+pub(crate) fn same_address(a: &[u8], b: &[u8]) -> bool {
+    address(a).eq_ignore_ascii_case(address(b))
+}
+
+/// The address part of a recipient, without brackets
+fn address(recipient: &[u8]) -> &[u8] {
+    let start = recipient
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(recipient.len());
+    let end = recipient
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |e| e + 1);
+    let recipient = &recipient[start..end];
+    recipient
+        .strip_prefix(b"<")
+        .and_then(|r| r.strip_suffix(b">"))
+        .unwrap_or(recipient)
+}
+
+#[cfg(feature = "decode-server")]
 impl Parsable for AddRecipient {
     const CODE: u8 = Self::CODE;
 
@@ -69,14 +137,146 @@ impl Writable for AddRecipient {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Add a recipient with ESMTP parameters.
+///
+/// Needs [`Capability::SMFIF_ADDRCPT_PAR`](crate::optneg::Capability::SMFIF_ADDRCPT_PAR).
+/// Does not change To in Header.
+///
+/// ```
+/// use miltr_common::{
+///     commands::{EsmtpArgs, Notify},
+///     modifications::recipients::AddRecipientPar,
+/// };
+///
+/// let args = EsmtpArgs::builder()
+///     .notify(Notify::FAILURE)
+///     .build()
+///     .expect("Valid parameters");
+/// let add = AddRecipientPar::new(b"<bob@example.com>", &args);
+/// assert_eq!(add.esmtp_args(), Ok(args));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
+pub struct AddRecipientPar {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
+    recipient: BytesMut,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::optional_bytes))]
+    args: Option<BytesMut>,
+}
+
+impl AddRecipientPar {
+    const CODE: u8 = codes::SMFIR_ADDRCPT_PAR;
+
+    /// Add the specified recipient with `args`, sent without parameters if
+    /// `args` are empty
+    #[must_use]
+    pub fn new(recipient: &[u8], args: &EsmtpArgs) -> Self {
+        let args = args.to_string();
+        Self {
+            recipient: BytesMut::from_iter(recipient),
+            args: (!args.is_empty()).then(|| BytesMut::from(args.as_bytes())),
+        }
+    }
+
+    /// The recipient to add
+    #[must_use]
+    pub fn recipient(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.recipient)
+    }
+
+    /// The ESMTP parameters as sent on the wire, `None` if none are sent
+    #[must_use]
+    pub fn args(&self) -> Option<Cow<'_, str>> {
+        self.args.as_deref().map(String::from_utf8_lossy)
+    }
+
+    /// The validated ESMTP parameters, empty if none are sent.
+    ///
+    /// # Errors
+    /// If the parameters received are invalid
+    pub fn esmtp_args(&self) -> Result<EsmtpArgs, EsmtpArgsError> {
+        self.args()
+            .map_or_else(|| Ok(EsmtpArgs::default()), |args| EsmtpArgs::parse(&args))
+    }
+
+    /// Whether this adds the same address as `recipient`, see
+    /// [`same_address`]
+    pub(crate) fn is_address(&self, recipient: &[u8]) -> bool {
+        same_address(&self.recipient, recipient)
+    }
+
+    /// Whether the recipient is a plausible address, see [`well_formed`]
+    pub(crate) fn is_well_formed(&self) -> bool {
+        well_formed(&self.recipient)
+    }
+}
+
+#[cfg(feature = "decode-server")]
+impl Parsable for AddRecipientPar {
+    const CODE: u8 = Self::CODE;
+
+    fn parse(mut buffer: BytesMut) -> Result<Self, ProtocolError> {
+        let Some(recipient) = buffer.delimited(0) else {
+            return Err(InvalidData::new(
+                "Received add recipient with parameters package without null byte terminating the recipient",
+                buffer,
+            )
+            .into());
+        };
+        if buffer.is_empty() {
+            return Ok(Self {
+                recipient,
+                args: None,
+            });
+        }
+        let Some(args) = buffer.delimited(0) else {
+            return Err(InvalidData::new(
+                "Received add recipient with parameters package without null byte terminating the parameters",
+                buffer,
+            )
+            .into());
+        };
+
+        Ok(Self {
+            recipient,
+            args: Some(args),
+        })
+    }
+}
+
+impl Writable for AddRecipientPar {
+    fn write(&self, buffer: &mut BytesMut) {
+        buffer.extend_from_slice(&self.recipient);
+        buffer.put_u8(0);
+        if let Some(args) = &self.args {
+            buffer.extend_from_slice(args);
+            buffer.put_u8(0);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.recipient.len() + 1 + self.args.as_ref().map_or(0, |args| args.len() + 1)
+    }
+
+    fn code(&self) -> u8 {
+        Self::CODE
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 /// Does not change To in Header
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct DeleteRecipient {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
     recipient: BytesMut,
 }
 
 impl DeleteRecipient {
-    const CODE: u8 = b'-';
+    const CODE: u8 = codes::SMFIR_DELRCPT;
 
     /// Delete the specified recipient
     #[must_use]
@@ -88,11 +288,18 @@ impl DeleteRecipient {
 
     /// The (exact) recipient to be deleted
     #[must_use]
-    pub fn recipient(&self) -> Cow<str> {
+    pub fn recipient(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.recipient)
     }
+
+    /// Whether this deletes the same address as `recipient`, see
+    /// [`same_address`]
+    pub(crate) fn is_address(&self, recipient: &[u8]) -> bool {
+        same_address(&self.recipient, recipient)
+    }
 }
 
+#[cfg(feature = "decode-server")]
 impl Parsable for DeleteRecipient {
     const CODE: u8 = Self::CODE;
 
@@ -144,6 +351,63 @@ mod test {
         assert_eq!(buffer, BytesMut::from("alex@gmail\0"));
     }
 
+    #[cfg(feature = "decode-server")]
+    #[test]
+    fn test_add_recipient_par() {
+        let args = EsmtpArgs::builder()
+            .orcpt("rfc822", "bob@test.local")
+            .build()
+            .expect("Valid parameters");
+        let mut buffer = BytesMut::new();
+        let add_rcpt = AddRecipientPar::new(b"<bob@test.local>", &args);
+        add_rcpt.write(&mut buffer);
+
+        assert_eq!(buffer.len(), add_rcpt.len());
+        assert_eq!(
+            buffer,
+            BytesMut::from("<bob@test.local>\0ORCPT=rfc822;bob@test.local\0")
+        );
+        let parsed = AddRecipientPar::parse(buffer).expect("Failed parsing");
+        assert_eq!(parsed, add_rcpt);
+
+        let invalid = BytesMut::from("<bob@test.local>\0NOTIFY=ALWAYS\0");
+        let parsed = AddRecipientPar::parse(invalid).expect("Failed parsing");
+        assert_eq!(parsed.args().as_deref(), Some("NOTIFY=ALWAYS"));
+        assert!(parsed.esmtp_args().is_err());
+    }
+
+    #[cfg(feature = "decode-server")]
+    #[test]
+    fn test_add_recipient_par_without_args() {
+        let parsed =
+            AddRecipientPar::parse(BytesMut::from("<bob@test.local>\0")).expect("Failed parsing");
+        assert_eq!(parsed.args(), None);
+        assert_eq!(parsed.esmtp_args(), Ok(EsmtpArgs::default()));
+
+        let mut buffer = BytesMut::new();
+        let add_rcpt = AddRecipientPar::new(b"<bob@test.local>", &EsmtpArgs::default());
+        add_rcpt.write(&mut buffer);
+        assert_eq!(buffer.len(), add_rcpt.len());
+        assert_eq!(buffer, BytesMut::from("<bob@test.local>\0"));
+        assert_eq!(add_rcpt, parsed);
+    }
+
+    #[test]
+    fn test_well_formed() {
+        assert!(well_formed(b"<bob@test.local>"));
+        assert!(well_formed(b"postmaster"));
+        assert!(well_formed(b"\"bob smith\"@test.local"));
+
+        assert!(!well_formed(b""));
+        assert!(!well_formed(b"<>"));
+        assert!(!well_formed(b"  "));
+        assert!(!well_formed(b"bob smith@test.local"));
+        assert!(!well_formed(b"<bob@test.local"));
+        assert!(!well_formed(b"<<bob@test.local>>"));
+        assert!(!well_formed(b"bob@test.local\r\nRCPT TO:<eve@test.local>"));
+        assert!(!well_formed(b"\"bob@test.local"));
+    }
+
     #[test]
     fn test_delete_recipient() {
         let mut buffer = BytesMut::new();
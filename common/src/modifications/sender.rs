@@ -0,0 +1,178 @@
+//! Change the envelope sender
+
+use alloc::{
+    borrow::Cow,
+    string::{String, ToString},
+};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::codes;
+use crate::commands::{EsmtpArgs, EsmtpArgsError};
+#[cfg(feature = "decode-server")]
+use crate::decoding::Parsable;
+use crate::encoding::Writable;
+#[cfg(feature = "decode-server")]
+use crate::{InvalidData, ProtocolError};
+#[cfg(feature = "decode-server")]
+use miltr_utils::ByteParsing;
+
+/// Change the envelope sender, optionally with ESMTP parameters.
+///
+/// Needs [`Capability::SMFIF_CHGFROM`](crate::optneg::Capability::SMFIF_CHGFROM).
+/// Does not change From in Header.
+///
+/// ```
+/// use miltr_common::{
+///     commands::EsmtpArgs,
+///     modifications::sender::ChangeFrom,
+/// };
+///
+/// let args = EsmtpArgs::builder()
+///     .envid("QQ314159")
+///     .build()
+///     .expect("Valid parameters");
+/// let change = ChangeFrom::new(b"<bounces@example.com>").with_args(&args);
+/// assert_eq!(change.sender(), "<bounces@example.com>");
+/// assert_eq!(change.esmtp_args(), Ok(args));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
+pub struct ChangeFrom {
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::bytes))]
+    sender: BytesMut,
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::optional_bytes))]
+    args: Option<BytesMut>,
+}
+
+impl ChangeFrom {
+    const CODE: u8 = codes::SMFIR_CHGFROM;
+
+    /// Change the envelope sender to `sender`
+    #[must_use]
+    pub fn new(sender: &[u8]) -> Self {
+        Self {
+            sender: BytesMut::from_iter(sender),
+            args: None,
+        }
+    }
+
+    /// Send `args` along with the new sender
+    #[must_use]
+    pub fn with_args(mut self, args: &EsmtpArgs) -> Self {
+        self.args = Some(BytesMut::from(args.to_string().as_bytes()));
+        self
+    }
+
+    /// The new envelope sender
+    #[must_use]
+    pub fn sender(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.sender)
+    }
+
+    /// The ESMTP parameters as sent on the wire, `None` if none are sent
+    #[must_use]
+    pub fn args(&self) -> Option<Cow<'_, str>> {
+        self.args.as_deref().map(String::from_utf8_lossy)
+    }
+
+    /// The validated ESMTP parameters, empty if none are sent.
+    ///
+    /// # Errors
+    /// If the parameters received are invalid
+    pub fn esmtp_args(&self) -> Result<EsmtpArgs, EsmtpArgsError> {
+        self.args()
+            .map_or_else(|| Ok(EsmtpArgs::default()), |args| EsmtpArgs::parse(&args))
+    }
+}
+
+#[cfg(feature = "decode-server")]
+impl Parsable for ChangeFrom {
+    const CODE: u8 = Self::CODE;
+
+    fn parse(mut buffer: BytesMut) -> Result<Self, ProtocolError> {
+        let Some(sender) = buffer.delimited(0) else {
+            return Err(InvalidData::new(
+                "Received change from package without null byte terminating the sender",
+                buffer,
+            )
+            .into());
+        };
+        if buffer.is_empty() {
+            return Ok(Self { sender, args: None });
+        }
+        let Some(args) = buffer.delimited(0) else {
+            return Err(InvalidData::new(
+                "Received change from package without null byte terminating the parameters",
+                buffer,
+            )
+            .into());
+        };
+
+        Ok(Self {
+            sender,
+            args: Some(args),
+        })
+    }
+}
+
+impl Writable for ChangeFrom {
+    fn write(&self, buffer: &mut BytesMut) {
+        buffer.extend_from_slice(&self.sender);
+        buffer.put_u8(0);
+        if let Some(args) = &self.args {
+            buffer.extend_from_slice(args);
+            buffer.put_u8(0);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.sender.len() + 1 + self.args.as_ref().map_or(0, |args| args.len() + 1)
+    }
+
+    fn code(&self) -> u8 {
+        Self::CODE
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(all(test, feature = "decode-server"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_change_from() {
+        let mut buffer = BytesMut::new();
+        let change = ChangeFrom::new(b"<bounces@test.local>");
+        change.write(&mut buffer);
+
+        assert_eq!(buffer.len(), change.len());
+        assert_eq!(buffer, BytesMut::from("<bounces@test.local>\0"));
+        let parsed = ChangeFrom::parse(buffer).expect("Failed parsing");
+        assert_eq!(parsed, change);
+        assert_eq!(parsed.esmtp_args(), Ok(EsmtpArgs::default()));
+    }
+
+    #[test]
+    fn test_change_from_args() {
+        let args = EsmtpArgs::builder()
+            .param("SIZE", Some("1024"))
+            .build()
+            .expect("Valid parameters");
+        let mut buffer = BytesMut::new();
+        let change = ChangeFrom::new(b"<bounces@test.local>").with_args(&args);
+        change.write(&mut buffer);
+
+        assert_eq!(buffer.len(), change.len());
+        assert_eq!(buffer, BytesMut::from("<bounces@test.local>\0SIZE=1024\0"));
+        let parsed = ChangeFrom::parse(buffer).expect("Failed parsing");
+        assert_eq!(parsed, change);
+
+        let invalid = BytesMut::from("<bounces@test.local>\0RET=SOME\0");
+        let parsed = ChangeFrom::parse(invalid).expect("Failed parsing");
+        assert!(parsed.esmtp_args().is_err());
+    }
+}
@@ -0,0 +1,524 @@
+//! Multiplex many milter sessions over a single transport
+//!
+//! This is not part of the milter protocol. Both ends have to be this crate
+//! and agree on it out-of-band, e.g. by a config flag. Connections to a
+//! foreign MTA or milter stay one session per transport.
+//!
+//! The connecting side (the MTA) creates a [`Connector`], the accepting side
+//! (the milter) an [`Acceptor`]. Each hands out [`Channel`]s, which are
+//! regular transports for the milter client and server. Every transport
+//! also needs its driver future polled, e.g. by spawning it.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    future::{self, Either},
+    io::{ReadHalf, WriteHalf},
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future, SinkExt, Stream, StreamExt,
+};
+
+/// Refuse frames larger than this
+const MAX_FRAME_SIZE: usize = 16 * 2_usize.pow(20);
+/// Channel id, frame kind and payload length
+const HEADER_LEN: usize = 9;
+/// Frames queued for sending, on top of one per channel
+const OUTBOUND_CAPACITY: usize = 64;
+/// Frames received for a channel, but not yet read
+const INBOUND_CAPACITY: usize = 16;
+/// Channels opened, but not yet accepted
+const ACCEPT_BACKLOG: usize = 16;
+
+/// Frame kinds on the wire
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const CLOSE: u8 = 2;
+
+/// What channels hand to the driver for sending
+enum Outbound {
+    /// Route data for `id` to `inbound`
+    Open(u32, Sender<Vec<u8>>),
+    Data(u32, Vec<u8>),
+    /// No more data from this end
+    Close(u32),
+    /// The channel was dropped, stop routing data to it. Closes it first
+    /// if it was still open.
+    Forget(u32, bool),
+}
+
+type Routes = Arc<Mutex<HashMap<u32, Sender<Vec<u8>>>>>;
+
+/// A single milter session multiplexed over a shared transport.
+///
+/// Reading returns EOF once the other end closed the channel. Closing only
+/// ends writing, replies can still be read until the channel is dropped.
+///
+/// Writing waits while the transport is behind. Received data not read is
+/// buffered up to a limit, beyond that receiving stops for all channels of
+/// the transport until this one is read or dropped.
+#[derive(Debug)]
+pub struct Channel {
+    id: u32,
+    outbound: Sender<Outbound>,
+    inbound: Receiver<Vec<u8>>,
+    /// Received, not yet read data
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    closed: bool,
+}
+
+impl std::fmt::Debug for Outbound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open(id, _) => write!(f, "Open({id})"),
+            Self::Data(id, data) => write!(f, "Data({id}, {} bytes)", data.len()),
+            Self::Close(id) => write!(f, "Close({id})"),
+            Self::Forget(id, close) => write!(f, "Forget({id}, {close})"),
+        }
+    }
+}
+
+impl Channel {
+    fn new(id: u32, outbound: Sender<Outbound>) -> (Self, Sender<Vec<u8>>) {
+        let (sender, inbound) = mpsc::channel(INBOUND_CAPACITY);
+        let channel = Self {
+            id,
+            outbound,
+            inbound,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+            closed: false,
+        };
+        (channel, sender)
+    }
+
+    /// The id of this channel on the shared transport
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Queue `outbound` for sending once the driver has room for it
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        outbound: impl FnOnce(u32) -> Outbound,
+    ) -> Poll<io::Result<()>> {
+        futures::ready!(self.outbound.poll_ready(cx)).map_err(|_| stopped())?;
+        let outbound = outbound(self.id);
+        Poll::Ready(self.outbound.start_send(outbound).map_err(|_| stopped()))
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "multiplexer stopped")
+}
+
+impl AsyncRead for Channel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.buffer_pos == self.buffer.len() {
+            match self.inbound.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => {
+                    self.buffer = data;
+                    self.buffer_pos = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let this = &mut *self;
+        let available = &this.buffer[this.buffer_pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        this.buffer_pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Channel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(MAX_FRAME_SIZE);
+        futures::ready!(self.poll_send(cx, |id| Outbound::Data(id, buf[..len].to_vec())))?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            futures::ready!(self.poll_send(cx, Outbound::Close))?;
+            self.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        // A new sender always has room for one message, even with the queue
+        // full. The driver may be gone already, nothing to clean up then.
+        let _ = self
+            .outbound
+            .clone()
+            .try_send(Outbound::Forget(self.id, !self.closed));
+    }
+}
+
+/// Opens channels to an [`Acceptor`]
+#[derive(Debug, Clone)]
+pub struct Connector {
+    outbound: Sender<Outbound>,
+    /// Locked while opening, for channels to be opened in order of their id
+    next_id: Arc<Mutex<u32>>,
+}
+
+impl Connector {
+    /// Multiplex channels over `transport`.
+    ///
+    /// The returned driver has to be polled to completion, e.g. by spawning
+    /// it. It finishes once the transport closes or this connector and all
+    /// its channels are dropped.
+    pub fn new<RW>(transport: RW) -> (Self, impl Future<Output = io::Result<()>>)
+    where
+        RW: AsyncRead + AsyncWrite,
+    {
+        let (outbound, queue) = mpsc::channel(OUTBOUND_CAPACITY);
+        let connector = Self {
+            outbound: outbound.clone(),
+            next_id: Arc::new(Mutex::new(1)),
+        };
+        // Only channels and the connector keep the driver running
+        drop(outbound);
+
+        (connector, drive(transport, queue, None))
+    }
+
+    /// Open a new channel
+    ///
+    /// # Errors
+    /// Errors if the driver stopped
+    pub fn open(&self) -> io::Result<Channel> {
+        let mut next_id = self.next_id.lock().unwrap_or_else(PoisonError::into_inner);
+        let id = *next_id;
+        *next_id = id
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("multiplexer ran out of channel ids"))?;
+
+        // The new sender of the channel has room for opening it
+        let (mut channel, inbound) = Channel::new(id, self.outbound.clone());
+        channel
+            .outbound
+            .try_send(Outbound::Open(id, inbound))
+            .map_err(|_| stopped())?;
+        Ok(channel)
+    }
+}
+
+/// Accepts channels opened by a [`Connector`]
+#[derive(Debug)]
+pub struct Acceptor {
+    incoming: Receiver<Channel>,
+}
+
+impl Acceptor {
+    /// Accept channels multiplexed over `transport`.
+    ///
+    /// The returned driver has to be polled to completion, e.g. by spawning
+    /// it. It finishes once the transport closes. Receiving stops while too
+    /// many channels wait to be accepted.
+    pub fn new<RW>(transport: RW) -> (Self, impl Future<Output = io::Result<()>>)
+    where
+        RW: AsyncRead + AsyncWrite,
+    {
+        let (outbound, queue) = mpsc::channel(OUTBOUND_CAPACITY);
+        let (incoming_sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+
+        let acceptor = Self { incoming };
+        (
+            acceptor,
+            drive(transport, queue, Some((outbound, incoming_sender))),
+        )
+    }
+}
+
+impl Stream for Acceptor {
+    type Item = Channel;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+/// Pump frames between `transport` and the channels.
+///
+/// `accept` holds what is needed to create channels for unknown ids, on the
+/// accepting side only.
+async fn drive<RW: AsyncRead + AsyncWrite>(
+    transport: RW,
+    queue: Receiver<Outbound>,
+    accept: Option<(Sender<Outbound>, Sender<Channel>)>,
+) -> io::Result<()> {
+    let (reader, writer) = transport.split();
+    let routes = Routes::default();
+
+    let read = Box::pin(read_frames(reader, Arc::clone(&routes), accept));
+    let write = Box::pin(write_frames(writer, queue, Arc::clone(&routes)));
+
+    let result = match future::select(read, write).await {
+        Either::Left((result, _)) | Either::Right((result, _)) => result,
+    };
+    // Channels still open read EOF
+    routes
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+    result
+}
+
+async fn read_frames<R: AsyncRead>(
+    mut reader: ReadHalf<R>,
+    routes: Routes,
+    mut accept: Option<(Sender<Outbound>, Sender<Channel>)>,
+) -> io::Result<()> {
+    let mut highest_accepted = 0;
+    loop {
+        let mut header = [0; HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let [i0, i1, i2, i3, kind, l0, l1, l2, l3] = header;
+        let id = u32::from_be_bytes([i0, i1, i2, i3]);
+        let len = u32::from_be_bytes([l0, l1, l2, l3]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "multiplexed frame exceeds the size limit",
+            ));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+
+        let lock = || routes.lock().unwrap_or_else(PoisonError::into_inner);
+        match kind {
+            OPEN => {
+                // Only new, higher ids open a channel, a connector never
+                // accepts one
+                let Some((outbound, incoming)) = accept.as_mut().filter(|_| id > highest_accepted)
+                else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected multiplexed channel opened",
+                    ));
+                };
+                highest_accepted = id;
+                let (channel, inbound) = Channel::new(id, outbound.clone());
+                // Nobody accepting channels anymore drops them right away
+                if incoming.send(channel).await.is_ok() {
+                    lock().insert(id, inbound);
+                }
+            }
+            DATA => {
+                // The channel may have been dropped locally
+                let route = lock().get(&id).cloned();
+                if let Some(mut route) = route {
+                    let _ = route.send(payload).await;
+                }
+            }
+            CLOSE => {
+                lock().remove(&id);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown multiplexed frame kind",
+                ))
+            }
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite>(
+    mut writer: WriteHalf<W>,
+    mut queue: Receiver<Outbound>,
+    routes: Routes,
+) -> io::Result<()> {
+    while let Some(outbound) = queue.next().await {
+        let (id, kind, payload) = match outbound {
+            Outbound::Open(id, inbound) => {
+                routes
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(id, inbound);
+                (id, OPEN, Vec::new())
+            }
+            Outbound::Data(id, payload) => (id, DATA, payload),
+            Outbound::Close(id) => (id, CLOSE, Vec::new()),
+            Outbound::Forget(id, close) => {
+                routes
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&id);
+                if !close {
+                    continue;
+                }
+                (id, CLOSE, Vec::new())
+            }
+        };
+
+        let len = u32::try_from(payload.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        writer.write_all(&id.to_be_bytes()).await?;
+        writer.write_all(&[kind]).await?;
+        writer.write_all(&len.to_be_bytes()).await?;
+        writer.write_all(&payload).await?;
+        writer.flush().await?;
+    }
+    writer.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, join, FutureExt};
+
+    use super::*;
+
+    #[test]
+    fn test_channels() {
+        block_on(async {
+            let (client_side, server_side) = tokio::io::duplex(1024);
+            let (client_side, server_side) = (compat(client_side), compat(server_side));
+            let (connector, connector_driver) = Connector::new(client_side);
+            let (mut acceptor, acceptor_driver) = Acceptor::new(server_side);
+
+            let echo = async move {
+                while let Some(mut channel) = acceptor.next().await {
+                    let mut received = Vec::new();
+                    channel
+                        .read_to_end(&mut received)
+                        .await
+                        .expect("Failed reading");
+                    received.reverse();
+                    channel.write_all(&received).await.expect("Failed writing");
+                    channel.close().await.expect("Failed closing");
+                }
+            };
+            let clients = async move {
+                let mut first = connector.open().expect("Failed opening");
+                let mut second = connector.open().expect("Failed opening");
+                drop(connector);
+
+                second.write_all(b"second").await.expect("Failed writing");
+                first.write_all(b"first").await.expect("Failed writing");
+                first.close().await.expect("Failed closing");
+                second.close().await.expect("Failed closing");
+
+                let (mut first_echo, mut second_echo) = (Vec::new(), Vec::new());
+                first
+                    .read_to_end(&mut first_echo)
+                    .await
+                    .expect("Failed reading");
+                second
+                    .read_to_end(&mut second_echo)
+                    .await
+                    .expect("Failed reading");
+                (first_echo, second_echo)
+            };
+
+            let ((first, second), connector_result, _, ()) =
+                join!(clients, connector_driver, acceptor_driver, echo);
+
+            connector_result.expect("Connector failed");
+            assert_eq!(first, b"tsrif");
+            assert_eq!(second, b"dnoces");
+        });
+    }
+
+    #[test]
+    fn test_write_waits_for_driver() {
+        let (client_side, _server_side) = tokio::io::duplex(1024);
+        let (connector, driver) = Connector::new(compat(client_side));
+        let mut channel = connector.open().expect("Failed opening");
+
+        // Opening and these fill the queue and the one message the channel
+        // may always queue on top
+        for _ in 0..OUTBOUND_CAPACITY {
+            channel
+                .write_all(b"x")
+                .now_or_never()
+                .expect("Write waited with room left")
+                .expect("Failed writing");
+        }
+        assert!(channel.write_all(b"x").now_or_never().is_none());
+
+        // Dropping is still queued, for the driver to forget the channel
+        drop(channel);
+        drop(connector);
+        block_on(driver).expect("Driver failed");
+    }
+
+    fn compat(stream: tokio::io::DuplexStream) -> impl AsyncRead + AsyncWrite {
+        Compat(stream)
+    }
+
+    /// Minimal tokio to futures io adapter, to not depend on `tokio-util`
+    struct Compat(tokio::io::DuplexStream);
+
+    impl AsyncRead for Compat {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut buf = tokio::io::ReadBuf::new(buf);
+            futures::ready!(tokio::io::AsyncRead::poll_read(
+                Pin::new(&mut self.0),
+                cx,
+                &mut buf
+            ))?;
+            Poll::Ready(Ok(buf.filled().len()))
+        }
+    }
+
+    impl AsyncWrite for Compat {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use super::ProtocolVersion;
+
 bitflags::bitflags! {
     /// What this milter can do.
     ///
@@ -34,12 +36,28 @@ impl Default for Capability {
 }
 
 impl Capability {
-    /// Merge `other` capabilities with `self`
+    /// Capabilities from a libmilter `SMFIF_*` value, e.g. the
+    /// `xxfi_flags` of a C milter.
     ///
-    /// Currently no version dependent merging implemented
+    /// Bits unknown to this crate are kept, as when received in an option
+    /// negotiation.
+    #[must_use]
+    pub fn from_smfif(flags: u32) -> Self {
+        Self::from_bits_retain(flags)
+    }
+
+    /// The libmilter `SMFIF_*` value of these capabilities
     #[must_use]
-    pub fn merge_regarding_version(self, _version: u32, other: Self) -> Self {
+    pub fn to_smfif(self) -> u32 {
+        self.bits()
+    }
+
+    /// Merge `other` capabilities with `self`, keeping only those
+    /// `version` supports
+    #[must_use]
+    pub fn merge_regarding_version(self, version: ProtocolVersion, other: Self) -> Self {
         self.intersection(other)
+            .intersection(version.supported_capabilities())
     }
 }
 
@@ -65,4 +83,34 @@ mod test {
 
         assert!(bitflags.is_none());
     }
+
+    #[test]
+    fn test_smfif_values() {
+        // As defined by libmilter's mfapi.h
+        let expected = [
+            (Capability::SMFIF_ADDHDRS, 0x0000_0001),
+            (Capability::SMFIF_CHGBODY, 0x0000_0002),
+            (Capability::SMFIF_ADDRCPT, 0x0000_0004),
+            (Capability::SMFIF_DELRCPT, 0x0000_0008),
+            (Capability::SMFIF_CHGHDRS, 0x0000_0010),
+            (Capability::SMFIF_QUARANTINE, 0x0000_0020),
+            (Capability::SMFIF_CHGFROM, 0x0000_0040),
+            (Capability::SMFIF_ADDRCPT_PAR, 0x0000_0080),
+        ];
+
+        for (capability, value) in expected {
+            assert_eq!(capability.to_smfif(), value);
+            assert_eq!(Capability::from_smfif(value), capability);
+        }
+        assert_eq!(expected.len(), Capability::all().iter().count());
+    }
+
+    #[test]
+    fn test_smfif_unknown_kept() {
+        // SMFIF_SETSYMLIST
+        let capability = Capability::from_smfif(0x0000_0101);
+
+        assert!(capability.contains(Capability::SMFIF_ADDHDRS));
+        assert_eq!(capability.to_smfif(), 0x0000_0101);
+    }
 }
@@ -1,4 +1,6 @@
-use std::{
+use alloc::{string::String, string::ToString, vec::Vec};
+
+use core::{
     borrow::BorrowMut,
     ops::{Index, IndexMut},
 };
@@ -9,6 +11,7 @@ use num_enum::IntoPrimitive;
 
 /// Macro stages requested by this milter server
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct MacroStages {
     stages: [Vec<String>; MACRO_STAGE_MAX_ID],
 }
@@ -64,7 +67,7 @@ impl MacroStages {
             for symbol in stage {
                 //                      The space separator
                 // The length of the macro string   |
-                accumulator += symbol.bytes().len() + 1;
+                accumulator += symbol.len() + 1;
             }
 
             // At the end, one space separator has been added to the accumulator
@@ -3,27 +3,38 @@
 mod capability;
 mod macros;
 mod protocol;
+mod version;
 
-use bytes::{Buf, BytesMut};
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
+use bytes::Buf;
+use bytes::BytesMut;
 use thiserror::Error;
 
+use crate::codes;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::decoding::Parsable;
 use crate::encoding::Writable;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::error::STAGE_DECODING;
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 use crate::{NotEnoughData, ProtocolError};
 
 pub use capability::Capability;
 pub use macros::{MacroStage, MacroStages};
 pub use protocol::Protocol;
+pub use version::ProtocolVersion;
 
 /// `SMFIC_OPTNEG`
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct OptNeg {
     /// The milter protocol version this implementation speaks
-    pub version: u32,
+    pub version: ProtocolVersion,
     /// Which modifications this milter may send to the client
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::capability))]
     pub capabilities: Capability,
     /// How the client should behave using this protocol
+    #[cfg_attr(any(test, feature = "arbitrary"), arbitrary(with = crate::arbitrary::protocol))]
     pub protocol: Protocol,
     /// Which macros this milter would like to get from the client
     pub macro_stages: MacroStages,
@@ -48,10 +59,38 @@ pub enum CompatibilityError {
     #[error("Received version {received} which is not compatible with {supported}")]
     UnsupportedVersion {
         /// The version received
-        received: u32,
+        received: ProtocolVersion,
         /// The version supported
-        supported: u32,
+        supported: ProtocolVersion,
     },
+    /// Thrown if required capabilities or protocol flags were not agreed on
+    #[error("Missing required capabilities {capabilities:?} and protocol flags {protocol:?}")]
+    MissingCapability {
+        /// The required capabilities that are absent
+        capabilities: Capability,
+        /// The required protocol flags that are absent
+        protocol: Protocol,
+    },
+    /// Thrown if options differ from those negotiated before
+    #[error("Negotiated options changed: {0:?}")]
+    Drift(OptNegDrift),
+}
+
+/// How options negotiated on a connection differ from those negotiated before.
+///
+/// See [`OptNeg::drift`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptNegDrift {
+    /// The previous and current version, if it changed
+    pub version: Option<(ProtocolVersion, ProtocolVersion)>,
+    /// Capabilities present now, but not before
+    pub capabilities_added: Capability,
+    /// Capabilities present before, but not now
+    pub capabilities_removed: Capability,
+    /// Protocol flags present now, but not before
+    pub protocol_added: Protocol,
+    /// Protocol flags present before, but not now
+    pub protocol_removed: Protocol,
 }
 
 impl OptNeg {
@@ -76,14 +115,16 @@ impl OptNeg {
     warning: milter inet:host:port: can't read SMFIC_DATA reply packet header: No such file or directory
     The remedy is to lower the Postfix milter_protocol version number. Postfix 2.8 and later will automatically turn off protocol features that the application's libmilter library does not expect. */
 
-    const VERSION: u32 = 6;
+    const VERSION: ProtocolVersion = ProtocolVersion::V6;
 
     const DATA_SIZE: usize = 4 + 4 + 4;
-    const CODE: u8 = b'O';
+    const CODE: u8 = codes::SMFIC_OPTNEG;
 
     /// Check whether `self` is compatible with `other`
     ///
     /// This includes comparing versions, the protocol and capabilities.
+    /// Capabilities and protocol flags are limited to those the older of
+    /// both versions supports.
     ///
     /// # Errors
     /// This errors when discovering an incompatibility between `self` and `other`
@@ -97,21 +138,93 @@ impl OptNeg {
 
         self.protocol = self
             .protocol
-            .merge_regarding_version(self.version, other.protocol);
+            .merge_regarding_version(other.version, other.protocol);
 
         self.capabilities = self
             .capabilities
-            .merge_regarding_version(self.version, other.capabilities);
+            .merge_regarding_version(other.version, other.capabilities);
 
         Ok(self)
     }
 
+    /// Check that `self` contains all of the given `capabilities` and
+    /// `protocol` flags.
+    ///
+    /// Use this on the result of [`Self::merge_compatible`] to make sure
+    /// features essential to a milter survived negotiation.
+    ///
+    /// # Errors
+    /// Errors with [`CompatibilityError::MissingCapability`] listing all
+    /// absent capabilities and protocol flags.
+    pub fn require(
+        &self,
+        capabilities: Capability,
+        protocol: Protocol,
+    ) -> Result<(), CompatibilityError> {
+        let missing_capabilities = capabilities.difference(self.capabilities);
+        let missing_protocol = protocol.difference(self.protocol);
+
+        if missing_capabilities.is_empty() && missing_protocol.is_empty() {
+            return Ok(());
+        }
+
+        Err(CompatibilityError::MissingCapability {
+            capabilities: missing_capabilities,
+            protocol: missing_protocol,
+        })
+    }
+
+    /// Compare `self` to options negotiated `previous`ly.
+    ///
+    /// Returns `None` if version, capabilities and protocol are unchanged.
+    /// Macro stages are not compared.
+    #[must_use]
+    pub fn drift(&self, previous: &Self) -> Option<OptNegDrift> {
+        let drift = OptNegDrift {
+            version: (self.version != previous.version).then_some((previous.version, self.version)),
+            capabilities_added: self.capabilities.difference(previous.capabilities),
+            capabilities_removed: previous.capabilities.difference(self.capabilities),
+            protocol_added: self.protocol.difference(previous.protocol),
+            protocol_removed: previous.protocol.difference(self.protocol),
+        };
+
+        let unchanged = drift.version.is_none()
+            && drift.capabilities_added.is_empty()
+            && drift.capabilities_removed.is_empty()
+            && drift.protocol_added.is_empty()
+            && drift.protocol_removed.is_empty();
+
+        (!unchanged).then_some(drift)
+    }
+
+    /// Whether the MTA understands skip as answer to body chunks, see
+    /// [`Protocol::SMFIP_SKIP`]
+    #[must_use]
+    pub fn supports_skip(&self) -> bool {
+        self.protocol.contains(Protocol::SMFIP_SKIP)
+    }
+
+    /// Whether the MTA also sends recipients it rejected itself, see
+    /// [`Protocol::SMFIP_RCPT_REJ`]
+    #[must_use]
+    pub fn wants_rejected_rcpts(&self) -> bool {
+        self.protocol.contains(Protocol::SMFIP_RCPT_REJ)
+    }
+
+    /// Whether header values are sent with the space following the colon,
+    /// see [`Protocol::SMFIP_HDR_LEADSPC`]
+    #[must_use]
+    pub fn leading_space_headers(&self) -> bool {
+        self.protocol.contains(Protocol::SMFIP_HDR_LEADSPC)
+    }
+
     // pub fn request_macro<S: ToString>(&mut self, stage: &MacroStage, macros: &[S]) {
     //     let index: u32 = stage.clone().into();
     //     self.macro_stages[index as usize] = macros.iter().map(ToString::to_string).collect();
     // }
 }
 
+#[cfg(any(feature = "decode-client", feature = "decode-server"))]
 impl Parsable for OptNeg {
     const CODE: u8 = Self::CODE;
 
@@ -130,7 +243,7 @@ impl Parsable for OptNeg {
 
         let mut version: [u8; 4] = [0; 4];
         version.copy_from_slice(&buffer[0..4]);
-        let version = u32::from_be_bytes(version);
+        let version = ProtocolVersion::from(u32::from_be_bytes(version));
 
         let mut capabilities: [u8; 4] = [0; 4];
         capabilities.copy_from_slice(&buffer[4..8]);
@@ -156,7 +269,7 @@ impl Parsable for OptNeg {
 
 impl Writable for OptNeg {
     fn write(&self, buffer: &mut BytesMut) {
-        buffer.extend_from_slice(&self.version.to_be_bytes());
+        buffer.extend_from_slice(&self.version.number().to_be_bytes());
         buffer.extend_from_slice(&self.capabilities.bits().to_be_bytes());
         buffer.extend_from_slice(&self.protocol.bits().to_be_bytes());
 
@@ -241,4 +354,68 @@ mod tests {
         assert_eq!(optneg.code(), b'O');
         assert_eq!(expected, buffer.to_vec());
     }
+
+    #[test]
+    fn test_require_present() {
+        let optneg = OptNeg::default();
+
+        optneg
+            .require(Capability::SMFIF_CHGBODY, Protocol::empty())
+            .expect("Default options should contain all capabilities");
+    }
+
+    #[test]
+    fn test_require_missing() {
+        let optneg = OptNeg {
+            capabilities: Capability::SMFIF_ADDHDRS,
+            protocol: Protocol::NR_HEADER,
+            ..Default::default()
+        };
+
+        let err = optneg
+            .require(
+                Capability::SMFIF_ADDHDRS | Capability::SMFIF_CHGBODY,
+                Protocol::NR_HEADER | Protocol::NR_BODY,
+            )
+            .expect_err("Missing capabilities not detected");
+
+        let CompatibilityError::MissingCapability {
+            capabilities,
+            protocol,
+        } = err
+        else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(capabilities, Capability::SMFIF_CHGBODY);
+        assert_eq!(protocol, Protocol::NR_BODY);
+    }
+
+    #[test]
+    fn test_drift_none() {
+        let optneg = OptNeg::default();
+
+        assert_eq!(optneg.drift(&OptNeg::default()), None);
+    }
+
+    #[test]
+    fn test_drift() {
+        let previous = OptNeg {
+            capabilities: Capability::SMFIF_ADDHDRS | Capability::SMFIF_CHGBODY,
+            protocol: Protocol::NR_HEADER,
+            ..Default::default()
+        };
+        let current = OptNeg {
+            capabilities: Capability::SMFIF_ADDHDRS | Capability::SMFIF_QUARANTINE,
+            protocol: Protocol::NR_HEADER | Protocol::NR_BODY,
+            ..Default::default()
+        };
+
+        let drift = current.drift(&previous).expect("Drift not detected");
+
+        assert_eq!(drift.version, None);
+        assert_eq!(drift.capabilities_added, Capability::SMFIF_QUARANTINE);
+        assert_eq!(drift.capabilities_removed, Capability::SMFIF_CHGBODY);
+        assert_eq!(drift.protocol_added, Protocol::NR_BODY);
+        assert_eq!(drift.protocol_removed, Protocol::empty());
+    }
 }
@@ -1,3 +1,4 @@
+use super::ProtocolVersion;
 use crate::commands::Command;
 
 bitflags::bitflags! {
@@ -74,6 +75,22 @@ impl Default for Protocol {
 }
 
 impl Protocol {
+    /// Protocol flags from a libmilter `SMFIP_*` value, e.g. as set by
+    /// `smfi_setprotocol` in a C milter.
+    ///
+    /// Bits unknown to this crate are kept, as when received in an option
+    /// negotiation.
+    #[must_use]
+    pub fn from_smfip(flags: u32) -> Self {
+        Self::from_bits_retain(flags)
+    }
+
+    /// The libmilter `SMFIP_*` value of these flags
+    #[must_use]
+    pub fn to_smfip(self) -> u32 {
+        self.bits()
+    }
+
     /// Whether `self` indicates that this command should be sent or not
     #[must_use]
     pub fn should_skip_send(&self, command: &Command) -> bool {
@@ -108,12 +125,61 @@ impl Protocol {
         }
     }
 
-    /// Merge `other` protocol with `self`
-    ///
-    /// Currently no version dependent merging implemented
+    /// Merge `other` protocol with `self`, keeping only flags `version`
+    /// supports
     #[must_use]
-    pub fn merge_regarding_version(self, _version: u32, other: Self) -> Self {
-        // No version dependent merging implemented yet
+    pub fn merge_regarding_version(self, version: ProtocolVersion, other: Self) -> Self {
         self.intersection(other)
+            .intersection(version.supported_protocol_flags())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smfip_values() {
+        // As defined by libmilter's mfdef.h
+        let expected = [
+            (Protocol::NO_CONNECT, 0x0000_0001),
+            (Protocol::NO_HELO, 0x0000_0002),
+            (Protocol::NO_MAIL, 0x0000_0004),
+            (Protocol::NO_RECIPIENT, 0x0000_0008),
+            (Protocol::NO_BODY, 0x0000_0010),
+            (Protocol::NO_HEADER, 0x0000_0020),
+            (Protocol::NO_END_OF_HEADER, 0x0000_0040),
+            (Protocol::NR_HEADER, 0x0000_0080),
+            (Protocol::NO_UNKNOWN, 0x0000_0100),
+            (Protocol::NO_DATA, 0x0000_0200),
+            (Protocol::SMFIP_SKIP, 0x0000_0400),
+            (Protocol::SMFIP_RCPT_REJ, 0x0000_0800),
+            (Protocol::NR_CONNECT, 0x0000_1000),
+            (Protocol::NR_HELO, 0x0000_2000),
+            (Protocol::NR_MAIL, 0x0000_4000),
+            (Protocol::NR_RECIPIENT, 0x0000_8000),
+            (Protocol::NR_DATA, 0x0001_0000),
+            (Protocol::NR_UNKNOWN, 0x0002_0000),
+            (Protocol::NR_END_OF_HEADER, 0x0004_0000),
+            (Protocol::NR_BODY, 0x0008_0000),
+            (Protocol::SMFIP_HDR_LEADSPC, 0x0010_0000),
+        ];
+
+        for (flag, value) in expected {
+            assert_eq!(flag.to_smfip(), value);
+            assert_eq!(Protocol::from_smfip(value), flag);
+        }
+        assert_eq!(expected.len(), Protocol::all().iter().count());
+    }
+
+    #[test]
+    fn test_smfip_combined() {
+        // SMFIP_NOCONNECT | SMFIP_NR_HDR | SMFIP_SKIP
+        let protocol = Protocol::from_smfip(0x0000_0481);
+
+        assert_eq!(
+            protocol,
+            Protocol::NO_CONNECT | Protocol::NR_HEADER | Protocol::SMFIP_SKIP
+        );
     }
 }
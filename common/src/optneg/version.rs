@@ -0,0 +1,172 @@
+use core::{
+    cmp::Ordering,
+    fmt::{self, Display},
+    hash::{Hash, Hasher},
+};
+
+use super::{Capability, Protocol};
+
+/// A milter protocol version, as sent in option negotiation.
+///
+/// Knows which capabilities and protocol flags each version supports, see
+/// [`Self::supported_capabilities`] and [`Self::supported_protocol_flags`].
+/// Versions compare by their number, `Unknown(6)` equals `V6`.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
+pub enum ProtocolVersion {
+    /// Sendmail 8.12, Postfix 2.3 up to 2.5
+    V2,
+    /// Sendmail 8.13
+    V4,
+    /// Sendmail 8.14, Postfix 2.6 and later
+    V6,
+    /// Any other version
+    Unknown(u32),
+}
+
+impl ProtocolVersion {
+    /// The version number sent on the wire
+    #[must_use]
+    pub fn number(self) -> u32 {
+        match self {
+            Self::V2 => 2,
+            Self::V4 => 4,
+            Self::V6 => 6,
+            Self::Unknown(number) => number,
+        }
+    }
+
+    /// The capabilities a milter may use with this version.
+    ///
+    /// Unknown versions support what the closest older known version does,
+    /// versions before 2 only the four original modifications.
+    #[must_use]
+    pub fn supported_capabilities(self) -> Capability {
+        match self.number() {
+            0..=1 => {
+                Capability::SMFIF_ADDHDRS
+                    | Capability::SMFIF_CHGBODY
+                    | Capability::SMFIF_ADDRCPT
+                    | Capability::SMFIF_DELRCPT
+            }
+            2..=5 => {
+                Self::Unknown(1).supported_capabilities()
+                    | Capability::SMFIF_CHGHDRS
+                    | Capability::SMFIF_QUARANTINE
+            }
+            _ => Capability::all(),
+        }
+    }
+
+    /// The protocol flags a client and milter may agree on with this
+    /// version.
+    ///
+    /// Unknown versions support what the closest older known version does.
+    #[must_use]
+    pub fn supported_protocol_flags(self) -> Protocol {
+        let v1 = Protocol::NO_CONNECT
+            | Protocol::NO_HELO
+            | Protocol::NO_MAIL
+            | Protocol::NO_RECIPIENT
+            | Protocol::NO_BODY
+            | Protocol::NO_HEADER;
+        match self.number() {
+            0..=1 => v1,
+            2..=3 => v1 | Protocol::NO_END_OF_HEADER,
+            4..=5 => {
+                v1 | Protocol::NO_END_OF_HEADER
+                    | Protocol::NR_HEADER
+                    | Protocol::NO_UNKNOWN
+                    | Protocol::NO_DATA
+            }
+            _ => Protocol::all(),
+        }
+    }
+}
+
+impl From<u32> for ProtocolVersion {
+    fn from(number: u32) -> Self {
+        match number {
+            2 => Self::V2,
+            4 => Self::V4,
+            6 => Self::V6,
+            number => Self::Unknown(number),
+        }
+    }
+}
+
+impl From<ProtocolVersion> for u32 {
+    fn from(version: ProtocolVersion) -> Self {
+        version.number()
+    }
+}
+
+impl PartialEq for ProtocolVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.number() == other.number()
+    }
+}
+
+impl Eq for ProtocolVersion {}
+
+impl PartialOrd for ProtocolVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ProtocolVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.number().cmp(&other.number())
+    }
+}
+
+impl Hash for ProtocolVersion {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.number().hash(state);
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_equals_known() {
+        assert_eq!(ProtocolVersion::Unknown(6), ProtocolVersion::V6);
+        assert!(matches!(ProtocolVersion::from(6), ProtocolVersion::V6));
+        assert!(ProtocolVersion::V2 < ProtocolVersion::Unknown(3));
+    }
+
+    #[test]
+    fn test_matrices_grow() {
+        let versions = [
+            ProtocolVersion::Unknown(1),
+            ProtocolVersion::V2,
+            ProtocolVersion::V4,
+            ProtocolVersion::V6,
+        ];
+
+        for pair in versions.windows(2) {
+            let (older, newer) = (pair[0], pair[1]);
+            assert!(newer
+                .supported_capabilities()
+                .contains(older.supported_capabilities()));
+            assert!(newer
+                .supported_protocol_flags()
+                .contains(older.supported_protocol_flags()));
+        }
+        assert!(!ProtocolVersion::V4
+            .supported_protocol_flags()
+            .contains(Protocol::SMFIP_SKIP));
+        assert!(ProtocolVersion::Unknown(7)
+            .supported_capabilities()
+            .contains(Capability::SMFIF_ADDRCPT_PAR));
+    }
+}
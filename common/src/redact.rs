@@ -0,0 +1,69 @@
+//! Keep mail content out of `Debug` output, see the `redact-debug` feature
+
+use core::fmt::{self, Debug};
+
+/// Whether `Debug` redacts mail content
+pub(crate) const REDACT: bool = cfg!(feature = "redact-debug");
+
+/// Debug `bytes` as is, or only their length and hash if `redact` is set
+pub(crate) fn bytes(bytes: &[u8], redact: bool) -> impl Debug + '_ {
+    DebugWith(move |f: &mut fmt::Formatter<'_>| {
+        if redact {
+            write!(f, "<{} bytes, fnv1a {:016x}>", bytes.len(), fnv1a(bytes))
+        } else {
+            escaped(bytes, f)
+        }
+    })
+}
+
+/// Like the `Debug` output of [`bytes::BytesMut`]
+fn escaped(bytes: &[u8], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("b\"")?;
+    for &b in bytes {
+        match b {
+            b'\n' => f.write_str("\\n")?,
+            b'\r' => f.write_str("\\r")?,
+            b'\t' => f.write_str("\\t")?,
+            b'\\' | b'"' => write!(f, "\\{}", char::from(b))?,
+            b'\0' => f.write_str("\\0")?,
+            0x20..=0x7f => write!(f, "{}", char::from(b))?,
+            _ => write!(f, "\\x{b:02x}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Debug anything using a closure
+pub(crate) struct DebugWith<F>(pub(crate) F);
+
+impl<F> Debug for DebugWith<F>
+where
+    F: Fn(&mut fmt::Formatter<'_>) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
+/// The 64 bit FNV-1a hash, stable across builds and platforms
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    #[test]
+    fn test_bytes() {
+        assert_eq!(format!("{:?}", bytes(b"secret", false)), r#"b"secret""#);
+        assert_eq!(
+            format!("{:?}", bytes(b"secret", true)),
+            "<6 bytes, fnv1a ab23f0eec020c951>"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Keep sensitive macro values, like SASL login names, out of logs
+
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::{
+    fmt::{self, Debug, Display},
+    hint::black_box,
+};
+
+/// A sensitive value, e.g. a SASL login name.
+///
+/// `Debug` and `Display` never show the value, so it does not end up in
+/// logs or traces by accident. Equality takes the same time for values of
+/// the same length, regardless of where they differ. Use
+/// [`Self::expose`] to get at the value on purpose.
+///
+/// ```
+/// use bytes::BytesMut;
+/// use miltr_common::secret::Secret;
+///
+/// let authen = Secret::new(BytesMut::from("alice"));
+/// assert_eq!(format!("{authen:?}"), "Secret(<redacted>)");
+/// assert_eq!(authen.expose_str(), "alice");
+/// assert!(authen.eq_bytes(b"alice"));
+/// ```
+#[derive(Clone, Default)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    /// Wrap `value`
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// The wrapped value, take care not to log it
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// Unwrap the value, take care not to log it
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: AsRef<[u8]>> Secret<T> {
+    /// The wrapped value as string, invalid utf-8 replaced.
+    ///
+    /// Take care not to log it.
+    #[must_use]
+    pub fn expose_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.0.as_ref())
+    }
+
+    /// Compare to `other` in constant time, for values of the same length
+    #[must_use]
+    pub fn eq_bytes(&self, other: &[u8]) -> bool {
+        constant_time_eq(self.0.as_ref(), other)
+    }
+}
+
+impl<T: AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_bytes(other.0.as_ref())
+    }
+}
+
+impl<T: AsRef<[u8]>> Eq for Secret<T> {}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl<T> Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Whether `a` equals `b`, visiting every byte if the lengths match
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    // Keep the compiler from returning on the first difference
+    black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use bytes::BytesMut;
+
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let secret = Secret::new(BytesMut::from("hunter2"));
+
+        assert_eq!(format!("{secret}"), "<redacted>");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret(<redacted>))");
+    }
+
+    #[test]
+    fn test_equality() {
+        let secret = Secret::new(BytesMut::from("alice"));
+
+        assert_eq!(secret, Secret::new(BytesMut::from("alice")));
+        assert!(!secret.eq_bytes(b"alicf"));
+        assert!(!secret.eq_bytes(b"alic"));
+    }
+}
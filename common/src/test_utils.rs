@@ -0,0 +1,47 @@
+//! Checks shared by the tests and fuzz harnesses of clients and servers
+//!
+//! This module is feature gated behind the `test-utils` flag.
+
+use bytes::BytesMut;
+
+/// Decode all frames in `data` with `decode`, checking invariants of the
+/// decoder:
+///
+/// - A single call to `decode` consumes at most one frame
+/// - Bytes after that frame are left untouched
+/// - An incomplete frame is not consumed
+///
+/// # Panics
+/// Panics if the decoder violates one of these invariants
+pub fn check_decode_invariants<T, E>(
+    data: &[u8],
+    mut decode: impl FnMut(&mut BytesMut) -> Result<Option<T>, E>,
+) {
+    let mut buffer = BytesMut::from(data);
+
+    loop {
+        let before = buffer.clone();
+        let result = decode(&mut buffer);
+
+        let consumed = before.len() - buffer.len();
+        assert_eq!(&before[consumed..], &buffer[..], "Leftover bytes modified");
+        let frame_len = before.get(..4).map(|prefix| {
+            4 + u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize
+        });
+
+        match result {
+            Ok(Some(_)) => assert_eq!(Some(consumed), frame_len, "Not exactly one frame consumed"),
+            Ok(None) => {
+                assert_eq!(consumed, 0, "Incomplete frame consumed");
+                return;
+            }
+            Err(_) => {
+                assert!(
+                    consumed == 0 || Some(consumed) == frame_len,
+                    "More than one frame consumed"
+                );
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Fixtures of the wire format of sendmail's libmilter.
+//!
+//! Each fixture is a frame as sendmail or libmilter put it on the wire,
+//! written by hand from their sources, not captured. It must parse, and
+//! encoding the parsed item again must give back the same bytes. This
+//! catches divergences like missing null terminators or index fields that
+//! a round trip through our own encoder would not.
+//!
+//! Only captured traffic makes this a differential test. Set
+//! `MILTR_LIBMILTER_DUMPS` to a directory of such dumps to check them. Files starting with `mta` hold frames sent by the MTA,
+//! files starting with `milter` frames sent by a libmilter filter, each
+//! frame with its 4 byte length prefix, back to back.
+
+#![cfg(all(feature = "decode-client", feature = "decode-server"))]
+
+use std::{env, fs, path::Path};
+
+use bytes::{Buf, BytesMut};
+use miltr_common::{
+    decoding::{ClientCommand, ClientControl, ClientFrameKind, ServerCommand},
+    encoding::Writable,
+};
+use pretty_assertions::assert_eq;
+
+/// Frames sent by sendmail to a milter, see `libmilter/docs/` and
+/// `sendmail/milter.c`
+const MTA_FRAMES: &[(&str, &[u8])] = &[
+    (
+        "optneg",
+        b"O\x00\x00\x00\x06\x00\x00\x01\xff\x00\x1f\xff\xff",
+    ),
+    (
+        "connect inet",
+        b"Cmail.example.com\x004\x00\x19192.0.2.1\x00",
+    ),
+    (
+        "connect inet6",
+        b"Cmail.example.com\x006\x00\x192001:db8::1\x00",
+    ),
+    ("connect unix", b"Clocalhost\x00L\x00\x00/var/run/smtp\x00"),
+    ("connect unknown", b"Clocalhost\x00U"),
+    ("helo", b"Hmail.example.com\x00"),
+    ("mail", b"M<sender@example.com>\x00"),
+    (
+        "mail with args",
+        b"M<sender@example.com>\x00SIZE=1024\x00BODY=8BITMIME\x00",
+    ),
+    ("rcpt", b"R<rcpt@example.com>\x00"),
+    ("rcpt with args", b"R<rcpt@example.com>\x00NOTIFY=NEVER\x00"),
+    ("header", b"LSubject\x00Hello there\x00"),
+    ("header empty value", b"LX-Empty\x00\x00"),
+    ("end of header", b"N"),
+    ("data", b"T"),
+    ("body", b"BHello\r\nWorld\r\n"),
+    ("end of body", b"E"),
+    ("unknown", b"UHELP\x00"),
+    ("abort", b"A"),
+    ("quit", b"Q"),
+    ("quit nc", b"K"),
+];
+
+/// A fixture name, the frame and the macros it holds
+type MacroFixture = (
+    &'static str,
+    &'static [u8],
+    &'static [(&'static str, &'static str)],
+);
+
+/// Macro frames sent by sendmail, only parsed as we never send macros
+const MTA_MACROS: &[MacroFixture] = &[
+    (
+        "macro connect",
+        b"DCj\x00mx.example.com\x00{daemon_name}\x00smtpd\x00",
+        &[("j", "mx.example.com"), ("{daemon_name}", "smtpd")],
+    ),
+    (
+        "macro mail",
+        b"DMi\x00ABC123\x00{auth_authen}\x00alice\x00",
+        &[("i", "ABC123"), ("{auth_authen}", "alice")],
+    ),
+];
+
+/// Frames sent by libmilter for the `smfi_*` calls of a filter, see
+/// `libmilter/smfi.c`
+const MILTER_FRAMES: &[(&str, &[u8])] = &[
+    (
+        "optneg",
+        b"O\x00\x00\x00\x06\x00\x00\x00\x01\x00\x00\x00\x00",
+    ),
+    ("continue", b"c"),
+    ("reject", b"r"),
+    ("discard", b"d"),
+    ("tempfail", b"t"),
+    ("skip", b"s"),
+    ("progress", b"p"),
+    ("addheader", b"hX-Scanned\x00yes\x00"),
+    ("insheader", b"i\x00\x00\x00\x01X-First\x00yes\x00"),
+    ("chgheader", b"m\x00\x00\x00\x02Subject\x00[SPAM] Hi\x00"),
+    ("chgheader delete", b"m\x00\x00\x00\x01Subject\x00\x00"),
+    ("addrcpt", b"+<bcc@example.com>\x00"),
+    ("addrcpt_par", b"2<bcc@example.com>\x00NOTIFY=NEVER\x00"),
+    ("addrcpt_par without args", b"2<bcc@example.com>\x00"),
+    ("delrcpt", b"-<rcpt@example.com>\x00"),
+    ("replbody", b"bNew body\r\n"),
+    ("quarantine", b"qSuspicious attachment\x00"),
+    ("chgfrom", b"e<bounces@example.com>\x00"),
+    (
+        "chgfrom with args",
+        b"e<bounces@example.com>\x00SIZE=1024\x00",
+    ),
+    ("replycode", b"y550 5.7.1 Go away\x00"),
+    ("replycode without message", b"y451 4.3.0\x00"),
+];
+
+/// Frames libmilter sends that we do not parse yet, with why.
+///
+/// Move a fixture to [`MILTER_FRAMES`] once it is fixed.
+const MILTER_DIVERGENCES: &[(&str, &[u8], &str)] = &[];
+
+/// Write `item` as a frame without the length prefix
+fn encode<W: Writable>(item: &W) -> Vec<u8> {
+    let mut buffer = BytesMut::with_capacity(item.len() + 1);
+    buffer.extend_from_slice(&[item.code()]);
+    item.write(&mut buffer);
+    buffer.to_vec()
+}
+
+/// Encode a parsed client command again, `None` for macros
+fn encode_client(command: ClientCommand) -> Option<Vec<u8>> {
+    Some(match command.into_kind() {
+        ClientFrameKind::Command(command) => encode(&command),
+        ClientFrameKind::Control(ClientControl::Abort(abort)) => encode(&abort),
+        ClientFrameKind::Control(ClientControl::OptNeg(optneg)) => encode(&optneg),
+        ClientFrameKind::Control(ClientControl::Quit(quit)) => encode(&quit),
+        ClientFrameKind::Control(ClientControl::QuitNc(quit_nc)) => encode(&quit_nc),
+        ClientFrameKind::Macro(_) => return None,
+    })
+}
+
+/// Encode a parsed server command again
+fn encode_server(command: ServerCommand) -> Vec<u8> {
+    match command {
+        ServerCommand::OptNeg(c) => encode(&c),
+        ServerCommand::Abort(c) => encode(&c),
+        ServerCommand::Continue(c) => encode(&c),
+        ServerCommand::Discard(c) => encode(&c),
+        ServerCommand::Reject(c) => encode(&c),
+        ServerCommand::Tempfail(c) => encode(&c),
+        ServerCommand::Skip(c) => encode(&c),
+        ServerCommand::Replycode(c) => encode(&c),
+        ServerCommand::Progress(c) => encode(&c),
+        ServerCommand::AddRecipient(c) => encode(&c),
+        ServerCommand::DeleteRecipient(c) => encode(&c),
+        ServerCommand::AddRecipientPar(c) => encode(&c),
+        ServerCommand::ReplaceBody(c) => encode(&c),
+        ServerCommand::AddHeader(c) => encode(&c),
+        ServerCommand::InsertHeader(c) => encode(&c),
+        ServerCommand::ChangeHeader(c) => encode(&c),
+        ServerCommand::Quarantine(c) => encode(&c),
+        ServerCommand::ChangeFrom(c) => encode(&c),
+    }
+}
+
+fn check_mta_frame(name: &str, frame: &[u8]) {
+    let command = ClientCommand::parse(BytesMut::from(frame))
+        .unwrap_or_else(|e| panic!("Failed to parse {name}: {e:?}"));
+    if let Some(encoded) = encode_client(command) {
+        assert_eq!(encoded, frame, "{name} differs after encoding");
+    }
+}
+
+fn check_milter_frame(name: &str, frame: &[u8]) {
+    let command = ServerCommand::parse(BytesMut::from(frame))
+        .unwrap_or_else(|e| panic!("Failed to parse {name}: {e:?}"));
+    assert_eq!(
+        encode_server(command),
+        frame,
+        "{name} differs after encoding"
+    );
+}
+
+#[test]
+fn test_mta_frames() {
+    for (name, frame) in MTA_FRAMES {
+        check_mta_frame(name, frame);
+    }
+}
+
+#[test]
+fn test_mta_macros() {
+    for (name, frame, expected) in MTA_MACROS {
+        let ClientCommand::Macro(parsed) = ClientCommand::parse(BytesMut::from(*frame))
+            .unwrap_or_else(|e| panic!("Failed to parse {name}: {e:?}"))
+        else {
+            panic!("{name} did not parse as macro");
+        };
+
+        assert_eq!(parsed.code, frame[1], "{name}");
+        let macros: Vec<(&[u8], &[u8])> = parsed.macros().collect();
+        let expected: Vec<(&[u8], &[u8])> = expected
+            .iter()
+            .map(|(k, v)| (k.as_bytes(), v.as_bytes()))
+            .collect();
+        assert_eq!(macros, expected, "{name}");
+    }
+}
+
+#[test]
+fn test_milter_frames() {
+    for (name, frame) in MILTER_FRAMES {
+        check_milter_frame(name, frame);
+    }
+}
+
+#[test]
+fn test_milter_divergences() {
+    for (name, frame, why) in MILTER_DIVERGENCES {
+        assert!(
+            ServerCommand::parse(BytesMut::from(*frame)).is_err(),
+            "{name} parses now ({why}), move it to MILTER_FRAMES"
+        );
+    }
+}
+
+#[test]
+fn test_dumps() {
+    let Some(dir) = env::var_os("MILTR_LIBMILTER_DUMPS") else {
+        return;
+    };
+    let entries = fs::read_dir(Path::new(&dir)).expect("Failed to read dump directory");
+    for entry in entries {
+        let path = entry.expect("Failed to read dump directory").path();
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let check: fn(&str, &[u8]) = if file_name.starts_with("mta") {
+            check_mta_frame
+        } else if file_name.starts_with("milter") {
+            check_milter_frame
+        } else {
+            continue;
+        };
+
+        let mut dump = BytesMut::from(&fs::read(&path).expect("Failed to read dump")[..]);
+        let mut index = 0;
+        while dump.has_remaining() {
+            assert!(dump.len() >= 4, "{file_name}: truncated length prefix");
+            let len = dump.get_u32() as usize;
+            assert!(dump.len() >= len, "{file_name}: truncated frame {index}");
+            let frame = dump.split_to(len);
+            check(&format!("{file_name} frame {index}"), &frame);
+            index += 1;
+        }
+    }
+}
@@ -18,3 +18,7 @@ sleep 20
 cd client
 cargo publish
 sleep 20
+
+# The facade crate last, it depends on all of the above
+cd "$(git rev-parse --show-toplevel)"
+cargo publish
@@ -0,0 +1,127 @@
+//! Benchmark the per-header overhead of answering with `Continue`.
+//!
+//! Compares a mail with hundreds of headers where each header is answered
+//! (using the pre-encoded continue frame) to one where `NR_HEADER` was
+//! negotiated and no answer is sent at all.
+
+// The criterion macros generate undocumented functions
+#![allow(missing_docs)]
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::{executor::block_on, io::Cursor, AsyncRead, AsyncWrite};
+
+use miltr_common::{
+    actions::{Action, Continue},
+    optneg::{Capability, Protocol},
+};
+use miltr_server::{Milter, Server};
+
+struct BenchMilter;
+
+#[async_trait]
+impl Milter for BenchMilter {
+    type Error = &'static str;
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        Ok(Continue.into())
+    }
+}
+
+/// Read from a fixed input, discard everything written
+struct Pipe {
+    input: Cursor<Vec<u8>>,
+    written: usize,
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.written += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn frame(buffer: &mut Vec<u8>, code: u8, payload: &[u8]) {
+    buffer.extend_from_slice(&u32::to_be_bytes(1 + payload.len() as u32));
+    buffer.push(code);
+    buffer.extend_from_slice(payload);
+}
+
+fn conversation(headers: usize, protocol: Protocol) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    let mut optneg = Vec::new();
+    optneg.extend_from_slice(&6_u32.to_be_bytes());
+    optneg.extend_from_slice(&Capability::all().bits().to_be_bytes());
+    optneg.extend_from_slice(&protocol.bits().to_be_bytes());
+    frame(&mut buffer, b'O', &optneg);
+
+    for i in 0..headers {
+        frame(
+            &mut buffer,
+            b'L',
+            format!("X-Bench-Header-{i}\0some header value\0").as_bytes(),
+        );
+    }
+    frame(&mut buffer, b'Q', &[]);
+
+    buffer
+}
+
+fn bench_headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("headers");
+
+    for headers in [100, 500] {
+        for (name, protocol) in [
+            ("continue", Protocol::empty()),
+            ("no_reply", Protocol::NR_HEADER),
+        ] {
+            let input = conversation(headers, protocol);
+            group.bench_with_input(BenchmarkId::new(name, headers), &input, |b, input| {
+                b.iter(|| {
+                    let mut milter = BenchMilter;
+                    let mut server = Server::default_postfix(&mut milter);
+                    let mut pipe = Pipe {
+                        input: Cursor::new(input.clone()),
+                        written: 0,
+                    };
+                    block_on(server.handle_connection(&mut pipe)).expect("Conversation failed");
+                    black_box(pipe.written)
+                });
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_headers);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+//! Benchmark reading large body chunks arriving in small TCP segments.
+//!
+//! Compares reserving read buffer space adaptively to reserving just the
+//! missing bytes of each frame. Before timing, how often the read buffer
+//! grew during a single conversation is printed for both strategies.
+
+// The criterion macros generate undocumented functions
+#![allow(missing_docs)]
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::{executor::block_on, AsyncRead, AsyncWrite};
+
+use miltr_common::{
+    actions::{Action, Continue},
+    commands::Body,
+    optneg::{Capability, Protocol},
+};
+use miltr_server::{Milter, Server};
+
+/// The size of a TCP segment on a typical ethernet link
+const SEGMENT: usize = 1460;
+/// The size of a body chunk as sent by postfix
+const CHUNK: usize = 65_535;
+
+/// Keeps all body chunks until the end of the mail, like a milter scanning
+/// the whole body at once
+#[derive(Default)]
+struct CollectingMilter {
+    chunks: Vec<Body>,
+}
+
+#[async_trait]
+impl Milter for CollectingMilter {
+    type Error = &'static str;
+
+    async fn body(&mut self, body: Body) -> Result<Action, Self::Error> {
+        self.chunks.push(body);
+        Ok(Continue.into())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.chunks.clear();
+        Ok(Continue.into())
+    }
+}
+
+/// Read from a fixed input in segments, discard everything written
+struct SegmentedPipe {
+    input: Vec<u8>,
+    position: usize,
+}
+
+impl AsyncRead for SegmentedPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let start = self.position;
+        let n = SEGMENT.min(buf.len()).min(self.input.len() - start);
+        buf[..n].copy_from_slice(&self.input[start..start + n]);
+        self.position += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for SegmentedPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn frame(buffer: &mut Vec<u8>, code: u8, payload: &[u8]) {
+    buffer.extend_from_slice(&u32::to_be_bytes(1 + payload.len() as u32));
+    buffer.push(code);
+    buffer.extend_from_slice(payload);
+}
+
+fn conversation(chunks: usize) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    let mut optneg = Vec::new();
+    optneg.extend_from_slice(&6_u32.to_be_bytes());
+    optneg.extend_from_slice(&Capability::all().bits().to_be_bytes());
+    optneg.extend_from_slice(&Protocol::empty().bits().to_be_bytes());
+    frame(&mut buffer, b'O', &optneg);
+
+    let chunk = vec![b'x'; CHUNK];
+    for _ in 0..chunks {
+        frame(&mut buffer, b'B', &chunk);
+    }
+    frame(&mut buffer, b'A', &[]);
+    frame(&mut buffer, b'Q', &[]);
+
+    buffer
+}
+
+/// Run a conversation, returning how often the read buffer had to grow
+fn run(input: &[u8], adaptive: bool) -> u64 {
+    let mut milter = CollectingMilter::default();
+    let mut server = Server::default_postfix(&mut milter).with_adaptive_reserve(adaptive);
+    let mut pipe = SegmentedPipe {
+        input: input.to_vec(),
+        position: 0,
+    };
+    block_on(server.handle_connection(&mut pipe)).expect("Conversation failed");
+    server.frame_sizes().buffer_grows()
+}
+
+fn bench_fragmented(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fragmented");
+
+    for chunks in [16, 64] {
+        let input = conversation(chunks);
+        for (name, adaptive) in [("exact", false), ("adaptive", true)] {
+            let grows = run(&input, adaptive);
+            println!("{name}/{chunks}: {grows} buffer grows");
+
+            group.bench_with_input(BenchmarkId::new(name, chunks), &input, |b, input| {
+                b.iter(|| black_box(run(input, adaptive)));
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_fragmented);
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+//! Stamp mails with an `Authentication-Results` header (RFC 8601) on
+//! whether the SMTP client authenticated using SASL.
+//!
+//! The result is taken from the `{auth_type}` and `{auth_authen}` macros,
+//! which postfix only sends if `milter_mail_macros` includes them. If an
+//! upstream relay forwarded the original client with `XFORWARD`, its
+//! address is added as comment.
+//!
+//! Configured by environment variables:
+//! - `LISTEN_ADDR`, default `127.0.0.1:8899`
+//! - `MAX_IN_FLIGHT`, connections to handle before shedding load, unset
+//!   by default
+//! - `AUTHSERV_ID`, the host name stamping the results, default `localhost`
+//!
+//! ```sh
+//! cargo run -p miltr-server --features bins --bin miltr-auth-results
+//! ```
+
+mod listener;
+
+use std::{env, fmt::Write, io, sync::Arc};
+
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue},
+    commands::Macro,
+    macros::MacroContext,
+    modifications::{headers::InsertHeader, ModificationResponse},
+};
+use miltr_server::{Milter, SessionContext};
+
+#[derive(Debug)]
+struct AuthResultsMilter {
+    authserv_id: Arc<str>,
+    ctx: SessionContext,
+    macros: MacroContext,
+}
+
+impl AuthResultsMilter {
+    fn new(authserv_id: Arc<str>) -> Self {
+        Self {
+            authserv_id,
+            ctx: SessionContext::default(),
+            macros: MacroContext::new(),
+        }
+    }
+
+    fn header_value(&self) -> String {
+        let auth = self.macros.auth();
+        // The login name is meant to be stamped, expose it
+        let authen = auth.authen.as_ref().map(|authen| authen.expose_str());
+        let mut value = match (authen, &auth.auth_type) {
+            (Some(authen), Some(auth_type)) => format!(
+                "{}; auth=pass ({auth_type}) smtp.auth={authen}",
+                self.authserv_id
+            ),
+            (Some(authen), None) => format!("{}; auth=pass smtp.auth={authen}", self.authserv_id),
+            (None, _) => format!("{}; auth=none", self.authserv_id),
+        };
+        if let Some(addr) = self.ctx.forwarded_client().and_then(|client| client.addr()) {
+            // Writing to a string does not fail
+            let _ = write!(value, " (client {addr}, unverified)");
+        }
+        value
+    }
+}
+
+#[async_trait]
+impl Milter for AuthResultsMilter {
+    type Error = &'static str;
+
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        Some(&mut self.ctx)
+    }
+
+    async fn macro_(&mut self, macro_: Macro) -> Result<(), Self::Error> {
+        self.macros.insert(macro_);
+        Ok(())
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        let mut response = ModificationResponse::builder();
+        // On top, as the newest trace header
+        response.push(InsertHeader::new(
+            0,
+            b"Authentication-Results",
+            self.header_value().as_bytes(),
+        ));
+        self.macros.clear_message();
+        Ok(response.contin())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.macros.clear_message();
+        Ok(Continue.into())
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let authserv_id: Arc<str> = env::var("AUTHSERV_ID")
+        .unwrap_or("localhost".to_string())
+        .into();
+    listener::run("miltr-auth-results", || {
+        AuthResultsMilter::new(Arc::clone(&authserv_id))
+    })
+    .await
+}
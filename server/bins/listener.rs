@@ -0,0 +1,124 @@
+//! Accept milter connections until Ctrl-C, shared by the bundled milters
+
+use std::{
+    env,
+    fmt::Debug,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use miltr_server::{LoadShedMilter, LoadShedder, Milter, Server, ServerStats};
+
+/// Serve a milter created by `new_milter` per connection on `LISTEN_ADDR`.
+///
+/// With `MAX_IN_FLIGHT` set, connections accepted while that many are
+/// handled get tempfail from a [`LoadShedMilter`], until the load dropped
+/// below 80% of it.
+///
+/// On Ctrl-C, stop accepting, wait for running connections and print the
+/// server stats.
+pub async fn run<M, F>(name: &str, new_milter: F) -> io::Result<()>
+where
+    M: Milter + 'static,
+    M::Error: Debug,
+    F: Fn() -> M,
+{
+    let addr = env::var("LISTEN_ADDR").unwrap_or("127.0.0.1:8899".to_string());
+    let listener = TcpListener::bind(&addr).await?;
+    let stats = ServerStats::new();
+    let mut shedder = env::var("MAX_IN_FLIGHT")
+        .ok()
+        .and_then(|max| max.parse::<u64>().ok())
+        .map(|max| LoadShedder::new(max).with_resume_below(max * 4 / 5));
+    let in_flight = Arc::new(AtomicU64::new(0));
+    println!("{name} listening on {addr}");
+
+    let mut connections = JoinSet::new();
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            Some(joined) = connections.join_next() => {
+                if let Err(err) = joined {
+                    eprintln!("Milter connection task failed: {err}");
+                }
+                continue;
+            }
+            _ = &mut shutdown => break,
+        };
+
+        if let Some(shedder) = &mut shedder {
+            let was_shedding = shedder.is_shedding();
+            let shedding = shedder.shed(in_flight.load(Ordering::Relaxed));
+            if shedding != was_shedding {
+                println!("Shedding load: {shedding}");
+            }
+            if shedding {
+                connections.spawn(async move {
+                    let mut milter = LoadShedMilter;
+                    let result = Server::default_postfix(&mut milter)
+                        .handle_connection(stream.compat())
+                        .await;
+                    if let Err(err) = result {
+                        eprintln!("Shed milter connection failed: {err:?}");
+                    }
+                });
+                continue;
+            }
+        }
+
+        let mut milter = new_milter();
+        let stats = stats.clone();
+        let guard = InFlight::new(&in_flight);
+        connections.spawn(async move {
+            let _guard = guard;
+            let result = Server::default_postfix(&mut milter)
+                .with_stats(stats)
+                .handle_connection(stream.compat())
+                .await;
+            match result {
+                Ok(summary) => println!(
+                    "Milter connection ended by {:?} after {} messages in {:?}",
+                    summary.ended_by, summary.messages, summary.duration
+                ),
+                Err(err) => eprintln!("Milter connection failed: {err:?}"),
+            }
+        });
+    }
+
+    println!(
+        "Shutting down, waiting for {} connections",
+        connections.len()
+    );
+    while let Some(joined) = connections.join_next().await {
+        if let Err(err) = joined {
+            eprintln!("Milter connection task failed: {err}");
+        }
+    }
+    print!("{}", stats.render());
+    Ok(())
+}
+
+/// Counts a connection handled by the real milter as in flight until
+/// dropped, taken on accepting it
+struct InFlight(Arc<AtomicU64>);
+
+impl InFlight {
+    fn new(count: &Arc<AtomicU64>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(count))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
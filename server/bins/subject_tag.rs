@@ -0,0 +1,129 @@
+//! Tag the subject of mails an upstream filter scored as spam.
+//!
+//! The score is read from a header added by the filter, e.g. `X-Spam-Score:
+//! 7.3`. If it reaches the threshold, the subject is prefixed with a tag.
+//!
+//! Configured by environment variables:
+//! - `LISTEN_ADDR`, default `127.0.0.1:8899`
+//! - `MAX_IN_FLIGHT`, connections to handle before shedding load, unset
+//!   by default
+//! - `SCORE_HEADER`, default `X-Spam-Score`
+//! - `SPAM_THRESHOLD`, default `5.0`
+//! - `SUBJECT_TAG`, default `[SPAM?]`
+//!
+//! ```sh
+//! cargo run -p miltr-server --features bins --bin miltr-subject-tag
+//! ```
+
+mod listener;
+
+use std::{env, io, sync::Arc};
+
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue},
+    commands::Header,
+    modifications::{
+        headers::{AddHeader, ChangeHeader},
+        ModificationResponse,
+    },
+};
+use miltr_server::{Milter, SessionContext};
+
+#[derive(Debug)]
+struct Config {
+    score_header: String,
+    threshold: f64,
+    tag: String,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            score_header: env::var("SCORE_HEADER").unwrap_or("X-Spam-Score".to_string()),
+            threshold: env::var("SPAM_THRESHOLD")
+                .ok()
+                .and_then(|threshold| threshold.parse().ok())
+                .unwrap_or(5.0),
+            tag: env::var("SUBJECT_TAG").unwrap_or("[SPAM?]".to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SubjectTagMilter {
+    config: Arc<Config>,
+    ctx: SessionContext,
+    score: Option<f64>,
+    subject: Option<String>,
+}
+
+impl SubjectTagMilter {
+    fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            ctx: SessionContext::default(),
+            score: None,
+            subject: None,
+        }
+    }
+
+    fn is_spam(&self) -> bool {
+        self.score
+            .is_some_and(|score| score >= self.config.threshold)
+    }
+}
+
+#[async_trait]
+impl Milter for SubjectTagMilter {
+    type Error = &'static str;
+
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        Some(&mut self.ctx)
+    }
+
+    async fn header(&mut self, header: Header) -> Result<Action, Self::Error> {
+        if header.name_eq_ignore_case(&self.config.score_header) {
+            self.score = header.value().trim().parse().ok();
+        } else if header.name_eq_ignore_case("Subject") && self.subject.is_none() {
+            self.subject = Some(header.value().into_owned());
+        }
+        Ok(Continue.into())
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        let mut response = ModificationResponse::builder();
+        if self.is_spam() {
+            let tag = &self.config.tag;
+            match self.subject.take() {
+                Some(subject) if subject.trim_start().starts_with(tag.as_str()) => {}
+                Some(subject) => {
+                    let tagged = format!("{tag} {}", subject.trim_start());
+                    response.push(ChangeHeader::new(1, b"Subject", tagged.as_bytes()));
+                }
+                None => response.push(AddHeader::new(b"Subject", tag.as_bytes())),
+            }
+        }
+        if let Some(took) = self.ctx.elapsed_in_message() {
+            println!("Scored {:?} in {took:?}", self.score);
+        }
+        self.score = None;
+        self.subject = None;
+        Ok(response.contin())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.score = None;
+        self.subject = None;
+        Ok(Continue.into())
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let config = Arc::new(Config::from_env());
+    listener::run("miltr-subject-tag", || {
+        SubjectTagMilter::new(Arc::clone(&config))
+    })
+    .await
+}
@@ -0,0 +1,110 @@
+//! Run a milter and an HTTP metrics endpoint in one process.
+//!
+//! Both share the tokio runtime and the server stats, and both shut down
+//! gracefully on Ctrl-C: the HTTP server stops accepting, the milter
+//! listener stops accepting and waits for running connections.
+//!
+//! ```sh
+//! LISTEN_ADDR=0.0.0.0:8080 METRICS_ADDR=0.0.0.0:9090 cargo run --example metrics_http
+//! curl localhost:9090/metrics
+//! ```
+use std::{env, future::IntoFuture};
+
+use async_trait::async_trait;
+use axum::{extract::State, routing::get, Router};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use tokio::{net::TcpListener, sync::watch, task::JoinSet};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use miltr_common::actions::{Action, Continue};
+use miltr_server::{Milter, Server, ServerStats};
+
+/// Accepts everything
+#[derive(Debug, Default)]
+struct AcceptMilter;
+
+#[async_trait]
+impl Milter for AcceptMilter {
+    type Error = &'static str;
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        Ok(Continue.into())
+    }
+}
+
+/// Handle milter connections until `shutdown` changes, then wait for
+/// running connections to finish
+async fn serve_milter(
+    listener: TcpListener,
+    stats: ServerStats,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<()> {
+    let mut connections = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => {
+                accepted.into_diagnostic().wrap_err("Failed accepting connection")?.0
+            }
+            _ = shutdown.changed() => break,
+        };
+
+        let stats = stats.clone();
+        connections.spawn(async move {
+            let mut milter = AcceptMilter;
+            let result = Server::default_postfix(&mut milter)
+                .with_stats(stats)
+                .handle_connection(stream.compat())
+                .await;
+            if let Err(err) = result {
+                eprintln!("Milter connection failed: {err}");
+            }
+        });
+    }
+
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn metrics(State(stats): State<ServerStats>) -> String {
+    stats.render()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let milter_addr = env::var("LISTEN_ADDR").unwrap_or("0.0.0.0:8080".to_string());
+    let metrics_addr = env::var("METRICS_ADDR").unwrap_or("0.0.0.0:9090".to_string());
+
+    let milter_listener = TcpListener::bind(&milter_addr)
+        .await
+        .into_diagnostic()
+        .wrap_err("Failed to bind the milter listener")?;
+    let metrics_listener = TcpListener::bind(&metrics_addr)
+        .await
+        .into_diagnostic()
+        .wrap_err("Failed to bind the metrics listener")?;
+
+    let stats = ServerStats::new();
+    let (shutdown, on_shutdown) = watch::channel(());
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Shutting down");
+        }
+        // Also shuts down if listening for the signal failed
+        let _ = shutdown.send(());
+    });
+
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(stats.clone());
+    let mut http_shutdown = on_shutdown.clone();
+    let http = axum::serve(metrics_listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = http_shutdown.changed().await;
+        })
+        .into_future();
+
+    println!("Milter on {milter_addr}, metrics on http://{metrics_addr}/metrics");
+    let (http, milter) = tokio::join!(http, serve_milter(milter_listener, stats, on_shutdown));
+    http.into_diagnostic().wrap_err("Metrics server failed")?;
+    milter
+}
@@ -0,0 +1,42 @@
+//! An example milter run by inetd or systemd socket activation, serving the
+//! one connection it is started for on stdin and stdout.
+//!
+//! Install `stdio-milter.socket` and `stdio-milter@.service` from this
+//! directory to let systemd start it for each connection of the MTA. As
+//! stdout is the connection, everything else goes to stderr.
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue},
+    modifications::{headers::AddHeader, ModificationResponse},
+};
+use miltr_server::{Milter, Server};
+
+#[derive(Debug, Default)]
+struct TagMilter;
+
+#[async_trait]
+impl Milter for TagMilter {
+    type Error = &'static str;
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        let mut builder = ModificationResponse::builder();
+        builder.push(AddHeader::new(b"X-Filtered-By", b"stdio-milter"));
+        Ok(builder.contin())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        Ok(Continue.into())
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut milter = TagMilter;
+    let mut server = Server::default_postfix(&mut milter);
+
+    let summary = server
+        .handle_stdio()
+        .await
+        .expect("Failed handling milter connection");
+    eprintln!("Connection ended: {summary:?}");
+}
@@ -0,0 +1,250 @@
+//! Answer connects by the client address before the milter sees them
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use miltr_common::{
+    actions::{Action, Continue, Reject, Tempfail},
+    commands::Connect,
+};
+use thiserror::Error;
+
+/// A network, written like `192.0.2.0/24` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// A [`Cidr`] could not be created
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum InvalidCidr {
+    /// The address part is no IP address
+    #[error("Invalid address in network: {0}")]
+    Address(String),
+    /// The prefix length is no number or too long for the address
+    #[error("Invalid prefix length in network: {0}")]
+    Prefix(String),
+}
+
+impl Cidr {
+    /// The network of `addr` with the first `prefix` bits fixed.
+    ///
+    /// # Errors
+    /// If `prefix` exceeds the bits of `addr`
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, InvalidCidr> {
+        if prefix > Self::bits(addr) {
+            return Err(InvalidCidr::Prefix(format!("{addr}/{prefix}")));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    /// The network of just `addr`
+    #[must_use]
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: Self::bits(addr),
+        }
+    }
+
+    /// Whether `addr` is part of this network.
+    ///
+    /// Addresses of the other IP version never are, IPv4 mapped IPv6
+    /// addresses are compared as IPv4.
+    #[must_use]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => Self::same_prefix(
+                u32::from(network).into(),
+                u32::from(addr).into(),
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                Self::same_prefix(network.into(), addr.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+
+    fn same_prefix(network: u128, addr: u128, prefix: u8, bits: u8) -> bool {
+        if prefix == 0 {
+            return true;
+        }
+        let shift = u32::from(bits - prefix);
+        network >> shift == addr >> shift
+    }
+
+    fn bits(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| InvalidCidr::Address(s.to_string()))?;
+        match prefix {
+            Some(prefix) => {
+                let prefix = prefix
+                    .parse()
+                    .map_err(|_| InvalidCidr::Prefix(s.to_string()))?;
+                Self::new(addr, prefix)
+            }
+            None => Ok(Self::host(addr)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// How to answer a connect matching an [`AccessList`] rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessVerdict {
+    /// Let the session pass, answering continue to all of it without
+    /// calling the milter
+    Accept,
+    /// Reject the session
+    Reject,
+    /// Fail the session temporarily
+    Tempfail,
+}
+
+impl AccessVerdict {
+    pub(crate) fn action(self) -> Action {
+        match self {
+            Self::Accept => Continue.into(),
+            Self::Reject => Reject.into(),
+            Self::Tempfail => Tempfail.into(),
+        }
+    }
+}
+
+/// Networks to answer connects from without asking the milter, see
+/// [`Server::with_access_list`](crate::Server::with_access_list).
+///
+/// Rules are checked in the order they were added, the first match
+/// decides. Connects matching no rule, or not from an IP address, go to
+/// the milter unless a default is set.
+///
+/// ```
+/// use miltr_server::{AccessList, AccessVerdict};
+///
+/// let list = AccessList::new()
+///     .allow("192.0.2.10".parse().unwrap())
+///     .deny("192.0.2.0/24".parse().unwrap())
+///     .with_rule("2001:db8::/32".parse().unwrap(), AccessVerdict::Tempfail);
+///
+/// assert_eq!(list.verdict("192.0.2.10".parse().unwrap()), Some(AccessVerdict::Accept));
+/// assert_eq!(list.verdict("192.0.2.11".parse().unwrap()), Some(AccessVerdict::Reject));
+/// assert_eq!(list.verdict("198.51.100.1".parse().unwrap()), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    rules: Vec<(Cidr, AccessVerdict)>,
+    default: Option<AccessVerdict>,
+}
+
+impl AccessList {
+    /// An empty list, sending every connect to the milter
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer connects from `network` with `verdict`
+    #[must_use]
+    pub fn with_rule(mut self, network: Cidr, verdict: AccessVerdict) -> Self {
+        self.rules.push((network, verdict));
+        self
+    }
+
+    /// Accept connects from `network`
+    #[must_use]
+    pub fn allow(self, network: Cidr) -> Self {
+        self.with_rule(network, AccessVerdict::Accept)
+    }
+
+    /// Reject connects from `network`
+    #[must_use]
+    pub fn deny(self, network: Cidr) -> Self {
+        self.with_rule(network, AccessVerdict::Reject)
+    }
+
+    /// Answer connects matching no rule with `verdict` instead of asking
+    /// the milter
+    #[must_use]
+    pub fn with_default(mut self, verdict: AccessVerdict) -> Self {
+        self.default = Some(verdict);
+        self
+    }
+
+    /// The verdict for `addr`, `None` to ask the milter.
+    ///
+    /// Listeners may use this with the peer address of the MTA as well, to
+    /// close connections from MTAs not allowed before handling them.
+    #[must_use]
+    pub fn verdict(&self, addr: IpAddr) -> Option<AccessVerdict> {
+        self.rules
+            .iter()
+            .find(|(network, _)| network.contains(addr))
+            .map(|(_, verdict)| *verdict)
+            .or(self.default)
+    }
+
+    /// The verdict for the client of `connect`
+    pub(crate) fn verdict_for(&self, connect: &Connect) -> Option<AccessVerdict> {
+        match connect.ip() {
+            Some(addr) => self.verdict(addr),
+            None => self.default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_contains() {
+        let network: Cidr = "10.1.0.0/16".parse().expect("Valid network");
+
+        assert!(network.contains("10.1.255.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!network.contains("2001:db8::1".parse().unwrap()));
+
+        let all: Cidr = "::/0".parse().expect("Valid network");
+        assert!(all.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_cidr_invalid() {
+        assert_eq!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(InvalidCidr::Prefix("10.0.0.0/33".to_string()))
+        );
+        assert_eq!(
+            "mx.local/8".parse::<Cidr>(),
+            Err(InvalidCidr::Address("mx.local/8".to_string()))
+        );
+    }
+}
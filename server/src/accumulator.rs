@@ -0,0 +1,312 @@
+//! Collect whole message bodies, spilling large ones to disk
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::BytesMut;
+use futures::{io::Cursor, lock::Mutex, AsyncRead};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// Names spill files apart within this process
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Collects the body chunks of a message, for milters that need to see the
+/// whole body at end of body, e.g. to hand it to a virus scanner.
+///
+/// Chunks are kept in memory up to a cap. Beyond that, the body is written
+/// to a file in the spill directory instead, which is removed once the
+/// accumulator and all readers of it are dropped.
+///
+/// Clones share a spilled file, so keep pushing through one of them only.
+/// That allows keeping the accumulator in the
+/// [`SessionContext::message_extensions`](crate::SessionContext::message_extensions),
+/// dropping it with the message.
+///
+/// ```
+/// use futures::AsyncReadExt;
+/// use miltr_server::BodyAccumulator;
+///
+/// # #[tokio::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let mut body = BodyAccumulator::new(4);
+/// body.push(b"Hello ").await?;
+/// body.push(b"World").await?;
+/// assert!(body.is_spilled());
+///
+/// let mut content = String::new();
+/// body.reader().await?.read_to_string(&mut content).await?;
+/// assert_eq!(content, "Hello World");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct BodyAccumulator {
+    memory_cap: usize,
+    dir: PathBuf,
+    memory: BytesMut,
+    spilled: Option<Arc<SpillFile>>,
+    len: u64,
+}
+
+impl BodyAccumulator {
+    /// Keep up to `memory_cap` bytes in memory, spill larger bodies to the
+    /// temp directory of the system
+    #[must_use]
+    pub fn new(memory_cap: usize) -> Self {
+        Self {
+            memory_cap,
+            dir: std::env::temp_dir(),
+            memory: BytesMut::new(),
+            spilled: None,
+            len: 0,
+        }
+    }
+
+    /// Spill to files in `dir` instead of the temp directory
+    #[must_use]
+    pub fn with_spill_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Append `chunk` to the body.
+    ///
+    /// # Errors
+    /// If the body had to be spilled and writing the file failed
+    pub async fn push(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.spilled.is_none() && self.memory.len() + chunk.len() <= self.memory_cap {
+            self.memory.extend_from_slice(chunk);
+            self.len += chunk.len() as u64;
+            return Ok(());
+        }
+
+        let spill = if let Some(spill) = &self.spilled {
+            Arc::clone(spill)
+        } else {
+            let spill = Arc::new(SpillFile::create(&self.dir).await?);
+            self.spilled = Some(Arc::clone(&spill));
+            spill
+        };
+        let mut file = spill.writer.lock().await;
+        if !self.memory.is_empty() {
+            file.write_all(&self.memory).await?;
+            self.memory = BytesMut::new();
+        }
+        file.write_all(chunk).await?;
+        file.flush().await?;
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes pushed since the last reset
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether nothing was pushed since the last reset
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the body exceeded the memory cap and was written to disk
+    #[must_use]
+    pub fn is_spilled(&self) -> bool {
+        self.spilled.is_some()
+    }
+
+    /// Take the body collected so far to read it, resetting this
+    /// accumulator for the next message.
+    ///
+    /// # Errors
+    /// If a spilled body could not be opened
+    pub async fn reader(&mut self) -> io::Result<BodyReader> {
+        let len = std::mem::take(&mut self.len);
+        let memory = std::mem::take(&mut self.memory);
+        let inner = match self.spilled.take() {
+            Some(spill) => {
+                let file = File::open(&spill.path).await?;
+                ReaderInner::File(file.compat(), spill)
+            }
+            None => ReaderInner::Memory(Cursor::new(memory)),
+        };
+        Ok(BodyReader { inner, len })
+    }
+
+    /// Drop the body collected so far, e.g. on abort
+    pub fn reset(&mut self) {
+        self.memory = BytesMut::new();
+        self.spilled = None;
+        self.len = 0;
+    }
+}
+
+impl fmt::Debug for BodyAccumulator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyAccumulator")
+            .field("memory_cap", &self.memory_cap)
+            .field("dir", &self.dir)
+            .field("len", &self.len)
+            .field("spilled", &self.spilled.as_ref().map(|spill| &spill.path))
+            .finish_non_exhaustive()
+    }
+}
+
+/// A body taken from a [`BodyAccumulator`], read from memory or its
+/// spilled file
+#[derive(Debug)]
+pub struct BodyReader {
+    inner: ReaderInner,
+    len: u64,
+}
+
+#[derive(Debug)]
+enum ReaderInner {
+    Memory(Cursor<BytesMut>),
+    // Keeps the file around until read
+    File(Compat<File>, Arc<SpillFile>),
+}
+
+impl BodyReader {
+    /// The length of the whole body
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the body is empty
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl AsyncRead for BodyReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.inner {
+            ReaderInner::Memory(cursor) => Pin::new(cursor).poll_read(cx, buf),
+            ReaderInner::File(file, _spill) => Pin::new(file).poll_read(cx, buf),
+        }
+    }
+}
+
+/// A spill file, removed on drop
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    /// Kept open for all chunks of the body
+    writer: Mutex<File>,
+}
+
+impl SpillFile {
+    /// Create a new file in `dir`, only accessible by the owner as it
+    /// holds mail content
+    async fn create(dir: &Path) -> io::Result<Self> {
+        loop {
+            let path = dir.join(format!(
+                "miltr-body-{}-{}",
+                process::id(),
+                SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            match options.open(&path).await {
+                Ok(file) => {
+                    return Ok(Self {
+                        path,
+                        writer: Mutex::new(file),
+                    })
+                }
+                // Left over by an earlier process with the same id
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // Nothing to do about it failing, the file may be gone already
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spill_and_cleanup() {
+        let mut body = BodyAccumulator::new(8);
+        body.push(b"1234").await.expect("Failed pushing");
+        assert!(!body.is_spilled());
+        body.push(b"56789").await.expect("Failed pushing");
+        body.push(b"0").await.expect("Failed pushing");
+        assert!(body.is_spilled());
+        let path = body.spilled.as_ref().expect("Not spilled").path.clone();
+
+        let mut reader = body.reader().await.expect("Failed opening body");
+        assert!(body.is_empty());
+        assert_eq!(reader.len(), 10);
+        let mut content = Vec::new();
+        reader
+            .read_to_end(&mut content)
+            .await
+            .expect("Failed reading body");
+        assert_eq!(content, b"1234567890");
+
+        assert!(path.exists());
+        drop(reader);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_spill_file_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut body = BodyAccumulator::new(0);
+        body.push(b"spilled").await.expect("Failed pushing");
+        let path = &body.spilled.as_ref().expect("Not spilled").path;
+
+        let mode = std::fs::metadata(path)
+            .expect("Spill file missing")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_reset_removes_file() {
+        let mut body = BodyAccumulator::new(0);
+        body.push(b"spilled").await.expect("Failed pushing");
+        let path = body.spilled.as_ref().expect("Not spilled").path.clone();
+
+        body.reset();
+        assert!(!path.exists());
+        assert!(!body.is_spilled());
+    }
+}
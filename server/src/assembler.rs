@@ -0,0 +1,143 @@
+//! Rebuild a message as received from the streamed headers and body
+
+use bytes::BytesMut;
+use miltr_common::{
+    commands::{Body, Header},
+    optneg::Protocol,
+};
+
+/// Rebuilds the message from the headers and body chunks a milter
+/// receives, e.g. to hand it to an external scanner as the MTA received
+/// it.
+///
+/// Unless [`Protocol::SMFIP_HDR_LEADSPC`] was negotiated, MTAs strip the
+/// space after the colon of a header, so a single space is put back.
+/// Folded header values arrive with bare line feeds, they are written with
+/// CRLF like the rest of the message. Body chunks are taken as they are.
+///
+/// ```
+/// use miltr_common::commands::{Body, Header};
+/// use miltr_server::MessageAssembler;
+///
+/// let mut message = MessageAssembler::new();
+/// message.push_header(&Header::new(b"Subject", b"Hello\n\tWorld"));
+/// message.end_of_header();
+/// message.push_body(&Body::from(b"Hi\r\n".as_slice()));
+///
+/// assert_eq!(message.message(), b"Subject: Hello\r\n\tWorld\r\n\r\nHi\r\n");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MessageAssembler {
+    leading_space: bool,
+    message: BytesMut,
+    header_ended: bool,
+}
+
+impl MessageAssembler {
+    /// An assembler for header values without their leading space
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An assembler for the header values sent with the negotiated
+    /// `protocol`
+    #[must_use]
+    pub fn for_protocol(protocol: Protocol) -> Self {
+        Self::new().with_leading_space(protocol.contains(Protocol::SMFIP_HDR_LEADSPC))
+    }
+
+    /// Whether header values arrive with their leading space, as with
+    /// [`Protocol::SMFIP_HDR_LEADSPC`]
+    #[must_use]
+    pub fn with_leading_space(mut self, leading_space: bool) -> Self {
+        self.leading_space = leading_space;
+        self
+    }
+
+    /// Append `header`
+    pub fn push_header(&mut self, header: &Header) {
+        self.message.extend_from_slice(header.name_bytes());
+        self.message.extend_from_slice(b":");
+        if !self.leading_space {
+            self.message.extend_from_slice(b" ");
+        }
+        let mut previous = 0;
+        for &byte in header.value_bytes() {
+            if byte == b'\n' && previous != b'\r' {
+                self.message.extend_from_slice(b"\r");
+            }
+            self.message.extend_from_slice(&[byte]);
+            previous = byte;
+        }
+        self.message.extend_from_slice(b"\r\n");
+    }
+
+    /// End the headers with an empty line.
+    ///
+    /// Called by [`Self::push_body`] if needed, e.g. if end of header is
+    /// not sent with [`Protocol::NO_END_OF_HEADER`].
+    pub fn end_of_header(&mut self) {
+        if !self.header_ended {
+            self.message.extend_from_slice(b"\r\n");
+            self.header_ended = true;
+        }
+    }
+
+    /// Append a chunk of the body
+    pub fn push_body(&mut self, body: &Body) {
+        self.end_of_header();
+        self.message.extend_from_slice(body.as_bytes());
+    }
+
+    /// The message assembled so far
+    #[must_use]
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// The length of the message assembled so far
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.message.len()
+    }
+
+    /// Whether nothing was assembled yet
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.message.is_empty()
+    }
+
+    /// Take the message assembled so far, starting over for the next one
+    pub fn take(&mut self) -> BytesMut {
+        self.header_ended = false;
+        std::mem::take(&mut self.message)
+    }
+
+    /// Drop the message assembled so far, e.g. on abort
+    pub fn reset(&mut self) {
+        self.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leading_space() {
+        let mut message = MessageAssembler::for_protocol(Protocol::SMFIP_HDR_LEADSPC);
+        message.push_header(&Header::new(b"Subject", b"  Indented"));
+        message.push_header(&Header::new(b"X-Crlf", b"a\r\n b"));
+        message.push_body(&Body::from(b"Body".as_slice()));
+
+        assert_eq!(
+            message.take().as_ref(),
+            b"Subject:  Indented\r\nX-Crlf:a\r\n b\r\n\r\nBody"
+        );
+        assert!(message.is_empty());
+
+        message.push_body(&Body::from(b"Next".as_slice()));
+        assert_eq!(message.message(), b"\r\nNext");
+    }
+}
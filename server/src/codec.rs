@@ -1,12 +1,24 @@
 use asynchronous_codec::{Decoder, Encoder};
 use bytes::{Buf, BufMut, BytesMut};
 
+use miltr_common::actions::{Action, ActionKind};
+use miltr_common::codes;
 use miltr_common::decoding::ClientCommand;
 use miltr_common::encoding::ServerMessage;
-use miltr_common::encoding::Writable;
-use miltr_common::ProtocolError;
+use miltr_common::encoding::{frame_len, Writable};
+use miltr_common::frame::{self, FrameHooks, FrameSizes, FrameTimer};
+use miltr_common::{ProtocolError, TooMuchData};
 use miltr_utils::trace;
 
+use crate::{summary::Tally, ServerStats};
+
+/// A complete, pre-encoded `Continue` frame.
+///
+/// Continue is by far the most sent response, once per header or body chunk.
+/// Writing it verbatim skips length calculation and dispatching to
+/// [`Writable`].
+pub(crate) const CONTINUE_FRAME: [u8; 5] = [0, 0, 0, 1, codes::SMFIR_CONTINUE];
+
 /// The `MilterCodec` is responsible for decoding from and encoding to bits on
 /// the wire from structs provided by this crate.
 ///
@@ -14,11 +26,40 @@ use miltr_utils::trace;
 #[derive(Debug, Clone)]
 pub(crate) struct MilterCodec {
     max_buffer_size: usize,
+    pub(crate) hooks: FrameHooks,
+    pub(crate) decode_timer: FrameTimer,
+    pub(crate) encode_timer: FrameTimer,
+    pub(crate) frame_sizes: FrameSizes,
+    pub(crate) strict_lengths: bool,
+    pub(crate) stats: Option<ServerStats>,
+    pub(crate) tally: Tally,
 }
 
 impl MilterCodec {
     pub(crate) fn new(max_buffer_size: usize) -> Self {
-        Self { max_buffer_size }
+        Self {
+            max_buffer_size,
+            hooks: FrameHooks::default(),
+            decode_timer: default_timer(),
+            encode_timer: default_timer(),
+            frame_sizes: FrameSizes::default(),
+            strict_lengths: false,
+            stats: None,
+            tally: Tally::default(),
+        }
+    }
+
+    pub(crate) fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+}
+
+/// Sample codec timings only if they can be traced
+fn default_timer() -> FrameTimer {
+    if cfg!(feature = "tracing") {
+        FrameTimer::default()
+    } else {
+        FrameTimer::new(0)
     }
 }
 
@@ -41,13 +82,15 @@ impl Decoder for &mut MilterCodec {
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
         if length > self.max_buffer_size {
-            return Err(ProtocolError::TooMuchData(length));
+            return Err(
+                TooMuchData::decoding(src.get(4).copied(), length, self.max_buffer_size).into(),
+            );
         }
 
         // If arrived data is smaller than 4 bytes of length marker + the
         // decoded length, we need more data.
         if src.len() < 4 + length {
-            src.reserve(4 + length - src.len());
+            self.frame_sizes.reserve(src, 4 + length);
             return Ok(None);
         }
 
@@ -55,10 +98,33 @@ impl Decoder for &mut MilterCodec {
         // this frame.
         let mut parse_buf = src.split_to(4 + length);
         parse_buf.advance(4);
+        self.frame_sizes.record(length);
+        self.tally.received(4 + length);
 
         trace!(length = parse_buf.len(), "Read bytes from the network");
+        if let Some(&code) = parse_buf.first() {
+            self.hooks.notify_received(code, length);
+        }
+        if let Some(stats) = &self.stats {
+            stats.frame_received();
+        }
 
-        Ok(Some(ClientCommand::parse(parse_buf)?))
+        if self.strict_lengths {
+            frame::check_min_len(&parse_buf)?;
+        }
+
+        #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+        let (command, elapsed) = self.decode_timer.time(|| ClientCommand::parse(parse_buf));
+        #[cfg(feature = "tracing")]
+        if let (Ok(command), Some(elapsed)) = (&command, elapsed) {
+            trace!(
+                code = command.code(),
+                decode_ns = elapsed.as_nanos() as u64,
+                "Decoded frame"
+            );
+        }
+
+        Ok(Some(command?))
     }
 }
 
@@ -67,16 +133,22 @@ impl Encoder for &mut MilterCodec {
     type Error = ProtocolError;
 
     fn encode(&mut self, item: &ServerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        // Don't send a string if it is longer than the other end will
-        // accept or  larger than we will be able to compute.
-        let item_len = item.len();
-        if item_len > self.max_buffer_size || item_len > usize::MAX - 1 {
-            return Err(ProtocolError::TooMuchData(item_len));
+        // Fast path for the most common response
+        if let ServerMessage::Action(Action::Continue(_)) = item {
+            dst.extend_from_slice(&CONTINUE_FRAME);
+            trace!(length = dst.len(), "Wrote bytes to the network");
+            self.hooks.notify_sent(codes::SMFIR_CONTINUE, 1);
+            self.tally
+                .sent(CONTINUE_FRAME.len(), Some(ActionKind::Continue));
+            if let Some(stats) = &self.stats {
+                stats.frame_sent();
+            }
+            return Ok(());
         }
 
-        let packet_len = 1_usize // single character code
-            .checked_add(item_len) // The rest of the stuff
-            .ok_or(ProtocolError::TooMuchData(item_len))?;
+        // Don't send a string if it is longer than the other end will
+        // accept or  larger than we will be able to compute.
+        let packet_len = frame_len(item, self.max_buffer_size)?;
 
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
@@ -88,9 +160,27 @@ impl Encoder for &mut MilterCodec {
         // Write the length, code and string to the buffer.
         dst.extend_from_slice(&packet_len_be);
         dst.put_u8(item.code());
-        item.write(dst);
+        #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+        let ((), elapsed) = self.encode_timer.time(|| item.write(dst));
+        #[cfg(feature = "tracing")]
+        if let Some(elapsed) = elapsed {
+            trace!(
+                code = item.code(),
+                encode_ns = elapsed.as_nanos() as u64,
+                "Encoded frame"
+            );
+        }
 
         trace!(length = dst.len(), "Wrote bytes to the network");
+        self.hooks.notify_sent(item.code(), packet_len);
+        let action = match item {
+            ServerMessage::Action(action) => Some(action.kind()),
+            _ => None,
+        };
+        self.tally.sent(4 + packet_len, action);
+        if let Some(stats) = &self.stats {
+            stats.frame_sent();
+        }
 
         Ok(())
     }
@@ -99,6 +189,29 @@ impl Encoder for &mut MilterCodec {
 #[cfg(test)]
 mod test {
     use super::*;
+    use miltr_common::actions::Continue;
+    use miltr_common::modifications::ModificationAction;
+    use miltr_common::test_utils::check_decode_invariants;
+
+    #[test]
+    fn test_continue_frame_matches_encoding() {
+        let continue_: Action = Continue.into();
+        let continue_: ServerMessage = continue_.into();
+
+        let mut expected = BytesMut::new();
+        expected.extend_from_slice(&u32::to_be_bytes(1 + continue_.len() as u32));
+        expected.put_u8(continue_.code());
+        continue_.write(&mut expected);
+
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::new();
+        (&mut codec)
+            .encode(&continue_, &mut buffer)
+            .expect("Failed encoding continue");
+
+        assert_eq!(expected, buffer);
+        assert_eq!(&CONTINUE_FRAME[..], &buffer[..]);
+    }
 
     #[test]
     fn test_decode_fuzz_1() {
@@ -136,4 +249,120 @@ mod test {
         let mut buffer = BytesMut::from_iter(&input);
         let _res = (&mut codec).decode(&mut buffer);
     }
+
+    #[test]
+    fn test_decode_too_much_data() {
+        let mut codec = MilterCodec::new(16);
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 17, b'L']);
+
+        let err = (&mut codec)
+            .decode(&mut buffer)
+            .expect_err("Oversized frame was accepted");
+
+        let ProtocolError::TooMuchData(err) = err else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(err.code, Some(b'L'));
+        assert_eq!(err.item, "Header");
+        assert_eq!((err.len, err.limit), (17, 16));
+    }
+
+    #[test]
+    fn test_encode_too_much_data() {
+        use miltr_common::modifications::headers::AddHeader;
+
+        let mut codec = MilterCodec::new(16);
+        let header: ServerMessage =
+            ModificationAction::from(AddHeader::new(b"X-Long", b"a value too long for the limit"))
+                .into();
+
+        let err = (&mut codec)
+            .encode(&header, &mut BytesMut::new())
+            .expect_err("Oversized frame was encoded");
+
+        let ProtocolError::TooMuchData(err) = err else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(err.stage, "encoding");
+        assert_eq!(err.code, Some(b'h'));
+        assert_eq!(err.item, "AddHeader");
+    }
+
+    #[test]
+    fn test_decode_invariants_fuzz_corpus() {
+        let corpus: [&[u8]; 5] = [
+            &[
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, b'f', b'f', 0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+            &[0, 0, 0, 5, 67, 58, 255, 1, 0],
+            &[
+                0, 0, 0, 21, 67, 230, 186, 186, 186, 186, 42, 255, 255, 255, 255, 255, 255, 255,
+                255, 255, 255, 186, 0, 52, 72, 255,
+            ],
+            &[0, 0, 0, 4, 109, 255, 255, 7],
+            // Two complete frames followed by an incomplete one
+            &[0, 0, 0, 1, b'A', 0, 0, 0, 1, b'A', 0, 0, 0, 1],
+        ];
+
+        for input in corpus {
+            let mut codec = MilterCodec::new(2_usize.pow(16));
+            check_decode_invariants(input, |buffer| (&mut codec).decode(buffer));
+        }
+    }
+
+    #[test]
+    fn test_decode_one_frame_per_call() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 1, b'A', 0, 0, 0, 1, b'A', 0, 0]);
+
+        let command = (&mut codec)
+            .decode(&mut buffer)
+            .expect("Failed decoding")
+            .expect("Complete frame not decoded");
+
+        assert!(matches!(command, ClientCommand::Abort(_)));
+        assert_eq!(&buffer[..], &[0, 0, 0, 1, b'A', 0, 0]);
+    }
+
+    #[test]
+    fn test_decode_length_zero() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 0, 0, 0, 0, 1, b'A']);
+
+        let err = (&mut codec)
+            .decode(&mut buffer)
+            .expect_err("Frame without code decoded");
+
+        assert!(matches!(err, ProtocolError::NotEnoughData(_)));
+        assert_eq!(&buffer[..], &[0, 0, 0, 1, b'A']);
+    }
+
+    #[test]
+    fn test_decode_length_one_unknown_code() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0, 0, 0, 1, 0xff]);
+
+        let err = (&mut codec)
+            .decode(&mut buffer)
+            .expect_err("Unknown code decoded");
+
+        assert!(matches!(err, ProtocolError::InvalidData(_)));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_length_max() {
+        let mut codec = MilterCodec::new(2_usize.pow(16));
+        let mut buffer = BytesMut::from_iter([0xff, 0xff, 0xff, 0xff, b'A']);
+
+        let err = (&mut codec)
+            .decode(&mut buffer)
+            .expect_err("Oversized frame accepted");
+
+        let ProtocolError::TooMuchData(err) = err else {
+            panic!("Wrong error received: {err:?}");
+        };
+        assert_eq!(err.len, u32::MAX as usize);
+        assert_eq!(&buffer[..], &[0xff, 0xff, 0xff, 0xff, b'A']);
+    }
 }
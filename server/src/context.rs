@@ -0,0 +1,509 @@
+//! State of a milter session the server keeps for the milter implementation
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use miltr_common::{
+    actions::SmtpStage,
+    clock::Clock,
+    commands::Macro,
+    decoding::ClientCommand,
+    encoding::Limits,
+    macros::well_known::{CLIENT_ADDR, CLIENT_NAME, CLIENT_PORT, MILTR_DEADLINE},
+    optneg::OptNeg,
+};
+
+use crate::Extensions;
+
+/// Timing information about the current session, and values milters store
+/// for each other in its [`Extensions`].
+///
+/// A milter opts in by returning it from
+/// [`Milter::session_context`](crate::Milter::session_context). The server
+/// updates it as each command arrives, before calling the milter.
+#[derive(Debug, Clone, Default)]
+pub struct SessionContext {
+    connected_at: Option<Instant>,
+    message_started_at: Option<Instant>,
+    /// When the current message must be handled, as told by the client
+    deadline: Option<Instant>,
+    previous_command_at: Option<Instant>,
+    command_at: Option<Instant>,
+    stage: Option<(SmtpStage, Instant)>,
+    stage_durations: HashMap<SmtpStage, Duration>,
+    forwarded: Option<ForwardedClient>,
+    limits: Option<Limits>,
+    options: Option<OptNeg>,
+    /// The clock of the server, `None` for the system clock
+    clock: Option<Arc<dyn Clock>>,
+    extensions: Extensions,
+    message_extensions: Extensions,
+}
+
+impl SessionContext {
+    /// Time passed since the smtp client connected.
+    ///
+    /// If the client did not send connect information, this counts from
+    /// the first command of the session.
+    #[must_use]
+    pub fn elapsed_since_connect(&self) -> Duration {
+        self.connected_at
+            .map(|at| self.now().duration_since(at))
+            .unwrap_or_default()
+    }
+
+    /// Time passed since the current mail started, `None` outside of a mail
+    #[must_use]
+    pub fn elapsed_in_message(&self) -> Option<Duration> {
+        self.message_started_at
+            .map(|at| self.now().duration_since(at))
+    }
+
+    /// When the current message must be handled by, if the client told.
+    ///
+    /// Only clients following miltr's convention send this, as
+    /// [`MILTR_DEADLINE`] macro. MTAs do not, `None` then.
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left until [`Self::deadline`], zero once it passed
+    #[must_use]
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|at| at.saturating_duration_since(self.now()))
+    }
+
+    /// When the command currently handled arrived
+    #[must_use]
+    pub fn command_at(&self) -> Option<Instant> {
+        self.command_at
+    }
+
+    /// When the command before the current one arrived
+    #[must_use]
+    pub fn previous_command_at(&self) -> Option<Instant> {
+        self.previous_command_at
+    }
+
+    /// Time between the previous and the current command
+    #[must_use]
+    pub fn since_previous_command(&self) -> Option<Duration> {
+        Some(self.command_at?.duration_since(self.previous_command_at?))
+    }
+
+    /// Total time this session spent in `stage` so far.
+    ///
+    /// A stage lasts from its command arriving until the next stage
+    /// starts, covering both the milter and the client side.
+    #[must_use]
+    pub fn elapsed_in_stage(&self, stage: SmtpStage) -> Duration {
+        let finished = self
+            .stage_durations
+            .get(&stage)
+            .copied()
+            .unwrap_or_default();
+        match self.stage {
+            Some((current, since)) if current == stage => {
+                finished + self.now().duration_since(since)
+            }
+            _ => finished,
+        }
+    }
+
+    /// The client of the current message as the MTA reports it in its
+    /// macros, `None` until it sent any of them for this message.
+    ///
+    /// If an upstream relay forwarded the original client with `XFORWARD`
+    /// or `XCLIENT`, the MTA reports that one. This is whatever the previous
+    /// hop claimed then, nothing authenticates it. Only trust it if the
+    /// relay is trusted.
+    #[must_use]
+    pub fn forwarded_client(&self) -> Option<&ForwardedClient> {
+        self.forwarded.as_ref()
+    }
+
+    /// The frame limits of this connection, `None` until the server started
+    /// handling it.
+    ///
+    /// Use [`Limits::max_body_chunk`] to allocate a buffer for body chunks
+    /// once instead of growing it chunk by chunk.
+    #[must_use]
+    pub fn limits(&self) -> Option<Limits> {
+        self.limits
+    }
+
+    /// The options negotiated with the client, `None` until negotiated.
+    ///
+    /// A client starting a new session on the connection without
+    /// negotiating again keeps the options negotiated before.
+    #[must_use]
+    pub fn options(&self) -> Option<&OptNeg> {
+        self.options.as_ref()
+    }
+
+    /// Whether the client understands skip, see [`OptNeg::supports_skip`]
+    #[must_use]
+    pub fn supports_skip(&self) -> bool {
+        self.options.as_ref().is_some_and(OptNeg::supports_skip)
+    }
+
+    /// Whether the client also sends recipients it rejected, see
+    /// [`OptNeg::wants_rejected_rcpts`]
+    #[must_use]
+    pub fn wants_rejected_rcpts(&self) -> bool {
+        self.options
+            .as_ref()
+            .is_some_and(OptNeg::wants_rejected_rcpts)
+    }
+
+    /// Whether header values arrive with their leading space, see
+    /// [`OptNeg::leading_space_headers`]
+    #[must_use]
+    pub fn leading_space_headers(&self) -> bool {
+        self.options
+            .as_ref()
+            .is_some_and(OptNeg::leading_space_headers)
+    }
+
+    /// Values stored for the whole session.
+    ///
+    /// Cleared when a new session starts on the connection.
+    #[must_use]
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Mutable access to [`Self::extensions`]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Values stored for the current message.
+    ///
+    /// Cleared after the message ended, i.e. after end of body was answered
+    /// or the message was aborted.
+    #[must_use]
+    pub fn message_extensions(&self) -> &Extensions {
+        &self.message_extensions
+    }
+
+    /// Mutable access to [`Self::message_extensions`]
+    pub fn message_extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.message_extensions
+    }
+
+    /// The current message ended
+    pub(crate) fn end_message(&mut self) {
+        self.message_extensions.clear();
+        self.deadline = None;
+        self.forwarded = None;
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        self.limits = Some(limits);
+    }
+
+    pub(crate) fn set_options(&mut self, options: OptNeg) {
+        self.options = Some(options);
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = Some(clock);
+    }
+
+    /// The current time of the server's clock
+    fn now(&self) -> Instant {
+        self.clock
+            .as_ref()
+            .map_or_else(Instant::now, |clock| clock.now())
+    }
+
+    /// Account for `command` arriving at `now`
+    pub(crate) fn on_command(&mut self, command: &ClientCommand, now: Instant) {
+        if let ClientCommand::OptNeg(_) | ClientCommand::QuitNc(_) = command {
+            // Limits, options and the clock belong to the connection, not
+            // the session
+            *self = Self {
+                limits: self.limits,
+                options: self.options.take(),
+                clock: self.clock.take(),
+                ..Self::default()
+            };
+        }
+
+        self.previous_command_at = self.command_at.replace(now);
+        self.connected_at.get_or_insert(now);
+
+        let stage = match command {
+            ClientCommand::Connect(_) => {
+                self.connected_at = Some(now);
+                SmtpStage::Connect
+            }
+            ClientCommand::Helo(_) => SmtpStage::Helo,
+            ClientCommand::Mail(_) => {
+                self.message_started_at = Some(now);
+                SmtpStage::Mail
+            }
+            ClientCommand::Recipient(_) => SmtpStage::Rcpt,
+            ClientCommand::Data(_) => SmtpStage::Data,
+            ClientCommand::Header(_) => SmtpStage::Header,
+            ClientCommand::EndOfHeader(_) => SmtpStage::EndOfHeader,
+            ClientCommand::Body(_) => SmtpStage::Body,
+            ClientCommand::EndOfBody(_) => SmtpStage::EndOfMessage,
+            ClientCommand::Unknown(_) => SmtpStage::Unknown,
+            // Macros belong to the stage they precede
+            ClientCommand::Macro(macros) => {
+                self.on_macro(macros, now);
+                return;
+            }
+            ClientCommand::OptNeg(_) => return,
+            ClientCommand::Abort(_) | ClientCommand::Quit(_) | ClientCommand::QuitNc(_) => {
+                self.finish_stage(now);
+                self.message_started_at = None;
+                self.deadline = None;
+                self.forwarded = None;
+                return;
+            }
+        };
+
+        self.finish_stage(now);
+        self.stage = Some((stage, now));
+    }
+
+    fn on_macro(&mut self, macros: &Macro, now: Instant) {
+        let remaining = macros
+            .macros()
+            .find(|(name, _)| *name == MILTR_DEADLINE.as_bytes())
+            .and_then(|(_, value)| std::str::from_utf8(value).ok()?.parse().ok());
+        if let Some(millis) = remaining {
+            self.deadline = Some(now + Duration::from_millis(millis));
+        }
+
+        for (name, value) in macros.macros() {
+            ForwardedClient::set(&mut self.forwarded, name, value);
+        }
+    }
+
+    fn finish_stage(&mut self, now: Instant) {
+        if let Some((stage, since)) = self.stage.take() {
+            *self.stage_durations.entry(stage).or_default() += now.duration_since(since);
+        }
+    }
+}
+
+/// The client of a message as the MTA reports it, unauthenticated.
+///
+/// Taken from the `{client_addr}`, `{client_name}` and `{client_port}`
+/// macros, which hold the original client if an upstream relay forwarded
+/// it with `XFORWARD` or `XCLIENT`. Kept for a single message only, as a
+/// relay forwards the client per message. Configure the MTA to send these
+/// macros for each message, e.g. with postfix's `milter_mail_macros`, to
+/// have them for every message of a connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedClient {
+    addr: Option<String>,
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+impl ForwardedClient {
+    /// The `{client_addr}` macro, the client's IP address
+    #[must_use]
+    pub fn addr(&self) -> Option<&str> {
+        self.addr.as_deref()
+    }
+
+    /// The `{client_name}` macro, the client's hostname
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The `{client_port}` macro, the client's port
+    #[must_use]
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Take the value of the macro `name` into `client`, if it is one of
+    /// the client macros
+    fn set(client: &mut Option<Self>, name: &[u8], value: &[u8]) {
+        let field = match std::str::from_utf8(name) {
+            Ok(CLIENT_ADDR) => &mut client.get_or_insert_with(Self::default).addr,
+            Ok(CLIENT_NAME) => &mut client.get_or_insert_with(Self::default).name,
+            Ok(CLIENT_PORT) => {
+                client.get_or_insert_with(Self::default).port = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|port| port.parse().ok());
+                return;
+            }
+            _ => return,
+        };
+        *field = Some(String::from_utf8_lossy(value).into_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miltr_common::actions::{Abort, QuitNc};
+    use miltr_common::clock::ManualClock;
+    use miltr_common::commands::{Body, Connect, Family, Mail};
+    use miltr_common::optneg::Protocol;
+
+    use super::*;
+
+    fn mail() -> ClientCommand {
+        Mail::from(b"<sender@test.local>".as_slice()).into()
+    }
+
+    #[test]
+    fn test_stage_accounting() {
+        let start = Instant::now();
+        let mut ctx = SessionContext::default();
+
+        ctx.on_command(
+            &Connect::new(b"localhost", Family::Unknown, None, b"").into(),
+            start,
+        );
+        ctx.on_command(&mail(), start + Duration::from_millis(10));
+        ctx.on_command(
+            &Body::from(b"first".as_slice()).into(),
+            start + Duration::from_millis(30),
+        );
+        ctx.on_command(
+            &Body::from(b"second".as_slice()).into(),
+            start + Duration::from_millis(35),
+        );
+
+        assert_eq!(
+            ctx.elapsed_in_stage(SmtpStage::Connect),
+            Duration::from_millis(10)
+        );
+        assert_eq!(
+            ctx.elapsed_in_stage(SmtpStage::Mail),
+            Duration::from_millis(20)
+        );
+        assert_eq!(ctx.elapsed_in_stage(SmtpStage::Helo), Duration::ZERO);
+        assert_eq!(ctx.since_previous_command(), Some(Duration::from_millis(5)));
+        assert!(ctx.elapsed_in_message().is_some());
+    }
+
+    #[test]
+    fn test_abort_ends_message() {
+        let start = Instant::now();
+        let mut ctx = SessionContext::default();
+
+        ctx.on_command(&mail(), start);
+        assert!(ctx.elapsed_in_message().is_some());
+        ctx.on_command(&Abort.into(), start + Duration::from_millis(10));
+
+        assert!(ctx.elapsed_in_message().is_none());
+        assert_eq!(
+            ctx.elapsed_in_stage(SmtpStage::Mail),
+            Duration::from_millis(10)
+        );
+        // Without connect information, the session starts with its first command
+        assert_eq!(ctx.connected_at, Some(start));
+    }
+
+    #[test]
+    fn test_elapsed_on_clock() {
+        let clock = ManualClock::new();
+        let mut ctx = SessionContext::default();
+        ctx.set_clock(Arc::new(clock.clone()));
+
+        ctx.on_command(&mail(), clock.now());
+        clock.advance(Duration::from_secs(3));
+
+        assert_eq!(ctx.elapsed_since_connect(), Duration::from_secs(3));
+        assert_eq!(ctx.elapsed_in_message(), Some(Duration::from_secs(3)));
+        assert_eq!(
+            ctx.elapsed_in_stage(SmtpStage::Mail),
+            Duration::from_secs(3)
+        );
+    }
+
+    #[test]
+    fn test_deadline_from_macro() {
+        let clock = ManualClock::new();
+        let mut ctx = SessionContext::default();
+        ctx.set_clock(Arc::new(clock.clone()));
+        assert_eq!(ctx.remaining_time(), None);
+
+        let mut macros = Macro::new(b'M');
+        macros.push(MILTR_DEADLINE.as_bytes(), b"1500");
+        ctx.on_command(&macros.into(), clock.now());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(ctx.remaining_time(), Some(Duration::from_millis(500)));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(ctx.remaining_time(), Some(Duration::ZERO));
+
+        ctx.on_command(&Abort.into(), clock.now());
+        assert_eq!(ctx.deadline(), None);
+    }
+
+    #[test]
+    fn test_extension_scopes() {
+        let now = Instant::now();
+        let mut ctx = SessionContext::default();
+        ctx.extensions_mut().insert("session");
+        ctx.message_extensions_mut().insert("message");
+
+        ctx.end_message();
+        assert_eq!(ctx.extensions().get::<&str>(), Some(&"session"));
+        assert!(ctx.message_extensions().is_empty());
+
+        ctx.on_command(&OptNeg::default().into(), now);
+        assert!(ctx.extensions().is_empty());
+    }
+
+    #[test]
+    fn test_options_kept_for_connection() {
+        let now = Instant::now();
+        let mut ctx = SessionContext::default();
+        assert!(!ctx.supports_skip());
+
+        ctx.set_options(OptNeg {
+            protocol: Protocol::SMFIP_SKIP | Protocol::SMFIP_HDR_LEADSPC,
+            ..OptNeg::default()
+        });
+        ctx.on_command(&QuitNc.into(), now);
+
+        assert!(ctx.supports_skip());
+        assert!(ctx.leading_space_headers());
+        assert!(!ctx.wants_rejected_rcpts());
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        let now = Instant::now();
+        let mut ctx = SessionContext::default();
+        assert!(ctx.forwarded_client().is_none());
+
+        let mut macros = Macro::new(b'M');
+        macros.push(b"i", b"4Bx1y2");
+        ctx.on_command(&macros.into(), now);
+        assert!(ctx.forwarded_client().is_none());
+
+        let mut macros = Macro::new(b'M');
+        macros.push(CLIENT_ADDR.as_bytes(), b"192.0.2.1");
+        macros.push(CLIENT_NAME.as_bytes(), b"mail.example.com");
+        macros.push(CLIENT_PORT.as_bytes(), b"4711");
+        ctx.on_command(&macros.into(), now);
+        ctx.on_command(&mail(), now);
+
+        let forwarded = ctx.forwarded_client().expect("Forwarded client missing");
+        assert_eq!(forwarded.addr(), Some("192.0.2.1"));
+        assert_eq!(forwarded.name(), Some("mail.example.com"));
+        assert_eq!(forwarded.port(), Some(4711));
+
+        ctx.end_message();
+        assert!(ctx.forwarded_client().is_none());
+    }
+}
@@ -0,0 +1,157 @@
+//! Typed values milters wrapping each other share through the session
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+/// A value stored in [`Extensions`]
+trait Extension: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn Extension>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Clone + Send + Sync + 'static> Extension for T {
+    fn clone_box(&self) -> Box<dyn Extension> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn Extension> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// Values keyed by their type, at most one per type.
+///
+/// Lets a milter wrapping another one pass on what it found out, e.g. the
+/// result of an SPF check, without global state. Use a type of your own
+/// per fact to avoid clashing with other milters.
+///
+/// ```
+/// use miltr_server::Extensions;
+///
+/// #[derive(Debug, Clone, PartialEq)]
+/// struct SpfResult(&'static str);
+///
+/// let mut extensions = Extensions::new();
+/// extensions.insert(SpfResult("pass"));
+/// assert_eq!(extensions.get::<SpfResult>(), Some(&SpfResult("pass")));
+/// ```
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Extension>>,
+}
+
+impl Extensions {
+    /// An empty storage
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `value`, returning the value of the same type stored before
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.into_any().downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// The value of type `T`, if stored
+    #[must_use]
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    /// The value of type `T` to modify, if stored
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+
+    /// Remove and return the value of type `T`
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Whether a value of type `T` is stored
+    #[must_use]
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    /// How many values are stored
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether no value is stored
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all values
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Score(u32);
+
+    #[test]
+    fn test_typed_values() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.insert(Score(1)), None);
+        assert_eq!(extensions.insert(Score(2)), Some(Score(1)));
+        extensions.insert("note");
+
+        if let Some(score) = extensions.get_mut::<Score>() {
+            score.0 += 1;
+        }
+        let cloned = extensions.clone();
+        assert_eq!(extensions.remove::<Score>(), Some(Score(3)));
+
+        assert!(!extensions.contains::<Score>());
+        assert_eq!(extensions.get::<&str>(), Some(&"note"));
+        assert_eq!(cloned.get::<Score>(), Some(&Score(3)));
+        assert_eq!(cloned.len(), 2);
+    }
+}
@@ -0,0 +1,74 @@
+//! Drop modifications the client did not grant capabilities for
+
+use std::{fmt, sync::Arc};
+
+use miltr_common::{
+    modifications::{DroppedModification, ModificationResponse},
+    optneg::Capability,
+};
+use miltr_utils::warn;
+
+use crate::{DroppedModsPolicy, ServerStats};
+
+pub(crate) type DroppedHook = Arc<dyn Fn(&[DroppedModification]) + Send + Sync>;
+
+/// Filters end of body responses by the negotiated capabilities
+#[derive(Clone)]
+pub(crate) struct CapabilityFilter {
+    /// The capabilities negotiated on the current connection
+    pub(crate) capabilities: Capability,
+    pub(crate) policy: DroppedModsPolicy,
+    pub(crate) hook: Option<DroppedHook>,
+    pub(crate) stats: Option<ServerStats>,
+}
+
+impl Default for CapabilityFilter {
+    fn default() -> Self {
+        Self {
+            capabilities: Capability::all(),
+            policy: DroppedModsPolicy::default(),
+            hook: None,
+            stats: None,
+        }
+    }
+}
+
+impl CapabilityFilter {
+    /// Drop what the capabilities do not allow from `response`, reporting
+    /// it and applying the policy if nothing is left
+    pub(crate) fn apply(&self, mut response: ModificationResponse) -> ModificationResponse {
+        let dropped = response.remove_unsupported(self.capabilities);
+        if dropped.is_empty() {
+            return response;
+        }
+
+        warn!(
+            "Dropped {} modifications lacking negotiated capabilities",
+            dropped.len()
+        );
+        if let Some(stats) = &self.stats {
+            stats.modifications_dropped(dropped.len());
+        }
+        if let Some(hook) = &self.hook {
+            hook(&dropped);
+        }
+
+        match &self.policy {
+            DroppedModsPolicy::Respond(action) if response.modifications().is_empty() => {
+                warn!("All modifications dropped, answering {}", action);
+                ModificationResponse::builder().build(action.clone())
+            }
+            _ => response,
+        }
+    }
+}
+
+impl fmt::Debug for CapabilityFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CapabilityFilter")
+            .field("capabilities", &self.capabilities)
+            .field("policy", &self.policy)
+            .field("hook", &self.hook.is_some())
+            .finish_non_exhaustive()
+    }
+}
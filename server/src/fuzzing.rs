@@ -4,7 +4,7 @@
 
 use asynchronous_codec::Decoder;
 use bytes::BytesMut;
-use miltr_common::{decoding::ClientCommand, ProtocolError};
+use miltr_common::{decoding::ClientCommand, test_utils, ProtocolError};
 
 use crate::codec::MilterCodec;
 
@@ -16,3 +16,13 @@ pub fn fuzz_parse(buffer: &mut BytesMut) -> Result<Option<ClientCommand>, Protoc
     let mut codec = MilterCodec::new(2_usize.pow(16));
     (&mut codec).decode(buffer)
 }
+
+/// Fuzzing harness decoding all frames in `data`
+///
+/// # Panics
+/// Panics if the decoder consumes more than one frame per call, modifies
+/// leftover bytes or consumes an incomplete frame.
+pub fn fuzz_decode_frames(data: &[u8]) {
+    let mut codec = MilterCodec::new(2_usize.pow(16));
+    test_utils::check_decode_invariants(data, |buffer| (&mut codec).decode(buffer));
+}
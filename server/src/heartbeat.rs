@@ -0,0 +1,123 @@
+//! Follow the progress of connections as it happens, e.g. for liveness
+//! probes and dashboards
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    Stream,
+};
+
+/// Heartbeats buffered for a lagging stream, more are dropped
+const BACKLOG: usize = 1024;
+
+/// A command received on a connection, with the counters up to it, see
+/// [`Server::heartbeats`](crate::Server::heartbeats)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// The connection, counting those handled by the server from 1
+    pub connection: u64,
+    /// The name of the command received, e.g. `Mail` or `EndOfBody`
+    pub command: &'static str,
+    /// Since the connection was accepted, measured with the server clock
+    pub elapsed: Duration,
+    /// Messages ended on this connection before this command
+    pub messages: u64,
+    /// Bytes of all frames received, including this command
+    pub bytes_in: u64,
+    /// Bytes of all frames sent so far
+    pub bytes_out: u64,
+}
+
+/// The [`Heartbeat`]s of every connection of a server, in order.
+///
+/// Ends when the server is dropped or another stream is requested. While
+/// the stream lags behind by too many heartbeats, new ones are dropped.
+#[derive(Debug)]
+pub struct Heartbeats {
+    receiver: Receiver<Heartbeat>,
+}
+
+impl Stream for Heartbeats {
+    type Item = Heartbeat;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Heartbeat>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Sends heartbeats while a stream listens.
+///
+/// Clones count connections together.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pulse {
+    sender: Option<Sender<Heartbeat>>,
+    connections: Arc<AtomicU64>,
+}
+
+impl Pulse {
+    /// Start a new stream, ending the previous one
+    pub(crate) fn listen(&mut self) -> Heartbeats {
+        let (sender, receiver) = mpsc::channel(BACKLOG);
+        self.sender = Some(sender);
+        Heartbeats { receiver }
+    }
+
+    /// Count a new connection
+    pub(crate) fn connected(&self) -> u64 {
+        self.connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Send `heartbeat`, if anyone listens and keeps up
+    pub(crate) fn beat(&mut self, heartbeat: impl FnOnce() -> Heartbeat) {
+        let Some(sender) = &mut self.sender else {
+            return;
+        };
+        if let Err(err) = sender.try_send(heartbeat()) {
+            if err.is_disconnected() {
+                // The stream was dropped, stop building heartbeats
+                self.sender = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn heartbeat() -> Heartbeat {
+        Heartbeat {
+            connection: 1,
+            command: "Mail",
+            elapsed: Duration::ZERO,
+            messages: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lagging_stream_drops() {
+        let mut pulse = Pulse::default();
+        let heartbeats = pulse.listen();
+
+        for _ in 0..2 * BACKLOG {
+            pulse.beat(heartbeat);
+        }
+        drop(pulse);
+
+        // The sender may always queue one on top
+        assert_eq!(heartbeats.count().await, BACKLOG + 1);
+    }
+}
@@ -1,43 +1,137 @@
 #![doc = include_str!("../Readme.md")]
 
+mod access;
+#[cfg(feature = "spill")]
+mod accumulator;
+mod assembler;
 mod codec;
+mod context;
+mod extensions;
+mod filter;
+mod heartbeat;
+#[cfg(feature = "listen")]
+mod listen;
+mod load_shed;
 mod milter;
+mod milter_fn;
+mod policy;
+mod progress;
+#[cfg(feature = "rspamd")]
+mod rspamd;
+mod scan;
+mod session;
+mod stats;
+#[cfg(feature = "stdio")]
+mod stdio;
+mod summary;
+mod timeout;
+mod translate;
+mod watchdog;
 
 #[cfg(feature = "_fuzzing")]
 pub mod fuzzing;
 
-use asynchronous_codec::Framed;
+use std::{fmt, marker::PhantomData, sync::Arc, time::Duration};
+
+pub use access::{AccessList, AccessVerdict, Cidr, InvalidCidr};
+#[cfg(feature = "spill")]
+pub use accumulator::{BodyAccumulator, BodyReader};
+pub use assembler::MessageAssembler;
+pub use context::{ForwardedClient, SessionContext};
+pub use extensions::Extensions;
+use filter::CapabilityFilter;
+use heartbeat::Pulse;
+pub use heartbeat::{Heartbeat, Heartbeats};
+pub use load_shed::{LoadShedMilter, LoadShedder};
 pub use milter::{Error, Milter};
+pub use milter_fn::{milter_fn, MilterFn};
+pub use policy::{
+    DroppedModsPolicy, ImplErrorAction, ImplErrorPolicy, MissingCapabilityPolicy,
+    NegotiationPolicy, OversizePolicy, RecipientPolicy, UnknownFamilyPolicy, Utf8Action,
+    Utf8Fields, Utf8Policy,
+};
+pub use progress::ProgressHandle;
+#[cfg(feature = "rspamd")]
+pub use rspamd::{RspamdAction, RspamdMilter, RspamdReply};
+pub use scan::{ClamdScanner, ScanBackend, ScanMilter, ScanVerdict};
+use session::Session;
+pub use stats::ServerStats;
+pub use summary::{ConnectionSummary, EndedBy};
+pub use timeout::CallbackTimeouts;
+pub use translate::{QuarantineFallback, ResponseTranslation, TranslationHook};
+pub use watchdog::SlowCallback;
+use watchdog::Watchdog;
 
-use futures::{AsyncRead, AsyncWrite, Future, SinkExt, StreamExt};
+use futures::{pin_mut, AsyncRead, AsyncWrite, Stream, StreamExt};
 use miltr_common::{
-    actions::Action,
-    decoding::ClientCommand,
-    encoding::ServerMessage,
-    optneg::{Capability, OptNeg},
+    clock::Clock,
+    frame::{FrameInfo, FrameSizes},
+    modifications::DroppedModification,
 };
-use miltr_utils::debug;
+use miltr_utils::warn;
 #[cfg(feature = "tracing")]
-use tracing::instrument;
+use tracing::{instrument, Instrument, Span};
 
 pub(crate) use self::codec::MilterCodec;
 
-/// The entry point to host a milter server
+/// The entry point to host a milter server.
+///
+/// A server either borrows one milter to handle connections one after
+/// another with, see [`Self::new`], or creates a milter per connection
+/// with a [`Factory`] to handle many of them at once, see
+/// [`Self::from_factory`].
 #[derive(Debug)]
-pub struct Server<'m, M: Milter> {
-    milter: &'m mut M,
+pub struct Server<'m, M: Milter, S = &'m mut M> {
+    milter: S,
     codec: MilterCodec,
+    config: Config,
+    lifetime: PhantomData<&'m mut M>,
+}
+
+/// Creates the milter of each connection a [`Server`] handles
+pub struct Factory<F>(Arc<F>);
+
+impl<F> Clone for Factory<F> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<F> fmt::Debug for Factory<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Factory(..)")
+    }
+}
+
+/// How a [`Server`] handles its connections, shared by all of them
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
     quit_on_abort: bool,
+    impl_error_policy: ImplErrorPolicy,
+    missing_capability_policy: MissingCapabilityPolicy,
+    negotiation_policy: NegotiationPolicy,
+    oversize_policy: OversizePolicy,
+    utf8_policy: Utf8Policy,
+    recipient_policy: RecipientPolicy,
+    unknown_family_policy: UnknownFamilyPolicy,
+    access: AccessList,
+    translation: ResponseTranslation,
+    watchdog: Watchdog,
+    filter: CapabilityFilter,
+    max_messages: Option<u64>,
+    #[cfg(feature = "listen")]
+    shutdown_deadline: Duration,
+    pulse: Pulse,
 }
 
 impl<'m, M: Milter> Server<'m, M> {
     /// Create a new Server to handle connections
     pub fn new(milter: &'m mut M, quit_on_abort: bool, max_buffer_size: usize) -> Self {
-        let codec = MilterCodec::new(max_buffer_size);
         Self {
             milter,
-            codec,
-            quit_on_abort,
+            codec: MilterCodec::new(max_buffer_size),
+            config: Config::new(quit_on_abort),
+            lifetime: PhantomData,
         }
     }
 
@@ -48,16 +142,16 @@ impl<'m, M: Milter> Server<'m, M> {
     ///
     /// AFAIK, originally there where three use cases individual methods:
     /// 1. Abort \
-    ///   The current smtp client that is connected to the milter client
-    ///   has finished. Next mail arrives.
+    ///    The current smtp client that is connected to the milter client
+    ///    has finished. Next mail arrives.
     /// 2. Quit \
-    ///   The current smtp client that was connected to the milter client
-    ///   has quit it's connection and the milter client will now quit this
-    ///   connection.
+    ///    The current smtp client that was connected to the milter client
+    ///    has quit it's connection and the milter client will now quit this
+    ///    connection.
     /// 3. Quit NC \
-    ///   The current smtp client that was connected to the milter client
-    ///   has quit it's connection but the milter client would like to re-use
-    ///   this connection for someone else.
+    ///    The current smtp client that was connected to the milter client
+    ///    has quit it's connection but the milter client would like to re-use
+    ///    this connection for someone else.
     ///
     /// Different implementation mix them up, making e.g. postfix just always
     /// opening up a new connection for every milter conversation.
@@ -67,9 +161,339 @@ impl<'m, M: Milter> Server<'m, M> {
     pub fn default_postfix(milter: &'m mut M) -> Self {
         Self::new(milter, true, 2_usize.pow(16))
     }
+}
+
+impl<M: Milter, F: Fn() -> M> Server<'static, M, Factory<F>> {
+    /// Create a new Server to handle connections, each with its own milter
+    /// created by `factory`.
+    ///
+    /// Share state between the milters through whatever `factory` hands
+    /// them, like an `Arc`.
+    pub fn from_factory(factory: F, quit_on_abort: bool, max_buffer_size: usize) -> Self {
+        Self {
+            milter: Factory(Arc::new(factory)),
+            codec: MilterCodec::new(max_buffer_size),
+            config: Config::new(quit_on_abort),
+            lifetime: PhantomData,
+        }
+    }
+
+    /// Create a server with defaults working with postfix, like
+    /// [`Server::default_postfix`], creating a milter per connection with
+    /// `factory`.
+    #[must_use]
+    pub fn default_postfix_factory(factory: F) -> Self {
+        Self::from_factory(factory, true, 2_usize.pow(16))
+    }
+}
+
+impl Config {
+    /// How long running connections may take to finish after a shutdown
+    #[cfg(feature = "listen")]
+    const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(30);
+
+    fn new(quit_on_abort: bool) -> Self {
+        Self {
+            quit_on_abort,
+            impl_error_policy: ImplErrorPolicy::default(),
+            missing_capability_policy: MissingCapabilityPolicy::default(),
+            negotiation_policy: NegotiationPolicy::default(),
+            oversize_policy: OversizePolicy::default(),
+            utf8_policy: Utf8Policy::default(),
+            recipient_policy: RecipientPolicy::default(),
+            unknown_family_policy: UnknownFamilyPolicy::default(),
+            access: AccessList::default(),
+            translation: ResponseTranslation::default(),
+            watchdog: Watchdog::default(),
+            filter: CapabilityFilter::default(),
+            max_messages: None,
+            #[cfg(feature = "listen")]
+            shutdown_deadline: Self::SHUTDOWN_DEADLINE,
+            pulse: Pulse::default(),
+        }
+    }
+}
+
+impl<M: Milter, S> Server<'_, M, S> {
+    /// Set how to react if the milter implementation returns an error.
+    ///
+    /// By default, the error is returned from [`Self::handle_connection`]
+    /// without answering the client.
+    #[must_use]
+    pub fn with_impl_error_policy(mut self, policy: ImplErrorPolicy) -> Self {
+        self.config.impl_error_policy = policy;
+        self
+    }
+
+    /// Set how to react if the client does not offer all
+    /// [`Milter::required_capabilities`].
+    ///
+    /// By default, the connection is closed with an error.
+    #[must_use]
+    pub fn with_missing_capability_policy(mut self, policy: MissingCapabilityPolicy) -> Self {
+        self.config.missing_capability_policy = policy;
+        self
+    }
+
+    /// Set how to answer option negotiation.
+    ///
+    /// By default, [`Milter::option_negotiation`] answers. Any other policy
+    /// answers without calling the milter.
+    #[must_use]
+    pub fn with_negotiation_policy(mut self, policy: NegotiationPolicy) -> Self {
+        self.config.negotiation_policy = policy;
+        self
+    }
+
+    /// Set how to react if a frame of an end of body response exceeds the
+    /// buffer size.
+    ///
+    /// By default, the connection is closed with an error before any part
+    /// of the response is sent.
+    #[must_use]
+    pub fn with_oversize_policy(mut self, policy: OversizePolicy) -> Self {
+        self.config.oversize_policy = policy;
+        self
+    }
+
+    /// Check textual fields of commands for valid UTF-8 before calling the
+    /// milter.
+    ///
+    /// By default, nothing is checked.
+    #[must_use]
+    pub fn with_utf8_validation(mut self, policy: Utf8Policy) -> Self {
+        self.config.utf8_policy = policy;
+        self
+    }
+
+    /// Check recipient modifications of end of body responses against the
+    /// recipients of the message.
+    ///
+    /// By default, they are sent unchecked.
+    #[must_use]
+    pub fn with_recipient_policy(mut self, policy: RecipientPolicy) -> Self {
+        self.config.recipient_policy = policy;
+        self
+    }
+
+    /// Set what to do with connect information of an unknown family.
+    ///
+    /// By default, the family byte is passed on to the milter as
+    /// [`Family::Other`](miltr_common::commands::Family::Other).
+    #[must_use]
+    pub fn with_unknown_family_policy(mut self, policy: UnknownFamilyPolicy) -> Self {
+        self.config.unknown_family_policy = policy;
+        self
+    }
+
+    /// Answer connects from the networks in `list` without calling the
+    /// milter.
+    ///
+    /// The client address of the connect information is checked. A
+    /// session answered with [`AccessVerdict::Accept`] gets continue for
+    /// all its commands. The milter is not called until the next session,
+    /// neither for macros nor for abort or quit. Messages of the session
+    /// still end in the session context. By default, every connect goes to
+    /// the milter.
+    #[must_use]
+    pub fn with_access_list(mut self, list: AccessList) -> Self {
+        self.config.access = list;
+        self
+    }
+
+    /// Set how responses unsupported by the negotiated protocol are
+    /// translated.
+    ///
+    /// By default, skip becomes continue and quarantines are added as
+    /// `X-Quarantine` header.
+    #[must_use]
+    pub fn with_response_translation(mut self, translation: ResponseTranslation) -> Self {
+        self.config.translation = translation;
+        self
+    }
+
+    /// Call `hook` for every frame received from the client.
+    ///
+    /// This is called before the frame is decoded, even if decoding fails.
+    #[must_use]
+    pub fn on_frame_received<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&FrameInfo) + Send + Sync + 'static,
+    {
+        self.codec.hooks.received = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` for every frame sent to the client
+    #[must_use]
+    pub fn on_frame_sent<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&FrameInfo) + Send + Sync + 'static,
+    {
+        self.codec.hooks.sent = Some(Arc::new(hook));
+        self
+    }
+
+    /// Report milter callbacks taking longer than `threshold`.
+    ///
+    /// Each is logged as a warning naming the callback and the queue id,
+    /// counted in the [`ServerStats`] and passed to the hook set with
+    /// [`Self::on_slow_callback`]. Off by default.
+    #[must_use]
+    pub fn with_slow_callback_threshold(mut self, threshold: Duration) -> Self {
+        self.config.watchdog.threshold = Some(threshold);
+        self
+    }
+
+    /// Call `hook` for every callback exceeding the threshold set with
+    /// [`Self::with_slow_callback_threshold`]
+    #[must_use]
+    pub fn on_slow_callback<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&SlowCallback) + Send + Sync + 'static,
+    {
+        self.config.watchdog.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Close the connection if a milter callback exceeds its timeout in
+    /// `timeouts`, returning [`Error::Timeout`].
+    ///
+    /// The callback is dropped unfinished. By default, callbacks may take
+    /// as long as they like.
+    #[must_use]
+    pub fn with_callback_timeouts(mut self, timeouts: CallbackTimeouts) -> Self {
+        self.config.watchdog.timeouts = timeouts;
+        self
+    }
+
+    /// Set what to do if all modifications of an end of body response are
+    /// dropped, as the client did not grant the capabilities for them.
+    ///
+    /// By default, the final action of the milter is sent regardless.
+    #[must_use]
+    pub fn with_dropped_mods_policy(mut self, policy: DroppedModsPolicy) -> Self {
+        self.config.filter.policy = policy;
+        self
+    }
+
+    /// Call `hook` with the modifications dropped from an end of body
+    /// response, as the client did not grant the capabilities for them
+    #[must_use]
+    pub fn on_dropped_modifications<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&[DroppedModification]) + Send + Sync + 'static,
+    {
+        self.config.filter.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Close each connection once `max` messages ended on it.
+    ///
+    /// The limit is only applied at a session boundary: the connection is
+    /// closed instead of awaiting a new session after the client sent
+    /// `quit_nc`. Clients quitting on their own are not affected. Closed
+    /// connections are counted as recycled in the [`ServerStats`].
+    #[must_use]
+    pub fn with_max_messages_per_connection(mut self, max: u64) -> Self {
+        self.config.max_messages = Some(max);
+        self
+    }
+
+    /// Close connections still running `deadline` after a shutdown, see
+    /// [`Self::serve_with_shutdown`].
+    ///
+    /// Defaults to 30 seconds. MTAs like postfix keep idle connections
+    /// open between messages, those would hold up a shutdown forever
+    /// without a deadline.
+    #[cfg(feature = "listen")]
+    #[must_use]
+    pub fn with_shutdown_deadline(mut self, deadline: Duration) -> Self {
+        self.config.shutdown_deadline = deadline;
+        self
+    }
+
+    /// Take the time from `clock` instead of the system clock.
+    ///
+    /// This covers the [`SessionContext`] timings, the slow callback
+    /// threshold and the duration in the [`ConnectionSummary`]. Tests pass
+    /// a [`ManualClock`](miltr_common::clock::ManualClock) to control them.
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.config.watchdog.clock = Arc::new(clock);
+        self
+    }
+
+    /// Count connections and frames in `stats`.
+    ///
+    /// Hand clones of the same stats to every server to get totals across
+    /// connections.
+    #[must_use]
+    pub fn with_stats(mut self, stats: ServerStats) -> Self {
+        self.codec.stats = Some(stats);
+        self
+    }
+
+    /// Trace the time spent decoding and encoding every `every`-th frame,
+    /// as `decode_ns` and `encode_ns` per frame code.
+    ///
+    /// Defaults to [`FrameTimer::DEFAULT_EVERY`], zero disables it.
+    ///
+    /// [`FrameTimer::DEFAULT_EVERY`]: miltr_common::frame::FrameTimer::DEFAULT_EVERY
+    #[cfg(feature = "tracing")]
+    #[must_use]
+    pub fn with_codec_timing(mut self, every: u32) -> Self {
+        self.codec.decode_timer = miltr_common::frame::FrameTimer::new(every);
+        self.codec.encode_timer = miltr_common::frame::FrameTimer::new(every);
+        self
+    }
+
+    /// Reject frames shorter than any valid frame of their code before
+    /// parsing them, see [`check_min_len`](miltr_common::frame::check_min_len).
+    ///
+    /// This also rejects frames of length zero. Off by default, leaving
+    /// short frames to the parser of their type.
+    #[must_use]
+    pub fn with_strict_lengths(mut self, strict: bool) -> Self {
+        self.codec.strict_lengths = strict;
+        self
+    }
+
+    /// Reserve read buffer space for several frames at once, see
+    /// [`FrameSizes`]. Enabled by default.
+    #[must_use]
+    pub fn with_adaptive_reserve(mut self, adaptive: bool) -> Self {
+        self.codec.frame_sizes = FrameSizes::new(adaptive);
+        self
+    }
+
+    /// The sizes of the frames read on the current or last connection
+    ///
+    /// Only connections handled by this server itself are covered. Those
+    /// served concurrently each track their sizes in a copy of the codec,
+    /// as they reserve their read buffers by them, and are not reported.
+    #[must_use]
+    pub fn frame_sizes(&self) -> &FrameSizes {
+        &self.codec.frame_sizes
+    }
 
+    /// A [`Heartbeat`] for every command received on the connections
+    /// handled from now on.
+    ///
+    /// Nothing is collected before this is called. Heartbeats are buffered
+    /// until consumed, up to a limit, those beyond are dropped. Calling this
+    /// again ends the previous stream.
+    pub fn heartbeats(&mut self) -> Heartbeats {
+        self.config.pulse.listen()
+    }
+}
+
+impl<M: Milter> Server<'_, M> {
     /// Handle a single milter connection.
     ///
+    /// Returns a [`ConnectionSummary`] of what happened on it, e.g. to log
+    /// or export it.
+    ///
     /// # Arguments
     /// - milter: the object implementing [`crate::Milter`]. It's methods will
     ///   be called at the appropriate times.
@@ -83,117 +507,155 @@ impl<'m, M: Milter> Server<'m, M> {
     pub async fn handle_connection<RW: AsyncRead + AsyncWrite + Unpin + Send>(
         &mut self,
         socket: RW,
-    ) -> Result<(), Error<M::Error>> {
-        let mut framed = Framed::new(socket, &mut self.codec);
-
-        let mut options: Option<OptNeg> = Option::None;
-
-        while let Some(command) = framed.next().await {
-            let command = command?;
-            debug!("Received {}", command);
-
-            match command {
-                // First, all the regular smtp related commands
-                ClientCommand::Helo(helo) => {
-                    Self::notify_respond_answer(self.milter.helo(helo), &mut framed).await?;
-                }
-                ClientCommand::Connect(connect) => {
-                    Self::notify_respond_answer(self.milter.connect(connect), &mut framed).await?;
-                }
-                ClientCommand::Mail(mail) => {
-                    Self::notify_respond_answer(self.milter.mail(mail), &mut framed).await?;
-                }
-                ClientCommand::Recipient(rcpt) => {
-                    Self::notify_respond_answer(self.milter.rcpt(rcpt), &mut framed).await?;
-                }
-                ClientCommand::Data(_v) => {
-                    Self::notify_respond_answer(self.milter.data(), &mut framed).await?;
-                }
-                ClientCommand::Header(header) => {
-                    Self::notify_respond_answer(self.milter.header(header), &mut framed).await?;
-                }
-                ClientCommand::EndOfHeader(_v) => {
-                    Self::notify_respond_answer(self.milter.end_of_header(), &mut framed).await?;
-                }
-                ClientCommand::Body(body) => {
-                    Self::notify_respond_answer(self.milter.body(body), &mut framed).await?;
-                }
-                ClientCommand::Unknown(unknown) => {
-                    Self::notify_respond_answer(self.milter.unknown(unknown), &mut framed).await?;
-                }
-                // Regular smtp session related commands that need special responses
-                ClientCommand::EndOfBody(_v) => {
-                    // Notify the milter trait implementation
-                    let mut responses = self
-                        .milter
-                        .end_of_body()
-                        .await
-                        .map_err(Error::from_app_error)?;
-
-                    // Filter those returned mod requests, keep only those
-                    // which have been set by the current capabilities.
-                    responses.filter_mods_by_caps(
-                        options
-                            .as_ref()
-                            .map_or(Capability::all(), |o| o.capabilities),
-                    );
-
-                    // And send them back
-                    let responses: Vec<ServerMessage> = responses.into();
-                    for response in responses {
-                        debug!("Sending response");
-                        framed.send(&response).await?;
-                    }
-                }
-                ClientCommand::Macro(macro_) => {
-                    self.milter
-                        .macro_(macro_)
-                        .await
-                        .map_err(Error::from_app_error)?;
-                    continue;
-                }
-
-                // Control flow cases
-                // Option Negotiation
-                ClientCommand::OptNeg(opt_neg) => {
-                    let response = self.milter.option_negotiation(opt_neg).await?;
-                    options = Some(response.clone());
-                    framed.send(&response.into()).await?;
-                }
-                // Abort the current smtp session handling
-                ClientCommand::Abort(_v) => {
-                    let response = self.milter.abort().await.map_err(Error::from_app_error)?;
-
-                    if self.quit_on_abort {
-                        self.milter.quit().await.map_err(Error::from_app_error)?;
-                        return Ok(());
-                    }
-                    framed.send(&response.into()).await?;
-                }
-                // Quit this connection
-                ClientCommand::Quit(_v) => {
-                    self.milter.quit().await.map_err(Error::from_app_error)?;
-                    return Ok(());
-                }
-                // Quit and re-use this connection
-                ClientCommand::QuitNc(_v) => {
-                    self.milter.quit_nc().await.map_err(Error::from_app_error)?;
-                    continue;
-                }
-            };
+    ) -> Result<ConnectionSummary, Error<M::Error>> {
+        let Some(stats) = self.codec.stats.clone() else {
+            return self.serve(socket).await;
+        };
+
+        let _active = stats.connection();
+        let result = self.serve(socket).await;
+        if result.is_err() {
+            stats.connection_failed();
+        }
+        result
+    }
+
+    /// Handle a connection like [`Self::handle_connection`], tracing it
+    /// within `parent` instead of the current span.
+    ///
+    /// Use this to nest the spans of a connection under one the accept
+    /// loop keeps per SMTP session. An OpenTelemetry context is attached by
+    /// setting it as parent of `parent` first, e.g. with
+    /// `tracing-opentelemetry`.
+    ///
+    /// # Errors
+    /// As [`Self::handle_connection`]
+    #[cfg(feature = "tracing")]
+    pub async fn handle_connection_in_span<RW: AsyncRead + AsyncWrite + Unpin + Send>(
+        &mut self,
+        socket: RW,
+        parent: &Span,
+    ) -> Result<ConnectionSummary, Error<M::Error>> {
+        self.handle_connection(socket)
+            .instrument(parent.clone())
+            .await
+    }
+
+    /// Handle the one connection on stdin and stdout, as handed over by
+    /// inetd or systemd socket activation with `Accept=yes`.
+    ///
+    /// The connection ends when the client quits or stdin is closed, stdout
+    /// is flushed before returning. Nothing else may be written to stdout
+    /// meanwhile, log to stderr instead. See `examples/stdio.rs` for a unit
+    /// file to run it with.
+    ///
+    /// # Errors
+    /// Like [`Self::handle_connection`]
+    #[cfg(feature = "stdio")]
+    pub async fn handle_stdio(&mut self) -> Result<ConnectionSummary, Error<M::Error>> {
+        use futures::AsyncWriteExt;
+
+        let mut stdio = stdio::Stdio::new();
+        let summary = self.handle_connection(&mut stdio).await?;
+        stdio.close().await?;
+        Ok(summary)
+    }
+
+    /// Handle each connection of `incoming` in turn, until it ends.
+    ///
+    /// This plugs any acceptor into the server, be it a listener, the
+    /// streams of a QUIC connection, an in-process channel or just
+    /// stdin/stdout when started from inetd, with the limits, stats and
+    /// policies of this server applying to all connections. End the stream
+    /// to shut down, e.g. with [`StreamExt::take_until`]; the connection
+    /// being handled is finished first. To handle connections concurrently,
+    /// create the server with a [`Factory`] instead and see
+    /// `Server::serve_concurrently` of the `listen` feature.
+    ///
+    /// Connections failing, also because the milter implementation
+    /// errored, are logged and counted in the [`ServerStats`], the next one
+    /// is handled. Returns the number of connections handled.
+    pub async fn serve_incoming<S, RW>(&mut self, incoming: S) -> u64
+    where
+        S: Stream<Item = RW>,
+        RW: AsyncRead + AsyncWrite + Unpin + Send,
+        M::Error: fmt::Debug,
+    {
+        pin_mut!(incoming);
+        let mut handled = 0;
+        while let Some(socket) = incoming.next().await {
+            handled += 1;
+            if let Err(err) = self.handle_connection(socket).await {
+                log_failure(&err);
+            }
+        }
+        handled
+    }
+
+    async fn serve<RW: AsyncRead + AsyncWrite + Unpin + Send>(
+        &mut self,
+        socket: RW,
+    ) -> Result<ConnectionSummary, Error<M::Error>> {
+        Session::new(self.milter, &self.config, &mut self.codec, socket)
+            .run()
+            .await
+    }
+}
+
+/// Log why handling a connection failed
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn log_failure<E: fmt::Debug>(err: &Error<E>) {
+    warn!("Connection failed: {:?}", err);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+    use tracing::{span::Attributes, subscriber, Id, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    use super::*;
+
+    /// The name of a span with the name of its parent
+    type Named = (&'static str, Option<&'static str>);
+
+    /// Records the name of each new span with the name of its parent
+    #[derive(Clone, Default)]
+    struct Parents(Arc<Mutex<Vec<Named>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Parents {
+        fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("Unknown span");
+            let parent = span.parent().map(|parent| parent.name());
+            self.0.lock().expect("Poisoned").push((span.name(), parent));
         }
-        Ok(())
     }
 
-    /// Helper function to notify the milter, handle errors and respond
-    async fn notify_respond_answer<RW: AsyncRead + AsyncWrite + Unpin>(
-        milter_fn: impl Future<Output = Result<impl Into<Action>, M::Error>>,
-        framed: &mut Framed<RW, &mut MilterCodec>,
-    ) -> Result<(), milter::Error<M::Error>> {
-        let response = milter_fn.await.map_err(Error::from_app_error)?;
-        let response: Action = response.into();
+    #[tokio::test]
+    async fn test_handle_connection_in_span() {
+        let parents = Parents::default();
+        let _guard = subscriber::set_default(tracing_subscriber::registry().with(parents.clone()));
+        let session = tracing::info_span!("smtp_session");
+
+        let (mut client_side, server_side) = tokio::io::duplex(2_usize.pow(16));
+        // Option negotiation for version 6 and quit
+        client_side
+            .write_all(b"\0\0\0\x0dO\0\0\0\x06\0\0\0\0\0\0\0\0\0\0\0\x01Q")
+            .await
+            .expect("Failed writing");
+        let mut milter = milter_fn();
+        Server::default_postfix(&mut milter)
+            .handle_connection_in_span(server_side.compat(), &session)
+            .await
+            .expect("Failed handling the connection");
 
-        framed.send(&response.into()).await?;
-        Ok(())
+        let parents = parents.0.lock().expect("Poisoned");
+        assert!(
+            parents.contains(&("handle_connection", Some("smtp_session"))),
+            "{parents:?}"
+        );
     }
 }
@@ -0,0 +1,241 @@
+//! Accept connections on TCP and unix sockets with tokio, handling each in
+//! its own task
+
+#[cfg(unix)]
+use std::path::Path;
+use std::{fmt, future::Future, io, marker::PhantomData, time::Duration};
+
+use futures::{pin_mut, AsyncRead, AsyncWrite, FutureExt, Stream, StreamExt};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::{JoinError, JoinSet},
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+#[cfg(feature = "tracing")]
+use tracing::Instrument;
+
+use miltr_utils::warn;
+
+use crate::{log_failure, Factory, Milter, Server};
+
+/// How long to pause accepting after it failed, e.g. as the process ran
+/// out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+impl<M, F> Server<'static, M, Factory<F>>
+where
+    M: Milter + 'static,
+    M::Error: fmt::Debug + 'static,
+    F: Fn() -> M + Send + Sync + 'static,
+{
+    /// Handle the connections of `incoming` concurrently, each in its own
+    /// task with its own milter created by the factory of this server.
+    ///
+    /// Up to `limit` connections are handled at once, the next one is only
+    /// taken from `incoming` once one of them ended. All connections share
+    /// the limits, stats, hooks and policies of this server. Once
+    /// `incoming` ends, the running connections are awaited.
+    ///
+    /// Connections failing, also because the milter implementation
+    /// errored, are logged and counted in the
+    /// [`ServerStats`](crate::ServerStats), the others go on. Returns the
+    /// number of connections handled.
+    pub async fn serve_concurrently<S, RW>(&self, incoming: S, limit: usize) -> u64
+    where
+        S: Stream<Item = RW>,
+        RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut connections = JoinSet::new();
+        let handled = self.spawn_each(incoming, limit, &mut connections).await;
+        while let Some(joined) = connections.join_next().await {
+            log_panic(joined);
+        }
+        handled
+    }
+
+    /// Handle the connections of `incoming` like
+    /// [`Self::serve_concurrently`], until `shutdown` completes.
+    ///
+    /// No more connections are taken from `incoming` after that. The
+    /// running ones may finish within the deadline set with
+    /// [`Self::with_shutdown_deadline`], those still running then are
+    /// closed, idle ones included. Returns the number of connections handled.
+    pub async fn serve_with_shutdown<S, RW>(
+        &self,
+        incoming: S,
+        limit: usize,
+        shutdown: impl Future<Output = ()>,
+    ) -> u64
+    where
+        S: Stream<Item = RW>,
+        RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let shutdown = shutdown.shared();
+        let mut connections = JoinSet::new();
+        let incoming = incoming.take_until(shutdown.clone());
+        let handled = self.spawn_each(incoming, limit, &mut connections).await;
+
+        let drain = async {
+            while let Some(joined) = connections.join_next().await {
+                log_panic(joined);
+            }
+        };
+        let deadline = async {
+            shutdown.await;
+            let clock = &self.config.watchdog.clock;
+            clock.sleep(self.config.shutdown_deadline).await;
+        };
+        let drained = tokio::select! {
+            () = drain => true,
+            () = deadline => false,
+        };
+        if !drained {
+            warn!("Shutdown deadline passed, closing the remaining connections");
+            connections.shutdown().await;
+        }
+        handled
+    }
+
+    /// Listen on the TCP `addr` and handle every connection accepted, see
+    /// [`Self::serve_concurrently`].
+    ///
+    /// Once `shutdown` completes, no more connections are accepted and the
+    /// running ones are finished before returning, see
+    /// [`Self::serve_with_shutdown`]. Returns the number of connections
+    /// handled.
+    ///
+    /// # Errors
+    /// If binding `addr` fails
+    pub async fn serve_tcp<A>(
+        &self,
+        addr: A,
+        limit: usize,
+        shutdown: impl Future<Output = ()>,
+    ) -> io::Result<u64>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        let incoming = tcp_incoming(listener);
+        Ok(self.serve_with_shutdown(incoming, limit, shutdown).await)
+    }
+
+    /// Listen on the unix socket at `path` and handle every connection
+    /// like [`Self::serve_tcp`].
+    ///
+    /// Binding fails if `path` exists, remove a socket left over by a
+    /// previous run first. The socket is not removed on return.
+    ///
+    /// # Errors
+    /// If binding `path` fails
+    #[cfg(unix)]
+    pub async fn serve_unix<P>(
+        &self,
+        path: P,
+        limit: usize,
+        shutdown: impl Future<Output = ()>,
+    ) -> io::Result<u64>
+    where
+        P: AsRef<Path>,
+    {
+        let listener = UnixListener::bind(path)?;
+        let incoming = unix_incoming(listener);
+        Ok(self.serve_with_shutdown(incoming, limit, shutdown).await)
+    }
+
+    /// Spawn a task into `connections` for every connection of `incoming`,
+    /// keeping at most `limit` running. Returns the number spawned.
+    async fn spawn_each<S, RW>(
+        &self,
+        incoming: S,
+        limit: usize,
+        connections: &mut JoinSet<()>,
+    ) -> u64
+    where
+        S: Stream<Item = RW>,
+        RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let limit = limit.max(1);
+        pin_mut!(incoming);
+        let mut handled = 0;
+        loop {
+            tokio::select! {
+                socket = incoming.next(), if connections.len() < limit => {
+                    let Some(socket) = socket else {
+                        return handled;
+                    };
+                    handled += 1;
+                    connections.spawn(self.connection(socket));
+                }
+                Some(joined) = connections.join_next() => log_panic(joined),
+            }
+        }
+    }
+
+    /// Handle `socket` with a new milter, independent of this server
+    fn connection<RW>(&self, socket: RW) -> impl Future<Output = ()> + Send + 'static
+    where
+        RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let factory = self.milter.clone();
+        let codec = self.codec.clone();
+        let config = self.config.clone();
+        let connection = async move {
+            let mut milter = (factory.0)();
+            let mut server = Server {
+                milter: &mut milter,
+                codec,
+                config,
+                lifetime: PhantomData,
+            };
+            if let Err(err) = server.handle_connection(socket).await {
+                log_failure(&err);
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let connection = connection.in_current_span();
+        connection
+    }
+}
+
+/// Log a connection task that panicked
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn log_panic(joined: Result<(), JoinError>) {
+    if let Err(err) = joined {
+        warn!("Connection task failed: {}", err);
+    }
+}
+
+/// The connections accepted by `listener`
+fn tcp_incoming(listener: TcpListener) -> impl Stream<Item = Compat<TcpStream>> {
+    futures::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => return Some((stream.compat(), listener)),
+                Err(err) => backoff(&err).await,
+            }
+        }
+    })
+}
+
+/// The connections accepted by `listener`
+#[cfg(unix)]
+fn unix_incoming(listener: UnixListener) -> impl Stream<Item = Compat<UnixStream>> {
+    futures::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => return Some((stream.compat(), listener)),
+                Err(err) => backoff(&err).await,
+            }
+        }
+    })
+}
+
+/// Pause after accepting failed
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+async fn backoff(err: &io::Error) {
+    warn!("Failed accepting connection: {}", err);
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+}
@@ -0,0 +1,139 @@
+//! Answer connections cheaply while overloaded
+
+use std::convert::Infallible;
+
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue, Tempfail},
+    commands::{Body, Connect, Header, Helo, Mail, Recipient, Unknown},
+    modifications::ModificationResponse,
+};
+
+use crate::Milter;
+
+/// A milter failing every session temporarily, to serve connections
+/// accepted while overloaded.
+///
+/// Refusing connections makes MTAs treat the milter as broken and apply
+/// their default action, possibly passing mail unfiltered. This milter
+/// negotiates as usual and answers with tempfail instead, so the MTA tells
+/// its clients to come back later. It does no work beyond that.
+///
+/// See [`LoadShedder`] for when to switch to it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadShedMilter;
+
+#[async_trait]
+impl Milter for LoadShedMilter {
+    type Error = Infallible;
+
+    async fn connect(&mut self, _connect_info: Connect) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn helo(&mut self, _helo: Helo) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn mail(&mut self, _mail: Mail) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn rcpt(&mut self, _recipient: Recipient) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn data(&mut self) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn header(&mut self, _header: Header) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn end_of_header(&mut self) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn body(&mut self, _body: Body) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        Ok(ModificationResponse::builder().build(Tempfail))
+    }
+
+    async fn unknown(&mut self, _cmd: Unknown) -> Result<Action, Self::Error> {
+        Ok(Tempfail.into())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        // Abort is never answered, nothing to refuse
+        Ok(Continue.into())
+    }
+}
+
+/// Decides when an accept loop hands new connections to a
+/// [`LoadShedMilter`] instead of the real milter.
+///
+/// Shedding starts once the connections in flight reach the threshold and
+/// stops once they dropped below the resume mark, by default the threshold
+/// itself. A lower resume mark keeps the loop from flapping at the edge.
+///
+/// ```
+/// use miltr_server::LoadShedder;
+///
+/// let mut shedder = LoadShedder::new(100).with_resume_below(80);
+/// assert!(!shedder.shed(99));
+/// assert!(shedder.shed(100));
+/// assert!(shedder.shed(90));
+/// assert!(!shedder.shed(79));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoadShedder {
+    threshold: u64,
+    resume_below: u64,
+    shedding: bool,
+}
+
+impl LoadShedder {
+    /// Shed connections while `threshold` connections are in flight
+    #[must_use]
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            resume_below: threshold,
+            shedding: false,
+        }
+    }
+
+    /// Keep shedding until fewer than `resume_below` connections are in
+    /// flight
+    #[must_use]
+    pub fn with_resume_below(mut self, resume_below: u64) -> Self {
+        self.resume_below = resume_below.min(self.threshold);
+        self
+    }
+
+    /// Whether to shed a new connection, with `in_flight` connections
+    /// handled by the real milter.
+    ///
+    /// Count them from accepting on, not only once the server started
+    /// handling them like the
+    /// [`ServerStats::active_connections`](crate::ServerStats::active_connections):
+    /// a burst of connections accepted before any task ran would all pass.
+    pub fn shed(&mut self, in_flight: u64) -> bool {
+        if self.shedding {
+            self.shedding = in_flight >= self.resume_below;
+        } else {
+            self.shedding = in_flight >= self.threshold;
+        }
+        self.shedding
+    }
+
+    /// Whether the last connection was shed
+    #[must_use]
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
+    }
+}
@@ -1,13 +1,15 @@
-use std::io;
+use std::{io, time::Duration};
 
 use async_trait::async_trait;
 use thiserror::Error;
 
+use crate::{timeout::TimedOut, ProgressHandle, SessionContext};
+
 use miltr_common::{
     actions::{Action, Continue},
     commands::{Body, Connect, Header, Helo, Macro, Mail, Recipient, Unknown},
     modifications::ModificationResponse,
-    optneg::OptNeg,
+    optneg::{Capability, OptNeg},
     ProtocolError,
 };
 
@@ -30,6 +32,24 @@ pub trait Milter: Send {
         Ok(ours)
     }
 
+    /// Capabilities this milter can not work without.
+    ///
+    /// If the client does not offer all of them during option negotiation,
+    /// the server applies its
+    /// [`MissingCapabilityPolicy`](crate::MissingCapabilityPolicy).
+    fn required_capabilities(&self) -> Capability {
+        Capability::empty()
+    }
+
+    /// Where the server keeps the [`SessionContext`] of this milter.
+    ///
+    /// Return a context owned by the milter to have the server update it
+    /// before each callback. Wrapping milters should hand out the context
+    /// of the milter they wrap.
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        None
+    }
+
     /// A macro sent by the milter client.
     #[doc(alias = "SMFIC_MACRO")]
     async fn macro_(&mut self, _macro: Macro) -> Result<(), Self::Error> {
@@ -95,6 +115,8 @@ pub trait Milter: Send {
     /// A body part was received.
     ///
     /// This may be called multiple times until the whole body was transmitted.
+    /// It is not called for messages without body, nor for empty parts,
+    /// [`Self::end_of_body`] is called regardless.
     #[doc(alias = "SMFIC_BODY")]
     #[doc(alias = "xxfi_body")]
     async fn body(&mut self, _body: Body) -> Result<Action, Self::Error> {
@@ -111,6 +133,20 @@ pub trait Milter: Send {
         Ok(ModificationResponse::empty_continue())
     }
 
+    /// Like [`Self::end_of_body`], with a handle to keep the client
+    /// waiting during long work, like scanning the message.
+    ///
+    /// The server calls this at the end of body. The default calls
+    /// [`Self::end_of_body`], without any progress.
+    #[doc(alias = "SMFIR_PROGRESS")]
+    #[doc(alias = "smfi_progress")]
+    async fn end_of_body_with_progress(
+        &mut self,
+        _progress: ProgressHandle,
+    ) -> Result<ModificationResponse, Self::Error> {
+        self.end_of_body().await
+    }
+
     /// A command not matching any Code is received as `unknown`.
     #[doc(alias = "SMFIC_UNKNOWN")]
     #[doc(alias = "xxfi_unknown")]
@@ -128,11 +164,27 @@ pub trait Milter: Send {
     /// This is the only function not covered by a default. The implementor
     /// needs to reset it's state to handle a new connection.
     ///
+    /// To reset per-message state only, prefer [`Milter::message_reset`],
+    /// which is called once per message whether it ends in an end of body
+    /// or an abort.
+    ///
     /// See [`Server::default_postfix`](crate::Server::default_postfix).
     #[doc(alias = "SMFIC_ABORT")]
     #[doc(alias = "xxfi_abort")]
     async fn abort(&mut self) -> Result<Action, Self::Error>;
 
+    /// Called whenever a message ends, to reset per-message state.
+    ///
+    /// The server calls this after answering the end of body, or after
+    /// [`Milter::abort`] if an abort arrives during a message. Between
+    /// messages, an abort does not call this again.
+    ///
+    /// The default does nothing, leaving milters resetting in `abort` as
+    /// they are.
+    async fn message_reset(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Called on quitting a connection from a milter client.
     ///
     /// Some clients (postfix) do not call this method and instead call
@@ -146,6 +198,10 @@ pub trait Milter: Send {
     }
 
     /// Called when a milter client want's to re-use this milter for a new mail.
+    ///
+    /// The client continues with a new session on the same connection,
+    /// either re-negotiating options or directly connecting with the options
+    /// negotiated before.
     #[doc(alias = "SMFIC_QUIT_NC")]
     async fn quit_nc(&mut self) -> Result<(), Self::Error> {
         Ok(())
@@ -172,6 +228,17 @@ pub enum Error<ImplError> {
         /// The application error patched through
         source: ImplError,
     },
+
+    /// A milter callback did not finish in time, see
+    /// [`Server::with_callback_timeouts`](crate::Server::with_callback_timeouts).
+    /// The connection was closed.
+    #[error("milter callback {callback} timed out after {timeout:?}")]
+    Timeout {
+        /// The [`Milter`] method called, e.g. `end_of_body`
+        callback: &'static str,
+        /// The timeout it exceeded
+        timeout: Duration,
+    },
 }
 
 impl<AppError> Error<AppError> {
@@ -179,3 +246,12 @@ impl<AppError> Error<AppError> {
         Self::Impl { source }
     }
 }
+
+impl<AppError> From<TimedOut> for Error<AppError> {
+    fn from(timed_out: TimedOut) -> Self {
+        Self::Timeout {
+            callback: timed_out.callback,
+            timeout: timed_out.timeout,
+        }
+    }
+}
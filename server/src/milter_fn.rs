@@ -0,0 +1,187 @@
+//! Build a milter from closures, e.g. for tests or small tools
+
+use std::{convert::Infallible, fmt};
+
+use async_trait::async_trait;
+use miltr_common::{
+    actions::{Action, Continue},
+    commands::{Body, Connect, Header, Helo, Mail, Recipient},
+    modifications::ModificationResponse,
+};
+
+use crate::{Milter, SessionContext};
+
+type Hook<T> = Box<dyn FnMut(&T) -> Action + Send>;
+type EndOfBodyHook = Box<dyn FnMut(&SessionContext) -> ModificationResponse + Send>;
+
+/// Start building a [`MilterFn`].
+///
+/// ```
+/// use miltr_common::actions::{Continue, Reject};
+/// use miltr_server::milter_fn;
+///
+/// let milter = milter_fn().on_rcpt(|rcpt| {
+///     if rcpt.recipient().contains("spam") {
+///         Reject.into()
+///     } else {
+///         Continue.into()
+///     }
+/// });
+/// ```
+#[must_use]
+pub fn milter_fn() -> MilterFn {
+    MilterFn::default()
+}
+
+/// A [`Milter`] calling closures, see [`milter_fn`].
+///
+/// Stages without a closure are answered with continue, end of body
+/// without modifications. Closures can not fail.
+#[derive(Default)]
+pub struct MilterFn {
+    connect: Option<Hook<Connect>>,
+    helo: Option<Hook<Helo>>,
+    mail: Option<Hook<Mail>>,
+    rcpt: Option<Hook<Recipient>>,
+    header: Option<Hook<Header>>,
+    body: Option<Hook<Body>>,
+    end_of_body: Option<EndOfBodyHook>,
+    context: SessionContext,
+}
+
+impl MilterFn {
+    /// Answer connect with `hook`
+    #[must_use]
+    pub fn on_connect<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Connect) -> Action + Send + 'static,
+    {
+        self.connect = Some(Box::new(hook));
+        self
+    }
+
+    /// Answer helo with `hook`
+    #[must_use]
+    pub fn on_helo<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Helo) -> Action + Send + 'static,
+    {
+        self.helo = Some(Box::new(hook));
+        self
+    }
+
+    /// Answer mail from with `hook`
+    #[must_use]
+    pub fn on_mail<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Mail) -> Action + Send + 'static,
+    {
+        self.mail = Some(Box::new(hook));
+        self
+    }
+
+    /// Answer each recipient with `hook`
+    #[must_use]
+    pub fn on_rcpt<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Recipient) -> Action + Send + 'static,
+    {
+        self.rcpt = Some(Box::new(hook));
+        self
+    }
+
+    /// Answer each header with `hook`
+    #[must_use]
+    pub fn on_header<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Header) -> Action + Send + 'static,
+    {
+        self.header = Some(Box::new(hook));
+        self
+    }
+
+    /// Answer each body part with `hook`
+    #[must_use]
+    pub fn on_body<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Body) -> Action + Send + 'static,
+    {
+        self.body = Some(Box::new(hook));
+        self
+    }
+
+    /// Answer end of body with `hook`, passing the session context
+    #[must_use]
+    pub fn on_eom<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&SessionContext) -> ModificationResponse + Send + 'static,
+    {
+        self.end_of_body = Some(Box::new(hook));
+        self
+    }
+}
+
+/// Call `hook` if set, continue otherwise
+fn call<T>(hook: &mut Option<Hook<T>>, value: &T) -> Action {
+    hook.as_mut().map_or(Continue.into(), |hook| hook(value))
+}
+
+#[async_trait]
+impl Milter for MilterFn {
+    type Error = Infallible;
+
+    fn session_context(&mut self) -> Option<&mut SessionContext> {
+        Some(&mut self.context)
+    }
+
+    async fn connect(&mut self, connect_info: Connect) -> Result<Action, Self::Error> {
+        Ok(call(&mut self.connect, &connect_info))
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<Action, Self::Error> {
+        Ok(call(&mut self.helo, &helo))
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<Action, Self::Error> {
+        Ok(call(&mut self.mail, &mail))
+    }
+
+    async fn rcpt(&mut self, recipient: Recipient) -> Result<Action, Self::Error> {
+        Ok(call(&mut self.rcpt, &recipient))
+    }
+
+    async fn header(&mut self, header: Header) -> Result<Action, Self::Error> {
+        Ok(call(&mut self.header, &header))
+    }
+
+    async fn body(&mut self, body: Body) -> Result<Action, Self::Error> {
+        Ok(call(&mut self.body, &body))
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        Ok(self
+            .end_of_body
+            .as_mut()
+            .map_or_else(ModificationResponse::empty_continue, |hook| {
+                hook(&self.context)
+            }))
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        Ok(Continue.into())
+    }
+}
+
+impl fmt::Debug for MilterFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MilterFn")
+            .field("connect", &self.connect.is_some())
+            .field("helo", &self.helo.is_some())
+            .field("mail", &self.mail.is_some())
+            .field("rcpt", &self.rcpt.is_some())
+            .field("header", &self.header.is_some())
+            .field("body", &self.body.is_some())
+            .field("end_of_body", &self.end_of_body.is_some())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,355 @@
+//! Configure how the server reacts to failures of the milter implementation
+
+use miltr_common::{
+    actions::{Action, Continue, Reject, Tempfail},
+    commands::{Connect, Family},
+    modifications::ModificationResponse,
+    optneg::{CompatibilityError, OptNeg},
+};
+use miltr_utils::warn;
+
+/// The answer sent to the client when the milter implementation errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImplErrorAction {
+    /// Do not answer, return the error from
+    /// [`Server::handle_connection`](crate::Server::handle_connection).
+    ///
+    /// This closes the connection, leaving the client to apply its own
+    /// default.
+    #[default]
+    Propagate,
+    /// Answer with a temporary failure
+    Tempfail,
+    /// Let the mail pass (fail-open)
+    Accept,
+    /// Reject the mail (fail-closed)
+    Reject,
+}
+
+/// How to go on when the milter implementation returns an error.
+///
+/// Stages the client expects no answer to (macros, quit) only log the
+/// error, unless the connection is to be closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImplErrorPolicy {
+    /// What to answer the client with
+    pub action: ImplErrorAction,
+    /// Whether to close the connection after answering.
+    ///
+    /// If set, the error is returned from
+    /// [`Server::handle_connection`](crate::Server::handle_connection).
+    pub close: bool,
+}
+
+impl ImplErrorPolicy {
+    /// Answer with `action` and keep handling the connection
+    #[must_use]
+    pub fn respond(action: ImplErrorAction) -> Self {
+        Self {
+            action,
+            close: false,
+        }
+    }
+
+    /// Close the connection after answering
+    #[must_use]
+    pub fn and_close(mut self) -> Self {
+        self.close = true;
+        self
+    }
+
+    /// The action to answer with, `None` if the error is propagated
+    pub(crate) fn response(self) -> Option<Action> {
+        match self.action {
+            ImplErrorAction::Propagate => None,
+            ImplErrorAction::Tempfail => Some(Tempfail.into()),
+            ImplErrorAction::Accept => Some(Continue.into()),
+            ImplErrorAction::Reject => Some(Reject.into()),
+        }
+    }
+
+    /// Whether handling the connection ends on an error
+    pub(crate) fn ends_connection(self) -> bool {
+        self.close || self.action == ImplErrorAction::Propagate
+    }
+}
+
+/// What to do if the client does not offer all
+/// [`Milter::required_capabilities`](crate::Milter::required_capabilities)
+#[derive(Debug, Clone, Default)]
+pub enum MissingCapabilityPolicy {
+    /// Close the connection without answering the option negotiation,
+    /// returning a [`CompatibilityError::MissingCapability`](miltr_common::optneg::CompatibilityError::MissingCapability).
+    #[default]
+    Error,
+    /// Finish option negotiation, but answer every following command with
+    /// this action instead of calling the milter.
+    Respond(Action),
+}
+
+/// How the server answers option negotiation.
+///
+/// Every policy but [`NegotiationPolicy::Milter`] answers without calling
+/// [`Milter::option_negotiation`](crate::Milter::option_negotiation), so
+/// keep the default for milters overriding it.
+#[derive(Debug, Clone, Default)]
+pub enum NegotiationPolicy {
+    /// Let [`Milter::option_negotiation`](crate::Milter::option_negotiation)
+    /// answer
+    #[default]
+    Milter,
+    /// Accept every capability the client offers, requesting no protocol
+    /// flags
+    AcceptAll,
+    /// Answer with the capabilities and protocol flags of these options
+    /// the client offers as well, see [`OptNeg::merge_compatible`]
+    ClampTo(OptNeg),
+    /// Answer with these options, whatever the client offers
+    Fixed(OptNeg),
+}
+
+impl NegotiationPolicy {
+    /// The answer to `theirs`, `None` if the milter is to answer
+    pub(crate) fn negotiate(&self, theirs: &OptNeg) -> Option<Result<OptNeg, CompatibilityError>> {
+        match self {
+            Self::Milter => None,
+            Self::AcceptAll => Some(OptNeg::default().merge_compatible(theirs)),
+            Self::ClampTo(ours) => Some(ours.clone().merge_compatible(theirs)),
+            Self::Fixed(ours) => Some(Ok(ours.clone())),
+        }
+    }
+}
+
+/// What to do with connect information of a family this implementation
+/// does not know, see [`Family::Other`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownFamilyPolicy {
+    /// Pass the family byte on to the milter as received
+    #[default]
+    Preserve,
+    /// Pass it on as [`Family::Unknown`], without a port
+    AsUnknown,
+    /// Close the connection with an
+    /// [`InvalidData`](miltr_common::InvalidData) error
+    Error,
+}
+
+impl UnknownFamilyPolicy {
+    /// Apply this policy to `connect`, returning the unknown family byte
+    /// if the connection is to be closed
+    pub(crate) fn apply(self, connect: &mut Connect) -> Result<(), u8> {
+        let Family::Other(byte) = connect.family else {
+            return Ok(());
+        };
+        match self {
+            Self::Preserve => Ok(()),
+            Self::AsUnknown => {
+                connect.family = Family::Unknown;
+                connect.port = None;
+                Ok(())
+            }
+            Self::Error => Err(byte),
+        }
+    }
+}
+
+/// What to do if a frame of an end of body response exceeds the buffer size
+/// of the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizePolicy {
+    /// Send nothing, close the connection with a
+    /// [`TooMuchData`](miltr_common::TooMuchData) error.
+    ///
+    /// Contrary to the codec failing on a single frame, no part of the
+    /// response reaches the client.
+    #[default]
+    Error,
+    /// Keep the original body, dropping all body replacements.
+    ///
+    /// If other frames are still too large, close the connection as for
+    /// [`OversizePolicy::Error`].
+    DropBodyReplacement,
+    /// Split body replacements into multiple frames.
+    ///
+    /// If other frames are still too large, close the connection as for
+    /// [`OversizePolicy::Error`].
+    Split,
+}
+
+/// What to do if all modifications of an end of body response are dropped,
+/// as the client did not grant the capabilities for them.
+///
+/// Dropped modifications are logged and counted in any case, see
+/// [`Server::on_dropped_modifications`](crate::Server::on_dropped_modifications)
+/// to inspect them.
+#[derive(Debug, Clone, Default)]
+pub enum DroppedModsPolicy {
+    /// Send the final action of the milter, passing the mail unmodified
+    #[default]
+    Continue,
+    /// Answer with this action instead, e.g. a tempfail to hold mail a
+    /// misconfigured client can not modify
+    Respond(Action),
+}
+
+/// What to do with recipient modifications of an end of body response
+/// MTAs would only warn about: deleting a recipient never given for the
+/// message, or adding one that is not a plausible address.
+///
+/// See
+/// [`ModificationResponse::remove_invalid_recipients`](miltr_common::modifications::ModificationResponse::remove_invalid_recipients)
+/// for what is checked.
+#[derive(Debug, Clone, Default)]
+pub enum RecipientPolicy {
+    /// Send them unchecked, leaving them to the MTA
+    #[default]
+    Unchecked,
+    /// Drop the invalid ones, sending the rest of the response
+    Trim,
+    /// Answer with this action instead of the response if any is invalid
+    Respond(Action),
+}
+
+impl RecipientPolicy {
+    /// Whether recipients of a message have to be remembered
+    pub(crate) fn is_checking(&self) -> bool {
+        !matches!(self, Self::Unchecked)
+    }
+
+    /// Apply this policy to `response`, given the `recipients` of the
+    /// message
+    pub(crate) fn apply(
+        &self,
+        mut response: ModificationResponse,
+        recipients: &[String],
+    ) -> ModificationResponse {
+        if !self.is_checking() {
+            return response;
+        }
+        let invalid = response.remove_invalid_recipients(recipients);
+        if invalid.is_empty() {
+            return response;
+        }
+
+        warn!(
+            "Milter sent {} invalid recipient modifications",
+            invalid.len()
+        );
+        match self {
+            Self::Respond(action) => {
+                warn!("Answering {} instead", action);
+                ModificationResponse::builder().build(action.clone())
+            }
+            _ => response,
+        }
+    }
+}
+
+/// The commands to check for valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Utf8Fields {
+    /// The helo greeting
+    pub helo: bool,
+    /// Sender and esmtp args of mail from
+    pub mail: bool,
+    /// Recipient and esmtp args of rcpt to
+    pub recipient: bool,
+    /// Name and value of each header
+    pub header: bool,
+}
+
+impl Utf8Fields {
+    /// Check all commands carrying text
+    #[must_use]
+    pub fn all() -> Self {
+        Self {
+            helo: true,
+            mail: true,
+            recipient: true,
+            header: true,
+        }
+    }
+}
+
+/// What to do with a command containing invalid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Utf8Action {
+    /// Answer with a reject instead of calling the milter
+    #[default]
+    Reject,
+    /// Answer with a temporary failure instead of calling the milter
+    Tempfail,
+    /// Replace invalid sequences by U+FFFD and call the milter
+    Replace,
+}
+
+/// Check textual fields for valid UTF-8 on decode.
+///
+/// Without this, invalid sequences are only converted lossily when the
+/// milter accesses them. The default checks no fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Utf8Policy {
+    /// The commands to check
+    pub fields: Utf8Fields,
+    /// What to do on invalid UTF-8
+    pub action: Utf8Action,
+}
+
+impl Utf8Policy {
+    /// Check `fields`, answering or replacing according to `action`
+    #[must_use]
+    pub fn new(fields: Utf8Fields, action: Utf8Action) -> Self {
+        Self { fields, action }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miltr_common::optneg::{Capability, Protocol};
+
+    use super::*;
+
+    fn options(capabilities: Capability, protocol: Protocol) -> OptNeg {
+        OptNeg {
+            capabilities,
+            protocol,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_negotiation_policy() {
+        let theirs = options(
+            Capability::SMFIF_ADDHDRS | Capability::SMFIF_CHGHDRS,
+            Protocol::NO_CONNECT | Protocol::NO_HELO,
+        );
+        let ours = options(
+            Capability::SMFIF_ADDHDRS | Capability::SMFIF_QUARANTINE,
+            Protocol::NO_HELO,
+        );
+
+        assert!(NegotiationPolicy::Milter.negotiate(&theirs).is_none());
+
+        let accepted = NegotiationPolicy::AcceptAll
+            .negotiate(&theirs)
+            .expect("No answer")
+            .expect("Incompatible");
+        assert_eq!(accepted, options(theirs.capabilities, Protocol::empty()));
+
+        let clamped = NegotiationPolicy::ClampTo(ours.clone())
+            .negotiate(&theirs)
+            .expect("No answer")
+            .expect("Incompatible");
+        assert_eq!(
+            clamped,
+            options(Capability::SMFIF_ADDHDRS, Protocol::NO_HELO)
+        );
+
+        let fixed = NegotiationPolicy::Fixed(ours.clone())
+            .negotiate(&theirs)
+            .expect("No answer")
+            .expect("Incompatible");
+        assert_eq!(fixed, ours);
+    }
+}
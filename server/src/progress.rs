@@ -0,0 +1,68 @@
+//! Keep the client waiting while a milter works on a message
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use futures::channel::mpsc::{self, Receiver, Sender};
+
+/// Sends progress notifications to the client while
+/// [`Milter::end_of_body_with_progress`](crate::Milter::end_of_body_with_progress)
+/// runs.
+///
+/// Each notification resets the timeout of the MTA awaiting the answer to
+/// end of body, e.g. postfix's `milter_content_timeout`. Clones notify
+/// the same client.
+#[derive(Debug, Clone)]
+pub struct ProgressHandle {
+    /// Shared by all clones, for a single notification to be pending
+    sender: Arc<Mutex<Sender<()>>>,
+}
+
+impl ProgressHandle {
+    /// A handle and the notifications it requests, for the server to send
+    pub(crate) fn channel() -> (Self, Receiver<()>) {
+        let (sender, receiver) = mpsc::channel(0);
+        let sender = Arc::new(Mutex::new(sender));
+        (Self { sender }, receiver)
+    }
+
+    /// A handle notifying no one, e.g. to call a milter in tests
+    #[must_use]
+    pub fn disconnected() -> Self {
+        Self::channel().0
+    }
+
+    /// Ask the client to keep waiting for the answer.
+    ///
+    /// The server sends the notification right away. Notifying again
+    /// before it was sent adds nothing, it is coalesced with the pending
+    /// one. After the answer was sent, this does nothing.
+    pub fn notify(&self) {
+        // Full with a notification pending, or the server stopped listening
+        // as the answer is due
+        let _ = self
+            .sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .try_send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_notify_coalesces() {
+        let (progress, requests) = ProgressHandle::channel();
+
+        for _ in 0..10 {
+            progress.notify();
+            progress.clone().notify();
+        }
+        drop(progress);
+
+        assert_eq!(requests.count().await, 1);
+    }
+}
@@ -0,0 +1,541 @@
+//! Check messages with rspamd through its HTTP worker
+
+use std::{collections::HashMap, io, net::IpAddr};
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future};
+use serde_json::Value;
+
+use miltr_common::{
+    actions::{Action, Continue, Reject, Replycode, Tempfail},
+    commands::{Body, Connect, Header, Helo, Macro, Mail, Recipient},
+    modifications::{
+        headers::{AddHeader, ChangeHeader},
+        ModificationResponse,
+    },
+};
+
+use crate::{MessageAssembler, Milter};
+
+/// The action rspamd recommends for a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RspamdAction {
+    /// Let the message pass
+    NoAction,
+    /// Delay the message, answered with a temporary failure
+    Greylist,
+    /// Mark the message as spam with a header
+    AddHeader,
+    /// Mark the message as spam in its subject
+    RewriteSubject,
+    /// Fail the message temporarily
+    SoftReject,
+    /// Reject the message
+    Reject,
+}
+
+impl RspamdAction {
+    fn parse(action: &str) -> Option<Self> {
+        Some(match action {
+            "no action" => Self::NoAction,
+            "greylist" => Self::Greylist,
+            "add header" => Self::AddHeader,
+            "rewrite subject" => Self::RewriteSubject,
+            "soft reject" => Self::SoftReject,
+            "reject" => Self::Reject,
+            _ => return None,
+        })
+    }
+}
+
+/// What rspamd replied to a `checkv2` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RspamdReply {
+    /// The recommended action
+    pub action: RspamdAction,
+    /// The score of the message
+    pub score: f64,
+    /// The new subject, for [`RspamdAction::RewriteSubject`]
+    pub subject: Option<String>,
+    /// The smtp message to answer rejections with
+    pub smtp_message: Option<String>,
+    /// Headers to add, from the `milter` block
+    pub add_headers: Vec<(String, String)>,
+    /// Headers to remove with their index, from the `milter` block. `0`
+    /// removes all headers of that name, negative indexes count from the
+    /// last.
+    pub remove_headers: Vec<(String, i64)>,
+}
+
+impl RspamdReply {
+    /// Parse the JSON body of a `checkv2` reply
+    ///
+    /// # Errors
+    /// If `json` is no valid reply
+    pub fn parse(json: &[u8]) -> io::Result<Self> {
+        let reply: Value = serde_json::from_slice(json)?;
+        let action = reply["action"]
+            .as_str()
+            .and_then(RspamdAction::parse)
+            .ok_or_else(|| invalid(format!("rspamd replied without action: {reply}")))?;
+
+        let milter = &reply["milter"];
+        let add_headers = milter["add_headers"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| {
+                // Either the value itself or an object with the value
+                let value = value.as_str().or_else(|| value["value"].as_str())?;
+                Some((name.clone(), value.to_string()))
+            })
+            .collect();
+        let remove_headers = milter["remove_headers"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, index)| Some((name.clone(), index.as_i64()?)))
+            .collect();
+
+        Ok(Self {
+            action,
+            score: reply["score"].as_f64().unwrap_or_default(),
+            subject: reply["subject"].as_str().map(ToString::to_string),
+            smtp_message: reply["messages"]["smtp_message"]
+                .as_str()
+                .map(ToString::to_string),
+            add_headers,
+            remove_headers,
+        })
+    }
+}
+
+/// Envelope of the current message, sent as request headers
+#[derive(Debug, Clone, Default)]
+struct Envelope {
+    ip: Option<IpAddr>,
+    hostname: Option<String>,
+    helo: Option<String>,
+    queue_id: Option<String>,
+    from: Option<String>,
+    rcpts: Vec<String>,
+}
+
+/// A [`Milter`] checking each message with rspamd, using the `checkv2`
+/// endpoint of its HTTP worker.
+///
+/// `connect` opens a new connection to rspamd for every message. The
+/// recommended action is mapped to the milter answer:
+///
+/// | rspamd            | milter                                          |
+/// |-------------------|-------------------------------------------------|
+/// | `reject`          | reject, with rspamd's smtp message if set       |
+/// | `soft reject`     | temporary failure                               |
+/// | `greylist`        | temporary failure `451 4.7.1`                   |
+/// | `rewrite subject` | change of the subject                           |
+/// | `add header`      | an `X-Spam: Yes` header                         |
+/// | `no action`       | continue                                        |
+///
+/// Headers rspamd asks to add or remove in its `milter` block are applied
+/// for all but rejections.
+#[derive(Debug)]
+pub struct RspamdMilter<F> {
+    connect: F,
+    host: String,
+    envelope: Envelope,
+    assembler: MessageAssembler,
+    header_counts: HashMap<String, u32>,
+}
+
+impl<F, Fut, S> RspamdMilter<F>
+where
+    F: Fn() -> Fut + Send,
+    Fut: Future<Output = io::Result<S>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Check messages using connections opened by `connect`
+    pub fn new(connect: F) -> Self {
+        Self {
+            connect,
+            host: String::from("localhost"),
+            envelope: Envelope::default(),
+            assembler: MessageAssembler::new(),
+            header_counts: HashMap::new(),
+        }
+    }
+
+    /// Send `host` as `Host` header instead of `localhost`
+    #[must_use]
+    pub fn with_host<H: Into<String>>(mut self, host: H) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Send `message` to rspamd and parse its reply
+    async fn check(&mut self, message: &[u8]) -> io::Result<RspamdReply> {
+        let mut request = format!(
+            "POST /checkv2 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            header_value(&self.host),
+            message.len()
+        );
+        let envelope = &self.envelope;
+        let mut field = |name: &str, value: &str| {
+            request.push_str(name);
+            request.push_str(": ");
+            request.push_str(&header_value(value));
+            request.push_str("\r\n");
+        };
+        if let Some(ip) = envelope.ip {
+            field("IP", &ip.to_string());
+        }
+        for (name, value) in [
+            ("Hostname", &envelope.hostname),
+            ("Helo", &envelope.helo),
+            ("Queue-Id", &envelope.queue_id),
+            ("From", &envelope.from),
+        ] {
+            if let Some(value) = value {
+                field(name, value);
+            }
+        }
+        for rcpt in &envelope.rcpts {
+            field("Rcpt", rcpt);
+        }
+        request.push_str("\r\n");
+
+        let mut stream = (self.connect)().await?;
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(message).await?;
+        stream.flush().await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        RspamdReply::parse(&http_body(&response)?)
+    }
+
+    /// The milter answer to `reply`
+    fn respond(&self, reply: RspamdReply) -> ModificationResponse {
+        let message = reply.smtp_message.as_deref();
+        match reply.action {
+            RspamdAction::Reject => {
+                let action: Action = match message {
+                    Some(message) => Replycode::new([5, 5, 4], [5, 7, 1], message).into(),
+                    None => Reject.into(),
+                };
+                return ModificationResponse::builder().build(action);
+            }
+            RspamdAction::SoftReject => {
+                return ModificationResponse::builder().build(Tempfail);
+            }
+            RspamdAction::Greylist => {
+                let message = message.unwrap_or("Try again later");
+                return ModificationResponse::builder().build(Replycode::new(
+                    [4, 5, 1],
+                    [4, 7, 1],
+                    message,
+                ));
+            }
+            RspamdAction::NoAction | RspamdAction::AddHeader | RspamdAction::RewriteSubject => {}
+        }
+
+        let mut response = ModificationResponse::builder();
+        for (name, index) in &reply.remove_headers {
+            let count = self.header_count(name);
+            let indexes = match *index {
+                0 => 1..=count,
+                index if index > 0 => {
+                    let index = u32::try_from(index).unwrap_or(u32::MAX);
+                    index..=index.min(count)
+                }
+                index => {
+                    let index = i64::from(count) + index + 1;
+                    let index = u32::try_from(index).unwrap_or_default();
+                    index.max(1)..=index
+                }
+            };
+            // Remove from the last, so earlier indexes stay valid
+            for index in indexes.rev() {
+                response.push(ChangeHeader::new(index, name.as_bytes(), b""));
+            }
+        }
+        for (name, value) in &reply.add_headers {
+            response.push(AddHeader::new(name.as_bytes(), value.as_bytes()));
+        }
+        match (reply.action, reply.subject) {
+            (RspamdAction::AddHeader, _) => {
+                response.push(AddHeader::new(b"X-Spam", b"Yes"));
+            }
+            (RspamdAction::RewriteSubject, Some(subject)) => {
+                if self.header_count("Subject") > 0 {
+                    response.push(ChangeHeader::new(1, b"Subject", subject.as_bytes()));
+                } else {
+                    response.push(AddHeader::new(b"Subject", subject.as_bytes()));
+                }
+            }
+            _ => {}
+        }
+        response.contin()
+    }
+
+    fn header_count(&self, name: &str) -> u32 {
+        self.header_counts
+            .get(&name.to_ascii_lowercase())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn reset_message(&mut self) {
+        self.envelope.queue_id = None;
+        self.envelope.from = None;
+        self.envelope.rcpts.clear();
+        self.assembler.reset();
+        self.header_counts.clear();
+    }
+}
+
+#[async_trait]
+impl<F, Fut, S> Milter for RspamdMilter<F>
+where
+    F: Fn() -> Fut + Send,
+    Fut: Future<Output = io::Result<S>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Error = io::Error;
+
+    async fn macro_(&mut self, macro_: Macro) -> Result<(), Self::Error> {
+        if let Some((_, value)) = macro_.macros().find(|(name, _)| *name == b"i") {
+            self.envelope.queue_id = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        Ok(())
+    }
+
+    async fn connect(&mut self, connect_info: Connect) -> Result<Action, Self::Error> {
+        self.envelope.ip = connect_info.ip();
+        self.envelope.hostname = Some(connect_info.hostname().into_owned());
+        Ok(Continue.into())
+    }
+
+    async fn helo(&mut self, helo: Helo) -> Result<Action, Self::Error> {
+        self.envelope.helo = Some(helo.helo().into_owned());
+        Ok(Continue.into())
+    }
+
+    async fn mail(&mut self, mail: Mail) -> Result<Action, Self::Error> {
+        self.envelope.from = Some(mail.sender().into_owned());
+        Ok(Continue.into())
+    }
+
+    async fn rcpt(&mut self, recipient: Recipient) -> Result<Action, Self::Error> {
+        self.envelope.rcpts.push(recipient.recipient().into_owned());
+        Ok(Continue.into())
+    }
+
+    async fn header(&mut self, header: Header) -> Result<Action, Self::Error> {
+        self.assembler.push_header(&header);
+        *self
+            .header_counts
+            .entry(header.name().to_ascii_lowercase())
+            .or_default() += 1;
+        Ok(Continue.into())
+    }
+
+    async fn end_of_header(&mut self) -> Result<Action, Self::Error> {
+        self.assembler.end_of_header();
+        Ok(Continue.into())
+    }
+
+    async fn body(&mut self, body: Body) -> Result<Action, Self::Error> {
+        self.assembler.push_body(&body);
+        Ok(Continue.into())
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        let message = self.assembler.take();
+        let result = self.check(&message).await;
+        let response = result.map(|reply| self.respond(reply));
+        self.reset_message();
+        response
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.reset_message();
+        Ok(Continue.into())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// `value` without line breaks, to not end the request header early
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// The body of a HTTP `response`, failing on any status but 200
+fn http_body(response: &[u8]) -> io::Result<Vec<u8>> {
+    let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Err(invalid(String::from("rspamd sent an incomplete response")));
+    };
+    let head = String::from_utf8_lossy(&response[..end]);
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(invalid(format!("rspamd failed checking: {status}")));
+    }
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().ok();
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    if chunked {
+        return dechunk(body);
+    }
+    match content_length {
+        Some(len) if len <= body.len() => Ok(body[..len].to_vec()),
+        Some(_) => Err(invalid(String::from("rspamd sent a truncated body"))),
+        None => Ok(body.to_vec()),
+    }
+}
+
+/// Join the chunks of a chunked transfer encoded `body`
+fn dechunk(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut joined = Vec::new();
+    loop {
+        let Some(end) = body.windows(2).position(|w| w == b"\r\n") else {
+            return Err(invalid(String::from("rspamd sent a truncated chunk")));
+        };
+        let size = String::from_utf8_lossy(&body[..end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid(format!("rspamd sent an invalid chunk size: {size}")))?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Ok(joined);
+        }
+        if body.len() < size + 2 {
+            return Err(invalid(String::from("rspamd sent a truncated chunk")));
+        }
+        joined.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miltr_common::{commands::Family, modifications::ModificationAction};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use super::*;
+
+    type Connector =
+        Box<dyn Fn() -> futures::future::Ready<io::Result<Compat<DuplexStream>>> + Send>;
+
+    #[test]
+    fn test_parse_reply() {
+        let reply = RspamdReply::parse(
+            br#"{"action": "add header", "score": 7.5,
+                "milter": {"add_headers": {"X-Spamd-Bar": "+++", "X-Rspamd-Server": {"value": "mx", "order": 0}},
+                           "remove_headers": {"X-Spam": 0}}}"#,
+        )
+        .expect("Failed parsing");
+
+        assert_eq!(reply.action, RspamdAction::AddHeader);
+        assert!((reply.score - 7.5).abs() < f64::EPSILON);
+        assert_eq!(reply.add_headers.len(), 2);
+        assert_eq!(reply.remove_headers, [(String::from("X-Spam"), 0)]);
+
+        RspamdReply::parse(br#"{"score": 1}"#).expect_err("Missing action not detected");
+    }
+
+    #[test]
+    fn test_http_body() {
+        let body = http_body(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}trailing")
+            .expect("Failed reading body");
+        assert_eq!(body, b"{}");
+
+        let body = http_body(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\n",
+        )
+        .expect("Failed reading chunked body");
+        assert_eq!(body, b"{\"a\":1}");
+
+        http_body(b"HTTP/1.1 500 Internal Server Error\r\n\r\n").expect_err("Failure not detected");
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_subject() {
+        let (client, mut rspamd) = tokio::io::duplex(4096);
+        let client = std::sync::Mutex::new(Some(client));
+        let connect: Connector = Box::new(move || {
+            let client = client.lock().expect("Poisoned").take();
+            futures::future::ready(client.map(TokioAsyncReadCompatExt::compat).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionRefused, "only one connection")
+            }))
+        });
+        let mut milter = RspamdMilter::new(connect);
+
+        let rspamd = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buffer = [0; 256];
+            while !received.ends_with(b"Hello\r\n") {
+                let read = rspamd.read(&mut buffer).await.expect("Failed reading");
+                received.extend_from_slice(&buffer[..read]);
+            }
+            let body = br#"{"action": "rewrite subject", "subject": "[SPAM] Hi"}"#;
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            rspamd
+                .write_all(response.as_bytes())
+                .await
+                .expect("Failed writing");
+            rspamd.write_all(body).await.expect("Failed writing");
+            String::from_utf8(received).expect("Request not utf-8")
+        });
+
+        milter
+            .connect(Connect::new(b"mx", Family::Inet, Some(25), b"192.0.2.1"))
+            .await
+            .expect("Failed connect");
+        milter
+            .mail(Mail::from(b"<a@test.local>".as_slice()))
+            .await
+            .expect("Failed mail");
+        milter
+            .rcpt(Recipient::from(b"<b@test.local>".as_slice()))
+            .await
+            .expect("Failed rcpt");
+        milter
+            .header(Header::new(b"Subject", b"Hi"))
+            .await
+            .expect("Failed header");
+        milter
+            .body(Body::from(b"Hello\r\n".as_slice()))
+            .await
+            .expect("Failed body");
+        let response = milter.end_of_body().await.expect("Failed checking");
+
+        let request = rspamd.await.expect("Fake rspamd failed");
+        assert!(request.starts_with("POST /checkv2 HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nIP: 192.0.2.1\r\n"));
+        assert!(request.contains("\r\nRcpt: <b@test.local>\r\n"));
+        assert!(request.ends_with("\r\n\r\nSubject: Hi\r\n\r\nHello\r\n"));
+
+        let [ModificationAction::ChangeHeader(change)] = response.modifications() else {
+            panic!("Unexpected modifications: {:?}", response.modifications());
+        };
+        assert_eq!(change.index(), 1);
+        assert_eq!(change.value(), "[SPAM] Hi");
+    }
+}
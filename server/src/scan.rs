@@ -0,0 +1,297 @@
+//! Hand message bodies to external content scanners
+
+use std::io;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future};
+
+use miltr_common::{
+    actions::{Action, Continue, Reject},
+    commands::Body,
+    modifications::{body::ReplaceBody, ModificationResponse},
+};
+
+use crate::Milter;
+
+/// The outcome of scanning a message body
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanVerdict {
+    /// The name of what the scanner found, `None` if the body is clean
+    pub found: Option<String>,
+    /// A body to replace the original with, e.g. with attachments removed
+    pub replacement: Option<BytesMut>,
+}
+
+impl ScanVerdict {
+    /// Nothing was found
+    #[must_use]
+    pub fn clean() -> Self {
+        Self::default()
+    }
+
+    /// The scanner found `name`
+    #[must_use]
+    pub fn found<S: Into<String>>(name: S) -> Self {
+        Self {
+            found: Some(name.into()),
+            replacement: None,
+        }
+    }
+
+    /// Replace the scanned body with `body`
+    #[must_use]
+    pub fn with_replacement(mut self, body: &[u8]) -> Self {
+        self.replacement = Some(BytesMut::from(body));
+        self
+    }
+}
+
+/// An external scanner receiving a message body while it streams in.
+///
+/// Body chunks are submitted in order. After the last one, the verdict is
+/// requested, which also readies the backend for the next message.
+#[async_trait]
+pub trait ScanBackend: Send {
+    /// An error talking to the scanner
+    type Error: Send;
+
+    /// Submit the next chunk of the body
+    async fn submit(&mut self, chunk: &[u8]) -> Result<(), Self::Error>;
+
+    /// All chunks were submitted, get the verdict
+    async fn verdict(&mut self) -> Result<ScanVerdict, Self::Error>;
+
+    /// Drop everything submitted for the current message
+    async fn reset(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A [`Milter`] streaming message bodies to a [`ScanBackend`].
+///
+/// If the backend finds something, the message is rejected. A replacement
+/// body from the backend is sent as [`ReplaceBody`], if
+/// [`Capability::SMFIF_CHGBODY`](miltr_common::optneg::Capability::SMFIF_CHGBODY)
+/// was negotiated.
+#[derive(Debug)]
+pub struct ScanMilter<B: ScanBackend> {
+    backend: B,
+    found_action: Action,
+}
+
+impl<B: ScanBackend> ScanMilter<B> {
+    /// Scan message bodies using `backend`
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            found_action: Reject.into(),
+        }
+    }
+
+    /// Answer with `action` instead of rejecting if the backend found
+    /// something
+    #[must_use]
+    pub fn with_found_action<A: Into<Action>>(mut self, action: A) -> Self {
+        self.found_action = action.into();
+        self
+    }
+
+    /// The backend used for scanning
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+#[async_trait]
+impl<B: ScanBackend> Milter for ScanMilter<B> {
+    type Error = B::Error;
+
+    async fn body(&mut self, body: Body) -> Result<Action, Self::Error> {
+        self.backend.submit(body.as_bytes()).await?;
+        Ok(Continue.into())
+    }
+
+    async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
+        let verdict = self.backend.verdict().await?;
+
+        if verdict.found.is_some() {
+            return Ok(ModificationResponse::builder().build(self.found_action.clone()));
+        }
+        let mut response = ModificationResponse::builder();
+        if let Some(replacement) = verdict.replacement {
+            response.push(ReplaceBody::new(&replacement));
+        }
+
+        Ok(response.contin())
+    }
+
+    async fn abort(&mut self) -> Result<Action, Self::Error> {
+        self.backend.reset().await?;
+        Ok(Continue.into())
+    }
+}
+
+/// A [`ScanBackend`] for `clamd`, speaking its `INSTREAM` socket protocol.
+///
+/// `connect` opens a new connection to `clamd` (TCP or unix socket) for
+/// every message, as `clamd` closes it after each scan. The first body
+/// chunk opens the connection, a message without body is clean.
+#[derive(Debug)]
+pub struct ClamdScanner<F, S> {
+    connect: F,
+    stream: Option<S>,
+}
+
+impl<F, Fut, S> ClamdScanner<F, S>
+where
+    F: Fn() -> Fut + Send,
+    Fut: Future<Output = io::Result<S>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Scan using connections opened by `connect`
+    pub fn new(connect: F) -> Self {
+        Self {
+            connect,
+            stream: None,
+        }
+    }
+
+    /// Parse a reply like `stream: OK` or `stream: Eicar-Signature FOUND`
+    fn parse_reply(reply: &[u8]) -> io::Result<ScanVerdict> {
+        let reply = String::from_utf8_lossy(reply);
+        let reply = reply.trim_end_matches(['\0', '\n']);
+        let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+        if result == "OK" {
+            return Ok(ScanVerdict::clean());
+        }
+        if let Some(name) = result.strip_suffix(" FOUND") {
+            return Ok(ScanVerdict::found(name));
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("clamd failed scanning: {reply}"),
+        ))
+    }
+}
+
+#[async_trait]
+impl<F, Fut, S> ScanBackend for ClamdScanner<F, S>
+where
+    F: Fn() -> Fut + Send,
+    Fut: Future<Output = io::Result<S>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Error = io::Error;
+
+    async fn submit(&mut self, chunk: &[u8]) -> Result<(), Self::Error> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk_len = u32::try_from(chunk.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let stream = if let Some(stream) = &mut self.stream {
+            stream
+        } else {
+            let mut stream = (self.connect)().await?;
+            stream.write_all(b"zINSTREAM\0").await?;
+            self.stream.insert(stream)
+        };
+
+        stream.write_all(&chunk_len.to_be_bytes()).await?;
+        stream.write_all(chunk).await
+    }
+
+    async fn verdict(&mut self) -> Result<ScanVerdict, Self::Error> {
+        let Some(mut stream) = self.stream.take() else {
+            return Ok(ScanVerdict::clean());
+        };
+
+        // A zero length chunk ends the stream
+        stream.write_all(&[0; 4]).await?;
+        stream.flush().await?;
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+
+        Self::parse_reply(&reply)
+    }
+
+    async fn reset(&mut self) -> Result<(), Self::Error> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.close().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use super::*;
+
+    type Scanner = ClamdScanner<
+        Box<dyn Fn() -> futures::future::Ready<io::Result<Compat<DuplexStream>>> + Send>,
+        Compat<DuplexStream>,
+    >;
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(
+            Scanner::parse_reply(b"stream: OK\0").expect("Failed parsing"),
+            ScanVerdict::clean()
+        );
+        assert_eq!(
+            Scanner::parse_reply(b"stream: Eicar-Signature FOUND\0").expect("Failed parsing"),
+            ScanVerdict::found("Eicar-Signature")
+        );
+        Scanner::parse_reply(b"INSTREAM size limit exceeded. ERROR\0")
+            .expect_err("Error reply not detected");
+    }
+
+    #[tokio::test]
+    async fn test_clamd_instream() {
+        let (client, mut clamd) = tokio::io::duplex(1024);
+        let client = std::sync::Mutex::new(Some(client));
+        let connect: Box<dyn Fn() -> _ + Send> = Box::new(move || {
+            let client = client.lock().expect("Poisoned").take();
+            futures::future::ready(client.map(TokioAsyncReadCompatExt::compat).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionRefused, "only one connection")
+            }))
+        });
+        let mut scanner: Scanner = ClamdScanner::new(connect);
+
+        let clamd = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buffer = [0; 64];
+            loop {
+                let read = clamd.read(&mut buffer).await.expect("Failed reading");
+                received.extend_from_slice(&buffer[..read]);
+                if received.ends_with(&[0; 4]) {
+                    break;
+                }
+            }
+            clamd
+                .write_all(b"stream: Eicar-Signature FOUND\0")
+                .await
+                .expect("Failed writing");
+            received
+        });
+
+        scanner.submit(b"first").await.expect("Failed submitting");
+        scanner.submit(b"second").await.expect("Failed submitting");
+        let verdict = scanner.verdict().await.expect("Failed scanning");
+
+        assert_eq!(verdict, ScanVerdict::found("Eicar-Signature"));
+        let received = clamd.await.expect("Fake clamd failed");
+        assert_eq!(
+            received,
+            b"zINSTREAM\0\x00\x00\x00\x05first\x00\x00\x00\x06second\x00\x00\x00\x00"
+        );
+    }
+}
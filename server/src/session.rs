@@ -0,0 +1,709 @@
+//! Serve the commands of a single connection
+
+use std::{sync::Arc, time::Instant};
+
+use asynchronous_codec::Framed;
+use bytes::BytesMut;
+use futures::{
+    channel::mpsc::Receiver,
+    future::{self, Either},
+    pin_mut, AsyncRead, AsyncWrite, Future, SinkExt, StreamExt,
+};
+use miltr_common::{
+    actions::{Action, Continue, Progress, Reject, Tempfail},
+    clock::Clock,
+    commands::{Connect, TextFields},
+    decoding::ClientCommand,
+    encoding::{Limits, ServerMessage},
+    macros::MacroContext,
+    modifications::ModificationResponse,
+    optneg::{Capability, OptNeg, Protocol},
+    InvalidData, ProtocolError,
+};
+use miltr_utils::{debug, warn};
+
+use crate::{
+    filter::CapabilityFilter, heartbeat::Pulse, summary::Tally, timeout, translate::Translator,
+    watchdog::Watchdog, Config, ConnectionSummary, EndedBy, Error, Heartbeat, Milter, MilterCodec,
+    MissingCapabilityPolicy, OversizePolicy, ProgressHandle, Utf8Action, Utf8Policy,
+};
+
+/// Time the milter method `$call` named `$name`, returning its result or
+/// the timeout from the enclosing function
+macro_rules! call {
+    ($self:ident, $name:literal, $call:expr) => {
+        $self.watchdog.time($name, &$self.context, $call).await?
+    };
+}
+
+/// The state of a connection being served
+pub(crate) struct Session<'a, M: Milter, RW> {
+    milter: &'a mut M,
+    config: &'a Config,
+    framed: Framed<RW, &'a mut MilterCodec>,
+    watchdog: Watchdog,
+    filter: CapabilityFilter,
+    pulse: Pulse,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    /// The number of this connection, for heartbeats
+    connection: u64,
+    /// Only kept to name the queue id of slow callbacks
+    context: MacroContext,
+    options: Option<OptNeg>,
+    /// Answer to every command if required capabilities are missing
+    refusal: Option<Action>,
+    /// Answer to every command of the session, set by the access list
+    bypass: Option<Action>,
+    /// Whether the client expects no action in reply to the current command
+    no_reply: bool,
+    /// After `quit_nc`, a new session has to start
+    after_quit_nc: bool,
+    /// Whether the milter has seen commands of a message not reset yet
+    in_message: bool,
+    /// Messages ended on this connection
+    messages: u64,
+    /// Recipients of the current message, if recipient modifications are checked
+    recipients: Vec<String>,
+}
+
+impl<'a, M: Milter, RW: AsyncRead + AsyncWrite + Unpin + Send> Session<'a, M, RW> {
+    /// Start serving `socket` with `milter`
+    pub(crate) fn new(
+        milter: &'a mut M,
+        config: &'a Config,
+        codec: &'a mut MilterCodec,
+        socket: RW,
+    ) -> Self {
+        let clock = Arc::clone(&config.watchdog.clock);
+        let started = clock.now();
+        codec.frame_sizes.reset();
+        codec.tally = Tally::default();
+        if let Some(ctx) = milter.session_context() {
+            ctx.set_limits(Limits::new(codec.max_buffer_size()));
+            ctx.set_clock(Arc::clone(&clock));
+        }
+        let stats = codec.stats.clone();
+        let watchdog = Watchdog {
+            stats: stats.clone(),
+            ..config.watchdog.clone()
+        };
+        let filter = CapabilityFilter {
+            capabilities: Capability::all(),
+            stats,
+            ..config.filter.clone()
+        };
+
+        Self {
+            milter,
+            config,
+            framed: Framed::new(socket, codec),
+            watchdog,
+            filter,
+            pulse: config.pulse.clone(),
+            connection: config.pulse.connected(),
+            clock,
+            started,
+            context: MacroContext::new(),
+            options: None,
+            refusal: None,
+            bypass: None,
+            no_reply: false,
+            after_quit_nc: false,
+            in_message: false,
+            messages: 0,
+            recipients: Vec::new(),
+        }
+    }
+
+    /// Serve commands until the connection ends
+    pub(crate) async fn run(mut self) -> Result<ConnectionSummary, Error<M::Error>> {
+        let ended_by = match self.serve().await {
+            Ok(ended_by) => ended_by,
+            Err(Error::Timeout { callback, timeout }) => {
+                // Only commands of an SMTP stage await an answer
+                if self.watchdog.timeouts.tempfail() && timeout::stage_of(callback).is_some() {
+                    self.send_action(Tempfail.into()).await?;
+                }
+                return Err(Error::Timeout { callback, timeout });
+            }
+            Err(err) => return Err(err),
+        };
+
+        let tally = std::mem::take(&mut self.framed.codec_mut().tally);
+        Ok(ConnectionSummary {
+            messages: self.messages,
+            actions: tally.actions,
+            duration: self.clock.now().duration_since(self.started),
+            bytes_in: tally.bytes_in,
+            bytes_out: tally.bytes_out,
+            ended_by,
+        })
+    }
+
+    async fn serve(&mut self) -> Result<EndedBy, Error<M::Error>> {
+        loop {
+            let Some(command) = self.framed.next().await else {
+                return Ok(EndedBy::Closed);
+            };
+            if let Some(ended_by) = self.handle(command?).await? {
+                return Ok(ended_by);
+            }
+        }
+    }
+
+    /// Handle a single command, returning how the connection ended if it
+    /// did
+    async fn handle(
+        &mut self,
+        mut command: ClientCommand,
+    ) -> Result<Option<EndedBy>, Error<M::Error>> {
+        debug!("Received {}", command);
+        self.pulse.beat(|| {
+            let tally = &self.framed.codec().tally;
+            Heartbeat {
+                connection: self.connection,
+                command: command.name(),
+                elapsed: self.clock.now().duration_since(self.started),
+                messages: self.messages,
+                bytes_in: tally.bytes_in,
+                bytes_out: tally.bytes_out,
+            }
+        });
+        if let Some(ctx) = self.milter.session_context() {
+            ctx.on_command(&command, self.clock.now());
+        }
+        self.no_reply = no_reply(self.options.as_ref(), &command);
+        self.in_message |= belongs_to_message(&command);
+
+        if self.after_quit_nc {
+            if !starts_session(&command) {
+                return Err(ProtocolError::from(InvalidData::new(
+                    "expected connect after quit_nc",
+                    BytesMut::from_iter([command.code()]),
+                ))
+                .into());
+            }
+            self.after_quit_nc = !matches!(command, ClientCommand::Connect(_));
+        }
+
+        if let Some(action) = &self.refusal {
+            if expects_answer(&command) {
+                debug!("Refusing command, required capabilities missing");
+                self.send_action(action.clone()).await?;
+                return Ok(None);
+            }
+        }
+
+        if let Some(action) = self.bypass.clone() {
+            return self.bypassed(command, action).await;
+        }
+
+        if let Some(action) = validate_utf8(self.config.utf8_policy, &mut command) {
+            self.send_action(action).await?;
+            return Ok(None);
+        }
+
+        self.dispatch(command).await
+    }
+
+    /// Pass `command` on to the milter
+    async fn dispatch(
+        &mut self,
+        command: ClientCommand,
+    ) -> Result<Option<EndedBy>, Error<M::Error>> {
+        match command {
+            // First, all the regular smtp related commands
+            ClientCommand::Helo(helo) => {
+                let result = call!(self, "helo", self.milter.helo(helo));
+                self.answer(result).await?;
+            }
+            ClientCommand::Connect(connect) => self.connect(connect).await?,
+            ClientCommand::Mail(mail) => {
+                self.recipients.clear();
+                let result = call!(self, "mail", self.milter.mail(mail));
+                self.answer(result).await?;
+            }
+            ClientCommand::Recipient(rcpt) => {
+                if self.config.recipient_policy.is_checking() {
+                    self.recipients.push(rcpt.recipient().into_owned());
+                }
+                let result = call!(self, "rcpt", self.milter.rcpt(rcpt));
+                self.answer(result).await?;
+            }
+            ClientCommand::Data(_v) => {
+                let result = call!(self, "data", self.milter.data());
+                self.answer(result).await?;
+            }
+            ClientCommand::Header(header) => {
+                let result = call!(self, "header", self.milter.header(header));
+                self.answer(result).await?;
+            }
+            ClientCommand::EndOfHeader(_v) => {
+                let result = call!(self, "end_of_header", self.milter.end_of_header());
+                self.answer(result).await?;
+            }
+            ClientCommand::Body(body) if body.as_bytes().is_empty() => {
+                // Nothing to inspect, spare the milter
+                debug!("Answering empty body part without the milter");
+                self.answer(Ok(Continue)).await?;
+            }
+            ClientCommand::Body(body) => {
+                let result = call!(self, "body", self.milter.body(body));
+                self.answer(result).await?;
+            }
+            ClientCommand::Unknown(unknown) => {
+                let result = call!(self, "unknown", self.milter.unknown(unknown));
+                self.answer(result).await?;
+            }
+            // Regular smtp session related commands that need special responses
+            ClientCommand::EndOfBody(_v) => self.end_of_body().await?,
+            ClientCommand::Macro(macro_) => {
+                if self.watchdog.is_enabled() {
+                    self.context.insert(macro_.clone());
+                }
+                let result = call!(self, "macro_", self.milter.macro_(macro_));
+                self.tolerate(result)?;
+            }
+
+            // Control flow cases
+            ClientCommand::OptNeg(opt_neg) => self.option_negotiation(opt_neg).await?,
+            ClientCommand::Abort(_v) => return self.abort().await,
+            ClientCommand::Quit(_v) => {
+                let result = call!(self, "quit", self.milter.quit());
+                self.tolerate(result)?;
+                return Ok(Some(EndedBy::Quit));
+            }
+            ClientCommand::QuitNc(_v) => return self.quit_nc().await,
+        }
+        Ok(None)
+    }
+
+    /// Handle `command` of a session the access list answered, without
+    /// the milter
+    ///
+    /// Every command expecting an answer gets `action`. Messages still end
+    /// as usual, so no per-message state carries over into the next one.
+    async fn bypassed(
+        &mut self,
+        command: ClientCommand,
+        action: Action,
+    ) -> Result<Option<EndedBy>, Error<M::Error>> {
+        match command {
+            ClientCommand::EndOfBody(_v) => {
+                self.send_action(action).await?;
+                self.end_message();
+            }
+            ClientCommand::Abort(_v) => {
+                if self.in_message {
+                    self.end_message();
+                }
+                if self.config.quit_on_abort {
+                    return Ok(Some(EndedBy::Abort));
+                }
+            }
+            ClientCommand::Quit(_v) => return Ok(Some(EndedBy::Quit)),
+            ClientCommand::QuitNc(_v) => return self.quit_nc().await,
+            ClientCommand::OptNeg(opt_neg) => {
+                self.bypass = None;
+                self.option_negotiation(opt_neg).await?;
+            }
+            command if expects_answer(&command) => self.send_action(action).await?,
+            // Macros are dropped like the rest of the session
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Answer the connect information by access list or the milter
+    async fn connect(&mut self, mut connect: Connect) -> Result<(), Error<M::Error>> {
+        if let Err(family) = self.config.unknown_family_policy.apply(&mut connect) {
+            return Err(ProtocolError::from(InvalidData::new(
+                "Received unknown protocol family for connection info",
+                BytesMut::from(&[family][..]),
+            ))
+            .into());
+        }
+        if let Some(verdict) = self.config.access.verdict_for(&connect) {
+            debug!("Answering connect by access list: {:?}", verdict);
+            let action = verdict.action();
+            self.send_action(action.clone()).await?;
+            self.bypass = Some(action);
+            return Ok(());
+        }
+
+        let result = call!(self, "connect", self.milter.connect(connect));
+        self.answer(result).await
+    }
+
+    /// Answer end of body with the modifications of the milter and end
+    /// the message
+    async fn end_of_body(&mut self) -> Result<(), Error<M::Error>> {
+        let (progress, requests) = ProgressHandle::channel();
+        let result = Self::with_progress(
+            self.watchdog.time(
+                "end_of_body",
+                &self.context,
+                self.milter.end_of_body_with_progress(progress),
+            ),
+            requests,
+            &mut self.framed,
+        )
+        .await??
+        .map(|response| {
+            self.config
+                .recipient_policy
+                .apply(response, &self.recipients)
+        });
+        self.recipients.clear();
+        self.respond_end_of_body(result).await?;
+
+        let result = call!(self, "message_reset", self.milter.message_reset());
+        self.tolerate(result)?;
+        self.end_message();
+        Ok(())
+    }
+
+    /// Negotiate the options of a new session
+    async fn option_negotiation(&mut self, opt_neg: OptNeg) -> Result<(), Error<M::Error>> {
+        self.refusal = None;
+        let required = self.milter.required_capabilities();
+        if let Err(err) = opt_neg.require(required, Protocol::empty()) {
+            warn!("Client misses required capabilities: {}", err);
+            match &self.config.missing_capability_policy {
+                MissingCapabilityPolicy::Error => {
+                    return Err(ProtocolError::CompatibilityError(err).into());
+                }
+                MissingCapabilityPolicy::Respond(action) => {
+                    self.refusal = Some(action.clone());
+                }
+            }
+        }
+
+        self.context.clear();
+        let response = match self.config.negotiation_policy.negotiate(&opt_neg) {
+            Some(response) => response.map_err(ProtocolError::CompatibilityError)?,
+            None => call!(
+                self,
+                "option_negotiation",
+                self.milter.option_negotiation(opt_neg)
+            )?,
+        };
+        self.filter.capabilities = response.capabilities;
+        if let Some(ctx) = self.milter.session_context() {
+            ctx.set_options(response.clone());
+        }
+        // Without connect, the session starts with whatever comes next
+        self.after_quit_nc &= !response.protocol.contains(Protocol::NO_CONNECT);
+        self.options = Some(response.clone());
+        self.framed.send(&response.into()).await?;
+        Ok(())
+    }
+
+    /// Abort the current smtp session handling
+    ///
+    /// The client expects no answer to an abort, whatever the milter
+    /// returns is dropped.
+    async fn abort(&mut self) -> Result<Option<EndedBy>, Error<M::Error>> {
+        self.recipients.clear();
+        let result = call!(self, "abort", self.milter.abort());
+        self.tolerate(result)?;
+        if self.in_message {
+            let result = call!(self, "message_reset", self.milter.message_reset());
+            self.tolerate(result)?;
+            self.end_message();
+        }
+
+        if self.config.quit_on_abort {
+            let result = call!(self, "quit", self.milter.quit());
+            self.tolerate(result)?;
+            return Ok(Some(EndedBy::Abort));
+        }
+        Ok(None)
+    }
+
+    /// Quit the session, keeping the connection for the next one.
+    ///
+    /// The negotiated options stay for the next session, which starts with
+    /// a connect like the first one. Only what belongs to the SMTP
+    /// connection ended is reset.
+    async fn quit_nc(&mut self) -> Result<Option<EndedBy>, Error<M::Error>> {
+        if self.bypass.is_none() {
+            let result = call!(self, "quit_nc", self.milter.quit_nc());
+            self.tolerate(result)?;
+        }
+        if self
+            .config
+            .max_messages
+            .is_some_and(|max| self.messages >= max)
+        {
+            debug!("Message limit reached, closing connection");
+            if let Some(stats) = &self.framed.codec().stats {
+                stats.connection_recycled();
+            }
+            return Ok(Some(EndedBy::MessageLimit));
+        }
+        self.after_quit_nc = !self
+            .options
+            .as_ref()
+            .is_some_and(|options| options.protocol.contains(Protocol::NO_CONNECT));
+        self.bypass = None;
+        self.context.clear();
+        self.recipients.clear();
+        Ok(None)
+    }
+
+    /// Reset the per-message state of the session context and count the
+    /// message ended
+    fn end_message(&mut self) {
+        if let Some(ctx) = self.milter.session_context() {
+            ctx.end_message();
+        }
+        self.in_message = false;
+        self.count_message();
+    }
+
+    /// Count a message ended on this connection
+    fn count_message(&mut self) {
+        self.messages += 1;
+        if let Some(stats) = &self.framed.codec().stats {
+            stats.message();
+        }
+    }
+
+    /// Handle the milter's answer or error and respond, see
+    /// [`Self::send_action`]
+    async fn answer(
+        &mut self,
+        result: Result<impl Into<Action>, M::Error>,
+    ) -> Result<(), Error<M::Error>> {
+        let policy = self.config.impl_error_policy;
+        let response: Action = match result {
+            Ok(response) => self.translator().action(response.into()),
+            Err(source) => {
+                let Some(action) = policy.response() else {
+                    return Err(Error::from_app_error(source));
+                };
+                warn!("Milter implementation errored, answering {}", action);
+
+                if policy.close {
+                    self.send_action(action).await?;
+                    return Err(Error::from_app_error(source));
+                }
+                action
+            }
+        };
+
+        self.send_action(response).await
+    }
+
+    /// Send `action` in reply to the current command.
+    ///
+    /// If the client expects no reply to the command, nothing is sent: the
+    /// client would take any answer as the reply to its next command. An
+    /// action other than `Continue` is dropped with a warning then.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    async fn send_action(&mut self, action: Action) -> Result<(), Error<M::Error>> {
+        if self.no_reply {
+            if matches!(action, Action::Continue(_)) {
+                debug!("Skip sending continue, no reply requested");
+            } else {
+                warn!("Dropping {}, no reply requested", action);
+            }
+            return Ok(());
+        }
+
+        self.framed.send(&action.into()).await?;
+        Ok(())
+    }
+
+    /// Await `milter_fn`, sending a progress notification for each of
+    /// `requests` meanwhile
+    async fn with_progress<T>(
+        milter_fn: impl Future<Output = T>,
+        mut requests: Receiver<()>,
+        framed: &mut Framed<RW, &'a mut MilterCodec>,
+    ) -> Result<T, ProtocolError> {
+        pin_mut!(milter_fn);
+        loop {
+            match future::select(milter_fn.as_mut(), requests.next()).await {
+                Either::Left((output, _)) => return Ok(output),
+                Either::Right((Some(()), _)) => {
+                    debug!("Sending progress");
+                    framed.send(&Progress.into()).await?;
+                }
+                // No handle left to request more
+                Either::Right((None, _)) => return Ok(milter_fn.await),
+            }
+        }
+    }
+
+    /// Send the modifications the milter answered end of body with
+    async fn respond_end_of_body(
+        &mut self,
+        result: Result<ModificationResponse, M::Error>,
+    ) -> Result<(), Error<M::Error>> {
+        let responses = match result {
+            Ok(responses) => responses,
+            Err(source) => {
+                // No modifications, just answer the final action
+                return self.answer(Err::<Action, _>(source)).await;
+            }
+        };
+
+        // Downgrade what the client does not support, then filter those
+        // returned mod requests, keep only those which have been set by the
+        // current capabilities.
+        let mut responses = self.filter.apply(self.translator().response(responses));
+
+        // Make sure the complete response fits before sending any of it
+        let limit = self.framed.codec().max_buffer_size();
+        if let Err(err) = responses.check_frame_len(limit) {
+            match self.config.oversize_policy {
+                OversizePolicy::Error => return Err(ProtocolError::from(err).into()),
+                OversizePolicy::DropBodyReplacement => {
+                    warn!("Dropping body replacement exceeding the buffer size");
+                    responses.drop_body_replacement();
+                }
+                OversizePolicy::Split => responses.split_body_replacement(limit),
+            }
+            responses
+                .check_frame_len(limit)
+                .map_err(ProtocolError::from)?;
+        }
+
+        // And send them back
+        let responses: Vec<ServerMessage> = responses.into();
+        for response in responses {
+            debug!("Sending response");
+            self.framed.send(&response).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle the result of a milter call the client expects no answer to
+    fn tolerate<T>(&self, result: Result<T, M::Error>) -> Result<(), Error<M::Error>> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(source) if self.config.impl_error_policy.ends_connection() => {
+                Err(Error::from_app_error(source))
+            }
+            Err(_) => {
+                warn!("Milter implementation errored, ignoring");
+                Ok(())
+            }
+        }
+    }
+
+    /// Downgrades responses to the negotiated protocol
+    fn translator(&self) -> Translator<'_> {
+        Translator::new(&self.config.translation, self.options.as_ref())
+    }
+}
+
+/// Apply `policy` to `command`, returning the action to answer with
+/// instead of calling the milter
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn validate_utf8(policy: Utf8Policy, command: &mut ClientCommand) -> Option<Action> {
+    let fields = policy.fields;
+    let text: &mut dyn TextFields = match command {
+        ClientCommand::Helo(helo) if fields.helo => helo,
+        ClientCommand::Mail(mail) if fields.mail => mail,
+        ClientCommand::Recipient(rcpt) if fields.recipient => rcpt,
+        ClientCommand::Header(header) if fields.header => header,
+        _ => return None,
+    };
+    let Err(err) = text.validate_utf8() else {
+        return None;
+    };
+
+    match policy.action {
+        Utf8Action::Reject => {
+            warn!("Received invalid utf-8, rejecting: {}", err);
+            Some(Reject.into())
+        }
+        Utf8Action::Tempfail => {
+            warn!("Received invalid utf-8, answering tempfail: {}", err);
+            Some(Tempfail.into())
+        }
+        Utf8Action::Replace => {
+            warn!("Received invalid utf-8, replacing: {}", err);
+            text.replace_invalid_utf8();
+            None
+        }
+    }
+}
+
+/// Whether `command` may precede the connect starting the session of a
+/// re-used connection
+///
+/// Macros for the connect stage precede it. The client may re-negotiate
+/// options first, but usually re-uses the previous ones.
+fn starts_session(command: &ClientCommand) -> bool {
+    matches!(
+        command,
+        ClientCommand::OptNeg(_)
+            | ClientCommand::Macro(_)
+            | ClientCommand::Connect(_)
+            | ClientCommand::Abort(_)
+            | ClientCommand::Quit(_)
+            | ClientCommand::QuitNc(_)
+    )
+}
+
+/// Whether `command` is part of a message, from mail to end of body
+fn belongs_to_message(command: &ClientCommand) -> bool {
+    matches!(
+        command,
+        ClientCommand::Mail(_)
+            | ClientCommand::Recipient(_)
+            | ClientCommand::Data(_)
+            | ClientCommand::Header(_)
+            | ClientCommand::EndOfHeader(_)
+            | ClientCommand::Body(_)
+            | ClientCommand::EndOfBody(_)
+    )
+}
+
+/// Whether `command` is answered with an action
+fn expects_answer(command: &ClientCommand) -> bool {
+    matches!(
+        command,
+        ClientCommand::Connect(_)
+            | ClientCommand::Helo(_)
+            | ClientCommand::Mail(_)
+            | ClientCommand::Recipient(_)
+            | ClientCommand::Data(_)
+            | ClientCommand::Header(_)
+            | ClientCommand::EndOfHeader(_)
+            | ClientCommand::Body(_)
+            | ClientCommand::Unknown(_)
+            | ClientCommand::EndOfBody(_)
+    )
+}
+
+/// Whether the client expects no action in reply to `command`, by its
+/// kind or as the negotiated `options` request none
+fn no_reply(options: Option<&OptNeg>, command: &ClientCommand) -> bool {
+    if !expects_answer(command) {
+        return true;
+    }
+    let Some(options) = options else {
+        return false;
+    };
+
+    let flag = match command {
+        ClientCommand::Connect(_) => Protocol::NR_CONNECT,
+        ClientCommand::Helo(_) => Protocol::NR_HELO,
+        ClientCommand::Mail(_) => Protocol::NR_MAIL,
+        ClientCommand::Recipient(_) => Protocol::NR_RECIPIENT,
+        ClientCommand::Data(_) => Protocol::NR_DATA,
+        ClientCommand::Header(_) => Protocol::NR_HEADER,
+        ClientCommand::EndOfHeader(_) => Protocol::NR_END_OF_HEADER,
+        ClientCommand::Body(_) => Protocol::NR_BODY,
+        ClientCommand::Unknown(_) => Protocol::NR_UNKNOWN,
+        _ => return false,
+    };
+
+    options.protocol.contains(flag)
+}
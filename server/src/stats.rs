@@ -0,0 +1,257 @@
+//! Count connections and frames of a server, e.g. to export them as metrics
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Counters shared by all servers created with the same stats, see
+/// [`Server::with_stats`](crate::Server::with_stats).
+///
+/// Clones share the same counters, so one can be handed to each server
+/// and another one to whatever exports them.
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    counters: Arc<[AtomicU64; 9]>,
+}
+
+impl ServerStats {
+    const CONNECTIONS: usize = 0;
+    const ACTIVE: usize = 1;
+    const FAILED: usize = 2;
+    const FRAMES_RECEIVED: usize = 3;
+    const FRAMES_SENT: usize = 4;
+    const SLOW_CALLBACKS: usize = 5;
+    const MESSAGES: usize = 6;
+    const RECYCLED: usize = 7;
+    const DROPPED: usize = 8;
+
+    /// Create counters starting at zero
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connections handled so far, including active ones
+    #[must_use]
+    pub fn connections(&self) -> u64 {
+        self.get(Self::CONNECTIONS)
+    }
+
+    /// Connections currently handled
+    #[must_use]
+    pub fn active_connections(&self) -> u64 {
+        self.get(Self::ACTIVE)
+    }
+
+    /// Connections that ended with an error
+    #[must_use]
+    pub fn failed_connections(&self) -> u64 {
+        self.get(Self::FAILED)
+    }
+
+    /// Frames received from clients
+    #[must_use]
+    pub fn frames_received(&self) -> u64 {
+        self.get(Self::FRAMES_RECEIVED)
+    }
+
+    /// Frames sent to clients
+    #[must_use]
+    pub fn frames_sent(&self) -> u64 {
+        self.get(Self::FRAMES_SENT)
+    }
+
+    /// Milter callbacks exceeding the slow callback threshold, see
+    /// [`Server::with_slow_callback_threshold`](crate::Server::with_slow_callback_threshold)
+    #[must_use]
+    pub fn slow_callbacks(&self) -> u64 {
+        self.get(Self::SLOW_CALLBACKS)
+    }
+
+    /// Messages ended, by end of body or abort
+    #[must_use]
+    pub fn messages(&self) -> u64 {
+        self.get(Self::MESSAGES)
+    }
+
+    /// Connections ended after their maximum number of messages, see
+    /// [`Server::with_max_messages_per_connection`](crate::Server::with_max_messages_per_connection)
+    #[must_use]
+    pub fn recycled_connections(&self) -> u64 {
+        self.get(Self::RECYCLED)
+    }
+
+    /// Modifications dropped as the client did not grant the capability
+    /// for them
+    #[must_use]
+    pub fn dropped_modifications(&self) -> u64 {
+        self.get(Self::DROPPED)
+    }
+
+    /// All counters in the Prometheus text exposition format
+    #[must_use]
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "miltr_connections_total",
+                "counter",
+                "Milter connections handled",
+                self.connections(),
+            ),
+            (
+                "miltr_connections_active",
+                "gauge",
+                "Milter connections currently handled",
+                self.active_connections(),
+            ),
+            (
+                "miltr_connections_failed_total",
+                "counter",
+                "Milter connections ended by an error",
+                self.failed_connections(),
+            ),
+            (
+                "miltr_frames_received_total",
+                "counter",
+                "Frames received from milter clients",
+                self.frames_received(),
+            ),
+            (
+                "miltr_frames_sent_total",
+                "counter",
+                "Frames sent to milter clients",
+                self.frames_sent(),
+            ),
+            (
+                "miltr_slow_callbacks_total",
+                "counter",
+                "Milter callbacks exceeding the slow callback threshold",
+                self.slow_callbacks(),
+            ),
+            (
+                "miltr_messages_total",
+                "counter",
+                "Messages ended by end of body or abort",
+                self.messages(),
+            ),
+            (
+                "miltr_connections_recycled_total",
+                "counter",
+                "Milter connections ended after their maximum number of messages",
+                self.recycled_connections(),
+            ),
+            (
+                "miltr_modifications_dropped_total",
+                "counter",
+                "Modifications dropped for lack of a negotiated capability",
+                self.dropped_modifications(),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            // Writing to a string does not fail
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        }
+        out
+    }
+
+    /// Count a new connection, active until the guard is dropped
+    pub(crate) fn connection(&self) -> ActiveConnection {
+        self.add(Self::CONNECTIONS);
+        self.add(Self::ACTIVE);
+        ActiveConnection {
+            stats: self.clone(),
+        }
+    }
+
+    pub(crate) fn connection_failed(&self) {
+        self.add(Self::FAILED);
+    }
+
+    pub(crate) fn frame_received(&self) {
+        self.add(Self::FRAMES_RECEIVED);
+    }
+
+    pub(crate) fn frame_sent(&self) {
+        self.add(Self::FRAMES_SENT);
+    }
+
+    pub(crate) fn slow_callback(&self) {
+        self.add(Self::SLOW_CALLBACKS);
+    }
+
+    pub(crate) fn message(&self) {
+        self.add(Self::MESSAGES);
+    }
+
+    pub(crate) fn connection_recycled(&self) {
+        self.add(Self::RECYCLED);
+    }
+
+    pub(crate) fn modifications_dropped(&self, count: usize) {
+        self.counters[Self::DROPPED].fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self, counter: usize) -> u64 {
+        self.counters[counter].load(Ordering::Relaxed)
+    }
+
+    fn add(&self, counter: usize) {
+        self.counters[counter].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a connection as active while alive
+pub(crate) struct ActiveConnection {
+    stats: ServerStats,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.counters[ServerStats::ACTIVE].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_connections() {
+        let stats = ServerStats::new();
+
+        let first = stats.connection();
+        let second = stats.connection();
+        drop(first);
+        stats.connection_failed();
+
+        assert_eq!(stats.connections(), 2);
+        assert_eq!(stats.active_connections(), 1);
+        assert_eq!(stats.failed_connections(), 1);
+        drop(second);
+        assert_eq!(stats.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_render() {
+        let stats = ServerStats::new();
+        stats.frame_received();
+        stats.frame_received();
+
+        let rendered = stats.render();
+
+        assert!(rendered.contains("# TYPE miltr_connections_active gauge\n"));
+        assert!(rendered.contains("\nmiltr_frames_received_total 2\n"));
+        assert!(rendered.contains("\nmiltr_frames_sent_total 0\n"));
+        assert!(rendered.contains("\nmiltr_slow_callbacks_total 0\n"));
+        assert!(rendered.ends_with("miltr_modifications_dropped_total 0\n"));
+    }
+}
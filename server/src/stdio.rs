@@ -0,0 +1,56 @@
+//! Serve a single connection over stdin and stdout
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{AsyncRead, AsyncWrite};
+use tokio::io::{Stdin, Stdout};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+/// Stdin and stdout of the process as one connection, as handed over by
+/// inetd or systemd socket activation with `Accept=yes`
+#[derive(Debug)]
+pub(crate) struct Stdio {
+    stdin: Compat<Stdin>,
+    stdout: Compat<Stdout>,
+}
+
+impl Stdio {
+    pub(crate) fn new() -> Self {
+        Self {
+            stdin: tokio::io::stdin().compat(),
+            stdout: tokio::io::stdout().compat_write(),
+        }
+    }
+}
+
+impl AsyncRead for Stdio {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_close(cx)
+    }
+}
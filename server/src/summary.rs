@@ -0,0 +1,67 @@
+//! Sum up a single connection once it ended
+
+use std::{collections::HashMap, time::Duration};
+
+use miltr_common::actions::ActionKind;
+
+/// How a connection ended without error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EndedBy {
+    /// The client sent quit
+    Quit,
+    /// The client aborted while the server quits on abort, see
+    /// [`Server::new`](crate::Server::new)
+    Abort,
+    /// The client sent `quit_nc` after the last message allowed, see
+    /// [`Server::with_max_messages_per_connection`](crate::Server::with_max_messages_per_connection)
+    MessageLimit,
+    /// The client closed the connection without quitting
+    Closed,
+}
+
+/// What happened on a connection, returned by
+/// [`Server::handle_connection`](crate::Server::handle_connection)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    /// Messages ended, by end of body or abort
+    pub messages: u64,
+    /// Actions sent to the client, by kind
+    pub actions: HashMap<ActionKind, u64>,
+    /// How long the connection was handled
+    pub duration: Duration,
+    /// Bytes of all frames received
+    pub bytes_in: u64,
+    /// Bytes of all frames sent
+    pub bytes_out: u64,
+    /// How the connection ended
+    pub ended_by: EndedBy,
+}
+
+impl ConnectionSummary {
+    /// How often an action of `kind` was sent
+    #[must_use]
+    pub fn action_count(&self, kind: ActionKind) -> u64 {
+        self.actions.get(&kind).copied().unwrap_or_default()
+    }
+}
+
+/// Counted by the codec while serving a connection
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tally {
+    pub(crate) actions: HashMap<ActionKind, u64>,
+    pub(crate) bytes_in: u64,
+    pub(crate) bytes_out: u64,
+}
+
+impl Tally {
+    pub(crate) fn received(&mut self, frame_len: usize) {
+        self.bytes_in += frame_len as u64;
+    }
+
+    pub(crate) fn sent(&mut self, frame_len: usize, action: Option<ActionKind>) {
+        self.bytes_out += frame_len as u64;
+        if let Some(kind) = action {
+            *self.actions.entry(kind).or_default() += 1;
+        }
+    }
+}
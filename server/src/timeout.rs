@@ -0,0 +1,123 @@
+//! Give up on milter callbacks taking too long
+
+use std::{collections::HashMap, time::Duration};
+
+use miltr_common::actions::SmtpStage;
+
+/// How long each milter callback may take before the connection is
+/// closed, see [`Server::with_callback_timeouts`](crate::Server::with_callback_timeouts).
+///
+/// MTAs give up on a milter not answering in time, e.g. postfix after its
+/// `milter_command_timeout`. Timing out a bit earlier on the server ends a
+/// stuck callback instead of letting it hang the connection forever.
+///
+/// Callbacks of an SMTP stage use the timeout of that stage, all others,
+/// like `abort` or `macro_`, the default. Without either, a callback may
+/// take as long as it likes.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use miltr_common::actions::SmtpStage;
+/// use miltr_server::CallbackTimeouts;
+///
+/// let timeouts = CallbackTimeouts::new()
+///     .with_default(Duration::from_secs(25))
+///     .with_stage(SmtpStage::EndOfMessage, Duration::from_secs(250))
+///     .with_tempfail(true);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CallbackTimeouts {
+    default: Option<Duration>,
+    stages: HashMap<SmtpStage, Duration>,
+    tempfail: bool,
+}
+
+impl CallbackTimeouts {
+    /// No timeouts at all
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time out callbacks without a timeout of their own after `timeout`
+    #[must_use]
+    pub fn with_default(mut self, timeout: Duration) -> Self {
+        self.default = Some(timeout);
+        self
+    }
+
+    /// Time out the callback of `stage` after `timeout`.
+    ///
+    /// [`SmtpStage::EndOfMessage`] covers `end_of_body`.
+    #[must_use]
+    pub fn with_stage(mut self, stage: SmtpStage, timeout: Duration) -> Self {
+        self.stages.insert(stage, timeout);
+        self
+    }
+
+    /// Answer tempfail to the command of a timed out callback before
+    /// closing the connection.
+    ///
+    /// Off by default, the connection is closed without an answer and the
+    /// MTA applies its own default action.
+    #[must_use]
+    pub fn with_tempfail(mut self, tempfail: bool) -> Self {
+        self.tempfail = tempfail;
+        self
+    }
+
+    /// Whether to answer tempfail on a timeout
+    pub(crate) fn tempfail(&self) -> bool {
+        self.tempfail
+    }
+
+    /// The timeout of the milter method `callback`, if any
+    pub(crate) fn of(&self, callback: &str) -> Option<Duration> {
+        stage_of(callback)
+            .and_then(|stage| self.stages.get(&stage).copied())
+            .or(self.default)
+    }
+}
+
+/// The SMTP stage the milter method `callback` handles
+pub(crate) fn stage_of(callback: &str) -> Option<SmtpStage> {
+    let stage = match callback {
+        "connect" => SmtpStage::Connect,
+        "helo" => SmtpStage::Helo,
+        "mail" => SmtpStage::Mail,
+        "rcpt" => SmtpStage::Rcpt,
+        "data" => SmtpStage::Data,
+        "header" => SmtpStage::Header,
+        "end_of_header" => SmtpStage::EndOfHeader,
+        "body" => SmtpStage::Body,
+        "end_of_body" => SmtpStage::EndOfMessage,
+        "unknown" => SmtpStage::Unknown,
+        _ => return None,
+    };
+    Some(stage)
+}
+
+/// A callback did not finish within its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TimedOut {
+    pub(crate) callback: &'static str,
+    pub(crate) timeout: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_overrides_default() {
+        let timeouts = CallbackTimeouts::new()
+            .with_default(Duration::from_secs(5))
+            .with_stage(SmtpStage::EndOfMessage, Duration::from_secs(60));
+
+        assert_eq!(timeouts.of("end_of_body"), Some(Duration::from_secs(60)));
+        assert_eq!(timeouts.of("header"), Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.of("abort"), Some(Duration::from_secs(5)));
+        assert_eq!(CallbackTimeouts::new().of("connect"), None);
+    }
+}
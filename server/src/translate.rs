@@ -0,0 +1,261 @@
+//! Downgrade responses to what the negotiated protocol supports
+
+use std::{fmt, sync::Arc};
+
+use miltr_common::{
+    actions::{Action, Continue, Tempfail},
+    modifications::{headers::AddHeader, ModificationAction, ModificationResponse},
+    optneg::{Capability, OptNeg, Protocol, ProtocolVersion},
+};
+use miltr_utils::debug;
+
+/// Called with the negotiated version and an already translated action,
+/// returning the action to send instead
+pub type TranslationHook = Arc<dyn Fn(ProtocolVersion, Action) -> Action + Send + Sync>;
+
+/// What to send instead of a quarantine the client does not support
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuarantineFallback {
+    /// Add a header with this name, carrying the quarantine reason
+    AddHeader(String),
+    /// Answer with a temporary failure instead of letting the mail pass
+    Tempfail,
+    /// Drop the quarantine
+    Drop,
+}
+
+impl Default for QuarantineFallback {
+    fn default() -> Self {
+        Self::AddHeader("X-Quarantine".to_string())
+    }
+}
+
+/// Translate milter responses the client can not handle.
+///
+/// Each response has a minimum protocol version it is known since, see
+/// [`ProtocolVersion`]. If the negotiated version is older, the response is
+/// translated:
+///
+/// | Response     | Since version | Translated to            |
+/// |--------------|---------------|--------------------------|
+/// | `Skip`       | 6             | `Continue`               |
+/// | `Quarantine` | 2             | a [`QuarantineFallback`] |
+///
+/// A quarantine is translated as well if the client did not offer
+/// [`Capability::SMFIF_QUARANTINE`], instead of being dropped silently.
+/// Translated modifications are still subject to the negotiated
+/// capabilities.
+#[derive(Clone, Default)]
+pub struct ResponseTranslation {
+    quarantine: QuarantineFallback,
+    hook: Option<TranslationHook>,
+}
+
+impl fmt::Debug for ResponseTranslation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseTranslation")
+            .field("quarantine", &self.quarantine)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
+
+impl ResponseTranslation {
+    /// Translate according to the table, adding quarantines as header
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `fallback` instead of an unsupported quarantine
+    #[must_use]
+    pub fn with_quarantine_fallback(mut self, fallback: QuarantineFallback) -> Self {
+        self.quarantine = fallback;
+        self
+    }
+
+    /// Call `hook` for every action sent, after the table was applied
+    #[must_use]
+    pub fn with_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(ProtocolVersion, Action) -> Action + Send + Sync + 'static,
+    {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Translate a single `action` for `version`
+    pub(crate) fn action(&self, version: ProtocolVersion, action: Action) -> Action {
+        let action = match action {
+            Action::Skip(_)
+                if !version
+                    .supported_protocol_flags()
+                    .contains(Protocol::SMFIP_SKIP) =>
+            {
+                debug!("Translating skip to continue for version {}", version);
+                Continue.into()
+            }
+            action => action,
+        };
+
+        match &self.hook {
+            Some(hook) => hook(version, action),
+            None => action,
+        }
+    }
+
+    /// Translate a complete end of body `response` for `options`
+    pub(crate) fn response(
+        &self,
+        options: &OptNeg,
+        response: ModificationResponse,
+    ) -> ModificationResponse {
+        let quarantine = Capability::SMFIF_QUARANTINE;
+        let quarantine_supported = options
+            .version
+            .supported_capabilities()
+            .contains(quarantine)
+            && options.capabilities.contains(quarantine);
+        let (modifications, final_action) = response.into_parts();
+
+        let mut builder = ModificationResponse::builder();
+        let mut tempfail = false;
+        for modification in modifications {
+            match modification {
+                ModificationAction::Quarantine(quarantine) if !quarantine_supported => {
+                    debug!("Translating unsupported quarantine");
+                    match &self.quarantine {
+                        QuarantineFallback::AddHeader(name) => builder.push(AddHeader::new(
+                            name.as_bytes(),
+                            quarantine.reason().as_bytes(),
+                        )),
+                        QuarantineFallback::Tempfail => tempfail = true,
+                        QuarantineFallback::Drop => {}
+                    }
+                }
+                modification => builder.push(modification),
+            }
+        }
+
+        let mut final_action = self.action(options.version, final_action);
+        if tempfail && matches!(final_action, Action::Continue(_) | Action::Skip(_)) {
+            final_action = Tempfail.into();
+        }
+
+        builder.build(final_action)
+    }
+}
+
+/// The translation applying to a single command
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Translator<'t> {
+    translation: &'t ResponseTranslation,
+    /// `None` before option negotiation, passing everything through
+    options: Option<&'t OptNeg>,
+}
+
+impl<'t> Translator<'t> {
+    pub(crate) fn new(translation: &'t ResponseTranslation, options: Option<&'t OptNeg>) -> Self {
+        Self {
+            translation,
+            options,
+        }
+    }
+
+    pub(crate) fn action(self, action: Action) -> Action {
+        match self.options {
+            Some(options) => self.translation.action(options.version, action),
+            None => action,
+        }
+    }
+
+    pub(crate) fn response(self, response: ModificationResponse) -> ModificationResponse {
+        match self.options {
+            Some(options) => self.translation.response(options, response),
+            None => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miltr_common::{
+        actions::{Reject, Skip},
+        modifications::quarantine::Quarantine,
+    };
+
+    use super::*;
+
+    fn options(version: u32, capabilities: Capability) -> OptNeg {
+        OptNeg {
+            version: version.into(),
+            capabilities,
+            ..Default::default()
+        }
+    }
+
+    fn quarantined() -> ModificationResponse {
+        let mut builder = ModificationResponse::builder();
+        builder.push(Quarantine::new(b"suspicious"));
+        builder.contin()
+    }
+
+    #[test]
+    fn test_skip() {
+        let translation = ResponseTranslation::new();
+
+        let old = translation.action(ProtocolVersion::V2, Skip.into());
+        let new = translation.action(ProtocolVersion::V6, Skip.into());
+
+        assert!(matches!(old, Action::Continue(_)));
+        assert!(matches!(new, Action::Skip(_)));
+    }
+
+    #[test]
+    fn test_quarantine_supported() {
+        let translation = ResponseTranslation::new();
+
+        let response = translation.response(&options(6, Capability::all()), quarantined());
+
+        assert!(matches!(
+            response.modifications(),
+            [ModificationAction::Quarantine(_)]
+        ));
+    }
+
+    #[test]
+    fn test_quarantine_as_header() {
+        let translation = ResponseTranslation::new();
+
+        let response = translation.response(&options(6, Capability::SMFIF_ADDHDRS), quarantined());
+
+        let [ModificationAction::AddHeader(header)] = response.modifications() else {
+            panic!("Quarantine not translated: {response:?}");
+        };
+        assert_eq!(header.name(), "X-Quarantine");
+        assert_eq!(header.value(), "suspicious");
+    }
+
+    #[test]
+    fn test_quarantine_as_tempfail() {
+        let translation =
+            ResponseTranslation::new().with_quarantine_fallback(QuarantineFallback::Tempfail);
+
+        let response = translation.response(&options(1, Capability::all()), quarantined());
+
+        assert!(response.modifications().is_empty());
+        assert!(matches!(response.final_action(), Action::Tempfail(_)));
+    }
+
+    #[test]
+    fn test_hook() {
+        let translation = ResponseTranslation::new().with_hook(|version, action| match action {
+            Action::Continue(_) if version < ProtocolVersion::V6 => Reject.into(),
+            action => action,
+        });
+
+        let action = translation.action(ProtocolVersion::V4, Skip.into());
+
+        assert!(matches!(action, Action::Reject(_)));
+    }
+}
@@ -0,0 +1,127 @@
+//! Report milter callbacks taking longer than expected
+
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use miltr_common::{
+    clock::{Clock, SystemClock},
+    macros::MacroContext,
+};
+use miltr_utils::warn;
+
+use crate::{
+    timeout::{CallbackTimeouts, TimedOut},
+    ServerStats,
+};
+
+/// A milter callback that took longer than the threshold, see
+/// [`Server::with_slow_callback_threshold`](crate::Server::with_slow_callback_threshold)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowCallback {
+    /// The [`Milter`](crate::Milter) method called, e.g. `end_of_body`
+    pub callback: &'static str,
+    /// How long it took
+    pub duration: Duration,
+    /// The queue id of the current message, if the client sent it
+    pub queue_id: Option<String>,
+}
+
+type SlowCallbackHook = Arc<dyn Fn(&SlowCallback) + Send + Sync>;
+
+/// Times milter callbacks against a threshold and their timeouts
+#[derive(Clone)]
+pub(crate) struct Watchdog {
+    pub(crate) threshold: Option<Duration>,
+    pub(crate) hook: Option<SlowCallbackHook>,
+    pub(crate) timeouts: CallbackTimeouts,
+    pub(crate) stats: Option<ServerStats>,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            threshold: None,
+            hook: None,
+            timeouts: CallbackTimeouts::default(),
+            stats: None,
+            clock: SystemClock::shared(),
+        }
+    }
+}
+
+impl Watchdog {
+    /// Whether callbacks are timed at all
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.threshold.is_some()
+    }
+
+    /// Await `callback`, reporting it if it is slow and giving up on it
+    /// after its timeout
+    pub(crate) async fn time<F: Future>(
+        &self,
+        name: &'static str,
+        macros: &MacroContext,
+        callback: F,
+    ) -> Result<F::Output, TimedOut> {
+        let timeout = self.timeouts.of(name);
+        if self.threshold.is_none() && timeout.is_none() {
+            return Ok(callback.await);
+        }
+
+        let start = self.clock.now();
+        let output = match timeout {
+            Some(timeout) => {
+                pin_mut!(callback);
+                match future::select(callback, self.clock.sleep(timeout)).await {
+                    Either::Left((output, _)) => output,
+                    Either::Right(((), _)) => {
+                        warn!("Milter callback {} timed out after {:?}", name, timeout);
+                        return Err(TimedOut {
+                            callback: name,
+                            timeout,
+                        });
+                    }
+                }
+            }
+            None => callback.await,
+        };
+        let duration = self.clock.now().duration_since(start);
+        if self.threshold.is_some_and(|threshold| duration > threshold) {
+            self.report(&SlowCallback {
+                callback: name,
+                duration,
+                queue_id: macros.queue_id().map(String::from),
+            });
+        }
+        Ok(output)
+    }
+
+    fn report(&self, slow: &SlowCallback) {
+        warn!(
+            "Milter callback {} took {}ms, queue id {}",
+            slow.callback,
+            slow.duration.as_millis(),
+            slow.queue_id.as_deref().unwrap_or("unknown")
+        );
+        if let Some(stats) = &self.stats {
+            stats.slow_callback();
+        }
+        if let Some(hook) = &self.hook {
+            hook(slow);
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("threshold", &self.threshold)
+            .field("hook", &self.hook.is_some())
+            .field("timeouts", &self.timeouts)
+            .finish_non_exhaustive()
+    }
+}
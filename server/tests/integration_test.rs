@@ -1,3 +1,5 @@
+//! Integration tests running milters against a local postfix instance
+
 use std::{
     fs,
     path::{Path, PathBuf},
@@ -324,9 +326,13 @@ async fn test_delete_rcpt() {
 /// This quarantines the message into a holding pool (/var/spool/postfix/hold) defined by the MTA.
 /// (First implemented in Sendmail in version 8.13; offered to the milter by
 ///    the `SMFIF_QUARANTINE` flag in "actions" of `SMFIC_OPTNEG`.)
+///
+/// Postfix moves the mail into its hold queue regardless of the reason. The
+/// reason itself only surfaces in the mail log, an empty one is accepted.
 #[derive(Debug, Clone)]
 struct QuarantineTestMilter {
     commands: Vec<String>,
+    reason: &'static [u8],
 }
 
 #[async_trait]
@@ -335,7 +341,7 @@ impl Milter for QuarantineTestMilter {
     async fn end_of_body(&mut self) -> Result<ModificationResponse, Self::Error> {
         self.commands.push("end_of_body".to_string());
         let mut builder = ModificationResponse::builder();
-        builder.push(Quarantine::new("Invalid Email".as_bytes()));
+        builder.push(Quarantine::new(self.reason));
         let response = builder.contin();
         Ok(response)
     }
@@ -344,9 +350,9 @@ impl Milter for QuarantineTestMilter {
         Ok(Continue.into())
     }
 }
-#[tokio::test(flavor = "multi_thread")]
-async fn test_quarantine() {
-    let test_name = "quarantine";
+
+/// Send a mail through `milter` and expect it to end up in the hold queue
+async fn expect_quarantined(milter: QuarantineTestMilter, test_name: &str) {
     let path = BASE_PATH.clone().join(test_name);
 
     let spool_dir = PathBuf::from("/var/spool/postfix/hold");
@@ -357,10 +363,6 @@ async fn test_quarantine() {
             .expect("Failed to empty holding spool");
     }
 
-    let milter = QuarantineTestMilter {
-        commands: Vec::new(),
-    };
-
     let _testcase_guard = TestCase::setup(milter, &path)
         .await
         .expect("Failed setting up test case");
@@ -396,6 +398,26 @@ async fn test_quarantine() {
     result.expect("Can not quarantine");
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_quarantine() {
+    let milter = QuarantineTestMilter {
+        commands: Vec::new(),
+        reason: b"Invalid Email",
+    };
+
+    expect_quarantined(milter, "quarantine").await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_quarantine_empty_reason() {
+    let milter = QuarantineTestMilter {
+        commands: Vec::new(),
+        reason: b"",
+    };
+
+    expect_quarantined(milter, "quarantine_empty_reason").await;
+}
+
 #[derive(Debug)]
 struct MacroRequestTestMilter {
     sender: Sender<Macro>,
@@ -447,6 +469,7 @@ impl Milter for MacroRequestTestMilter {
 /// But we will only send "j","{client_addr}","{client_connections}" in Connect MacroStage (more details in optneg.rs) .
 /// If Milter and Postfix work, we will receive:
 ///Macro { code: b'C', body: b"j\x00localhost\x00{client_addr}\x00127.0.0.1\x00{client_connections}\x000\x00}
+#[allow(clippy::doc_markdown)] // Macro names as listed by postfix
 #[tokio::test(flavor = "multi_thread")]
 async fn test_macro_request() {
     let test_name = "macro_request";
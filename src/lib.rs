@@ -1 +1,11 @@
 #![doc = include_str!("../Readme.md")]
+
+pub mod prelude;
+
+pub use miltr_common as common;
+
+#[cfg(feature = "client")]
+pub use miltr_client as client;
+
+#[cfg(feature = "server")]
+pub use miltr_server as server;
@@ -0,0 +1,17 @@
+//! The items most milters and MTAs need, `use miltr::prelude::*;`
+
+pub use miltr_common::{
+    actions::{Action, Continue, Discard, Reject, Replycode, Skip, Tempfail},
+    commands::{Body, Connect, Family, Header, Helo, Mail, Recipient},
+    modifications::ModificationResponse,
+    optneg::{Capability, OptNeg, Protocol},
+    ProtocolError,
+};
+
+#[cfg(feature = "client")]
+pub use miltr_client::{Client, Connection, ResponseError};
+
+#[cfg(feature = "server")]
+pub use async_trait::async_trait;
+#[cfg(feature = "server")]
+pub use miltr_server::{milter_fn, Milter, Server};
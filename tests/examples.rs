@@ -1,4 +1,5 @@
 #[test]
+#[allow(clippy::zombie_processes)] // The test process exits right after
 fn client_v_server() {
     println!("Building and spawn the server");
     let server = escargot::CargoBuild::new()
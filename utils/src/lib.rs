@@ -1,6 +1,7 @@
 #![doc = include_str!("../Readme.md")]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-use std::mem::size_of;
+use core::mem::size_of;
 
 use bytes::{Buf, BytesMut};
 
@@ -11,6 +12,19 @@ pub trait ByteParsing {
     /// Return the split off bytes without the delimiter
     fn delimited(&mut self, delimiter: u8) -> Option<BytesMut>;
 
+    /// Split off two consecutive fields, each terminated by `delimiter`.
+    ///
+    /// This is the `name\0value\0` layout used by many milter packets.
+    /// Returns both fields without their delimiters. If not both delimiters
+    /// are present, `None` is returned and `self` is left untouched.
+    fn delimited_pair(&mut self, delimiter: u8) -> Option<(BytesMut, BytesMut)>;
+
+    /// Split off the bytes up to the next null byte.
+    ///
+    /// Return the split off bytes without the null byte. They are not
+    /// checked to be valid UTF-8.
+    fn take_null_terminated(&mut self) -> Option<BytesMut>;
+
     /// Bounds checked variant of [`bytes::BytesMut::split_to`]
     fn safe_split_to(&mut self, at: usize) -> Option<BytesMut>;
 
@@ -28,10 +42,21 @@ impl ByteParsing for BytesMut {
     fn delimited(&mut self, delimiter: u8) -> Option<BytesMut> {
         let index = self.iter().position(|&b| b == delimiter)?;
 
-        let off = self.split_to(index);
-        self.advance(1);
+        Some(split_delimited(self, index))
+    }
+
+    fn delimited_pair(&mut self, delimiter: u8) -> Option<(BytesMut, BytesMut)> {
+        let first = self.iter().position(|&b| b == delimiter)?;
+        let second = self[first + 1..].iter().position(|&b| b == delimiter)?;
 
-        Some(off)
+        let first = split_delimited(self, first);
+        let second = split_delimited(self, second);
+
+        Some((first, second))
+    }
+
+    fn take_null_terminated(&mut self) -> Option<BytesMut> {
+        self.delimited(0)
     }
 
     fn safe_split_to(&mut self, at: usize) -> Option<Self> {
@@ -42,7 +67,7 @@ impl ByteParsing for BytesMut {
     }
 
     fn safe_split_off(&mut self, at: usize) -> Option<Self> {
-        if at > self.capacity() {
+        if at > self.len() {
             return None;
         }
         Some(self.split_off(at))
@@ -63,6 +88,16 @@ impl ByteParsing for BytesMut {
     }
 }
 
+/// Split off `index` bytes and skip the delimiter following them.
+///
+/// The caller must ensure a delimiter is present at `index`.
+fn split_delimited(buffer: &mut BytesMut, index: usize) -> BytesMut {
+    let off = buffer.split_to(index);
+    buffer.advance(1);
+
+    off
+}
+
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)+) => {
@@ -73,6 +108,16 @@ macro_rules! debug {
     }
 }
 
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        {
+            tracing::warn!($($arg)+);
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! trace {
     ($($arg:tt)+) => {
@@ -82,3 +127,66 @@ macro_rules! trace {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_safe_split_off_respects_len() {
+        let mut buffer = BytesMut::with_capacity(64);
+        buffer.extend_from_slice(b"abc");
+
+        assert!(buffer.safe_split_off(10).is_none());
+        assert_eq!(buffer, BytesMut::from("abc"));
+
+        let off = buffer.safe_split_off(3).expect("Split at len is in bounds");
+        assert!(off.is_empty());
+
+        let off = buffer.safe_split_off(1).expect("Split inside is in bounds");
+        assert_eq!(off, BytesMut::from("bc"));
+        assert_eq!(buffer, BytesMut::from("a"));
+    }
+
+    #[test]
+    fn test_delimited_pair() {
+        let mut buffer = BytesMut::from("name\0value\0rest");
+
+        let (name, value) = buffer.delimited_pair(0).expect("Pair not found");
+
+        assert_eq!(name, BytesMut::from("name"));
+        assert_eq!(value, BytesMut::from("value"));
+        assert_eq!(buffer, BytesMut::from("rest"));
+    }
+
+    #[test]
+    fn test_delimited_pair_empty_fields() {
+        let mut buffer = BytesMut::from("\0\0");
+
+        let (name, value) = buffer.delimited_pair(0).expect("Pair not found");
+
+        assert!(name.is_empty());
+        assert!(value.is_empty());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_delimited_pair_missing_delimiter_untouched() {
+        for input in ["name\0value", "namevalue", ""] {
+            let mut buffer = BytesMut::from(input);
+
+            assert!(buffer.delimited_pair(0).is_none());
+            assert_eq!(buffer, BytesMut::from(input));
+        }
+    }
+
+    #[test]
+    fn test_take_null_terminated() {
+        let mut buffer = BytesMut::from("first\0second");
+
+        let first = buffer.take_null_terminated().expect("Missing string");
+        assert_eq!(first, BytesMut::from("first"));
+        assert!(buffer.take_null_terminated().is_none());
+        assert_eq!(buffer, BytesMut::from("second"));
+    }
+}